@@ -0,0 +1,97 @@
+//! Packet builders shared by the benches.
+
+#![allow(dead_code)]
+
+use bytes::BytesMut;
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::wire::{
+    Icmpv4Packet, Icmpv4Repr, IpAddress, IpProtocol, Ipv4Address, Ipv4Packet, Ipv4Repr, TcpControl, TcpPacket,
+    TcpRepr, TcpSeqNumber, UdpPacket, UdpRepr,
+};
+
+/// Builds a client SYN from 10.11.12.2:`src_port` to 1.2.3.4:443.
+pub fn build_syn(src_port: u16) -> BytesMut {
+    build_tcp(src_port, TcpControl::Syn, None, &[])
+}
+
+/// Builds a client segment from 10.11.12.2:`src_port` to 1.2.3.4:443.
+pub fn build_tcp(src_port: u16, control: TcpControl, ack_number: Option<TcpSeqNumber>, payload: &[u8]) -> BytesMut {
+    let src_addr = Ipv4Address::new(10, 11, 12, 2);
+    let dst_addr = Ipv4Address::new(1, 2, 3, 4);
+    let caps = ChecksumCapabilities::default();
+    let tcp_repr = TcpRepr {
+        src_port,
+        dst_port: 443,
+        control,
+        seq_number: TcpSeqNumber(1000),
+        ack_number,
+        window_len: 64240,
+        window_scale: None,
+        max_seg_size: (control == TcpControl::Syn).then_some(1460),
+        sack_permitted: false,
+        sack_ranges: [None, None, None],
+        payload,
+    };
+    let ip_repr = Ipv4Repr {
+        src_addr,
+        dst_addr,
+        next_header: IpProtocol::Tcp,
+        payload_len: tcp_repr.buffer_len(),
+        hop_limit: 64,
+    };
+    let mut buf = vec![0u8; 20 + tcp_repr.buffer_len()];
+    let mut ip = Ipv4Packet::new_unchecked(&mut buf);
+    ip_repr.emit(&mut ip, &caps);
+    let mut tcp = TcpPacket::new_unchecked(ip.payload_mut());
+    tcp_repr.emit(&mut tcp, &IpAddress::Ipv4(src_addr), &IpAddress::Ipv4(dst_addr), &caps);
+    BytesMut::from(&buf[..])
+}
+
+/// Builds a 64-byte UDP datagram from 10.11.12.2:5353 to 1.2.3.4:53.
+pub fn build_udp() -> BytesMut {
+    let src_addr = Ipv4Address::new(10, 11, 12, 2);
+    let dst_addr = Ipv4Address::new(1, 2, 3, 4);
+    let caps = ChecksumCapabilities::default();
+    let payload = [0u8; 64];
+    let ip_repr = Ipv4Repr {
+        src_addr,
+        dst_addr,
+        next_header: IpProtocol::Udp,
+        payload_len: 8 + payload.len(),
+        hop_limit: 64,
+    };
+    let udp_repr = UdpRepr { src_port: 5353, dst_port: 53 };
+    let mut buf = vec![0u8; 20 + 8 + payload.len()];
+    let mut ip = Ipv4Packet::new_unchecked(&mut buf);
+    ip_repr.emit(&mut ip, &caps);
+    let mut udp = UdpPacket::new_unchecked(ip.payload_mut());
+    udp_repr.emit(
+        &mut udp,
+        &IpAddress::Ipv4(src_addr),
+        &IpAddress::Ipv4(dst_addr),
+        payload.len(),
+        |buf| buf.copy_from_slice(&payload),
+        &caps,
+    );
+    BytesMut::from(&buf[..])
+}
+
+/// Builds an echo request from 10.11.12.2 to the gateway (10.11.12.1), which smoltcp answers.
+pub fn build_gateway_ping(seq_no: u16) -> BytesMut {
+    let src_addr = Ipv4Address::new(10, 11, 12, 2);
+    let dst_addr = Ipv4Address::new(10, 11, 12, 1);
+    let caps = ChecksumCapabilities::default();
+    let icmp_repr = Icmpv4Repr::EchoRequest { ident: 7, seq_no, data: &[0u8; 56] };
+    let ip_repr = Ipv4Repr {
+        src_addr,
+        dst_addr,
+        next_header: IpProtocol::Icmp,
+        payload_len: icmp_repr.buffer_len(),
+        hop_limit: 64,
+    };
+    let mut buf = vec![0u8; 20 + icmp_repr.buffer_len()];
+    let mut ip = Ipv4Packet::new_unchecked(&mut buf);
+    ip_repr.emit(&mut ip, &caps);
+    icmp_repr.emit(&mut Icmpv4Packet::new_unchecked(ip.payload_mut()), &caps);
+    BytesMut::from(&buf[..])
+}
@@ -0,0 +1,77 @@
+//! Idle-tunnel benchmark for the egress pump.
+//!
+//! Opens many idle tunnels, then pushes a burst of blind-relayed UDP through the stack.
+//! None of that traffic touches a socket, so with the idle skip the loop never scans
+//! `active_tunnels`; with `always_pump_egress` every wake-up walks all of them.
+//!
+//! Run with `cargo bench --bench idle_pump`.
+
+mod common;
+
+use common::{build_syn, build_udp};
+use prism::device::PrismDevice;
+use prism::stack::{PrismConfig, PrismStack};
+use smoltcp::phy::Medium;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+const IDLE_TUNNELS: u16 = 2000;
+const UDP_PACKETS: usize = 50_000;
+
+async fn run_once(always_pump_egress: bool) -> Duration {
+    let (os_tx, os_rx) = mpsc::channel(1024);
+    let (tun_tx, mut tun_rx) = mpsc::channel(4096);
+    let device = PrismDevice::new(os_rx, tun_tx, 1500, Medium::Ip);
+    let mut stack = PrismStack::new(device, PrismConfig {
+        always_pump_egress,
+        ..Default::default()
+    });
+
+    let (req_tx, mut req_rx) = mpsc::channel(IDLE_TUNNELS as usize);
+    let (relay_tx, mut relay_rx) = mpsc::channel(1024);
+    stack.set_tunnel_request_sender(req_tx);
+    stack.set_blind_relay_sender(relay_tx);
+    tokio::spawn(stack.run());
+    // Whatever the stack writes back to the TUN is irrelevant here
+    tokio::spawn(async move { while tun_rx.recv().await.is_some() {} });
+
+    // Open the idle tunnels and keep the relayer halves alive
+    let mut tunnels = Vec::with_capacity(IDLE_TUNNELS as usize);
+    for port in 0..IDLE_TUNNELS {
+        os_tx.send(build_syn(20000 + port)).await.unwrap();
+        tunnels.push(req_rx.recv().await.unwrap());
+    }
+
+    let udp = build_udp();
+    let start = Instant::now();
+    let producer = {
+        let os_tx = os_tx.clone();
+        tokio::spawn(async move {
+            for _ in 0..UDP_PACKETS {
+                os_tx.send(udp.clone()).await.unwrap();
+            }
+        })
+    };
+    for _ in 0..UDP_PACKETS {
+        relay_rx.recv().await.unwrap();
+    }
+    let elapsed = start.elapsed();
+    producer.await.unwrap();
+    drop(tunnels);
+    elapsed
+}
+
+fn main() {
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    for always_pump_egress in [true, false] {
+        let elapsed = rt.block_on(run_once(always_pump_egress));
+        println!(
+            "always_pump_egress={:<5} {} idle tunnels, {} relayed packets: {:?} ({:.0} ns/packet)",
+            always_pump_egress,
+            IDLE_TUNNELS,
+            UDP_PACKETS,
+            elapsed,
+            elapsed.as_nanos() as f64 / UDP_PACKETS as f64,
+        );
+    }
+}
@@ -0,0 +1,90 @@
+//! Egress pump cost with many tunnels but only a handful of busy ones.
+//!
+//! Opens 10k tunnels, then streams segments on just a few of them. The stack only pumps
+//! tunnels that were handed packets, so the sockets visited stay proportional to the
+//! traffic; `always_pump_egress` walks all 10k on every wake-up for comparison.
+//!
+//! Run with `cargo bench --bench sparse_pump`.
+
+mod common;
+
+use common::{build_syn, build_tcp, build_udp};
+use prism::device::PrismDevice;
+use prism::stack::{PrismConfig, PrismStack};
+use smoltcp::phy::Medium;
+use smoltcp::wire::{TcpControl, TcpSeqNumber};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+const TUNNELS: u16 = 10_000;
+const BUSY_TUNNELS: u16 = 4;
+const SEGMENTS: usize = 20_000;
+
+struct Run {
+    elapsed: Duration,
+    visited: u64,
+}
+
+async fn run_once(always_pump_egress: bool) -> Run {
+    let (os_tx, os_rx) = mpsc::channel(1024);
+    let (tun_tx, mut tun_rx) = mpsc::channel(4096);
+    let device = PrismDevice::new(os_rx, tun_tx, 1500, Medium::Ip);
+    let mut stack = PrismStack::new(device, PrismConfig {
+        always_pump_egress,
+        ..Default::default()
+    });
+
+    let (req_tx, mut req_rx) = mpsc::channel(TUNNELS as usize);
+    let (relay_tx, mut relay_rx) = mpsc::channel(16);
+    stack.set_tunnel_request_sender(req_tx);
+    stack.set_blind_relay_sender(relay_tx);
+    let stats = stack.stats();
+    tokio::spawn(stack.run());
+    tokio::spawn(async move { while tun_rx.recv().await.is_some() {} });
+
+    let mut tunnels = Vec::with_capacity(TUNNELS as usize);
+    for port in 0..TUNNELS {
+        os_tx.send(build_syn(20000 + port)).await.unwrap();
+        tunnels.push(req_rx.recv().await.unwrap());
+    }
+
+    let segments: Vec<_> = (0..BUSY_TUNNELS)
+        .map(|i| build_tcp(20000 + i, TcpControl::None, Some(TcpSeqNumber(1)), &[0u8; 512]))
+        .collect();
+    let before = stats.snapshot().egress_sockets_visited;
+    let start = Instant::now();
+    for i in 0..SEGMENTS {
+        os_tx.send(segments[i % segments.len()].clone()).await.unwrap();
+    }
+    // Packets are handled in order, so the relayed sentinel marks the end of the run
+    os_tx.send(build_udp()).await.unwrap();
+    relay_rx.recv().await.unwrap();
+    let elapsed = start.elapsed();
+    let visited = stats.snapshot().egress_sockets_visited - before;
+    drop(tunnels);
+    Run { elapsed, visited }
+}
+
+fn main() {
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    let mut runs = Vec::new();
+    for always_pump_egress in [true, false] {
+        let run = rt.block_on(run_once(always_pump_egress));
+        println!(
+            "always_pump_egress={:<5} {} tunnels ({} busy), {} segments: {:?}, {} sockets visited ({:.2} per segment)",
+            always_pump_egress,
+            TUNNELS,
+            BUSY_TUNNELS,
+            SEGMENTS,
+            run.elapsed,
+            run.visited,
+            run.visited as f64 / SEGMENTS as f64,
+        );
+        runs.push(run);
+    }
+
+    // Dirty tracking: at most one socket per segment, plus the odd periodic sweep
+    let (full, dirty) = (&runs[0], &runs[1]);
+    assert!(dirty.visited <= SEGMENTS as u64 + 2 * TUNNELS as u64, "dirty pump visited {}", dirty.visited);
+    assert!(dirty.visited * 10 < full.visited, "dirty {} vs full {}", dirty.visited, full.visited);
+}
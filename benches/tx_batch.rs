@@ -0,0 +1,59 @@
+//! TX channel overhead with and without `tx_batch`.
+//!
+//! Floods the gateway with pings, which smoltcp answers itself, so every packet in produces
+//! one packet out towards the TUN. The writer drains the TX channel with `recv_many` like a
+//! batching TUN writer would.
+//!
+//! Run with `cargo bench --bench tx_batch`.
+
+mod common;
+
+use common::build_gateway_ping;
+use prism::device::PrismDevice;
+use prism::stack::{PrismConfig, PrismStack};
+use smoltcp::phy::Medium;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+const PINGS: usize = 200_000;
+
+async fn run_once(tx_batch: usize) -> Duration {
+    let (os_tx, os_rx) = mpsc::channel(1024);
+    // Room for every reply: a full TX channel drops packets, which would skew the count
+    let (tun_tx, mut tun_rx) = mpsc::channel(PINGS);
+    let device = PrismDevice::new(os_rx, tun_tx, 1500, Medium::Ip);
+    let stack = PrismStack::new(device, PrismConfig { tx_batch, ..Default::default() });
+    tokio::spawn(stack.run());
+
+    let start = Instant::now();
+    let producer = tokio::spawn(async move {
+        for seq_no in 0..PINGS {
+            os_tx.send(build_gateway_ping(seq_no as u16)).await.unwrap();
+        }
+        os_tx
+    });
+    let mut replies = 0;
+    let mut buf = Vec::with_capacity(64);
+    while replies < PINGS {
+        replies += tun_rx.recv_many(&mut buf, 64).await;
+        buf.clear();
+    }
+    let elapsed = start.elapsed();
+    drop(producer.await.unwrap());
+    elapsed
+}
+
+fn main() {
+    let rt = tokio::runtime::Builder::new_multi_thread().worker_threads(2).enable_all().build().unwrap();
+    for tx_batch in [1, 8, 32, 64] {
+        let elapsed = rt.block_on(run_once(tx_batch));
+        println!(
+            "tx_batch={:<3} {} pings: {:?} ({:.0} ns/packet, {:.2} Mpps)",
+            tx_batch,
+            PINGS,
+            elapsed,
+            elapsed.as_nanos() as f64 / PINGS as f64,
+            PINGS as f64 / elapsed.as_secs_f64() / 1e6,
+        );
+    }
+}
@@ -1,9 +1,7 @@
 use tun_rs::DeviceBuilder;
 use std::io;
-use smoltcp::phy::Medium;
-use tokio::sync::mpsc;
-use bytes::{Bytes, BytesMut};
-use prism::stack::{PrismStack, PrismConfig, HandshakeMode};
+use bytes::Bytes;
+use prism::stack::{PrismStack, PrismConfig, HandshakeMode, OffloadMode};
 use prism::device::PrismDevice;
 use std::sync::Arc;
 use clap::Parser;
@@ -74,92 +72,25 @@ async fn main() -> io::Result<()> {
     println!("✅ TUN Device Created: {} (IP: 10.11.12.1, IPv6: fd00::1)", dev.name().unwrap_or("unknown".to_string()));
     let dev = Arc::new(dev); // Wrap in Arc for shared access
     
-    // 2. Setup Prism Channels (TUN <-> Stack)
-    let (tun_tx, mut tun_rx) = mpsc::channel::<Bytes>(8192); // Stack -> OS
-    let (os_tx, os_rx) = mpsc::channel::<BytesMut>(8192); // OS -> Stack (BytesMut for Zero-Copy)
-
-    // Spawn Bridge Tasks
-    // Reader Task
-    let reader_dev = dev.clone();
-    let reader_offload = args.offload;
-    tokio::spawn(async move {
-        // Optimization A: Buffer Reuse (Smart Batching)
-        let mut buf = BytesMut::with_capacity(1024 * 1024);
-        
-        // Extra space for virtio_net_hdr when offload is enabled
-        let extra_hdr = if reader_offload { prism::constants::VIRTIO_NET_HDR_SIZE } else { 0 };
-        
-        loop {
-            if buf.capacity() < 65535 + extra_hdr {
-                buf.reserve(65535 + extra_hdr);
-            }
-            
-            unsafe { buf.set_len(65535 + extra_hdr) };
-            
-            match reader_dev.recv(&mut buf).await {
-                Ok(n) => {
-                    if n > 0 {
-                         unsafe { buf.set_len(n) };
-                         
-                         // Linux GSO: Strip virtio_net_hdr before passing to stack
-                         let packet = if reader_offload && n > prism::constants::VIRTIO_NET_HDR_SIZE {
-                             let stripped = buf.split_to(n);
-                             BytesMut::from(&stripped[prism::constants::VIRTIO_NET_HDR_SIZE..])
-                         } else {
-                             buf.split_to(n)
-                         };
-                         
-                         if os_tx.send(packet).await.is_err() { break; }
-                    }
-                }
-                Err(e) => {
-                    eprintln!("TUN Read Error: {}", e);
-                    break;
-                }
-            }
-        }
-    });
-
-    // Writer Task
-    let writer_dev = dev.clone();
-    let writer_offload = args.offload;
-    tokio::spawn(async move {
-        while let Some(pkt) = tun_rx.recv().await {
-            // Linux GSO: Prepend virtio_net_hdr for TX
-            let to_send: bytes::Bytes = if writer_offload {
-                #[cfg(target_os = "linux")]
-                {
-                    prism::offload::prepend_virtio_hdr_csum(&pkt).freeze()
-                }
-                #[cfg(not(target_os = "linux"))]
-                {
-                    pkt
-                }
-            } else {
-                pkt
-            };
-            if let Err(e) = writer_dev.send(&to_send).await {
-                eprintln!("TUN Write Error: {}", e);
-            }
-        }
-    });
+    // 2. Bridge TUN <-> Stack
+    // The crate spawns the reader/writer tasks; if the TUN fails for good (device removed),
+    // the bridge closes the link and `stack.run()` returns the error.
+    let device = PrismDevice::run_tun_bridge(dev, args.mtu);
+    let tun_tx = device.tx_queue.clone(); // Blind Relay echoes go back to the TUN
 
     // 3. Create Prism Stack
     let config = PrismConfig {
         handshake_mode,
         egress_mtu: args.egress_mtu,
-        linux_offload: args.offload,
+        offload: if args.offload { OffloadMode::Gso } else { OffloadMode::Off },
+        ..Default::default()
     };
     
-    let device = PrismDevice::new(os_rx, tun_tx.clone(), args.mtu, Medium::Ip);
     let mut stack = PrismStack::new(device, config);
     
     // 4. Setup Tunnel Request Handling AND Blind Relay
-    let (req_tx, mut req_rx) = mpsc::channel(128);
-    stack.set_tunnel_request_sender(req_tx);
-    
-    let (blind_tx, mut blind_rx) = mpsc::channel(8192);
-    stack.set_blind_relay_sender(blind_tx);
+    let mut req_rx = stack.tunnel_request_channel();
+    let mut blind_rx = stack.blind_relay_channel();
     
     // Stack Runner
     tokio::spawn(async move {
@@ -174,7 +105,7 @@ async fn main() -> io::Result<()> {
         let mut tunnel_count = 0;
         while let Some(req) = req_rx.recv().await {
             tunnel_count += 1;
-            println!("[TCP #{}] New Connection: {}", tunnel_count, req.target);
+            println!("[TCP #{}] New Connection: {} (id {})", tunnel_count, req.target, req.id);
             
             if let Some(resp) = req.response_tx {
                 let _ = resp.send(true);
@@ -295,6 +226,14 @@ async fn main() -> io::Result<()> {
                             _ => {}
                         }
                         
+                        // With offload on, everything written to the TUN needs a virtio_net_hdr
+                        #[cfg(target_os = "linux")]
+                        let response = if args.offload {
+                            prism::offload::prepend_virtio_hdr_none(&response).to_vec()
+                        } else {
+                            response
+                        };
+
                         // Send back to TUN (Echo)
                         let _ = tun_tx.send(Bytes::from(response)).await;
                     }
@@ -0,0 +1,28 @@
+//! Allocation hook for the stack's large buffers.
+
+use bytes::BytesMut;
+
+/// Allocates the buffers Prism creates on its hot paths: tunnel socket rings (2 per tunnel)
+/// and the TX arenas packets are carved from. Plug in an implementation to serve them from
+/// a pool or a pre-faulted region; see [`PrismDevice::with_allocator`](crate::device::PrismDevice::with_allocator).
+///
+/// The default methods use the global allocator, i.e. the behavior without a hook. Buffers
+/// are handed out as `Vec<u8>`/`BytesMut`, so their memory still has to come from the global
+/// allocator; a hook decides *when* and *how much* is allocated, and can recycle.
+pub trait BufferAllocator: Send + Sync {
+    /// A zeroed buffer of exactly `len` bytes for a tunnel socket's rx or tx ring.
+    fn socket_buffer(&self, len: usize) -> Vec<u8> {
+        vec![0; len]
+    }
+
+    /// An empty arena with at least `capacity` bytes of capacity for outgoing packets.
+    fn tx_arena(&self, capacity: usize) -> BytesMut {
+        BytesMut::with_capacity(capacity)
+    }
+}
+
+/// [`BufferAllocator`] backed by the global allocator.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GlobalBufferAllocator;
+
+impl BufferAllocator for GlobalBufferAllocator {}
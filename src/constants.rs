@@ -1,15 +1,55 @@
+use std::time::Duration;
+
 /// Maximum number of packets to process per event-loop wakeup.
 /// Higher values reduce context switching overhead but increase latency jitter.
+/// Default of `PrismConfig::rx_batch`; also the TUN bridge's read and write batch.
 pub const BATCH_SIZE: usize = 64;
 
-/// Internal mpsc channel queue depth for TUN <-> Stack communication.
+/// Max remote -> client messages handled per wake-up before smoltcp is polled. `SelectAll`
+/// hands out ready tunnels round-robin, so one batch serves up to this many distinct tunnels
+/// instead of one message per (expensive) `iface.poll`.
+pub const INGRESS_BATCH_SIZE: usize = 16;
+
+/// Max extra smoltcp polls per wake-up while a poll keeps reporting socket changes (the pump
+/// just fed sockets new data or state to send). Bounds the inline re-polling so a burst can't
+/// starve the other event sources.
+pub const MAX_REPOLLS: usize = 4;
+
+/// Blind Relay packets held by the stack while the relay channel is full
+/// (`BlindRelayPolicy::DropOldest`). Beyond that the oldest one is dropped.
+pub const BLIND_RELAY_BACKLOG: usize = 256;
+
+/// Default of `PrismConfig::pending_packets_cap`: packets queued for smoltcp's next poll.
+/// A poll always drains the queue, so only a pathological burst between two polls reaches it.
+pub const PENDING_PACKETS_CAP: usize = 4096;
+
+/// How far `PendingOverflowPolicy::DropOldest` looks into a full packet queue for one that
+/// isn't a SYN. Keeps a queue flooded with SYNs from costing a full scan per packet.
+pub const PENDING_DROP_SCAN: usize = 64;
+
+/// Default of `PrismConfig::admission_queue_cap`: SYNs waiting for `admit_per_poll`. Past it
+/// a SYN is refused with a RST (`syns_queue_dropped`).
+pub const ADMISSION_QUEUE_CAP: usize = 1024;
+
+/// Tunnel requests held by the stack while the relayer's request channel is full, and sent
+/// as it frees up. Beyond that a new connection is refused (`setup_request_rejected`).
+pub const TUNNEL_REQUEST_BACKLOG: usize = 64;
+
+/// Internal mpsc channel queue depth for TUN <-> Stack communication. Also the default of
+/// `PrismConfig::blind_relay_depth`.
 pub const CHANNEL_SIZE: usize = 8192;
 
-/// Single TCP connection receive buffer size.
+/// Failed TUN writes in a row after which `PrismDevice::spawn_tun_bridge` gives up on the
+/// device (removed, gone down) and closes the link. Isolated failures only drop the packet.
+pub const TUN_WRITE_ERROR_LIMIT: usize = 32;
+
+/// Default receive buffer of a tunnel socket (`PrismConfig::tcp_rx_buffer`).
 /// Large buffers (2MB) are needed to saturate high Bandwidth-Delay Product (BDP) links (10Gbps).
+/// The window only grows past 64KB when the client's SYN offers window scaling; smoltcp then
+/// answers with a shift of 6, enough to advertise the whole buffer.
 pub const TCP_RX_BUFFER_SIZE: usize = 2 * 1024 * 1024;
 
-/// Single TCP connection send buffer size.
+/// Default send buffer of a tunnel socket (`PrismConfig::tcp_tx_buffer`).
 pub const TCP_TX_BUFFER_SIZE: usize = 2 * 1024 * 1024;
 
 /// TX buffer pool pre-allocation count.
@@ -24,9 +64,89 @@ pub const TX_POOL_RECYCLE_THRESHOLD: usize = 2048;
 /// Arena allocation chunk size for TX buffers (64KB = one Jumbo Frame).
 pub const TX_ARENA_SIZE: usize = 65535;
 
+/// How often the stack sweeps all tunnels for sockets that closed on a timer.
+/// Between sweeps only tunnels that received packets are visited.
+pub const TUNNEL_REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The socket set is compacted on a tunnel sweep once it spans at least this many slots...
+pub const SOCKET_COMPACT_MIN_SLOTS: usize = 256;
+
+/// ...and its highest live socket sits below 1/`SOCKET_COMPACT_RATIO` of them. smoltcp never
+/// shrinks the set on its own, so after a spike it keeps one slot per peak connection.
+pub const SOCKET_COMPACT_RATIO: usize = 4;
+
+/// How long a tunnel's `FlowPeek` waits for more client data once the first bytes arrived
+/// short of `PrismConfig::peek_bytes`. A client that sends its opening in a few segments (a
+/// large TLS ClientHello) is usually done well within it.
+pub const PEEK_WINDOW: Duration = Duration::from_millis(50);
+
+/// How long an admitted SYN is remembered. A SYN for the same 4-tuple within this window is a
+/// retransmit and goes to the existing socket; after it, the flow is treated as new. A refused
+/// SYN isn't remembered, so its retransmits are handled afresh.
+pub const SYN_CACHE_TTL: Duration = Duration::from_secs(4);
+
+/// ICMP errors the gateway generates itself (`reject_unsupported`, `OversizePolicy::Reject`)
+/// share one token bucket: up to this many at once, refilled over `ICMP_ERROR_WINDOW`. Past
+/// it the offending packet is just dropped (RFC 1812 4.3.2.8, RFC 4443 2.4 (f)).
+pub const ICMP_ERROR_BURST: u32 = 16;
+
+/// See `ICMP_ERROR_BURST`.
+pub const ICMP_ERROR_WINDOW: Duration = Duration::from_secs(1);
+
+/// A UDP tunnel (`PrismConfig::udp_trap_ports`) that carried no datagram either way for this
+/// long is closed on the next tunnel sweep; the flow's next datagram opens a new one.
+pub const UDP_TUNNEL_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Default of `PrismConfig::max_udp_tunnels`: UDP tunnels open at once. Datagrams of further
+/// flows go to the Blind Relay (`udp_tunnels_over_limit`).
+pub const MAX_UDP_TUNNELS: usize = 1024;
+
+/// IPv4 TCP segments being reassembled at once; fragments of further ones are dropped.
+pub const IPV4_REASSEMBLY_MAX_PACKETS: usize = 64;
+
+/// An IPv4 packet still missing fragments this long after its first one is dropped (checked on
+/// the tunnel sweep). Short, as the sender retransmits the whole segment anyway.
+pub const IPV4_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A path MTU reported through a `PathMtuReporter` lowers the MSS clamp of new connections to
+/// its destination for this long (the RFC 1191 aging interval), then the configured clamp
+/// applies again.
+pub const PATH_MTU_TTL: Duration = Duration::from_secs(600);
+
+/// Destinations with a reported path MTU kept at once; reports for further ones are ignored.
+pub const PATH_MTU_CACHE_SIZE: usize = 1024;
+
+/// How often `PrismConfig::static_neighbors` are announced to smoltcp again. Its neighbor
+/// cache forgets an entry a minute after it was filled.
+pub const STATIC_NEIGHBOR_REFRESH: Duration = Duration::from_secs(30);
+
 /// Default MSS clamp value for egress path compatibility.
 pub const DEFAULT_MSS_CLAMP: u16 = 1280;
 
+/// IPv6 extension headers skipped looking for the TCP header. Longer chains aren't trapped
+/// (the packet is classified as non-TCP); the bound keeps crafted chains from costing more.
+pub const IPV6_MAX_EXT_HEADERS: usize = 10;
+
+/// Capacity of the interface routing table, set by smoltcp's `iface-max-route-count-16`
+/// feature in `Cargo.toml`. The two default routes take two entries, leaving the rest for
+/// `PrismConfig::routes`.
+pub const MAX_ROUTES: usize = 16;
+
+/// Capacity of the interface address table, set by smoltcp's `iface-max-addr-count-8`
+/// feature in `Cargo.toml` (smoltcp's features stop at 8; build with
+/// `SMOLTCP_IFACE_MAX_ADDR_COUNT` instead for more). The gateway addresses take one entry per
+/// family, every other entry holds a destination with open tunnels: SYNs to further
+/// destinations are refused (`setup_addr_table_full`) until one closes.
+pub const MAX_ADDRS: usize = 8;
+
 /// Size of the virtio_net_hdr structure (Linux GSO/GRO).
 /// When IFF_VNET_HDR is enabled, the TUN device prepends this header to each packet.
 pub const VIRTIO_NET_HDR_SIZE: usize = 10;
+
+/// Client flows waiting for a reply that the `trace-latency` feature keeps a timestamp for.
+/// Packets of further flows aren't sampled until some of these are answered.
+pub const LATENCY_TRACE_MAX_FLOWS: usize = 4096;
+
+/// How long the `trace-latency` feature waits for a reply to a client packet before
+/// forgetting it.
+pub const LATENCY_TRACE_TTL: Duration = Duration::from_secs(10);
@@ -1,9 +1,20 @@
-use smoltcp::phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken};
+use smoltcp::phy::{Checksum, Device, DeviceCapabilities, Medium, RxToken, TxToken};
 use smoltcp::time::Instant;
 use tokio::sync::mpsc;
 use crate::constants::{TX_POOL_CAPACITY, TX_POOL_MAX_SIZE, TX_POOL_RECYCLE_THRESHOLD, TX_ARENA_SIZE};
+use crate::constants::{BATCH_SIZE, CHANNEL_SIZE, TUN_WRITE_ERROR_LIMIT};
+#[cfg(target_os = "linux")]
+use crate::constants::VIRTIO_NET_HDR_SIZE;
+use crate::stack::OffloadMode;
+use crate::buffer::{BufferAllocator, GlobalBufferAllocator};
+use crate::stats::PrismStats;
+use std::future::Future;
+use std::io;
+use std::sync::{Arc, Mutex};
 use std::collections::VecDeque;
-use tracing::warn;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, warn};
 use bytes::{Bytes, BytesMut};
 
 /// A TunDevice that bridges tokio mpsc channels to smoltcp.
@@ -15,6 +26,117 @@ pub struct PrismDevice {
     pub mtu: usize,
     pub medium: Medium,
     pub tx_pool: Vec<BytesMut>,
+    /// Packets on `rx_queue`/`tx_queue` carry a `virtio_net_hdr` (TUN opened with
+    /// `IFF_VNET_HDR`). Linux only; always on when `offload` is not `Off`.
+    pub vnet_hdr: bool,
+    /// Checksum/GSO hints for the vnet header, set from `PrismConfig::offload` by the stack
+    pub offload: OffloadMode,
+    /// Segment size limit used to build GSO headers (`OffloadMode::Gso`)
+    pub egress_mtu: usize,
+    /// Source of TX arenas and, through the stack, tunnel socket buffers
+    pub allocator: Arc<dyn BufferAllocator>,
+    /// Set by [`PrismDevice::loopback`]: emitted packets are collected here instead of
+    /// being sent to `tx_queue`
+    pub loopback: Option<Vec<Bytes>>,
+    /// Emitted packets are handed to `tx_queue` in groups of this many (or when the stack
+    /// flushes at the end of a poll) to cut per-packet channel overhead. `1` sends each
+    /// packet right away. Set from `PrismConfig::tx_batch` by the stack
+    pub tx_batch: usize,
+    /// Packets emitted but not yet handed to `tx_queue`, in order
+    pub tx_staged: Vec<Bytes>,
+    /// Why the TUN link went down, set by [`PrismDevice::spawn_tun_bridge`] when reading from or
+    /// writing to the TUN failed for good. [`PrismStack::run`](crate::stack::PrismStack::run)
+    /// returns it once `rx_queue` closes.
+    ///
+    /// Custom bridges report a failed TUN the same way: the writer stores its error here and
+    /// drops the receiving end of `tx_queue`, which ends the stack's run loop with that error.
+    pub link_error: Arc<Mutex<Option<io::Error>>>,
+    /// Set once a send finds `tx_queue` closed: whatever writes to the TUN is gone, so nothing
+    /// the stack emits can leave any more. [`PrismStack::run`](crate::stack::PrismStack::run)
+    /// stops with an error when it sees it. A full queue only drops the packet (`tx_queue_full`).
+    pub tx_closed: bool,
+    /// Counters of the stack driving this device, shared by the stack on construction
+    pub(crate) stats: Arc<PrismStats>,
+    /// Set by [`PrismStack::set_latency_sender`](crate::stack::PrismStack::set_latency_sender)
+    #[cfg(feature = "trace-latency")]
+    pub(crate) latency: Option<crate::latency::LatencyTracer>,
+}
+
+/// Tasks spawned by [`PrismDevice::spawn_tun_bridge`]. Both end on their own once the
+/// link fails or the stack is dropped; dropping the handles detaches them.
+pub struct TunBridgeHandles {
+    /// TUN -> stack
+    pub reader: JoinHandle<()>,
+    /// Stack -> TUN
+    pub writer: JoinHandle<()>,
+}
+
+/// Feeds the devices of [`PrismDevice::new_multi_queue`], picking the queue of each packet
+/// from its flow ([`flow_hash`](crate::trap::flow_hash)) so that a connection always lands
+/// on the same stack.
+pub struct FlowDispatcher {
+    queues: Vec<mpsc::Sender<BytesMut>>,
+    /// Packets start with a `virtio_net_hdr`, skipped before hashing (Linux only)
+    pub vnet_hdr: bool,
+}
+
+impl FlowDispatcher {
+    /// Number of devices fed.
+    pub fn queues(&self) -> usize {
+        self.queues.len()
+    }
+
+    /// Index of the device that gets `packet`.
+    pub fn queue_for(&self, packet: &[u8]) -> usize {
+        // A single stack needs no hashing
+        if self.queues.len() == 1 {
+            return 0;
+        }
+        #[cfg(target_os = "linux")]
+        let packet = if self.vnet_hdr { packet.get(VIRTIO_NET_HDR_SIZE..).unwrap_or_default() } else { packet };
+        (crate::trap::flow_hash(packet) % self.queues.len() as u64) as usize
+    }
+
+    /// Hands `packet` to its device, waiting for room. Fails if that device's stack is gone.
+    pub async fn send(&self, packet: BytesMut) -> Result<(), mpsc::error::SendError<BytesMut>> {
+        self.queues[self.queue_for(&packet)].send(packet).await
+    }
+
+    /// Like [`FlowDispatcher::send`], failing instead of waiting when the queue is full.
+    pub fn try_send(&self, packet: BytesMut) -> Result<(), mpsc::error::TrySendError<BytesMut>> {
+        self.queues[self.queue_for(&packet)].try_send(packet)
+    }
+
+    /// Completes once any of the devices is dropped.
+    pub async fn closed(&self) {
+        futures::future::select_all(self.queues.iter().map(|queue| Box::pin(queue.closed()))).await;
+    }
+}
+
+/// Packet I/O of an async TUN device, as driven by [`PrismDevice::spawn_tun_bridge`].
+/// Implemented for `tun_rs::AsyncDevice` (`tun` feature).
+pub trait TunIo: Send + Sync + 'static {
+    /// Waits for the next packet and copies it into `buf`.
+    fn recv(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<usize>> + Send;
+    /// Like `recv`, but fails with `WouldBlock` instead of waiting.
+    fn try_recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+    /// Writes one packet.
+    fn send(&self, buf: &[u8]) -> impl Future<Output = io::Result<usize>> + Send;
+}
+
+#[cfg(feature = "tun")]
+impl TunIo for tun_rs::AsyncDevice {
+    fn recv(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<usize>> + Send {
+        tun_rs::AsyncDevice::recv(self, buf)
+    }
+
+    fn try_recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        tun_rs::AsyncDevice::try_recv(self, buf)
+    }
+
+    fn send(&self, buf: &[u8]) -> impl Future<Output = io::Result<usize>> + Send {
+        tun_rs::AsyncDevice::send(self, buf)
+    }
 }
 
 impl PrismDevice {
@@ -31,8 +153,248 @@ impl PrismDevice {
             mtu,
             medium,
             tx_pool: Vec::with_capacity(TX_POOL_CAPACITY),
+            vnet_hdr: false,
+            offload: OffloadMode::Off,
+            egress_mtu: mtu,
+            allocator: Arc::new(GlobalBufferAllocator),
+            loopback: None,
+            tx_batch: 1,
+            tx_staged: Vec::new(),
+            link_error: Arc::new(Mutex::new(None)),
+            tx_closed: false,
+            stats: Arc::new(PrismStats::default()),
+            #[cfg(feature = "trace-latency")]
+            latency: None,
         }
     }
+
+    /// `queues` devices sharing one TUN, for as many stacks (each polled on its own task or
+    /// thread). Each device gets its own `rx_queue`, fed by the returned [`FlowDispatcher`]
+    /// so that every packet of a flow, SYN and data alike, reaches the same stack. All of
+    /// them emit to `tx_queue` and share one `link_error`.
+    ///
+    /// Tunnel ids are only unique per stack: a relayer serving several stacks should give
+    /// each its own request channel, or key tunnels by stack as well.
+    pub fn new_multi_queue(queues: usize, tx_queue: mpsc::Sender<Bytes>, mtu: usize, medium: Medium) -> (Vec<Self>, FlowDispatcher) {
+        assert!(queues > 0, "a multi-queue device needs at least one queue");
+        let link_error = Arc::new(Mutex::new(None));
+        let (senders, devices) = (0..queues)
+            .map(|_| {
+                let (os_tx, os_rx) = mpsc::channel::<BytesMut>(CHANNEL_SIZE);
+                let mut device = Self::new(os_rx, tx_queue.clone(), mtu, medium);
+                device.link_error = link_error.clone();
+                (os_tx, device)
+            })
+            .unzip();
+        (devices, FlowDispatcher { queues: senders, vnet_hdr: false })
+    }
+
+    /// Like [`PrismDevice::spawn_tun_bridge`], with the bridge tasks detached.
+    pub fn run_tun_bridge<T: TunIo>(tun: Arc<T>, mtu: usize) -> Self {
+        Self::spawn_tun_bridge(tun, mtu).0
+    }
+
+    /// A device bridged to `tun` by two spawned tasks, one per direction, which reuse one
+    /// large read buffer and cut each packet from it without copying. Must be called inside a
+    /// tokio runtime.
+    ///
+    /// Errors aren't swallowed: a failed read, or `TUN_WRITE_ERROR_LIMIT` failed writes in a
+    /// row, store the error in `link_error` and stop the bridge. `rx_queue` then closes, so
+    /// the stack's run loop ends and returns that error. The bridge also stops once the stack
+    /// is dropped.
+    pub fn spawn_tun_bridge<T: TunIo>(tun: Arc<T>, mtu: usize) -> (Self, TunBridgeHandles) {
+        let (mut devices, handles) = Self::spawn_multi_queue_bridge(tun, mtu, 1);
+        (devices.pop().unwrap(), handles)
+    }
+
+    /// Like [`PrismDevice::spawn_tun_bridge`], for `queues` stacks sharing `tun`: one reader
+    /// spreads packets over the devices by flow ([`FlowDispatcher`]), one writer serves them
+    /// all. The bridge stops as soon as any of the stacks is dropped, which closes the
+    /// `rx_queue` of the others too.
+    ///
+    /// The flow hash is taken over plain IP packets, so with more than one queue the TUN must
+    /// not carry virtio headers (`offload` left `Off`).
+    pub fn spawn_multi_queue_bridge<T: TunIo>(tun: Arc<T>, mtu: usize, queues: usize) -> (Vec<Self>, TunBridgeHandles) {
+        let (tun_tx, mut tun_rx) = mpsc::channel::<Bytes>(CHANNEL_SIZE);
+        let (devices, dispatcher) = Self::new_multi_queue(queues, tun_tx, mtu, Medium::Ip);
+        let writer_failed = Arc::new(Notify::new());
+
+        // Reader: TUN -> stacks
+        let (reader, link_error, stop) = (tun.clone(), devices[0].link_error.clone(), writer_failed.clone());
+        let reader_task = tokio::spawn(async move {
+            // Room for the largest packet, plus a virtio_net_hdr (Linux offload)
+            #[cfg(target_os = "linux")]
+            let read_len = 65535 + VIRTIO_NET_HDR_SIZE;
+            #[cfg(not(target_os = "linux"))]
+            let read_len = 65535;
+            // Zeroed once per chunk, not per read: packets are cut from the front until the rest
+            // can't hold another one, then the next chunk is allocated (the old one lives on in
+            // the packets still referencing it)
+            let chunk_len = 1024 * 1024;
+            let mut buf = BytesMut::zeroed(chunk_len);
+            loop {
+                if buf.len() < read_len {
+                    buf = BytesMut::zeroed(chunk_len);
+                }
+                let res = tokio::select! {
+                    res = reader.recv(&mut buf[..read_len]) => res,
+                    _ = dispatcher.closed() => break,
+                    _ = stop.notified() => break,
+                };
+                let n = match res {
+                    Ok(n) => n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        warn!("TUN read failed, closing the link: {}", e);
+                        link_error.lock().unwrap().get_or_insert(e);
+                        break;
+                    }
+                };
+                if n == 0 {
+                    continue;
+                }
+                // Drain whatever else is queued without waiting, up to BATCH_SIZE packets per
+                // wake-up; each packet is cut to its exact size
+                let mut batch = Vec::with_capacity(BATCH_SIZE);
+                batch.push(buf.split_to(n));
+                while batch.len() < BATCH_SIZE {
+                    if buf.len() < read_len {
+                        buf = BytesMut::zeroed(chunk_len);
+                    }
+                    match reader.try_recv(&mut buf[..read_len]) {
+                        Ok(n) if n > 0 => batch.push(buf.split_to(n)),
+                        // WouldBlock (or an error the next recv reports): done
+                        _ => break,
+                    }
+                }
+                for pkt in batch {
+                    if dispatcher.send(pkt).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        // Writer: stack -> TUN, whole batches at a time (see `tx_batch`)
+        let (writer, link_error) = (tun, devices[0].link_error.clone());
+        let writer_task = tokio::spawn(async move {
+            let mut batch = Vec::with_capacity(BATCH_SIZE);
+            let mut failures = 0;
+            while tun_rx.recv_many(&mut batch, BATCH_SIZE).await > 0 {
+                for pkt in batch.drain(..) {
+                    match writer.send(&pkt).await {
+                        Ok(_) => failures = 0,
+                        Err(e) => {
+                            failures += 1;
+                            if failures < TUN_WRITE_ERROR_LIMIT {
+                                warn!("TUN write failed: {}", e);
+                                continue;
+                            }
+                            warn!("TUN write failed {} times in a row, closing the link: {}", failures, e);
+                            link_error.lock().unwrap().get_or_insert(e);
+                            writer_failed.notify_one();
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+
+        (devices, TunBridgeHandles { reader: reader_task, writer: writer_task })
+    }
+
+    /// A device without a TUN behind it, for tests: feed packets with
+    /// [`PrismStack::inject`](crate::stack::PrismStack::inject), drive the stack with
+    /// [`PrismStack::poll_once`](crate::stack::PrismStack::poll_once) and collect what it
+    /// emitted with [`PrismDevice::take_transmitted`]. Not meant for [`PrismStack::run`](crate::stack::PrismStack::run),
+    /// which returns right away since nothing can ever arrive on `rx_queue`.
+    pub fn loopback(mtu: usize, medium: Medium) -> Self {
+        let (_, rx_queue) = mpsc::channel(1);
+        let (tx_queue, _) = mpsc::channel(1);
+        let mut device = Self::new(rx_queue, tx_queue, mtu, medium);
+        device.loopback = Some(Vec::new());
+        device
+    }
+
+    /// Packets emitted since the last call (loopback devices only, empty otherwise).
+    pub fn take_transmitted(&mut self) -> Vec<Bytes> {
+        self.loopback.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Sends a packet built outside smoltcp down the same TX path (pool, vnet header, loopback).
+    pub fn transmit_packet(&mut self, packet: &[u8]) {
+        TxTokenImpl(self).consume(packet.len(), |buf| buf.copy_from_slice(packet));
+    }
+
+    /// Hands every staged packet to `tx_queue`, reserving room for the whole batch at once.
+    pub fn flush_tx(&mut self) {
+        if self.tx_staged.is_empty() {
+            return;
+        }
+        #[cfg(feature = "trace-latency")]
+        let hdr_len = self.vnet_hdr_len();
+        if let Ok(permits) = self.tx_queue.try_reserve_many(self.tx_staged.len()) {
+            for (permit, packet) in permits.zip(self.tx_staged.drain(..)) {
+                #[cfg(feature = "trace-latency")]
+                if let Some(tracer) = self.latency.as_mut() {
+                    tracer.egress(&packet[hdr_len..], tokio::time::Instant::now());
+                }
+                permit.send(packet);
+            }
+            return;
+        }
+        // Not enough room for all of them (or closed): send what fits, in order
+        for packet in std::mem::take(&mut self.tx_staged) {
+            self.send_tx(packet);
+        }
+    }
+
+    /// Hands one packet to `tx_queue`. A full queue drops it; a closed one sets `tx_closed`.
+    fn send_tx(&mut self, packet: Bytes) {
+        #[cfg(feature = "trace-latency")]
+        let traced = self.latency.is_some().then(|| packet.slice(self.vnet_hdr_len()..));
+        match self.tx_queue.try_send(packet) {
+            Ok(()) => {
+                #[cfg(feature = "trace-latency")]
+                if let (Some(tracer), Some(packet)) = (self.latency.as_mut(), traced) {
+                    tracer.egress(&packet, tokio::time::Instant::now());
+                }
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                debug!("TX queue full, dropping packet");
+                PrismStats::bump(&self.stats.tx_queue_full);
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                if !self.tx_closed {
+                    error!("TX queue closed, the TUN writer is gone");
+                    self.tx_closed = true;
+                }
+            }
+        }
+    }
+
+    /// Length of the `virtio_net_hdr` in front of each packet on the channels
+    #[cfg(feature = "trace-latency")]
+    pub(crate) fn vnet_hdr_len(&self) -> usize {
+        #[cfg(target_os = "linux")]
+        if self.vnet_hdr {
+            return VIRTIO_NET_HDR_SIZE;
+        }
+        0
+    }
+
+    /// Marks the channels as carrying `virtio_net_hdr`-framed packets. Only needed without
+    /// offload (headers are then plain GSO_NONE); any `OffloadMode` other than `Off` implies it.
+    pub fn with_vnet_hdr(mut self, vnet_hdr: bool) -> Self {
+        self.vnet_hdr = vnet_hdr;
+        self
+    }
+
+    /// Routes TX arena and tunnel socket buffer allocations through `allocator`.
+    pub fn with_allocator(mut self, allocator: Arc<dyn BufferAllocator>) -> Self {
+        self.allocator = allocator;
+        self
+    }
 }
 
 impl Device for PrismDevice {
@@ -62,6 +424,11 @@ impl Device for PrismDevice {
         let mut caps = DeviceCapabilities::default();
         caps.max_transmission_unit = self.mtu;
         caps.medium = self.medium;
+        if self.offload != OffloadMode::Off {
+            // The kernel finishes TCP/UDP checksums from the vnet header seed
+            caps.checksum.tcp = Checksum::Rx;
+            caps.checksum.udp = Checksum::Rx;
+        }
         caps
     }
 }
@@ -86,39 +453,74 @@ impl<'a> TxToken for TxTokenImpl<'a> {
     where
         F: FnOnce(&mut [u8]) -> R,
     {
+        // Room for the virtio_net_hdr in front of the IP packet (Linux offload)
+        #[cfg(target_os = "linux")]
+        let hdr_len = if self.0.vnet_hdr { VIRTIO_NET_HDR_SIZE } else { 0 };
+        #[cfg(not(target_os = "linux"))]
+        let hdr_len = 0;
+        let total = hdr_len + len;
+
         // Optimization: Arena Allocation (Slab-like)
         // 1. Try get from pool
-        let mut buffer = self.0.tx_pool.pop().unwrap_or_else(|| {
-             BytesMut::with_capacity(TX_ARENA_SIZE)
+        let pooled = self.0.tx_pool.pop();
+        let hit = pooled.as_ref().is_some_and(|buffer| buffer.capacity() >= total);
+        let mut buffer = pooled.unwrap_or_else(|| {
+             self.0.allocator.tx_arena(TX_ARENA_SIZE)
         });
 
         // 2. Ensure capacity
-        // If the popped buffer is too small (shouldn't happen with our logic, but safe guard)
-        if buffer.capacity() < len {
-             buffer = BytesMut::with_capacity(TX_ARENA_SIZE);
+        // A recycled remainder may be smaller than a large (GSO) packet: keep it for later
+        // and carve this one from a fresh arena instead.
+        if buffer.capacity() < total {
+             self.0.tx_pool.push(buffer);
+             buffer = self.0.allocator.tx_arena(TX_ARENA_SIZE.max(total));
         }
+        PrismStats::bump(if hit { &self.0.stats.tx_pool_hits } else { &self.0.stats.tx_pool_misses });
         
         // 3. Set length safely (avoid memset)
-        // We set length to `len` so `f` can write into it.
-        // Safety: `f` (smoltcp) will initialize it.
-        unsafe { buffer.set_len(len) };
+        // We set length to `total` so `f` can write into it.
+        // Safety: `f` (smoltcp) will initialize the packet, the header is written below.
+        unsafe { buffer.set_len(total) };
         
         // 4. Write data
-        let result = f(&mut buffer);
+        let result = f(&mut buffer[hdr_len..]);
+        #[cfg(target_os = "linux")]
+        if hdr_len > 0 {
+            match self.0.offload {
+                OffloadMode::Off => crate::offload::VirtioNetHdr::none().write_to(&mut buffer[..hdr_len]),
+                OffloadMode::Checksum => crate::offload::write_virtio_hdr(&mut buffer[..total], None),
+                OffloadMode::Gso => crate::offload::write_virtio_hdr(&mut buffer[..total], Some(self.0.egress_mtu)),
+            }
+        }
         
         // 5. Zero-Copy Send via Splitting
-        // `split_to(len)` returns a new BytesMut containing [0, len)
-        // `buffer` retains [len, capacity) - effectively the "rest" of the allocation
-        let packet = buffer.split_to(len).freeze();
+        // `split_to(total)` returns a new BytesMut containing [0, total)
+        // `buffer` retains [total, capacity) - effectively the "rest" of the allocation
+        let packet = buffer.split_to(total).freeze();
         
         // 6. Recycle remaining capacity
-        // Recycle if has enough space AND pool isn't full (prevent OOM)
-        if buffer.capacity() > TX_POOL_RECYCLE_THRESHOLD && self.0.tx_pool.len() < TX_POOL_MAX_SIZE {
+        // Recycle if has enough space AND pool isn't full (prevent OOM).
+        // The frozen packet and the remainder share one allocation, which is freed once both are gone.
+        if buffer.capacity() >= TX_POOL_RECYCLE_THRESHOLD && self.0.tx_pool.len() < TX_POOL_MAX_SIZE {
              self.0.tx_pool.push(buffer);
+             PrismStats::bump(&self.0.stats.tx_pool_recycled);
+        } else {
+             PrismStats::bump(&self.0.stats.tx_pool_discarded);
         }
         
-        if let Err(e) = self.0.tx_queue.try_send(packet) {
-             warn!("TX Queue Full/Closed: {}", e);
+        if let Some(transmitted) = self.0.loopback.as_mut() {
+            #[cfg(feature = "trace-latency")]
+            if let Some(tracer) = self.0.latency.as_mut() {
+                tracer.egress(&packet[hdr_len..], tokio::time::Instant::now());
+            }
+            transmitted.push(packet);
+        } else if self.0.tx_batch > 1 {
+            self.0.tx_staged.push(packet);
+            if self.0.tx_staged.len() >= self.0.tx_batch {
+                self.0.flush_tx();
+            }
+        } else {
+            self.0.send_tx(packet);
         }
         
         result
@@ -0,0 +1,90 @@
+//! In-stack dwell time of TCP packets, for chasing tail latency.
+//!
+//! Only compiled with the `trace-latency` feature: without it neither the stack nor the
+//! device carries any tracing state or code. Enable it with
+//! [`PrismStack::set_latency_sender`](crate::stack::PrismStack::set_latency_sender).
+
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
+use crate::constants::{LATENCY_TRACE_MAX_FLOWS, LATENCY_TRACE_TTL};
+use crate::stack::FlowKey;
+
+/// A client packet and the first packet the stack sent back on the same flow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySample {
+    /// (client, target), as in `TunnelRequest`
+    pub flow: FlowKey,
+    /// When the stack took the client packet off `rx_queue`. Every packet of a batch gets the
+    /// batch's instant, so time spent behind the rest of the batch counts as dwell time.
+    pub enqueued: Instant,
+    /// When the reply was handed to `tx_queue` (after any `tx_batch` staging)
+    pub dequeued: Instant,
+}
+
+impl LatencySample {
+    pub fn dwell(&self) -> Duration {
+        self.dequeued.duration_since(self.enqueued)
+    }
+}
+
+/// Pairs client packets with the stack's next packet on their flow.
+pub(crate) struct LatencyTracer {
+    tx: mpsc::Sender<LatencySample>,
+    /// Oldest unanswered client packet of each flow
+    pub(crate) pending: HashMap<FlowKey, Instant>,
+    /// When the current `rx_queue` batch was taken
+    batch_started: Instant,
+    /// When `pending` was last swept for flows left unanswered past `LATENCY_TRACE_TTL`
+    swept: Instant,
+}
+
+impl LatencyTracer {
+    pub(crate) fn new(tx: mpsc::Sender<LatencySample>) -> Self {
+        let now = Instant::now();
+        Self { tx, pending: HashMap::new(), batch_started: now, swept: now }
+    }
+
+    /// Called when the run loop wakes up with packets on `rx_queue`. Once per
+    /// `LATENCY_TRACE_TTL`, forgets the packets that have gone unanswered that long.
+    pub(crate) fn start_batch(&mut self, now: Instant) {
+        self.batch_started = now;
+        if now.duration_since(self.swept) >= LATENCY_TRACE_TTL {
+            self.pending.retain(|_, enqueued| now.duration_since(*enqueued) < LATENCY_TRACE_TTL);
+            self.swept = now;
+        }
+    }
+
+    /// Records a client packet (an IP packet, without vnet header) of the current batch. Later
+    /// packets of a flow that's still waiting for a reply don't move its start. Flows past
+    /// `LATENCY_TRACE_MAX_FLOWS` unanswered ones aren't traced. RSTs and bare ACKs aren't
+    /// either: nothing answers them.
+    pub(crate) fn ingress(&mut self, packet: &[u8]) {
+        const FIN: u8 = 0x01;
+        const SYN: u8 = 0x02;
+        const RST: u8 = 0x04;
+
+        let Some(flow) = crate::trap::tcp_flow(packet) else { return };
+        let flags = crate::trap::tcp_flags(packet).unwrap_or(0);
+        if flags & RST != 0 || (flags & (SYN | FIN) == 0 && crate::trap::tcp_payload_len(packet) == Some(0)) {
+            return;
+        }
+        if self.pending.len() < LATENCY_TRACE_MAX_FLOWS || self.pending.contains_key(&flow) {
+            self.pending.entry(flow).or_insert(self.batch_started);
+        }
+    }
+
+    /// Records a packet leaving for the client and emits the sample it completes, if any.
+    /// A full channel drops the sample.
+    pub(crate) fn egress(&mut self, packet: &[u8], now: Instant) {
+        let Some((target, client)) = crate::trap::tcp_flow(packet) else { return };
+        if let Some(enqueued) = self.pending.remove(&(client, target)) {
+            let _ = self.tx.try_send(LatencySample { flow: (client, target), enqueued, dequeued: now });
+        }
+    }
+
+    /// Drops the unanswered packet of a flow whose tunnel is gone.
+    pub(crate) fn forget(&mut self, flow: &FlowKey) {
+        self.pending.remove(flow);
+    }
+}
@@ -6,10 +6,18 @@ pub mod device;
 pub mod stack;
 pub mod trap;
 pub mod constants;
+pub mod stats;
+pub mod buffer;
+pub mod relay;
+pub(crate) mod reassembly;
 
 #[cfg(target_os = "linux")]
 pub mod offload;
 
+#[cfg(feature = "trace-latency")]
+pub mod latency;
+
 pub use stack::PrismStack;
 pub use device::PrismDevice;
 pub use trap::PrismTrap;
+pub use stats::PrismStats;
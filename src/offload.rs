@@ -4,8 +4,9 @@
 //! It provides helpers to strip and prepend the 10-byte `virtio_net_hdr`
 //! that the TUN device prepends/expects when `IFF_VNET_HDR` is enabled.
 
-use bytes::{BytesMut, BufMut};
+use bytes::{Buf, BufMut, BytesMut};
 use crate::constants::VIRTIO_NET_HDR_SIZE;
+use smoltcp::wire::IpProtocol;
 
 // virtio_net_hdr flags
 pub const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
@@ -85,47 +86,127 @@ pub fn prepend_virtio_hdr_none(packet: &[u8]) -> BytesMut {
 /// For UDP: `csum_start` = IP header length, `csum_offset` = 6 (UDP checksum field offset).
 ///
 /// This tells the kernel to compute the checksum, saving CPU cycles.
+/// Any checksum already in the packet is replaced by the pseudo-header seed.
 pub fn prepend_virtio_hdr_csum(packet: &[u8]) -> BytesMut {
-    if packet.is_empty() {
-        return prepend_virtio_hdr_none(packet);
-    }
+    let mut buf = BytesMut::with_capacity(VIRTIO_NET_HDR_SIZE + packet.len());
+    buf.put_bytes(0, VIRTIO_NET_HDR_SIZE);
+    buf.put_slice(packet);
+    write_virtio_hdr(&mut buf, None);
+    buf
+}
 
-    let version = packet[0] >> 4;
-    let (ip_hdr_len, protocol) = match version {
-        4 => {
-            let ihl = (packet[0] & 0x0F) as usize * 4;
-            let proto = packet[9];
-            (ihl, proto)
-        }
-        6 => {
-            // IPv6: fixed 40-byte header, next_header at offset 6
-            let proto = packet[6];
-            (40usize, proto)
+/// Fill in the virtio_net_hdr for a TX buffer laid out as `[hdr space | IP packet]`.
+///
+/// TCP/UDP get `NEEDS_CSUM` with the checksum field seeded with the pseudo-header sum,
+/// which is what the kernel expects to finish the checksum. With `gso_mtu` set, TCP
+/// packets longer than it are also marked for segmentation at that MTU.
+/// Anything else gets an empty (GSO_NONE) header.
+pub fn write_virtio_hdr(buf: &mut [u8], gso_mtu: Option<usize>) {
+    let (hdr_space, packet) = buf.split_at_mut(VIRTIO_NET_HDR_SIZE);
+    let hdr = match csum_location(packet) {
+        Some((csum_start, csum_offset, protocol)) => {
+            let seed = fold(pseudo_header_sum(packet, csum_start, protocol));
+            let field = csum_start + csum_offset;
+            packet[field..field + 2].copy_from_slice(&seed.to_be_bytes());
+
+            let mut hdr = VirtioNetHdr {
+                flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+                gso_type: VIRTIO_NET_HDR_GSO_NONE,
+                hdr_len: 0,
+                gso_size: 0,
+                csum_start: csum_start as u16,
+                csum_offset: csum_offset as u16,
+            };
+            if let Some(mtu) = gso_mtu {
+                let headers = tcp_headers_len(packet, csum_start);
+                if protocol == 6 && packet.len() > mtu && mtu > headers {
+                    hdr.gso_type = if packet[0] >> 4 == 4 { VIRTIO_NET_HDR_GSO_TCPV4 } else { VIRTIO_NET_HDR_GSO_TCPV6 };
+                    hdr.hdr_len = headers as u16;
+                    hdr.gso_size = (mtu - headers) as u16;
+                }
+            }
+            hdr
         }
-        _ => return prepend_virtio_hdr_none(packet),
+        None => VirtioNetHdr::none(),
     };
+    hdr.write_to(hdr_space);
+}
+
+/// Strip the virtio_net_hdr from a received packet and finish a partial checksum
+/// (`NEEDS_CSUM`) so the rest of the stack sees an ordinary, valid IP packet.
+/// Returns false if the buffer is too short to be a vnet packet.
+pub fn finish_virtio_rx(buf: &mut BytesMut) -> bool {
+    let Some(hdr) = VirtioNetHdr::parse(buf) else { return false };
+    buf.advance(VIRTIO_NET_HDR_SIZE);
+
+    if hdr.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+        let start = hdr.csum_start as usize;
+        let field = start + hdr.csum_offset as usize;
+        if field + 2 > buf.len() {
+            return false;
+        }
+        // The field holds the pseudo-header seed, so summing from csum_start covers everything
+        let csum = !fold(ones_complement_sum(&buf[start..], 0));
+        buf[field..field + 2].copy_from_slice(&csum.to_be_bytes());
+    }
+    true
+}
 
+/// Returns `(csum_start, csum_offset, protocol)` for TCP/UDP packets.
+fn csum_location(packet: &[u8]) -> Option<(usize, usize, u8)> {
+    let (ip_hdr_len, protocol) = match packet.first()? >> 4 {
+        4 => ((packet[0] & 0x0F) as usize * 4, *packet.get(9)?),
+        // IPv6: the L4 header follows the fixed header and any extension headers
+        6 => match crate::trap::skip_ipv6_headers(packet).ok()? {
+            // A fragment header left over means a non-first fragment: no L4 header to finish
+            (IpProtocol::Ipv6Frag, _) => return None,
+            (next_header, offset) => (offset, u8::from(next_header)),
+        },
+        _ => return None,
+    };
     // Protocol numbers: TCP=6, UDP=17
-    let csum_offset: u16 = match protocol {
-        6 => 16,  // TCP checksum field offset within TCP header
-        17 => 6,  // UDP checksum field offset within UDP header
-        _ => return prepend_virtio_hdr_none(packet),
+    let (csum_offset, l4_min) = match protocol {
+        6 => (16, 20), // TCP checksum field offset within TCP header
+        17 => (6, 8),  // UDP checksum field offset within UDP header
+        _ => return None,
     };
+    if packet.len() < ip_hdr_len + l4_min {
+        return None;
+    }
+    Some((ip_hdr_len, csum_offset, protocol))
+}
 
-    let hdr = VirtioNetHdr {
-        flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
-        gso_type: VIRTIO_NET_HDR_GSO_NONE, // We're just offloading checksum, not segmentation
-        hdr_len: 0,
-        gso_size: 0,
-        csum_start: ip_hdr_len as u16,
-        csum_offset,
-    };
+/// Length of everything a GSO segment repeats (`hdr_len`): the IP header with its options
+/// or extension headers, i.e. `tcp_start` as found by `csum_location`, plus the TCP header
+/// with its options (data offset).
+fn tcp_headers_len(packet: &[u8], tcp_start: usize) -> usize {
+    tcp_start + (packet[tcp_start + 12] >> 4) as usize * 4
+}
 
-    let mut buf = BytesMut::with_capacity(VIRTIO_NET_HDR_SIZE + packet.len());
-    buf.resize(VIRTIO_NET_HDR_SIZE + packet.len(), 0);
-    hdr.write_to(&mut buf[..VIRTIO_NET_HDR_SIZE]);
-    buf[VIRTIO_NET_HDR_SIZE..].copy_from_slice(packet);
-    buf
+/// Sum of the TCP/UDP pseudo-header (addresses, protocol, upper-layer length).
+fn pseudo_header_sum(packet: &[u8], l4_start: usize, protocol: u8) -> u32 {
+    let l4_len = (packet.len() - l4_start) as u32;
+    let addrs = if packet[0] >> 4 == 4 { &packet[12..20] } else { &packet[8..40] };
+    ones_complement_sum(addrs, protocol as u32 + l4_len)
+}
+
+fn ones_complement_sum(data: &[u8], mut acc: u32) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        acc += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+        acc = (acc & 0xFFFF) + (acc >> 16);
+    }
+    if let [last] = chunks.remainder() {
+        acc += (*last as u32) << 8;
+    }
+    acc
+}
+
+fn fold(mut acc: u32) -> u16 {
+    while acc > 0xFFFF {
+        acc = (acc & 0xFFFF) + (acc >> 16);
+    }
+    acc as u16
 }
 
 #[cfg(test)]
@@ -208,6 +289,74 @@ mod tests {
         assert_eq!(hdr.csum_offset, 6);  // UDP checksum offset
     }
 
+    #[test]
+    fn test_prepend_virtio_hdr_csum_v6_extension_headers() {
+        // IPv6, Hop-by-Hop Options (8 bytes, one PadN option), TCP with a valid checksum
+        let mut packet = vec![0u8; 40 + 8 + 20];
+        packet[0] = 0x60;
+        packet[4..6].copy_from_slice(&28u16.to_be_bytes());
+        packet[6] = 0; // Next Header = Hop-by-Hop
+        packet[7] = 64;
+        packet[8] = 0xfd;
+        packet[23] = 1;
+        packet[24] = 0xfd;
+        packet[39] = 2;
+        packet[40] = 6; // Next Header = TCP
+        packet[42..44].copy_from_slice(&[1, 4]); // PadN, 4 bytes
+        packet[48..50].copy_from_slice(&443u16.to_be_bytes());
+        packet[50..52].copy_from_slice(&40000u16.to_be_bytes());
+        packet[60] = 5 << 4; // Data offset
+        packet[61] = 0x10; // ACK
+        let sum = ones_complement_sum(&packet[48..], ones_complement_sum(&packet[8..40], 6 + 20));
+        packet[64..66].copy_from_slice(&(!fold(sum)).to_be_bytes());
+
+        let mut buf = prepend_virtio_hdr_csum(&packet);
+        let hdr = VirtioNetHdr::parse(&buf).unwrap();
+        assert_eq!(hdr.flags, VIRTIO_NET_HDR_F_NEEDS_CSUM);
+        assert_eq!(hdr.csum_start, 48); // Past the Hop-by-Hop header
+        assert_eq!(hdr.csum_offset, 16);
+
+        // The seed only covers the TCP segment's length, so finishing restores the checksum
+        assert!(finish_virtio_rx(&mut buf));
+        assert_eq!(&buf[..], &packet[..]);
+
+        // A non-first fragment has no TCP header to checksum
+        let mut fragment = packet[..48].to_vec();
+        fragment[6] = 44; // Next Header = Fragment
+        fragment[42..44].copy_from_slice(&(185u16 << 3).to_be_bytes());
+        fragment.extend_from_slice(&[0; 20]);
+        assert_eq!(VirtioNetHdr::parse(&prepend_virtio_hdr_csum(&fragment)).unwrap().flags, 0);
+    }
+
+    #[test]
+    fn test_virtio_hdr_v6_offsets_follow_the_l4_protocol() {
+        // packet[6] says Destination Options; the UDP header behind it picks csum_offset 6
+        let mut packet = vec![0u8; 40 + 8 + 8];
+        packet[0] = 0x60;
+        packet[4..6].copy_from_slice(&16u16.to_be_bytes());
+        packet[6] = 60; // Next Header = Destination Options
+        packet[40] = 17; // Next Header = UDP
+        let hdr = VirtioNetHdr::parse(&prepend_virtio_hdr_csum(&packet)).unwrap();
+        assert_eq!(hdr.flags, VIRTIO_NET_HDR_F_NEEDS_CSUM);
+        assert_eq!((hdr.csum_start, hdr.csum_offset), (48, 6));
+
+        // GSO counts the extension header into the headers repeated in every segment
+        let mut packet = vec![0u8; 40 + 8 + 20 + 3000];
+        packet[0] = 0x60;
+        packet[4..6].copy_from_slice(&((8 + 20 + 3000) as u16).to_be_bytes());
+        packet[6] = 0; // Next Header = Hop-by-Hop
+        packet[40] = 6; // Next Header = TCP
+        packet[60] = 5 << 4;
+        let mut buf = vec![0u8; VIRTIO_NET_HDR_SIZE];
+        buf.extend_from_slice(&packet);
+        write_virtio_hdr(&mut buf, Some(1280));
+        let hdr = VirtioNetHdr::parse(&buf).unwrap();
+        assert_eq!((hdr.csum_start, hdr.csum_offset), (48, 16));
+        assert_eq!(hdr.gso_type, VIRTIO_NET_HDR_GSO_TCPV6);
+        assert_eq!(hdr.hdr_len, 68);
+        assert_eq!(hdr.gso_size, 1280 - 68);
+    }
+
     #[test]
     fn test_prepend_virtio_hdr_csum_unknown_proto() {
         // ICMP (protocol 1) — should fall back to none
@@ -220,4 +369,117 @@ mod tests {
         assert_eq!(hdr.flags, 0); // No offload
         assert_eq!(hdr.gso_type, VIRTIO_NET_HDR_GSO_NONE);
     }
+
+    /// Builds an IPv4 TCP segment with a valid checksum and some payload.
+    fn build_tcp_v4(payload: &[u8]) -> Vec<u8> {
+        use smoltcp::phy::ChecksumCapabilities;
+        use smoltcp::wire::{IpProtocol, Ipv4Address, Ipv4Packet, Ipv4Repr, TcpControl, TcpPacket, TcpRepr, TcpSeqNumber};
+
+        let src = Ipv4Address::new(10, 11, 12, 1);
+        let dst = Ipv4Address::new(10, 11, 12, 2);
+        let tcp_repr = TcpRepr {
+            src_port: 443,
+            dst_port: 40000,
+            control: TcpControl::Psh,
+            seq_number: TcpSeqNumber(7),
+            ack_number: Some(TcpSeqNumber(1001)),
+            window_len: 1024,
+            window_scale: None,
+            max_seg_size: None,
+            sack_permitted: false,
+            sack_ranges: [None; 3],
+            payload,
+        };
+        let ip_repr = Ipv4Repr {
+            src_addr: src,
+            dst_addr: dst,
+            next_header: IpProtocol::Tcp,
+            payload_len: tcp_repr.buffer_len(),
+            hop_limit: 64,
+        };
+        let caps = ChecksumCapabilities::default();
+        let mut buf = vec![0u8; 20 + tcp_repr.buffer_len()];
+        let mut ip = Ipv4Packet::new_unchecked(&mut buf);
+        ip_repr.emit(&mut ip, &caps);
+        tcp_repr.emit(&mut TcpPacket::new_unchecked(ip.payload_mut()), &src.into(), &dst.into(), &caps);
+        buf
+    }
+
+    #[test]
+    fn test_csum_seed_roundtrip() {
+        // Odd payload length exercises the trailing byte
+        let packet = build_tcp_v4(b"hello, kernel");
+        let mut buf = prepend_virtio_hdr_csum(&packet);
+        assert_ne!(&buf[VIRTIO_NET_HDR_SIZE..], &packet[..]); // seeded, not final
+
+        // Finishing the partial checksum (as the kernel would) yields the real one
+        assert!(finish_virtio_rx(&mut buf));
+        assert_eq!(&buf[..], &packet[..]);
+    }
+
+    #[test]
+    fn test_write_virtio_hdr_gso() {
+        let packet = build_tcp_v4(&[0xAB; 3000]);
+        let mut buf = vec![0u8; VIRTIO_NET_HDR_SIZE];
+        buf.extend_from_slice(&packet);
+        write_virtio_hdr(&mut buf, Some(1280));
+
+        let hdr = VirtioNetHdr::parse(&buf).unwrap();
+        assert_eq!(hdr.gso_type, VIRTIO_NET_HDR_GSO_TCPV4);
+        assert_eq!(hdr.hdr_len, 40);
+        assert_eq!(hdr.gso_size, 1240);
+
+        // Small packets never get a GSO type
+        let mut buf = vec![0u8; VIRTIO_NET_HDR_SIZE];
+        buf.extend_from_slice(&build_tcp_v4(b"x"));
+        write_virtio_hdr(&mut buf, Some(1280));
+        assert_eq!(VirtioNetHdr::parse(&buf).unwrap().gso_type, VIRTIO_NET_HDR_GSO_NONE);
+    }
+
+    #[test]
+    fn test_gso_hdr_len_per_header_layout() {
+        // (IP header, extension headers, TCP header): hdr_len is their sum
+        for (ip_len, ext_len, tcp_len) in [(20, 0, 20), (24, 0, 32), (40, 0, 20), (40, 0, 32), (40, 16, 20), (40, 24, 40)] {
+            let mut packet = vec![0u8; ip_len + ext_len + tcp_len + 3000];
+            if ip_len == 20 || ip_len == 24 {
+                packet[0] = 0x40 | (ip_len / 4) as u8;
+                packet[9] = 6;
+            } else {
+                packet[0] = 0x60;
+                let payload_len = packet.len() - 40;
+                packet[4..6].copy_from_slice(&(payload_len as u16).to_be_bytes());
+                // 8 bytes of Hop-by-Hop, then Destination Options for the rest
+                packet[6] = if ext_len == 0 { 6 } else { 0 };
+                if ext_len > 0 {
+                    packet[40] = 60;
+                    packet[48] = 6;
+                    packet[49] = ((ext_len - 8) / 8 - 1) as u8;
+                }
+            }
+            packet[ip_len + ext_len + 12] = ((tcp_len / 4) as u8) << 4;
+
+            let mut buf = vec![0u8; VIRTIO_NET_HDR_SIZE];
+            buf.extend_from_slice(&packet);
+            write_virtio_hdr(&mut buf, Some(1280));
+            let hdr = VirtioNetHdr::parse(&buf).unwrap();
+            let headers = ip_len + ext_len + tcp_len;
+            let layout = (ip_len, ext_len, tcp_len);
+            assert_eq!(hdr.csum_start as usize, ip_len + ext_len, "{:?}", layout);
+            assert_eq!(hdr.hdr_len as usize, headers, "{:?}", layout);
+            assert_eq!(hdr.gso_size as usize, 1280 - headers, "{:?}", layout);
+            let gso_type = if ip_len == 40 { VIRTIO_NET_HDR_GSO_TCPV6 } else { VIRTIO_NET_HDR_GSO_TCPV4 };
+            assert_eq!(hdr.gso_type, gso_type, "{:?}", layout);
+        }
+    }
+
+    #[test]
+    fn test_finish_virtio_rx_plain() {
+        let packet = build_tcp_v4(b"abc");
+        let mut buf = BytesMut::from(&prepend_virtio_hdr_none(&packet)[..]);
+        assert!(finish_virtio_rx(&mut buf));
+        assert_eq!(&buf[..], &packet[..]);
+
+        let mut short = BytesMut::from(&[0u8; 4][..]);
+        assert!(!finish_virtio_rx(&mut short));
+    }
 }
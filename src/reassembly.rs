@@ -0,0 +1,224 @@
+//! Reassembly of fragmented IPv4 TCP segments.
+//!
+//! smoltcp is built without `proto-ipv4-fragmentation`, so it drops fragments, and a SYN can
+//! only be trapped once its TCP header is whole. The stack puts such segments back together
+//! before classifying them; the result goes through the usual paths as if it had arrived in
+//! one piece.
+
+use std::collections::{BTreeMap, HashMap};
+use bytes::{Bytes, BytesMut};
+use smoltcp::wire::{Ipv4Address, Ipv4Packet};
+use tokio::time::Instant;
+use crate::constants::{IPV4_REASSEMBLY_MAX_PACKETS, IPV4_REASSEMBLY_TIMEOUT};
+
+/// (source, destination, identification)
+type FragmentKey = (Ipv4Address, Ipv4Address, u16);
+
+struct PartialPacket {
+    /// Arrival of the first fragment
+    started: Instant,
+    /// IP header of the fragment at offset 0, once seen
+    header: Option<Bytes>,
+    /// Fragment payloads by byte offset, none overlapping another
+    fragments: BTreeMap<usize, Bytes>,
+    /// Payload bytes held in `fragments`
+    stored: usize,
+    /// Payload length, known once the last fragment (MF clear) arrived
+    total_len: Option<usize>,
+}
+
+impl PartialPacket {
+    /// Whether `len` bytes at `offset` would overlap a fragment already held, other than an
+    /// exact duplicate of one.
+    fn overlaps(&self, offset: usize, len: usize) -> bool {
+        let before = self.fragments.range(..offset).next_back();
+        let after = self.fragments.range(offset..).next();
+        before.is_some_and(|(&start, payload)| start + payload.len() > offset)
+            || after.is_some_and(|(&start, payload)| start < offset + len && (start, payload.len()) != (offset, len))
+    }
+
+    /// The reassembled packet. Fragments don't overlap, so they cover the whole payload once
+    /// their length adds up to it.
+    fn assemble(&self) -> Option<BytesMut> {
+        let (header, total_len) = (self.header.as_ref()?, self.total_len?);
+        if self.stored != total_len {
+            return None;
+        }
+
+        let mut packet = BytesMut::with_capacity(header.len() + total_len);
+        packet.extend_from_slice(header);
+        for payload in self.fragments.values() {
+            packet.extend_from_slice(payload);
+        }
+        let mut ip = Ipv4Packet::new_unchecked(&mut packet[..]);
+        ip.set_total_len((header.len() + total_len) as u16);
+        ip.set_more_frags(false);
+        ip.set_frag_offset(0);
+        ip.fill_checksum();
+        Some(packet)
+    }
+}
+
+/// What became of a fragment handed to [`Ipv4Reassembler::push`].
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Reassembly {
+    /// It completed its packet
+    Complete(BytesMut),
+    /// Kept until the rest of its packet arrives
+    Pending,
+    /// Malformed, or over one of the limits
+    Dropped,
+}
+
+/// Collects the fragments of IPv4 packets until they are complete.
+#[derive(Default)]
+pub(crate) struct Ipv4Reassembler {
+    pending: HashMap<FragmentKey, PartialPacket>,
+}
+
+impl Ipv4Reassembler {
+    /// Adds a fragment. A fragment of a new packet while `IPV4_REASSEMBLY_MAX_PACKETS` are in
+    /// progress is dropped. One that would grow its packet past 64 KiB, overlaps a fragment
+    /// already held (an exact duplicate is ignored) or lies past the end of the packet is
+    /// dropped along with the packet.
+    pub(crate) fn push(&mut self, fragment: &[u8], now: Instant) -> Reassembly {
+        let Ok(ip) = Ipv4Packet::new_checked(fragment) else { return Reassembly::Dropped };
+        let key = (ip.src_addr(), ip.dst_addr(), ip.ident());
+        if self.pending.len() >= IPV4_REASSEMBLY_MAX_PACKETS && !self.pending.contains_key(&key) {
+            return Reassembly::Dropped;
+        }
+        let header_len = ip.header_len() as usize;
+        let offset = ip.frag_offset() as usize;
+        let len = ip.payload().len();
+        let end = offset + len;
+
+        let partial = self.pending.entry(key).or_insert_with(|| PartialPacket {
+            started: now,
+            header: None,
+            fragments: BTreeMap::new(),
+            stored: 0,
+            total_len: None,
+        });
+        let last_end = partial.fragments.last_key_value().map_or(0, |(&start, payload)| start + payload.len());
+        let malformed = header_len + end > u16::MAX as usize
+            || partial.stored + len > u16::MAX as usize
+            || partial.overlaps(offset, len)
+            || partial.total_len.is_some_and(|total_len| end > total_len || (!ip.more_frags() && end != total_len))
+            || (!ip.more_frags() && end < last_end);
+        if malformed {
+            self.pending.remove(&key);
+            return Reassembly::Dropped;
+        }
+        if partial.fragments.get(&offset).is_some_and(|payload| payload.len() == len) {
+            return Reassembly::Pending;
+        }
+
+        if offset == 0 {
+            partial.header = Some(Bytes::copy_from_slice(&fragment[..header_len]));
+        }
+        if !ip.more_frags() {
+            partial.total_len = Some(end);
+        }
+        partial.fragments.insert(offset, Bytes::copy_from_slice(ip.payload()));
+        partial.stored += len;
+
+        match partial.assemble() {
+            Some(packet) => {
+                self.pending.remove(&key);
+                Reassembly::Complete(packet)
+            }
+            None => Reassembly::Pending,
+        }
+    }
+
+    /// Drops packets still incomplete `IPV4_REASSEMBLY_TIMEOUT` after their first fragment,
+    /// and returns how many.
+    pub(crate) fn expire(&mut self, now: Instant) -> usize {
+        let before = self.pending.len();
+        self.pending.retain(|_, partial| now.duration_since(partial.started) < IPV4_REASSEMBLY_TIMEOUT);
+        before - self.pending.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::phy::ChecksumCapabilities;
+    use smoltcp::wire::{IpProtocol, Ipv4Repr};
+
+    /// IPv4 fragment of packet `ident` carrying `payload` at `offset` bytes.
+    fn fragment(ident: u16, offset: usize, more: bool, payload: &[u8]) -> Vec<u8> {
+        let repr = Ipv4Repr {
+            src_addr: Ipv4Address::new(10, 11, 12, 2),
+            dst_addr: Ipv4Address::new(1, 2, 3, 4),
+            next_header: IpProtocol::Tcp,
+            payload_len: payload.len(),
+            hop_limit: 64,
+        };
+        let mut buf = vec![0u8; 20 + payload.len()];
+        let mut ip = Ipv4Packet::new_unchecked(&mut buf[..]);
+        repr.emit(&mut ip, &ChecksumCapabilities::default());
+        ip.set_ident(ident);
+        ip.set_more_frags(more);
+        ip.set_frag_offset(offset as u16);
+        ip.fill_checksum();
+        ip.payload_mut().copy_from_slice(payload);
+        buf
+    }
+
+    #[test]
+    fn test_fragments_in_any_order() {
+        let payload: Vec<u8> = (0..40).collect();
+        let now = Instant::now();
+        let mut reassembler = Ipv4Reassembler::default();
+        assert_eq!(reassembler.push(&fragment(7, 32, false, &payload[32..]), now), Reassembly::Pending);
+        assert_eq!(reassembler.push(&fragment(7, 8, true, &payload[8..16]), now), Reassembly::Pending);
+        // A retransmitted fragment is ignored
+        assert_eq!(reassembler.push(&fragment(7, 8, true, &payload[8..16]), now), Reassembly::Pending);
+        assert_eq!(reassembler.push(&fragment(7, 16, true, &payload[16..32]), now), Reassembly::Pending);
+        let Reassembly::Complete(packet) = reassembler.push(&fragment(7, 0, true, &payload[..8]), now) else {
+            panic!("all fragments are in");
+        };
+        let ip = Ipv4Packet::new_checked(&packet[..]).unwrap();
+        assert!(ip.verify_checksum());
+        assert_eq!((ip.total_len(), ip.more_frags(), ip.frag_offset()), (60, false, 0));
+        assert_eq!(ip.payload(), &payload[..]);
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn test_incomplete_packets_expire_and_are_bounded() {
+        let now = Instant::now();
+        let mut reassembler = Ipv4Reassembler::default();
+        for ident in 0..IPV4_REASSEMBLY_MAX_PACKETS as u16 {
+            assert_eq!(reassembler.push(&fragment(ident, 0, true, &[0; 8]), now), Reassembly::Pending);
+        }
+        assert_eq!(reassembler.push(&fragment(u16::MAX, 0, true, &[0; 8]), now), Reassembly::Dropped);
+        // Further fragments of packets already in progress are still taken
+        assert_eq!(reassembler.push(&fragment(0, 16, true, &[0; 8]), now), Reassembly::Pending);
+
+        assert_eq!(reassembler.expire(now + IPV4_REASSEMBLY_TIMEOUT / 2), 0);
+        assert_eq!(reassembler.expire(now + IPV4_REASSEMBLY_TIMEOUT), IPV4_REASSEMBLY_MAX_PACKETS);
+        // Too large once put together
+        assert_eq!(reassembler.push(&fragment(1, 65528, false, &[0; 8]), now), Reassembly::Dropped);
+    }
+
+    #[test]
+    fn test_overlapping_and_stray_fragments_drop_the_packet() {
+        let now = Instant::now();
+        let mut reassembler = Ipv4Reassembler::default();
+        // Overlap with the fragment before
+        assert_eq!(reassembler.push(&fragment(1, 0, true, &[0; 16]), now), Reassembly::Pending);
+        assert_eq!(reassembler.push(&fragment(1, 8, true, &[0; 16]), now), Reassembly::Dropped);
+        assert!(reassembler.pending.is_empty());
+        // Same offset, another length
+        assert_eq!(reassembler.push(&fragment(2, 8, true, &[0; 8]), now), Reassembly::Pending);
+        assert_eq!(reassembler.push(&fragment(2, 8, true, &[0; 16]), now), Reassembly::Dropped);
+        // Past the end of the packet, or a last fragment short of the ones held
+        assert_eq!(reassembler.push(&fragment(3, 8, false, &[0; 8]), now), Reassembly::Pending);
+        assert_eq!(reassembler.push(&fragment(3, 16, true, &[0; 8]), now), Reassembly::Dropped);
+        assert_eq!(reassembler.push(&fragment(4, 16, true, &[0; 8]), now), Reassembly::Pending);
+        assert_eq!(reassembler.push(&fragment(4, 0, false, &[0; 8]), now), Reassembly::Dropped);
+        assert!(reassembler.pending.is_empty());
+    }
+}
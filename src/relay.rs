@@ -0,0 +1,652 @@
+use bytes::Bytes;
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::wire::{
+    ArpOperation, ArpPacket, ArpRepr, EthernetAddress, EthernetFrame, EthernetProtocol, EthernetRepr, Icmpv4Packet,
+    Icmpv6Packet, Icmpv6Repr, IpAddress, IpProtocol, Ipv4Address, Ipv4Packet, Ipv4Repr, Ipv6Address, Ipv6Packet,
+    Ipv6Repr, NdiscNeighborFlags, NdiscRepr, UdpPacket, UdpRepr,
+};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// ICMP error messages may quote this much of an IPv4 datagram (RFC 1812 4.3.2.3).
+const ICMPV4_ERROR_MAX_LEN: usize = 576;
+/// An ICMPv6 error must not exceed the minimum IPv6 MTU (RFC 4443 2.4).
+const ICMPV6_ERROR_MAX_LEN: usize = 1280;
+/// IP header of the error plus the 8 byte ICMP header in front of the quotation.
+const ICMPV4_OVERHEAD: usize = 20 + 8;
+const ICMPV6_OVERHEAD: usize = 40 + 8;
+/// Fixed DNS header: ID, flags and the four section counts (RFC 1035 4.1.1).
+const DNS_HEADER_LEN: usize = 12;
+/// Longest domain name on the wire, length octets included (RFC 1035 2.3.4).
+const DNS_MAX_NAME_LEN: usize = 255;
+
+/// Builds a "port unreachable" error for `orig`, sent back to its source from its
+/// destination: ICMPv4 Type 3 Code 3, or ICMPv6 Type 1 Code 4. The error quotes as much of
+/// `orig` as fits the family's limit, so the sender can match it to its socket.
+///
+/// Returns `None` where no error may be sent (RFC 1122 3.2.2, RFC 4443 2.4): `orig` is
+/// malformed, a non-first fragment, an ICMP error itself, or was sent from or to an address
+/// that isn't a single host.
+pub fn icmp_port_unreachable(orig: &[u8]) -> Option<Bytes> {
+    match orig.first()? >> 4 {
+        4 => icmpv4_unreachable(orig, 3, None, [0; 4]),
+        6 => icmpv6_error(orig, 1, 4, None, [0; 4]),
+        _ => None,
+    }
+}
+
+/// Builds a "network unreachable" error for `orig`, sent back to its source from `router`:
+/// ICMPv4 Type 3 Code 0, or ICMPv6 Type 1 Code 0 (no route to destination). Same rules as
+/// [`icmp_port_unreachable`]; also `None` if `router` isn't of `orig`'s family.
+pub fn icmp_net_unreachable(orig: &[u8], router: IpAddr) -> Option<Bytes> {
+    match (orig.first()? >> 4, router) {
+        (4, IpAddr::V4(router)) => icmpv4_unreachable(orig, 0, Some(router.into()), [0; 4]),
+        (6, IpAddr::V6(router)) => icmpv6_error(orig, 1, 0, Some(router.into()), [0; 4]),
+        _ => None,
+    }
+}
+
+/// Builds a "packet too big" error for `orig`, sent back to its source from `router`, telling
+/// it to stay within `mtu`: ICMPv4 Type 3 Code 4 (fragmentation needed, RFC 1191) or ICMPv6
+/// Type 2. Same rules as [`icmp_net_unreachable`]; also `None` for an IPv4 packet without DF,
+/// which a router would fragment instead. IPv6 requires every link to carry 1280 bytes, so a
+/// smaller `mtu` is reported as 1280.
+pub fn icmp_packet_too_big(orig: &[u8], router: IpAddr, mtu: u16) -> Option<Bytes> {
+    match (orig.first()? >> 4, router) {
+        // Next-hop MTU in the low half of the otherwise unused word
+        (4, IpAddr::V4(router)) => {
+            if !Ipv4Packet::new_checked(orig).ok()?.dont_frag() {
+                return None;
+            }
+            let [hi, lo] = mtu.to_be_bytes();
+            icmpv4_unreachable(orig, 4, Some(router.into()), [0, 0, hi, lo])
+        }
+        (6, IpAddr::V6(router)) => {
+            icmpv6_error(orig, 2, 0, Some(router.into()), u32::from(mtu.max(1280)).to_be_bytes())
+        }
+        _ => None,
+    }
+}
+
+/// Reads an ICMP fragmentation needed (ICMPv6 packet too big): the destination of the packet
+/// that didn't fit, and the MTU the router asked for. `None` for anything else.
+pub fn packet_too_big(icmp: &[u8]) -> Option<(IpAddr, u16)> {
+    match icmp.first()? >> 4 {
+        4 => {
+            let ip = Ipv4Packet::new_checked(icmp).ok()?;
+            let msg = ip.payload();
+            if ip.next_header() != IpProtocol::Icmp || msg.len() < 8 || msg[0] != 3 || msg[1] != 4 {
+                return None;
+            }
+            let orig = msg.get(8..28)?;
+            let dst: [u8; 4] = orig[16..20].try_into().ok()?;
+            Some((IpAddr::V4(dst.into()), u16::from_be_bytes([msg[6], msg[7]])))
+        }
+        6 => {
+            let ip = Ipv6Packet::new_checked(icmp).ok()?;
+            let msg = ip.payload();
+            if ip.next_header() != IpProtocol::Icmpv6 || msg.len() < 8 || msg[0] != 2 {
+                return None;
+            }
+            let orig = msg.get(8..48)?;
+            let dst: [u8; 16] = orig[24..40].try_into().ok()?;
+            let mtu = u32::from_be_bytes([msg[4], msg[5], msg[6], msg[7]]);
+            Some((IpAddr::V6(dst.into()), mtu.min(u16::MAX as u32) as u16))
+        }
+        _ => None,
+    }
+}
+
+/// Builds the Ethernet frame telling the interface `to` that `ip` is at `mac`: an ARP reply
+/// for IPv4, an unsolicited neighbor advertisement (override flag set) for IPv6. Returns
+/// `None` if `ip` and the interface's address are of different families.
+pub fn neighbor_announcement(ip: IpAddress, mac: EthernetAddress, to: (IpAddress, EthernetAddress)) -> Option<Bytes> {
+    let (ethertype, len) = match ip {
+        IpAddress::Ipv4(_) => (EthernetProtocol::Arp, 28),
+        // IPv6 header, then a neighbor advertisement with its link-layer address option
+        IpAddress::Ipv6(_) => (EthernetProtocol::Ipv6, 40 + 32),
+    };
+    let mut buf = vec![0u8; 14 + len];
+    let mut frame = EthernetFrame::new_unchecked(&mut buf);
+    EthernetRepr { src_addr: mac, dst_addr: to.1, ethertype }.emit(&mut frame);
+    match (ip, to.0) {
+        (IpAddress::Ipv4(src), IpAddress::Ipv4(dst)) => ArpRepr::EthernetIpv4 {
+            operation: ArpOperation::Reply,
+            source_hardware_addr: mac,
+            source_protocol_addr: src,
+            target_hardware_addr: to.1,
+            target_protocol_addr: dst,
+        }
+        .emit(&mut ArpPacket::new_unchecked(frame.payload_mut())),
+        (IpAddress::Ipv6(src), IpAddress::Ipv6(dst)) => {
+            let advert = Icmpv6Repr::Ndisc(NdiscRepr::NeighborAdvert {
+                flags: NdiscNeighborFlags::OVERRIDE,
+                target_addr: src,
+                lladdr: Some(mac.into()),
+            });
+            // Neighbor discovery only trusts messages that no router forwarded
+            let ip_repr =
+                Ipv6Repr { src_addr: src, dst_addr: dst, next_header: IpProtocol::Icmpv6, payload_len: 32, hop_limit: 255 };
+            let mut packet = Ipv6Packet::new_unchecked(frame.payload_mut());
+            ip_repr.emit(&mut packet);
+            let caps = ChecksumCapabilities::default();
+            advert.emit(&ip, &dst.into(), &mut Icmpv6Packet::new_unchecked(packet.payload_mut()), &caps);
+        }
+        _ => return None,
+    }
+    Some(Bytes::from(buf))
+}
+
+/// Builds a UDP datagram from `src` to `dst` carrying `payload`, checksummed. Returns `None`
+/// if the addresses are of different families or the datagram would exceed 64 KiB.
+pub fn build_udp(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Option<Bytes> {
+    let caps = ChecksumCapabilities::default();
+    let udp_len = 8 + payload.len();
+    let emit_udp = |buf: &mut [u8], src_addr: IpAddress, dst_addr: IpAddress| {
+        UdpRepr { src_port: src.port(), dst_port: dst.port() }.emit(
+            &mut UdpPacket::new_unchecked(buf),
+            &src_addr,
+            &dst_addr,
+            payload.len(),
+            |p| p.copy_from_slice(payload),
+            &caps,
+        );
+    };
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            if 20 + udp_len > u16::MAX as usize {
+                return None;
+            }
+            let repr = Ipv4Repr {
+                src_addr: s.into(),
+                dst_addr: d.into(),
+                next_header: IpProtocol::Udp,
+                payload_len: udp_len,
+                hop_limit: 64,
+            };
+            let mut packet = vec![0u8; repr.buffer_len() + udp_len];
+            repr.emit(&mut Ipv4Packet::new_unchecked(&mut packet[..]), &caps);
+            emit_udp(&mut packet[repr.buffer_len()..], repr.src_addr.into(), repr.dst_addr.into());
+            Some(Bytes::from(packet))
+        }
+        (IpAddr::V6(s), IpAddr::V6(d)) => {
+            if udp_len > u16::MAX as usize {
+                return None;
+            }
+            let repr = Ipv6Repr {
+                src_addr: s.into(),
+                dst_addr: d.into(),
+                next_header: IpProtocol::Udp,
+                payload_len: udp_len,
+                hop_limit: 64,
+            };
+            let mut packet = vec![0u8; repr.buffer_len() + udp_len];
+            repr.emit(&mut Ipv6Packet::new_unchecked(&mut packet[..]));
+            emit_udp(&mut packet[repr.buffer_len()..], repr.src_addr.into(), repr.dst_addr.into());
+            Some(Bytes::from(packet))
+        }
+        _ => None,
+    }
+}
+
+/// Destination unreachable with `code`, from `from` or else from `orig`'s destination. `rest`
+/// is the second word of the ICMP header.
+fn icmpv4_unreachable(orig: &[u8], code: u8, from: Option<Ipv4Address>, rest: [u8; 4]) -> Option<Bytes> {
+    let ip = Ipv4Packet::new_checked(orig).ok()?;
+    let (src, dst) = (Ipv4Addr::from(ip.src_addr()), Ipv4Addr::from(ip.dst_addr()));
+    if !is_unicast_v4(src) || !is_unicast_v4(dst) || ip.frag_offset() != 0 {
+        return None;
+    }
+    let header_len = ip.header_len() as usize;
+    // Types 0, 8 and 13..=18 are queries (echo, timestamp, ...); everything else is an error
+    if ip.next_header() == IpProtocol::Icmp {
+        let msg_type = *orig.get(header_len)?;
+        if !matches!(msg_type, 0 | 8 | 13..=18) {
+            return None;
+        }
+    }
+
+    let orig = &orig[..ip.total_len() as usize];
+    let quote = &orig[..orig.len().min(ICMPV4_ERROR_MAX_LEN - ICMPV4_OVERHEAD)];
+    let reply = Ipv4Repr {
+        src_addr: from.unwrap_or(ip.dst_addr()),
+        dst_addr: ip.src_addr(),
+        next_header: IpProtocol::Icmp,
+        payload_len: 8 + quote.len(),
+        hop_limit: 64,
+    };
+    let mut packet = vec![0u8; reply.buffer_len() + reply.payload_len];
+    reply.emit(&mut Ipv4Packet::new_unchecked(&mut packet[..]), &ChecksumCapabilities::default());
+    let icmp = &mut packet[reply.buffer_len()..];
+    // Type, code, checksum, then `rest` before the quotation
+    icmp[0] = 3;
+    icmp[1] = code;
+    icmp[4..8].copy_from_slice(&rest);
+    icmp[8..].copy_from_slice(quote);
+    Icmpv4Packet::new_unchecked(icmp).fill_checksum();
+    Some(Bytes::from(packet))
+}
+
+/// Error `msg_type` with `code`, from `from` or else from `orig`'s destination. `rest` is the
+/// second word of the ICMPv6 header.
+fn icmpv6_error(orig: &[u8], msg_type: u8, code: u8, from: Option<Ipv6Address>, rest: [u8; 4]) -> Option<Bytes> {
+    let ip = Ipv6Packet::new_checked(orig).ok()?;
+    let (src, dst) = (Ipv6Addr::from(ip.src_addr()), Ipv6Addr::from(ip.dst_addr()));
+    if src.is_unspecified() || src.is_multicast() || dst.is_multicast() {
+        return None;
+    }
+    let (next_header, offset) = crate::trap::skip_ipv6_headers(orig).ok()?;
+    match next_header {
+        // Only non-first fragments come back as a fragment header
+        IpProtocol::Ipv6Frag => return None,
+        // Types below 128 are errors
+        IpProtocol::Icmpv6 if *orig.get(offset)? < 128 => return None,
+        _ => {}
+    }
+
+    let orig = &orig[..ip.total_len()];
+    let quote = &orig[..orig.len().min(ICMPV6_ERROR_MAX_LEN - ICMPV6_OVERHEAD)];
+    let reply = Ipv6Repr {
+        src_addr: from.unwrap_or(ip.dst_addr()),
+        dst_addr: ip.src_addr(),
+        next_header: IpProtocol::Icmpv6,
+        payload_len: 8 + quote.len(),
+        hop_limit: 64,
+    };
+    let mut packet = vec![0u8; reply.buffer_len() + reply.payload_len];
+    reply.emit(&mut Ipv6Packet::new_unchecked(&mut packet[..]));
+    let icmp = &mut packet[reply.buffer_len()..];
+    icmp[0] = msg_type;
+    icmp[1] = code;
+    icmp[4..8].copy_from_slice(&rest);
+    icmp[8..].copy_from_slice(quote);
+    Icmpv6Packet::new_unchecked(icmp)
+        .fill_checksum(&IpAddress::Ipv6(reply.src_addr), &IpAddress::Ipv6(reply.dst_addr));
+    Some(Bytes::from(packet))
+}
+
+/// A DNS query seen on its way to the Blind Relay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsQuery {
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+    /// First question's name, without the trailing dot (`.` for the root). Bytes other than
+    /// printable ASCII are written as `\DDD`, as `dig` does.
+    pub name: String,
+    /// First question's QTYPE (1 = A, 28 = AAAA, ...)
+    pub qtype: u16,
+}
+
+/// Reads the first question of a DNS query in a UDP packet to port 53. `None` for anything
+/// else: other protocols or ports, fragments, responses, and malformed messages (truncated,
+/// bad label types, names too long, compression pointers that don't point backwards).
+pub fn dns_query(packet: &[u8]) -> Option<DnsQuery> {
+    let (client, server, payload) = crate::trap::udp_datagram(packet)?;
+    if server.port() != 53 {
+        return None;
+    }
+    let (name, qtype) = dns_question(&packet[payload])?;
+    Some(DnsQuery { client, server, name, qtype })
+}
+
+/// Name and QTYPE of the first question of the DNS query `msg`.
+fn dns_question(msg: &[u8]) -> Option<(String, u16)> {
+    let header = msg.get(..DNS_HEADER_LEN)?;
+    // QR set: a response, not a query
+    if header[2] & 0x80 != 0 || u16::from_be_bytes([header[4], header[5]]) == 0 {
+        return None;
+    }
+    let mut name = String::new();
+    let mut wire_len = 0;
+    let mut pos = DNS_HEADER_LEN;
+    // Where the question continues once the name ends, set at the first pointer
+    let mut resume = None;
+    loop {
+        let len = *msg.get(pos)?;
+        match len & 0xc0 {
+            0x00 if len == 0 => break,
+            0x00 => {
+                wire_len += 1 + len as usize;
+                if wire_len + 1 > DNS_MAX_NAME_LEN {
+                    return None;
+                }
+                for &byte in msg.get(pos + 1..pos + 1 + len as usize)? {
+                    match byte {
+                        b'.' | b'\\' => { name.push('\\'); name.push(byte as char); }
+                        0x21..=0x7e => name.push(byte as char),
+                        _ => name.push_str(&format!("\\{byte:03}")),
+                    }
+                }
+                name.push('.');
+                pos += 1 + len as usize;
+            }
+            0xc0 => {
+                let target = usize::from(u16::from_be_bytes([len, *msg.get(pos + 1)?]) & 0x3fff);
+                // Pointers only jump backwards and every label counts towards the name's
+                // length limit, so a crafted chain can't loop
+                if target >= pos {
+                    return None;
+                }
+                resume.get_or_insert(pos + 2);
+                pos = target;
+            }
+            // 0x40 and 0x80 label types are obsolete or unassigned (RFC 6891 5)
+            _ => return None,
+        }
+    }
+    let end = resume.unwrap_or(pos + 1);
+    let qtype = msg.get(end..end + 2)?;
+    if name.is_empty() {
+        name.push('.');
+    } else {
+        name.pop();
+    }
+    Some((name, u16::from_be_bytes([qtype[0], qtype[1]])))
+}
+
+fn is_unicast_v4(addr: Ipv4Addr) -> bool {
+    !addr.is_unspecified() && !addr.is_broadcast() && !addr.is_multicast()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_udp_v4(payload_len: usize) -> Vec<u8> {
+        let total = 20 + 8 + payload_len;
+        let mut pkt = vec![0u8; total];
+        pkt[0] = 0x45;
+        pkt[2..4].copy_from_slice(&(total as u16).to_be_bytes());
+        pkt[6] = 0x40; // DF
+        pkt[8] = 64;
+        pkt[9] = 17;
+        pkt[12..16].copy_from_slice(&[10, 0, 0, 2]);
+        pkt[16..20].copy_from_slice(&[10, 0, 0, 1]);
+        let mut ip = Ipv4Packet::new_unchecked(&mut pkt[..]);
+        ip.fill_checksum();
+        pkt[20..22].copy_from_slice(&5353u16.to_be_bytes());
+        pkt[22..24].copy_from_slice(&9999u16.to_be_bytes());
+        pkt[24..26].copy_from_slice(&((8 + payload_len) as u16).to_be_bytes());
+        for (i, b) in pkt[28..].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        pkt
+    }
+
+    fn build_udp_v6(payload_len: usize) -> Vec<u8> {
+        let mut pkt = vec![0u8; 40 + 8 + payload_len];
+        pkt[0] = 0x60;
+        pkt[4..6].copy_from_slice(&((8 + payload_len) as u16).to_be_bytes());
+        pkt[6] = 17;
+        pkt[7] = 64;
+        pkt[8..24].copy_from_slice(&"fd00::2".parse::<Ipv6Addr>().unwrap().octets());
+        pkt[24..40].copy_from_slice(&"fd00::1".parse::<Ipv6Addr>().unwrap().octets());
+        pkt[40..42].copy_from_slice(&5353u16.to_be_bytes());
+        pkt[42..44].copy_from_slice(&9999u16.to_be_bytes());
+        pkt[44..46].copy_from_slice(&((8 + payload_len) as u16).to_be_bytes());
+        pkt
+    }
+
+    /// One's complement sum over `data`, folded; 0 when a filled-in checksum is correct.
+    fn fold(data: &[u8], mut sum: u32) -> u16 {
+        for chunk in data.chunks(2) {
+            sum += u32::from(u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]));
+        }
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+
+    #[test]
+    fn test_icmpv4_port_unreachable_layout() {
+        let orig = build_udp_v4(4);
+        let reply = icmp_port_unreachable(&orig).unwrap();
+
+        assert_eq!(reply.len(), 20 + 8 + orig.len());
+        // IPv4 header: version/IHL, total length, TTL, protocol ICMP, swapped addresses
+        assert_eq!(reply[0], 0x45);
+        assert_eq!(&reply[2..4], &(reply.len() as u16).to_be_bytes());
+        assert_eq!(reply[8], 64);
+        assert_eq!(reply[9], 1);
+        assert_eq!(&reply[12..16], &[10, 0, 0, 1]);
+        assert_eq!(&reply[16..20], &[10, 0, 0, 2]);
+        assert_eq!(fold(&reply[..20], 0), 0);
+        // ICMP: type 3, code 3, unused word zero, then the whole original datagram
+        assert_eq!(&reply[20..22], &[3, 3]);
+        assert_eq!(&reply[24..28], &[0; 4]);
+        assert_eq!(&reply[28..], &orig[..]);
+        assert_eq!(fold(&reply[20..], 0), 0);
+    }
+
+    #[test]
+    fn test_icmpv6_port_unreachable_layout() {
+        let orig = build_udp_v6(4);
+        let reply = icmp_port_unreachable(&orig).unwrap();
+
+        assert_eq!(reply.len(), 40 + 8 + orig.len());
+        assert_eq!(reply[0] >> 4, 6);
+        assert_eq!(&reply[4..6], &((8 + orig.len()) as u16).to_be_bytes());
+        assert_eq!(reply[6], 58);
+        assert_eq!(&reply[8..24], &orig[24..40]);
+        assert_eq!(&reply[24..40], &orig[8..24]);
+        // ICMPv6: type 1, code 4, unused word zero, then the original datagram
+        assert_eq!(&reply[40..42], &[1, 4]);
+        assert_eq!(&reply[44..48], &[0; 4]);
+        assert_eq!(&reply[48..], &orig[..]);
+        // Pseudo-header: addresses, upper-layer length, next header
+        let icmp = &reply[40..];
+        let mut pseudo = reply[8..40].to_vec();
+        pseudo.extend_from_slice(&(icmp.len() as u32).to_be_bytes());
+        pseudo.extend_from_slice(&[0, 0, 0, 58]);
+        let sum = u32::from(!fold(&pseudo, 0));
+        assert_eq!(fold(icmp, sum), 0);
+    }
+
+    #[test]
+    fn test_icmp_net_unreachable_comes_from_the_router() {
+        let router: IpAddr = "10.0.0.254".parse().unwrap();
+        let orig = build_udp_v4(4);
+        let reply = icmp_net_unreachable(&orig, router).unwrap();
+        assert_eq!(&reply[12..16], &[10, 0, 0, 254]);
+        assert_eq!(&reply[16..20], &[10, 0, 0, 2]);
+        assert_eq!(&reply[20..22], &[3, 0]);
+        assert_eq!(&reply[28..], &orig[..]);
+        assert_eq!(fold(&reply[20..], 0), 0);
+
+        let router: IpAddr = "fd00::fe".parse().unwrap();
+        let reply = icmp_net_unreachable(&build_udp_v6(4), router).unwrap();
+        assert_eq!(&reply[8..24], &Ipv6Addr::from(Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 0xfe)).octets());
+        assert_eq!(&reply[40..42], &[1, 0]);
+
+        // The router must be of the datagram's family
+        assert!(icmp_net_unreachable(&orig, router).is_none());
+    }
+
+    #[test]
+    fn test_icmp_packet_too_big_carries_the_mtu() {
+        let error = icmp_packet_too_big(&build_udp_v4(1400), "10.0.0.254".parse().unwrap(), 1280).unwrap();
+        let ip = Ipv4Packet::new_checked(&error[..]).unwrap();
+        assert_eq!(ip.src_addr(), Ipv4Address::new(10, 0, 0, 254));
+        let icmp = ip.payload();
+        assert_eq!((icmp[0], icmp[1]), (3, 4));
+        assert_eq!(&icmp[4..8], &[0, 0, 0x05, 0x00]);
+        assert_eq!(fold(icmp, 0), 0);
+
+        let error = icmp_packet_too_big(&build_udp_v6(1400), "fd00::fe".parse().unwrap(), 1280).unwrap();
+        let ip = Ipv6Packet::new_checked(&error[..]).unwrap();
+        let icmp = ip.payload();
+        assert_eq!((icmp[0], icmp[1]), (2, 0));
+        assert_eq!(&icmp[4..8], &1280u32.to_be_bytes());
+        assert_eq!(error.len(), ICMPV6_ERROR_MAX_LEN);
+
+        assert!(icmp_packet_too_big(&build_udp_v6(1400), "10.0.0.254".parse().unwrap(), 1280).is_none());
+    }
+
+    #[test]
+    fn test_icmp_packet_too_big_follows_the_family_rules() {
+        // Without DF a router fragments instead of answering
+        let mut orig = build_udp_v4(1400);
+        orig[6] = 0;
+        Ipv4Packet::new_unchecked(&mut orig[..]).fill_checksum();
+        assert!(icmp_packet_too_big(&orig, "10.0.0.254".parse().unwrap(), 1000).is_none());
+
+        // IPv4 may go below 1280, IPv6 may not
+        let error = icmp_packet_too_big(&build_udp_v4(1400), "10.0.0.254".parse().unwrap(), 1000).unwrap();
+        assert_eq!(&error[24..28], &[0, 0, 0x03, 0xe8]);
+        let error = icmp_packet_too_big(&build_udp_v6(1400), "fd00::fe".parse().unwrap(), 1000).unwrap();
+        assert_eq!(&error[44..48], &1280u32.to_be_bytes());
+    }
+
+    #[test]
+    fn test_packet_too_big_is_read_back() {
+        for orig in [build_udp_v4(1400), build_udp_v6(1400)] {
+            let router = if orig[0] >> 4 == 4 { "10.0.0.254" } else { "fd00::fe" };
+            let error = icmp_packet_too_big(&orig, router.parse().unwrap(), 1280).unwrap();
+            let dst = crate::trap::destination_ip(&orig).unwrap();
+            assert_eq!(packet_too_big(&error), Some((dst, 1280)));
+        }
+        // Other ICMP errors, and anything that isn't ICMP, aren't
+        assert!(packet_too_big(&icmp_port_unreachable(&build_udp_v4(4)).unwrap()).is_none());
+        assert!(packet_too_big(&icmp_net_unreachable(&build_udp_v6(4), "fd00::fe".parse().unwrap()).unwrap()).is_none());
+        assert!(packet_too_big(&build_udp_v4(1400)).is_none());
+    }
+
+    #[test]
+    fn test_icmp_port_unreachable_quotation_is_capped() {
+        let reply = icmp_port_unreachable(&build_udp_v4(1400)).unwrap();
+        assert_eq!(reply.len(), ICMPV4_ERROR_MAX_LEN);
+
+        let reply = icmp_port_unreachable(&build_udp_v6(1400)).unwrap();
+        assert_eq!(reply.len(), ICMPV6_ERROR_MAX_LEN);
+    }
+
+    #[test]
+    fn test_icmp_port_unreachable_ignores_link_padding() {
+        let orig = build_udp_v4(4);
+        let mut padded = orig.clone();
+        padded.extend_from_slice(&[0xee; 10]);
+        let reply = icmp_port_unreachable(&padded).unwrap();
+        assert_eq!(&reply[28..], &orig[..]);
+    }
+
+    #[test]
+    fn test_icmp_port_unreachable_refused_cases() {
+        // Broadcast destination
+        let mut pkt = build_udp_v4(4);
+        pkt[16..20].copy_from_slice(&[255; 4]);
+        assert!(icmp_port_unreachable(&pkt).is_none());
+
+        // Non-first fragment
+        let mut pkt = build_udp_v4(4);
+        pkt[6..8].copy_from_slice(&1u16.to_be_bytes());
+        assert!(icmp_port_unreachable(&pkt).is_none());
+
+        // An ICMP error (destination unreachable) is never answered with another
+        let error = icmp_port_unreachable(&build_udp_v4(4)).unwrap();
+        assert!(icmp_port_unreachable(&error).is_none());
+        let error = icmp_port_unreachable(&build_udp_v6(4)).unwrap();
+        assert!(icmp_port_unreachable(&error).is_none());
+
+        // An echo request may be
+        let mut ping = build_udp_v4(4);
+        ping[9] = 1;
+        ping[20] = 8;
+        assert!(icmp_port_unreachable(&ping).is_some());
+
+        // Multicast destination
+        let mut pkt = build_udp_v6(4);
+        pkt[24] = 0xff;
+        assert!(icmp_port_unreachable(&pkt).is_none());
+
+        assert!(icmp_port_unreachable(&[]).is_none());
+        assert!(icmp_port_unreachable(&[0x45, 0, 0]).is_none());
+    }
+
+    /// `msg` as a UDP payload from 10.0.0.2:5353 (or fd00::2) to port 53.
+    fn build_dns(msg: &[u8], v6: bool) -> Vec<u8> {
+        let mut pkt = if v6 { build_udp_v6(msg.len()) } else { build_udp_v4(msg.len()) };
+        let udp = if v6 { 40 } else { 20 };
+        pkt[udp + 2..udp + 4].copy_from_slice(&53u16.to_be_bytes());
+        pkt[udp + 8..].copy_from_slice(msg);
+        pkt
+    }
+
+    /// `dig example.com`: recursion desired, AD bit, an EDNS OPT record with a client cookie.
+    const DIG_EXAMPLE_COM: &[u8] = &[
+        0x4f, 0x1a, 0x01, 0x20, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+        7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0x00, 0x01, 0x00, 0x01,
+        0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0c,
+        0x00, 0x0a, 0x00, 0x08, 0x8d, 0x3b, 0x27, 0x70, 0x1a, 0x52, 0x6e, 0x03,
+    ];
+
+    #[test]
+    fn test_dns_query_real_layouts() {
+        let query = dns_query(&build_dns(DIG_EXAMPLE_COM, false)).unwrap();
+        assert_eq!(query.name, "example.com");
+        assert_eq!(query.qtype, 1);
+        assert_eq!(query.client, "10.0.0.2:5353".parse().unwrap());
+        assert_eq!(query.server, "10.0.0.1:53".parse().unwrap());
+
+        // glibc stub resolver: AAAA, no EDNS, over IPv6
+        let glibc = [
+            0xa3, 0x07, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            3, b'w', b'w', b'w', 6, b'g', b'o', b'o', b'g', b'l', b'e', 3, b'c', b'o', b'm', 0,
+            0x00, 0x1c, 0x00, 0x01,
+        ];
+        let query = dns_query(&build_dns(&glibc, true)).unwrap();
+        assert_eq!((query.name.as_str(), query.qtype), ("www.google.com", 28));
+        assert_eq!(query.server, "[fd00::1]:53".parse().unwrap());
+
+        // `dig . NS`: the root
+        let root = [0x00, 0x01, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0, 0x00, 0x02, 0x00, 0x01];
+        assert_eq!(dns_query(&build_dns(&root, false)).unwrap().name, ".");
+
+        // Odd bytes in a label are escaped, not passed through
+        let mut odd = DIG_EXAMPLE_COM[..12].to_vec();
+        odd.extend_from_slice(&[4, b'a', b'.', b' ', 0xff, 0, 0x00, 0x10, 0x00, 0x01]);
+        assert_eq!(dns_query(&build_dns(&odd, false)).unwrap().name, "a\\.\\032\\255");
+    }
+
+    #[test]
+    fn test_dns_query_refused_cases() {
+        // Every truncation of a valid query that cuts into its name or QTYPE
+        let pkt = build_dns(DIG_EXAMPLE_COM, false);
+        for len in 0..20 + 8 + 12 + 13 + 2 {
+            let mut short = pkt[..len].to_vec();
+            if len >= 20 {
+                short[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+            }
+            assert!(dns_query(&short).is_none(), "{len} bytes");
+        }
+
+        // A response, another port, a fragment
+        let mut response = DIG_EXAMPLE_COM.to_vec();
+        response[2] |= 0x80;
+        assert!(dns_query(&build_dns(&response, false)).is_none());
+        assert!(dns_query(&build_udp_v4(DIG_EXAMPLE_COM.len())).is_none());
+        let mut fragment = build_dns(DIG_EXAMPLE_COM, false);
+        fragment[6] |= 0x20;
+        assert!(dns_query(&fragment).is_none());
+
+        let with_name = |name: &[u8]| {
+            let mut msg = DIG_EXAMPLE_COM[..12].to_vec();
+            msg.extend_from_slice(name);
+            msg.extend_from_slice(&[0x00, 0x01, 0x00, 0x01]);
+            dns_query(&build_dns(&msg, false))
+        };
+        // Pointer to itself, forward, and back to a label that leads to it again
+        assert!(with_name(&[0xc0, 12]).is_none());
+        assert!(with_name(&[0xc0, 14, 0]).is_none());
+        assert!(with_name(&[1, b'a', 0xc0, 12]).is_none());
+        // Extended (0x40) label type
+        assert!(with_name(&[0x41, 0]).is_none());
+        // 256 bytes on the wire
+        let long: Vec<u8> = std::iter::repeat_n([63].iter().chain(&[b'x'; 63]).copied().collect::<Vec<_>>(), 4)
+            .flatten()
+            .chain([0])
+            .collect();
+        assert_eq!(long.len(), 257);
+        assert!(with_name(&long).is_none());
+        assert_eq!(with_name(&long[64..]).unwrap().name.len(), 3 * 64 - 1);
+    }
+}
@@ -1,28 +1,234 @@
-use smoltcp::iface::{Config, Interface, SocketSet, SocketHandle};
+use smoltcp::iface::{Config, Interface, Route, SocketSet, SocketHandle, SocketStorage};
+use smoltcp::socket::{AnySocket, Socket};
 use smoltcp::socket::tcp;
 use smoltcp::time::Instant;
-use smoltcp::wire::{IpAddress, IpCidr, Ipv4Address, Ipv6Address, HardwareAddress, EthernetAddress};
+use smoltcp::wire::{IpAddress, IpCidr, Ipv4Address, Ipv4Cidr, Ipv6Address, Ipv6Cidr, HardwareAddress, EthernetAddress};
 use tokio::time::{self, Duration};
 use tokio::sync::{mpsc, oneshot};
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
 use crate::device::PrismDevice;
-use crate::trap::PrismTrap;
-use crate::constants::{TCP_RX_BUFFER_SIZE, TCP_TX_BUFFER_SIZE, BATCH_SIZE};
-use std::collections::{HashMap, HashSet};
-use std::net::SocketAddr;
+use crate::trap::{MssClamp, PrismTrap, SynSummary};
+use crate::stats::{MemoryEstimate, PrismStats, StatsSnapshot};
+use crate::constants::{CHANNEL_SIZE, TCP_RX_BUFFER_SIZE, TCP_TX_BUFFER_SIZE, BATCH_SIZE, INGRESS_BATCH_SIZE, MAX_REPOLLS, BLIND_RELAY_BACKLOG, TUNNEL_REQUEST_BACKLOG, PENDING_PACKETS_CAP, PENDING_DROP_SCAN, ADMISSION_QUEUE_CAP, TUNNEL_REAP_INTERVAL, SYN_CACHE_TTL, UDP_TUNNEL_IDLE_TIMEOUT, MAX_UDP_TUNNELS, PATH_MTU_TTL, PATH_MTU_CACHE_SIZE, STATIC_NEIGHBOR_REFRESH, MAX_ROUTES, SOCKET_COMPACT_MIN_SLOTS, SOCKET_COMPACT_RATIO, PEEK_WINDOW, ICMP_ERROR_BURST, ICMP_ERROR_WINDOW};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
 use tracing::{debug, warn, error};
 use smoltcp::phy::Device;
 use bytes::{Bytes, BytesMut};
-use futures::stream::{StreamExt, SelectAll, BoxStream};
-use tokio_stream::wrappers::ReceiverStream;
+use futures::FutureExt;
+use futures::stream::{Stream, StreamExt, SelectAll};
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
 
 /// Configuration for the Prism Stack.
 #[derive(Debug, Clone)]
 pub struct PrismConfig {
     pub handshake_mode: HandshakeMode,
     pub egress_mtu: usize,
-    /// Enable Linux Native GSO/GRO via IFF_VNET_HDR (Linux only, ignored on other platforms).
+    /// Linux `IFF_VNET_HDR` offload (Linux only, ignored on other platforms). When not `Off`,
+    /// packets on the device channels carry a `virtio_net_hdr` in both directions.
+    pub offload: OffloadMode,
+    /// Former on/off switch for the vnet header offload: `true` with `offload` left `Off`
+    /// means `OffloadMode::Gso`.
+    #[deprecated(note = "use `offload`")]
     pub linux_offload: bool,
+    /// What to do with packets addressed to broadcast or multicast destinations.
+    pub cast_policy: CastPolicy,
+    /// How long a Consistent handshake waits for the relayer before the SYN is dropped, and a
+    /// tunnel from `PrismStack::open_tunnel` for its client's SYN.
+    pub handshake_timeout: Duration,
+    /// Log (at debug) the first N client bytes of every new tunnel as hex. `0` disables it,
+    /// which is what production deployments should use since payloads may be sensitive.
+    pub log_payload_prefix: usize,
+    /// Size of the [`FlowPeek`] taken from the start of each tunnel's client data (e.g. a TLS
+    /// ClientHello, for routing on its SNI), once `set_peek_sender` is called. The peek is
+    /// shorter if the client sends its FIN or pauses for `PEEK_WINDOW` first. `0` disables it.
+    pub peek_bytes: usize,
+    /// Scan every tunnel for client data on each loop iteration, even when smoltcp's poll
+    /// reported no change. Off by default; only useful to rule out the idle skip when debugging.
+    pub always_pump_egress: bool,
+    /// Upper bound on the bytes taken from a socket per `recv`, i.e. on the size of one
+    /// message sent to a tunnel's egress channel.
+    pub max_egress_chunk: usize,
+    /// Drop (and count) TCP segments with impossible flag combinations (SYN+FIN, SYN+RST,
+    /// NULL and XMAS scans) instead of trapping them or handing them to smoltcp.
+    pub drop_invalid_flags: bool,
+    /// Hold tunnel requests for this long after the first SYN to a target, and send every
+    /// stream opened to that target meanwhile as one request (see `TunnelRequest::coalesced`).
+    /// `None` sends each request immediately.
+    pub syn_coalesce_window: Option<Duration>,
+    /// Allow at most N new connections per window to each destination IP (token bucket,
+    /// refilled continuously). SYNs over the limit are dropped. `None` disables the limit.
+    pub syn_rate_limit: Option<(u32, Duration)>,
+    /// Keep-alive interval of tunnel sockets. `None` disables keep-alive probes.
+    pub keep_alive: Option<Duration>,
+    /// Abort a tunnel socket when the client hasn't acknowledged anything for this long
+    /// (smoltcp's `set_timeout`). `None` waits forever.
+    pub timeout: Option<Duration>,
+    /// Delayed ACK timeout of tunnel sockets. `None` acknowledges every segment immediately.
+    /// Whatever the delay, smoltcp acknowledges at least every second segment and lets an ACK
+    /// ride on outgoing data. See [`PrismConfig::low_latency`] / [`PrismConfig::high_throughput`].
+    pub ack_delay: Option<Duration>,
+    /// Only trap TCP to these destination ports; TCP to any other port goes to the Blind
+    /// Relay (or to smoltcp, which resets it, if no relay is set). `None` traps every port.
+    pub trap_ports: Option<PortSet>,
+    /// Steer UDP to these destination ports (e.g. 443 for QUIC) through a [`UdpTunnelRequest`]
+    /// per flow instead of the Blind Relay. Needs `set_udp_tunnel_request_sender`; without it
+    /// these datagrams are relayed like any other. `None` traps no UDP.
+    pub udp_trap_ports: Option<PortSet>,
+    /// UDP tunnels open at once. While that many are open, datagrams of a new flow go to the
+    /// Blind Relay like any other UDP.
+    pub max_udp_tunnels: usize,
+    /// Only handle TCP to destinations in these prefixes; TCP to anywhere else gets the
+    /// `default_action` (by default dropped and counted, never trapped, relayed or answered).
+    /// The stack adds each trapped destination to the interface itself (there's no catch-all
+    /// `any_ip`, and at most `MAX_ADDRS` entries), so this bounds what it takes over when the
+    /// routes into the TUN are broader than intended. The gateway addresses are in scope only
+    /// if listed. `None` handles every destination.
+    pub trap_cidrs: Option<Vec<IpCidr>>,
+    /// What happens to TCP whose destination is outside `trap_cidrs` (nothing is, without it).
+    pub default_action: DefaultAction,
+    /// Ports of the gateway's own addresses whose SYNs go to smoltcp, for listeners passed to
+    /// `new_with_sockets`. A SYN to any other gateway port is refused with a RST instead of
+    /// being trapped into a tunnel back to the gateway. No port by default; `None` traps
+    /// gateway SYNs like any other.
+    pub gateway_tcp_ports: Option<PortSet>,
+    /// After each poll, check that every re-injected SYN moved its socket out of Listen. One
+    /// that didn't was dropped by smoltcp without a trace (bad checksum, destination not on
+    /// the interface, ...); it is logged and counted in `reinjected_syns_rejected`.
+    pub verify_reinjected_syns: bool,
+    /// Admit at most N new connections per smoltcp poll. Further SYNs wait in a FIFO queue and
+    /// are admitted over the following polls; ones still queued after `handshake_timeout` are
+    /// dropped. Smooths bursts instead of rejecting them like `syn_rate_limit`. `None` admits
+    /// every SYN right away.
+    pub admit_per_poll: Option<usize>,
+    /// SYNs the `admit_per_poll` queue holds at most. A SYN that finds it full is refused
+    /// with a RST.
+    pub admission_queue_cap: usize,
+    /// What to do with a packet for the Blind Relay when its channel is full.
+    pub blind_relay_policy: BlindRelayPolicy,
+    /// What to do with trapped SYNs while no tunnel request sender is set (see
+    /// `set_tunnel_request_sender`). Either way the first one is logged as a warning.
+    pub missing_relayer: MissingRelayerPolicy,
+    /// Packets taken off `rx_queue` per wake-up of the run loop before smoltcp is polled.
+    /// Lower it to bound the delay a packet can see behind the rest of a burst, raise it to
+    /// poll less often under load. `0` counts as `1`.
+    pub rx_batch: usize,
+    /// Hand packets for the TUN to the TX channel in batches of up to this many; whatever is
+    /// left is flushed at the end of every poll, so nothing waits for a batch to fill up.
+    /// `1` sends each packet as smoltcp emits it. Pair it with a writer that drains the
+    /// channel with `recv_many`.
+    pub tx_batch: usize,
+    /// MSS clamp for trapped IPv4 SYNs. `None` derives it from `egress_mtu` (minus 40 bytes
+    /// of IPv4 and TCP headers), the largest MSS whose segments still fit.
+    pub mss_clamp_v4: Option<u16>,
+    /// MSS clamp for trapped IPv6 SYNs. `None` derives it from `egress_mtu` (minus 60 bytes).
+    pub mss_clamp_v6: Option<u16>,
+    /// MAC address of the stack on an Ethernet (TAP) device, e.g. to match a DHCP reservation.
+    /// `None` picks a random locally administered one. Must be unicast (a multicast address is
+    /// reported by `check` and replaced by a random one). Ignored on `Medium::Ip`.
+    pub hardware_addr: Option<EthernetAddress>,
+    /// Neighbor cache entries `(ip, mac)` that never expire, e.g. for the nexthop of a static
+    /// route whose MAC is known. Each IP must lie on the gateway subnet of its family and each
+    /// MAC be unicast; entries that don't are reported by `check` and skipped. smoltcp's cache
+    /// holds 4 neighbors (see the `many-neighbors` feature) and static entries share it.
+    /// Ignored on `Medium::Ip`, which has no link layer.
+    pub static_neighbors: Vec<(IpAddress, EthernetAddress)>,
+    /// Seeds the interface's random number generator (TCP initial sequence numbers, ...) and
+    /// the random MAC, so runs are reproducible. For tests only: predictable sequence numbers
+    /// make connections easy to spoof. `None` draws fresh entropy for every stack.
+    pub random_seed: Option<u64>,
+    /// With no Blind Relay set, answer non-TCP packets with an ICMP port unreachable (built by
+    /// `relay::icmp_port_unreachable`) instead of handing them to smoltcp, which only rejects
+    /// some of them. Traffic for the gateway itself still goes to smoltcp, so its pings and
+    /// sockets passed to `new_with_sockets` keep working. At most `ICMP_ERROR_BURST` errors
+    /// go out per `ICMP_ERROR_WINDOW`, shared with `OversizePolicy::Reject`.
+    pub reject_unsupported: bool,
+    /// What to do with a non-TCP packet larger than `egress_mtu`, which would be dropped
+    /// further down the path. It never reaches the Blind Relay either way.
+    pub oversize_policy: OversizePolicy,
+    /// Static routes `(prefix, nexthop)` added after the default routes through the gateway.
+    /// The longest matching prefix wins, and a `/0` here replaces the default of its family.
+    /// Each nexthop must lie on the gateway subnet of its family (10.11.12.0/24 or fd00::/64);
+    /// routes that don't, or beyond `MAX_ROUTES`, are reported by `check` and skipped. Empty
+    /// keeps the default sink.
+    pub routes: Vec<(IpCidr, IpAddress)>,
+    /// Per-tunnel cap on client -> remote throughput, in bits per second (token bucket holding
+    /// up to one second's worth). A tunnel over its budget is simply not read: the data waits
+    /// in the socket and TCP flow control slows the client down. Remote -> client data isn't
+    /// paced. `None` disables the cap; the bytes moved either way are always counted and
+    /// reported by `TunnelEvent::Closed`.
+    pub rate_limit_bps: Option<u64>,
+    /// Receive buffer of each tunnel socket, i.e. the most client data in flight. smoltcp
+    /// derives the window scale it offers from it (2MB: shift 6), but only answers a SYN that
+    /// offers window scaling itself; without it the window stays capped at 64KB whatever the size.
+    pub tcp_rx_buffer: usize,
+    /// Send buffer of each tunnel socket: remote data not yet acknowledged by the client.
+    pub tcp_tx_buffer: usize,
+    /// Put each tunnel's ingress (remote -> client) messages back in order, for relayers whose
+    /// transport may reorder them (multiplexed or multi-path). Every message then starts with
+    /// an 8-byte big-endian sequence number, counting from 0 per tunnel (see
+    /// [`sequenced_chunk`]); duplicates are dropped. At most N messages wait for a missing
+    /// one: more, a message too short for its number, or a channel closing on a gap resets
+    /// the tunnel rather than hand the client a corrupted stream. `None` writes messages as
+    /// they come.
+    pub ingress_reorder: Option<usize>,
+    /// Keep only a [`SynSummary`] of each SYN waiting for its Consistent handshake and rebuild
+    /// the SYN from it once the tunnel is up, instead of holding the trapped packet. SYNs the
+    /// summary can't carry (data on the SYN, bad checksum) are still held whole.
+    pub compact_pending_syns: bool,
+    /// What a second SYN for a flow whose handshake isn't done yet means (see
+    /// [`DuplicateSynPolicy`]).
+    pub duplicate_syns: DuplicateSynPolicy,
+    /// Address families the stack serves. Only their gateway addresses and default routes
+    /// are installed, and packets of the other family are dropped on arrival.
+    pub ip_mode: IpMode,
+    /// Messages each of a tunnel's two channels holds (client -> remote and back, TCP or UDP).
+    /// Deeper channels absorb longer bursts from a slow relayer, at the cost of memory and
+    /// of latency for what queues behind them. `0` is reported by `check` and counts as `1`.
+    pub tunnel_channel_depth: usize,
+    /// Depth of the tunnel request channel made by [`PrismStack::tunnel_request_channel`].
+    /// `0` is reported by `check` and counts as `1`.
+    pub control_channel_depth: usize,
+    /// Depth of the Blind Relay channel made by [`PrismStack::blind_relay_channel`].
+    /// `0` is reported by `check` and counts as `1`.
+    pub blind_relay_depth: usize,
+    /// Most packets queued for smoltcp between two polls (TCP data, packets for the gateway,
+    /// ...). Past it `pending_overflow` decides which one is dropped. SYNs re-injected for a
+    /// socket that was just set up are always queued, so a trapped connection never loses its
+    /// SYN to the cap. `0` counts as `1`.
+    pub pending_packets_cap: usize,
+    /// Which packet to drop when `pending_packets_cap` is reached.
+    pub pending_overflow: PendingOverflowPolicy,
+}
+
+/// Addresses of the virtual gateway, one per family.
+const GATEWAY_CIDRS: [IpCidr; 2] = [
+    IpCidr::Ipv4(Ipv4Cidr::new(Ipv4Address::new(10, 11, 12, 1), 24)),
+    // IPv6 ULA (Unique Local Address)
+    IpCidr::Ipv6(Ipv6Cidr::new(Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 1), 64)),
+];
+
+/// The gateway address of `pkt`'s family, where the stack's ICMP errors come from.
+fn gateway_for(pkt: &[u8]) -> IpAddr {
+    let gateway = if pkt.first().is_some_and(|b| b >> 4 == 4) { GATEWAY_CIDRS[0] } else { GATEWAY_CIDRS[1] };
+    gateway.address().into()
+}
+
+/// The host route the stack adds to the interface for a tunnel to `target`.
+fn host_cidr(target: SocketAddr) -> IpCidr {
+    match target {
+        SocketAddr::V4(addr) => IpCidr::new(IpAddress::Ipv4(Ipv4Address::from_bytes(&addr.ip().octets())), 32),
+        SocketAddr::V6(addr) => IpCidr::new(IpAddress::Ipv6(Ipv6Address::from_bytes(&addr.ip().octets())), 128),
+    }
+}
+
+/// The endpoint a tunnel socket to `target` listens on.
+fn listen_endpoint(target: SocketAddr) -> smoltcp::wire::IpEndpoint {
+    smoltcp::wire::IpEndpoint::new(host_cidr(target).address(), target.port())
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -31,491 +237,6935 @@ pub enum HandshakeMode {
     Consistent,
 }
 
-impl Default for PrismConfig {
-    fn default() -> Self {
-        Self {
-            handshake_mode: HandshakeMode::Fast,
-            egress_mtu: 1280,
-            linux_offload: false,
-        }
-    }
+/// How much work is handed to the kernel through the `virtio_net_hdr` (Linux TUN with
+/// `IFF_VNET_HDR`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OffloadMode {
+    /// Plain IP packets, smoltcp computes every checksum.
+    Off,
+    /// The kernel fills TCP/UDP checksums on TX; RX partial checksums are finished here.
+    Checksum,
+    /// `Checksum`, plus TCP packets larger than `egress_mtu` are marked for kernel segmentation.
+    Gso,
 }
 
-/// Request to create a tunnel to a remote target.
-pub struct TunnelRequest {
-    pub target: SocketAddr,
-    /// Channel to write data TO the remote tunnel (PrismStack -> TLS)
-    pub tx: mpsc::Sender<Bytes>,
-    /// Channel to read data FROM the remote tunnel (TLS -> PrismStack)
-    pub rx: mpsc::Receiver<Bytes>,
-    /// Optional feedback channel for Consistent Handshake.
-    pub response_tx: Option<oneshot::Sender<bool>>,
+/// Policy for broadcast (255.255.255.255, subnet broadcast) and multicast
+/// (224.0.0.0/4, ff00::/8) traffic. Such packets are never trapped as TCP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CastPolicy {
+    /// Silently drop the packet.
+    Drop,
+    /// Forward the packet to the Blind Relay like any other non-TCP traffic.
+    Relay,
+    /// Hand the packet to smoltcp.
+    Pass,
 }
 
-/// The virtual network stack structure.
-pub struct PrismStack {
-    pub iface: Interface,
-    pub sockets: SocketSet<'static>,
-    // Removed trap_rx channel, we handle it directly in loop
-    
-    /// Control channel to request new tunnels from the Relayer
-    pub tunnel_req_tx: Option<mpsc::Sender<TunnelRequest>>,
-    
-    /// Blind Relay channel for non-TCP packets (UDP, ICMP, etc.)
-    pub blind_relay_tx: Option<mpsc::Sender<Bytes>>,
-    
-    /// Map of active sockets to their EGRESS data channels
-    /// Key: SocketHandle, Value: tx_to_remote
-    /// RX is handled via ingress_streams
-    pub active_tunnels: HashMap<SocketHandle, mpsc::Sender<Bytes>>,
-    
-    /// Aggregated stream of incoming data from all active tunnels
-    /// Yields: (SocketHandle, Data)
-    pub ingress_streams: SelectAll<BoxStream<'static, (SocketHandle, Bytes)>>,
+/// Behavior of the Blind Relay channel when the relayer falls behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlindRelayPolicy {
+    /// Drop the new packet (`blind_relay_dropped_full`). Fine for DNS and other retrying traffic.
+    DropOnFull,
+    /// Keep up to `BLIND_RELAY_BACKLOG` packets in a ring owned by the stack, sent as the
+    /// channel frees up; when the ring overflows its oldest packet is dropped
+    /// (`blind_relay_dropped_oldest`).
+    DropOldest,
+    /// Never drop while the relayer is alive: the send is moved to a task that waits for room,
+    /// so the poll loop isn't stalled. Order between waiting packets isn't guaranteed, and
+    /// memory grows with the backlog. Packets for a closed channel count as
+    /// `blind_relay_dropped_blocked`.
+    Block,
+}
 
-    /// The PHY device
-    pub device: PrismDevice,
-    /// Stack configuration
-    pub config: PrismConfig,
-    /// Pending SYNs waiting for tunnel confirmation (Consistent Mode)
-    pub pending_syns: HashMap<SocketAddr, (PrismTrap, mpsc::Sender<Bytes>, mpsc::Receiver<Bytes>)>,
-    /// Tracks which IPs are registered for each socket handle (for cleanup on close)
-    pub active_ips: HashMap<SocketHandle, IpCidr>,
-    /// Set of all dynamically-registered IP CIDRs (to prevent re-adding)
-    pub registered_ips: HashSet<IpCidr>,
-    /// Internal feedback channel to receive signals from the async bridge tasks
-    pub feedback_tx: mpsc::Sender<(SocketAddr, bool)>,
-    pub feedback_rx: mpsc::Receiver<(SocketAddr, bool)>,
+/// Handling of TCP to destinations outside `trap_cidrs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction {
+    /// Trap it anyway: `trap_cidrs` then only marks the intended scope
+    Trap,
+    /// Answer a SYN with an ICMP network unreachable from the gateway: the client fails right
+    /// away. Other segments are dropped as with `Drop`
+    Reject,
+    /// Drop it silently: the client times out
+    Drop,
 }
 
-impl PrismStack {
-    /// Creates a new PrismStack instance with the given Device.
-    pub fn new(mut device: PrismDevice, config: PrismConfig) -> Self {
-        let medium = device.capabilities().medium;
-        let hardware_addr = match medium {
-            smoltcp::phy::Medium::Ethernet => {
-                let mut bytes = [0u8; 6];
-                rand::thread_rng().fill(&mut bytes);
-                bytes[0] &= 0xfe; // Unicast
-                bytes[0] |= 0x02; // Local
-                HardwareAddress::Ethernet(EthernetAddress(bytes))
-            }
-            smoltcp::phy::Medium::Ip => HardwareAddress::Ip,
-            _ => panic!("Unsupported medium"),
-        };
+/// Handling of non-TCP packets that don't fit `egress_mtu`, see `PrismConfig::oversize_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OversizePolicy {
+    /// Drop it silently (`blind_relay_oversize_dropped`)
+    Drop,
+    /// Drop it and tell the client to send smaller packets: an ICMP fragmentation needed
+    /// (ICMPv6 packet too big) from the gateway carrying `egress_mtu`, as a router would.
+    /// Like a router it only answers IPv4 packets with DF set, never reports an IPv6 MTU below
+    /// 1280, and stays within `ICMP_ERROR_BURST`
+    Reject,
+}
 
-        let mut iface_config = Config::new(hardware_addr);
-        iface_config.random_seed = rand::random::<u64>();
+/// Handling of trapped SYNs when nobody listens for tunnel requests. Without a relayer the
+/// connection has no data path, so it is never accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingRelayerPolicy {
+    /// Answer with a RST: the client fails right away.
+    Reset,
+    /// Drop the SYN: the client retransmits, which helps if the sender is set shortly after
+    /// start-up.
+    Drop,
+}
 
-        // Removed trap channel creation - no longer needed in Device
+/// Address families of a stack, see `PrismConfig::ip_mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpMode {
+    DualStack,
+    V4Only,
+    V6Only,
+}
 
-        let mut iface = Interface::new(iface_config, &mut device, Instant::now());
+impl IpMode {
+    /// Whether the stack serves IPv6 (`true`) or IPv4 (`false`).
+    pub fn serves(self, ipv6: bool) -> bool {
+        match self {
+            IpMode::DualStack => true,
+            IpMode::V4Only => !ipv6,
+            IpMode::V6Only => ipv6,
+        }
+    }
 
-        // Configure IP addresses (virtual gateway IP)
-        // We generally pick a link-local or private IP that won't conflict
-        iface.update_ip_addrs(|ip_addrs| {
-            ip_addrs.push(IpCidr::new(IpAddress::v4(10, 11, 12, 1), 24)).unwrap();
-            // Add IPv6 ULA (Unique Local Address) for virtual gateway
-            ip_addrs.push(IpCidr::new(IpAddress::v6(0xfd00, 0, 0, 0, 0, 0, 0, 1), 64)).unwrap();
-        });
+    /// The gateway addresses (and subnets) installed for this mode.
+    fn gateways(self) -> impl Iterator<Item = IpCidr> {
+        GATEWAY_CIDRS.into_iter().filter(move |cidr| self.serves(matches!(cidr, IpCidr::Ipv6(_))))
+    }
+}
 
-        // Configure default route to sink all traffic
-        // NOTE: add_default_ipv4_route requires Ipv4Address, not IpAddress enum
-        iface.routes_mut().add_default_ipv4_route(Ipv4Address::new(10, 11, 12, 1)).unwrap();
-        iface.routes_mut().add_default_ipv6_route(Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 1)).unwrap();
+/// Each trapped SYN gets its own single-connection socket (or held Consistent handshake), so
+/// a second SYN for the same 4-tuple either belongs to that attempt or starts a new one.
+/// SYNs with the sequence number already seen are always retransmits, answered by the
+/// existing socket (Fast) or dropped while the tunnel comes up (Consistent).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateSynPolicy {
+    /// Every SYN for a flow within `SYN_CACHE_TTL` of its first one (or while its Consistent
+    /// handshake is pending) belongs to the first attempt, whatever its sequence number.
+    Coalesce,
+    /// A SYN with a new sequence number restarts a handshake that isn't done yet, keeping its
+    /// tunnel: the Fast socket answers the new SYN instead, and a Consistent handshake
+    /// re-injects the new SYN once the tunnel is up. Counted in `handshakes_restarted`.
+    /// Established connections are left alone.
+    Restart,
+}
 
-        let sockets = SocketSet::new(vec![]);
-        let (feedback_tx, feedback_rx) = mpsc::channel(128);
+/// Handling of a packet for smoltcp when `PrismConfig::pending_packets_cap` is reached. Either
+/// way the dropped packet counts as `pending_overflow_dropped`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PendingOverflowPolicy {
+    /// Drop the new packet.
+    DropNewest,
+    /// Drop the oldest queued packet that isn't a SYN, or the new one if the first
+    /// `PENDING_DROP_SCAN` of them all are.
+    DropOldest,
+}
 
+impl Default for PrismConfig {
+    #[allow(deprecated)]
+    fn default() -> Self {
         Self {
-            iface,
-            sockets,
-            tunnel_req_tx: None,
-            blind_relay_tx: None,
-            active_tunnels: HashMap::new(),
-            ingress_streams: SelectAll::new(),
-            device,
-            config,
-            pending_syns: HashMap::new(),
-            active_ips: HashMap::new(),
-            registered_ips: HashSet::new(),
-            feedback_tx,
-            feedback_rx,
+            handshake_mode: HandshakeMode::Fast,
+            egress_mtu: 1280,
+            offload: OffloadMode::Off,
+            linux_offload: false,
+            cast_policy: CastPolicy::Relay,
+            handshake_timeout: Duration::from_secs(30),
+            log_payload_prefix: 0,
+            peek_bytes: 2048,
+            always_pump_egress: false,
+            max_egress_chunk: 64 * 1024,
+            drop_invalid_flags: true,
+            syn_coalesce_window: None,
+            syn_rate_limit: None,
+            keep_alive: Some(Duration::from_secs(60)),
+            timeout: None,
+            ack_delay: Some(Duration::from_millis(10)),
+            trap_ports: None,
+            udp_trap_ports: None,
+            max_udp_tunnels: MAX_UDP_TUNNELS,
+            trap_cidrs: None,
+            default_action: DefaultAction::Drop,
+            gateway_tcp_ports: Some(PortSet::new()),
+            verify_reinjected_syns: false,
+            admit_per_poll: None,
+            admission_queue_cap: ADMISSION_QUEUE_CAP,
+            blind_relay_policy: BlindRelayPolicy::DropOnFull,
+            missing_relayer: MissingRelayerPolicy::Reset,
+            rx_batch: BATCH_SIZE,
+            tx_batch: 1,
+            mss_clamp_v4: None,
+            mss_clamp_v6: None,
+            hardware_addr: None,
+            static_neighbors: Vec::new(),
+            random_seed: None,
+            reject_unsupported: false,
+            oversize_policy: OversizePolicy::Drop,
+            routes: Vec::new(),
+            rate_limit_bps: None,
+            tcp_rx_buffer: TCP_RX_BUFFER_SIZE,
+            tcp_tx_buffer: TCP_TX_BUFFER_SIZE,
+            ingress_reorder: None,
+            compact_pending_syns: false,
+            duplicate_syns: DuplicateSynPolicy::Coalesce,
+            ip_mode: IpMode::DualStack,
+            tunnel_channel_depth: 1024,
+            control_channel_depth: 1024,
+            blind_relay_depth: CHANNEL_SIZE,
+            pending_packets_cap: PENDING_PACKETS_CAP,
+            pending_overflow: PendingOverflowPolicy::DropNewest,
         }
     }
+}
 
-    pub fn set_tunnel_request_sender(&mut self, tx: mpsc::Sender<TunnelRequest>) {
-        self.tunnel_req_tx = Some(tx);
-    }
-    
-    pub fn set_blind_relay_sender(&mut self, tx: mpsc::Sender<Bytes>) {
-        self.blind_relay_tx = Some(tx);
+impl PrismConfig {
+    /// Defaults tuned for interactive tunnels (SSH, RPC, games): every client segment is
+    /// acknowledged right away (`ack_delay: None`), so the client never waits on a delayed
+    /// ACK and its RTT samples aren't inflated by one.
+    pub fn low_latency() -> Self {
+        Self { ack_delay: None, ..Default::default() }
     }
 
-    /// Runs the virtual stack poll loop (Event-Driven).
-    pub async fn run(mut self) -> anyhow::Result<()> {
-        debug!("Prism Stack started (Event-Driven Mode).");
-
-        loop {
-            let now = Instant::now();
-            
-            // 1. Calculate Poll Delay
-            // smoltcp tells us when it needs to be called next (e.g. retransmit timer)
-            let poll_delay = self.iface.poll_delay(now, &self.sockets).map(|d| Duration::from(d));
-            
-            // 2. Select on Events
-            tokio::select! {
-                // Event A: Network Packet from TUN
-                // We pull directly from device.rx_queue because device.receive() is now passive/dumb
-                // BATCHING: Try to consume up to 64 packets per wake-up to reduce context switching
-                res = self.device.rx_queue.recv() => {
-                    if let Some(pkt) = res {
-                        let mut count = 0;
-                        let mut current_pkt = Some(pkt);
-                        
-                        while let Some(pkt) = current_pkt {
-                            // PROTOCOL CLASSIFICATION
-                            // We only intercept TCP. Everything else goes to Blind Relay.
-                            let pkt_type = if matches!(self.device.medium, smoltcp::phy::Medium::Ip) {
-                                crate::trap::get_packet_type(&pkt)
-                            } else {
-                                // L2 Frames: For now treat as "Unknown/Other" -> Blind Relay if we wanted L2 bridge
-                                // But smoltcp stack expects IP.
-                                // Let's just pass to stack if we are unsure, or drop?
-                                // For now, pass to stack so it might answer ARP?
-                                // Actually, ARP is L2, so get_packet_type might return Unknown.
-                                crate::trap::PacketType::Unknown
-                            };
-
-                            match pkt_type {
-                                crate::trap::PacketType::Tcp => {
-                                    // TCP: Check for SYN Trap
-                                    if let Some(event) = crate::trap::inspect_packet(&pkt) {
-                                        self.handle_trap(event, pkt, TCP_RX_BUFFER_SIZE, TCP_TX_BUFFER_SIZE);
-                                    } else {
-                                        // TCP Data/ACK -> Stack
-                                        self.device.pending_packets.push_back(pkt);
-                                    }
-                                }
-                                crate::trap::PacketType::Other => {
-                                    // [Added] Check packet size to prevent huge UDP packets from blocking physical NIC
-                                    if pkt.len() > self.config.egress_mtu {
-                                        tracing::warn!(
-                                            "Dropping huge UDP packet: {} > {}",
-                                            pkt.len(),
-                                            self.config.egress_mtu
-                                        );
-                                        // Drop directly, do not put into blind_relay_tx
-                                    } else {
-                                        // UDP/ICMP/Gre etc. -> Blind Relay
-                                        if let Some(ref relay) = self.blind_relay_tx {
-                                            // Fire and forget, don't block main loop
-                                            let _ = relay.try_send(pkt.freeze());
-                                        } else {
-                                            // If no relay configured, drop or let stack reject it (ICMP Unreachable)
-                                            // Letting stack see it might generate "Port Unreachable", which is good.
-                                            self.device.pending_packets.push_back(pkt);
-                                        }
-                                    }
-                                }
-                                crate::trap::PacketType::Unknown => {
-                                     // Debug log to catch IPv6 parsing failures
-                                     if pkt.len() > 0 {
-                                         let ver = pkt[0] >> 4;
-                                         if ver == 6 {
-                                             tracing::warn!("IPv6 Packet failed classification! Len: {}", pkt.len());
-                                         }
-                                     }
-                                     self.device.pending_packets.push_back(pkt);
-                                }
-                            }
-                            
-                            count += 1;
-                            if count >= BATCH_SIZE { break; }
-                            
-                            // Try get next without waiting
-                            match self.device.rx_queue.try_recv() {
-                                Ok(p) => current_pkt = Some(p),
-                                Err(_) => current_pkt = None,
-                            }
-                        }
-                    } else {
-                        debug!("Network Interface Closed.");
-                        break;
-                    }
-                },
+    /// Defaults tuned for bulk transfers: a lone client segment is acknowledged after up to
+    /// 40ms, or with the next data sent back, whichever comes first. smoltcp still ACKs at
+    /// least every second segment, so a full-speed upload isn't slowed down, but fewer pure
+    /// ACKs are sent.
+    pub fn high_throughput() -> Self {
+        Self { ack_delay: Some(Duration::from_millis(40)), ..Default::default() }
+    }
 
-                // Event B: Data from Active Tunnels (Fan-in)
-                Some((handle, data)) = self.ingress_streams.next() => {
-                    let socket = self.sockets.get_mut::<tcp::Socket>(handle);
-                    {
-                        if socket.can_send() {
-                            // Write to socket TX buffer (Simulated RX from network perspective)
-                            // Wait, socket.send_slice() writes to the socket's TX buffer?
-                            // No! socket.send_slice() writes data that the socket will SEND to the network (to Client).
-                            // Here 'data' comes FROM network (Tunnel/Remote) intended FOR Client.
-                            // So we should write to socket's "send buffer".
-                            // smoltcp `socket.send_slice` queues data to be sent over TCP.
-                            // Yes.
-                            let sent = socket.send_slice(&data).unwrap_or(0);
-                            if sent < data.len() {
-                                warn!("Socket buffer full (Handle {:?}), dropped {} bytes", handle, data.len() - sent);
-                            }
-                        }
-                    }
-                },
+    /// The MSS clamp applied to trapped SYNs of each family.
+    pub fn mss_clamp(&self) -> MssClamp {
+        let derived = MssClamp::for_mtu(self.egress_mtu);
+        MssClamp {
+            v4: self.mss_clamp_v4.unwrap_or(derived.v4),
+            v6: self.mss_clamp_v6.unwrap_or(derived.v6),
+        }
+    }
 
-                // Event C: Feedback from Consistent Handshake
-                Some((target, success)) = self.feedback_rx.recv() => {
-                     self.handle_handshake_feedback(target, success, TCP_RX_BUFFER_SIZE, TCP_TX_BUFFER_SIZE);
-                },
+    /// `offload`, or `Gso` when only the deprecated `linux_offload` is set.
+    pub(crate) fn offload_mode(&self) -> OffloadMode {
+        #[allow(deprecated)]
+        let legacy = self.linux_offload;
+        if self.offload == OffloadMode::Off && legacy { OffloadMode::Gso } else { self.offload }
+    }
 
-                // Event D: Timer Expiry
-                // If poll_delay is None, we wait forever (for IO)
-                // If poll_delay is Some, we sleep until then
-                _ = async {
-                    if let Some(d) = poll_delay {
-                        time::sleep(d).await;
-                    } else {
-                        // If no delay, wait forever (future never completes, but select! waits for others)
-                        std::future::pending::<()>().await;
-                    }
-                } => {}
+    /// Looks for incoherent settings. The stack logs each issue as a warning on construction.
+    pub fn check(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        // Clamped client MSS = largest segment we send back; it has to fit egress_mtu
+        let clamp = self.mss_clamp();
+        for (ipv6, ip_header, mss) in [(false, 20, clamp.v4), (true, 40, clamp.v6)] {
+            if !self.ip_mode.serves(ipv6) {
+                continue;
             }
-
-            // 3. Poll smoltcp (Process packets, timers, state updates)
-            // This consumes packets from pending_packets
-            let poll_now = Instant::now();
-            self.iface.poll(poll_now, &mut self.device, &mut self.sockets);
-
-            // 4. Data Pumping (Egress: Socket -> Tunnel)
-            // Iterate sockets to see if they have data for us
-            let mut sockets_to_remove = Vec::new();
-            
-            for (handle, tx_to_remote) in self.active_tunnels.iter_mut() {
-                let socket = self.sockets.get_mut::<tcp::Socket>(*handle);
-
-                // Check for closure
-                if socket.state() == tcp::State::Closed || socket.state() == tcp::State::TimeWait {
-                    // We can remove it
-                    // But wait, if TimeWait, maybe we still need to send ACKs?
-                    // socket.recv() reads payload data (from Client).
-                    // If Closed, no more data from Client.
-                    sockets_to_remove.push(*handle);
-                    continue;
-                }
-
-                if !socket.can_recv() {
-                     continue;
-                }
-
-                // Ingress (Socket -> Tunnel) (Data FROM Client TO Remote)
-                while let Ok(data) = socket.recv(|buf| (buf.len(), Bytes::copy_from_slice(buf))) {
-                    if data.is_empty() { break; }
-                     // Optimization: Use try_send to avoid blocking loop
-                    if let Err(_) = tx_to_remote.try_send(data) {
-                         // Backpressure: drop or break? 
-                         // If we break, we leave data in socket buffer (Good).
-                        break; 
-                    }
-                }
+            let required = mss as usize + ip_header + 20;
+            if required > self.egress_mtu {
+                issues.push(ConfigIssue::MtuBelowMssClamp { ipv6, required, egress_mtu: self.egress_mtu });
             }
-            
-            for handle in sockets_to_remove {
-                // Drop the tx sender — this causes the remote rx to close,
-                // which in turn ends the BoxStream in ingress_streams (SelectAll auto-removes ended streams).
-                self.active_tunnels.remove(&handle);
-                
-                // Clean up dynamically-registered IP address to prevent ip_addrs table leak
-                if let Some(cidr) = self.active_ips.remove(&handle) {
-                    self.registered_ips.remove(&cidr);
-                    self.iface.update_ip_addrs(|ip_addrs| {
-                        ip_addrs.retain(|addr| *addr != cidr);
-                    });
-                }
-                
-                self.sockets.remove(handle);
+        }
+        if let Some(addr) = self.hardware_addr.filter(|addr| !addr.is_unicast()) {
+            issues.push(ConfigIssue::HardwareAddrNotUnicast { addr });
+        }
+        let depths = [
+            ("tunnel_channel_depth", self.tunnel_channel_depth),
+            ("control_channel_depth", self.control_channel_depth),
+            ("blind_relay_depth", self.blind_relay_depth),
+        ];
+        for (name, _) in depths.into_iter().filter(|&(_, depth)| depth == 0) {
+            issues.push(ConfigIssue::ZeroChannelDepth { name });
+        }
+        for &(ip, mac) in self.static_neighbors.iter().filter(|&&(ip, mac)| !self.is_valid_neighbor(ip, mac)) {
+            issues.push(ConfigIssue::StaticNeighborInvalid { ip, mac });
+        }
+        let mut usable = 0;
+        for &(cidr, via) in &self.routes {
+            if !route_is_reachable(cidr, via, self.ip_mode) {
+                issues.push(ConfigIssue::RouteNexthopUnreachable { cidr, via });
+            } else if usable + self.ip_mode.gateways().count() < MAX_ROUTES {
+                usable += 1;
+            } else {
+                issues.push(ConfigIssue::RouteTableFull { cidr, via });
             }
         }
-        
-        Ok(())
+        issues
     }
 
-    // Helper to handle Trap Logic
-    fn handle_trap(&mut self, event: crate::trap::TrapEvent, pkt: BytesMut, rx_buf_size: usize, tx_buf_size: usize) {
-        debug!("Trapped SYN for target: {}", event.dst);
+    /// Whether a `static_neighbors` entry can be installed: a unicast MAC for an address on a
+    /// served gateway subnet, other than the gateway itself.
+    fn is_valid_neighbor(&self, ip: IpAddress, mac: EthernetAddress) -> bool {
+        mac.is_unicast() && self.ip_mode.gateways().any(|gateway| gateway.contains_addr(&ip) && gateway.address() != ip)
+    }
+}
 
-        // Register IP to Interface (needed for both modes)
-        let cidr = match event.dst {
-            std::net::SocketAddr::V4(addr) => {
-                let endpoint_ip = Ipv4Address::from_bytes(&addr.ip().octets());
-                IpCidr::new(IpAddress::Ipv4(endpoint_ip), 32)
-            },
-            std::net::SocketAddr::V6(addr) => {
-                let endpoint_ip = Ipv6Address::from_bytes(&addr.ip().octets());
-                IpCidr::new(IpAddress::Ipv6(endpoint_ip), 128)
-            }
-        };
+/// A configuration problem found by [`PrismConfig::check`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigIssue {
+    /// A full segment at the MSS clamp (`required` bytes with IP and TCP headers) doesn't
+    /// fit `egress_mtu`, so it gets fragmented or dropped on the way out.
+    MtuBelowMssClamp { ipv6: bool, required: usize, egress_mtu: usize },
+    /// `hardware_addr` is a multicast (or broadcast) address; a random one is used instead.
+    HardwareAddrNotUnicast { addr: EthernetAddress },
+    /// The nexthop of a static route isn't on the gateway subnet of the route's family.
+    RouteNexthopUnreachable { cidr: IpCidr, via: IpAddress },
+    /// The routing table (`MAX_ROUTES`) was full when this static route came up.
+    RouteTableFull { cidr: IpCidr, via: IpAddress },
+    /// A static neighbor isn't on a gateway subnet, is the gateway, or has a MAC that isn't
+    /// unicast.
+    StaticNeighborInvalid { ip: IpAddress, mac: EthernetAddress },
+    /// A channel depth (the field `name`) is 0, which no channel can have; 1 is used instead.
+    ZeroChannelDepth { name: &'static str },
+}
 
-        if !self.registered_ips.contains(&cidr) {
-            self.iface.update_ip_addrs(|ip_addrs| {
-                let _ = ip_addrs.push(cidr);
-            });
-            self.registered_ips.insert(cidr);
+impl std::fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigIssue::MtuBelowMssClamp { ipv6, required, egress_mtu } => write!(
+                f,
+                "egress_mtu {} can't carry an MSS-clamped {} segment ({} bytes)",
+                egress_mtu,
+                if *ipv6 { "IPv6" } else { "IPv4" },
+                required
+            ),
+            ConfigIssue::HardwareAddrNotUnicast { addr } => {
+                write!(f, "hardware_addr {} isn't unicast, using a random one", addr)
+            }
+            ConfigIssue::RouteNexthopUnreachable { cidr, via } => {
+                write!(f, "route {} via {}: nexthop isn't on a gateway subnet, skipping it", cidr, via)
+            }
+            ConfigIssue::RouteTableFull { cidr, via } => {
+                write!(f, "route {} via {}: more than {} routes, skipping it", cidr, via, MAX_ROUTES)
+            }
+            ConfigIssue::StaticNeighborInvalid { ip, mac } => {
+                write!(f, "static neighbor {} at {}: not a unicast neighbor on a gateway subnet, skipping it", ip, mac)
+            }
+            ConfigIssue::ZeroChannelDepth { name } => write!(f, "{} is 0, using 1", name),
         }
+    }
+}
 
-        // Dispatch to handshake mode
-        if self.config.handshake_mode == HandshakeMode::Consistent {
-            self.initiate_consistent_handshake(event, pkt);
+/// A socket holding no buffers, used to fill free slots of a set.
+fn placeholder_socket() -> tcp::Socket<'static> {
+    tcp::Socket::new(tcp::SocketBuffer::new(vec![]), tcp::SocketBuffer::new(vec![]))
+}
+
+/// Finds the slot of every socket in a set handed to the stack, and the free slots between
+/// them. `SocketHandle` keeps its slot private, but handles order like their slots and a set
+/// fills its lowest free slot first: once placeholders have filled the set up to its last
+/// socket, the rank of each handle is its slot.
+fn index_sockets(sockets: &mut SocketSet<'static>) -> (HashMap<SocketHandle, usize>, BTreeSet<usize>) {
+    let Some(last) = sockets.iter().map(|(handle, _)| handle).max() else {
+        return (HashMap::new(), BTreeSet::new());
+    };
+    let mut placeholders = HashSet::new();
+    loop {
+        let placeholder = sockets.add(placeholder_socket());
+        placeholders.insert(placeholder);
+        if placeholder > last {
+            break;
+        }
+    }
+    let mut handles: Vec<SocketHandle> = sockets.iter().map(|(handle, _)| handle).collect();
+    handles.sort();
+    let (mut slots, mut free) = (HashMap::new(), BTreeSet::new());
+    for (slot, handle) in handles.into_iter().enumerate() {
+        if placeholders.contains(&handle) {
+            free.insert(slot);
         } else {
-            self.initiate_fast_handshake(event, pkt, rx_buf_size, tx_buf_size, cidr);
+            slots.insert(handle, slot);
         }
     }
+    for placeholder in placeholders {
+        sockets.remove(placeholder);
+    }
+    // The placeholder past the last socket doesn't count as a hole
+    free.pop_last();
+    (slots, free)
+}
 
-    fn initiate_consistent_handshake(&mut self, event: crate::trap::TrapEvent, pkt: BytesMut) {
-        // Guard against SYN retransmits creating duplicate tunnel requests.
-        if self.pending_syns.contains_key(&event.dst) {
-            debug!("Consistent Handshake: Ignoring SYN retransmit for {}", event.dst);
-            return;
-        }
+/// Adds a socket taken out of another set. Only TCP and UDP sockets are supported: the other
+/// variants exist when another crate enables their smoltcp features.
+fn add_any_socket(sockets: &mut SocketSet<'static>, socket: Socket<'static>) -> SocketHandle {
+    #[allow(unreachable_patterns)]
+    match socket {
+        Socket::Tcp(socket) => sockets.add(socket),
+        Socket::Udp(socket) => sockets.add(socket),
+        _ => unreachable!("only TCP and UDP sockets are moved"),
+    }
+}
 
-        debug!("Consistent Handshake: Buffering SYN for {}", event.dst);
-        
-        if let Some(ref req_tx) = self.tunnel_req_tx {
-            let (tx_to_remote, rx_from_internal) = mpsc::channel::<Bytes>(1024);
-            let (tx_to_internal, rx_from_remote) = mpsc::channel::<Bytes>(1024);
-            let (resp_tx, resp_rx) = oneshot::channel();
+/// A static route can only go through a host the gateway reaches directly.
+fn route_is_reachable(cidr: IpCidr, via: IpAddress, ip_mode: IpMode) -> bool {
+    let family_matches = matches!((cidr, via), (IpCidr::Ipv4(_), IpAddress::Ipv4(_)) | (IpCidr::Ipv6(_), IpAddress::Ipv6(_)));
+    family_matches && via.is_unicast() && ip_mode.gateways().any(|gateway| gateway.contains_addr(&via))
+}
 
-            let request = TunnelRequest {
-                target: event.dst,
-                tx: tx_to_internal,
-                rx: rx_from_internal,
-                response_tx: Some(resp_tx),
-            };
+/// A set of ports and port ranges, e.g. `PortSet::new().with_port(443).with_range(8000..=8999)`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PortSet {
+    ranges: Vec<RangeInclusive<u16>>,
+}
 
-            if let Err(e) = req_tx.try_send(request) {
-                error!("Failed to request tunnel (Consistent): {}", e);
-            } else {
-                 let trap = PrismTrap { dst: event.dst, packet: pkt.freeze() };
-                 self.pending_syns.insert(event.dst, (trap, tx_to_remote, rx_from_remote));
-                 
-                 // Spawn wait task with timeout to prevent memory leak
-                 let feedback_tx = self.feedback_tx.clone();
-                 let target = event.dst;
-                 tokio::spawn(async move {
-                      let success = match tokio::time::timeout(
-                          Duration::from_secs(30),
-                          resp_rx,
-                      ).await {
-                          Ok(Ok(val)) => val,
-                          Ok(Err(_)) => false,
-                          Err(_) => {
-                              tracing::warn!("Consistent Handshake timeout for {}", target);
-                              false
-                          }
-                      };
-                      let _ = feedback_tx.send((target, success)).await;
-                 });
-            }
-        }
+impl PortSet {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    fn initiate_fast_handshake(&mut self, event: crate::trap::TrapEvent, pkt: BytesMut, rx_buf_size: usize, tx_buf_size: usize, cidr: IpCidr) {
-        let mut socket = tcp::Socket::new(
-            tcp::SocketBuffer::new(vec![0; rx_buf_size]),
-            tcp::SocketBuffer::new(vec![0; tx_buf_size]),
-        );
-        socket.set_keep_alive(Some(Duration::from_secs(60).into()));
-        socket.set_nagle_enabled(false);
+    pub fn with_port(self, port: u16) -> Self {
+        self.with_range(port..=port)
+    }
 
-        let endpoint = match event.dst {
-            std::net::SocketAddr::V4(addr) => smoltcp::wire::IpEndpoint::new(
-                smoltcp::wire::IpAddress::Ipv4(Ipv4Address::from_bytes(&addr.ip().octets())),
-                addr.port(),
-            ),
-            std::net::SocketAddr::V6(addr) => smoltcp::wire::IpEndpoint::new(
-                smoltcp::wire::IpAddress::Ipv6(Ipv6Address::from_bytes(&addr.ip().octets())),
-                addr.port(),
-            ),
-        };
+    pub fn with_range(mut self, range: RangeInclusive<u16>) -> Self {
+        self.ranges.push(range);
+        self
+    }
 
-        if let Err(e) = socket.listen(endpoint) {
-            warn!("Failed to listen: {}", e);
-            return;
+    pub fn contains(&self, port: u16) -> bool {
+        self.ranges.iter().any(|range| range.contains(&port))
+    }
+}
+
+/// Identifies a trapped flow: (client source, remote destination).
+pub type FlowKey = (SocketAddr, SocketAddr);
+
+/// Decides where each IP packet from the TUN goes (see [`PrismStack::set_classifier`]).
+pub type PacketClassifier = Box<dyn Fn(&[u8]) -> crate::trap::Verdict + Send>;
+
+/// Request to create a tunnel to a remote target.
+pub struct TunnelRequest {
+    /// Correlation ID of the connection, also found in its `TunnelEvent`s and the stack's log
+    /// spans. Unique per stack; propagate it to upstream spans for end-to-end tracing.
+    pub id: u64,
+    pub target: SocketAddr,
+    /// The client that opened the stream, for per-source policy or logging. This is its
+    /// address inside the TUN namespace (as seen in the trapped SYN), not a real peer.
+    pub source: SocketAddr,
+    /// Channel to write data TO the remote tunnel (PrismStack -> TLS)
+    pub tx: mpsc::Sender<Bytes>,
+    /// Channel to read data FROM the remote tunnel (TLS -> PrismStack). With
+    /// `ingress_reorder` set, each message is framed by [`sequenced_chunk`].
+    pub rx: mpsc::Receiver<Bytes>,
+    /// Optional feedback channel for Consistent Handshake.
+    pub response_tx: Option<oneshot::Sender<bool>>,
+    /// Resets the client connection, e.g. when the target turns out to be unreachable after
+    /// a Fast handshake already accepted it. Dropping `tx` closes it gracefully (FIN) instead.
+    pub abort: TunnelAbort,
+    /// Further streams to the same target opened within `syn_coalesce_window`, so a pooling
+    /// relayer can set them up together. Each is a complete request of its own (and never
+    /// has `coalesced` entries itself). Always empty when coalescing is off.
+    pub coalesced: Vec<TunnelRequest>,
+}
+
+/// Request to steer one UDP flow (a QUIC connection, say) through the relayer, see
+/// `PrismConfig::udp_trap_ports`.
+///
+/// Unlike the Blind Relay, which forwards whole IP packets one by one and keeps no state, the
+/// stack tracks the flow by its 5-tuple: the relayer gets bare payloads, one message per
+/// datagram, and whatever it sends back reaches the client as a datagram from `target`. The
+/// tunnel ends when the relayer drops `tx`, or after `UDP_TUNNEL_IDLE_TIMEOUT` without traffic.
+pub struct UdpTunnelRequest {
+    /// Correlation ID, from the same sequence as [`TunnelRequest::id`]
+    pub id: u64,
+    /// Client address (inside the TUN namespace)
+    pub source: SocketAddr,
+    pub target: SocketAddr,
+    /// Datagrams FROM the client, payload only. Full channels drop them, as a congested link would.
+    pub rx: mpsc::Receiver<Bytes>,
+    /// Datagrams TO the client, payload only
+    pub tx: mpsc::Sender<Bytes>,
+}
+
+/// A trapped UDP flow, see [`UdpTunnelRequest`].
+pub(crate) struct UdpTunnel {
+    pub(crate) id: u64,
+    /// Client datagrams to the relayer
+    pub(crate) tx: mpsc::Sender<Bytes>,
+    pub(crate) last_activity: time::Instant,
+}
+
+/// Datagrams from the relayer, tagged with their flow and tunnel ID; `None` once it dropped
+/// its sender.
+type UdpReturnStream = futures::stream::BoxStream<'static, (FlowKey, u64, Option<Bytes>)>;
+
+/// Lets the relayer reset one tunnel's client connection, see [`TunnelRequest::abort`].
+#[derive(Debug, Clone)]
+pub struct TunnelAbort {
+    id: u64,
+    tx: mpsc::UnboundedSender<u64>,
+}
+
+impl TunnelAbort {
+    /// Sends the client a RST and drops the tunnel, whatever is still buffered either way.
+    /// Does nothing once the tunnel is gone (or the stack with it).
+    pub fn abort(self) {
+        let _ = self.tx.send(self.id);
+    }
+}
+
+/// One tunnel's client socket, see [`PrismStack::tunnel_states`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TunnelSnapshot {
+    pub handle: SocketHandle,
+    /// Correlation ID, see [`TunnelRequest::id`]
+    pub id: u64,
+    /// Client address (inside the TUN namespace)
+    pub source: SocketAddr,
+    pub target: SocketAddr,
+    pub state: tcp::State,
+}
+
+/// Asks a running stack about its tunnels, see [`PrismStack::inspector`]. The stack answers
+/// between two events of its loop, so a snapshot costs nothing until someone asks for one.
+#[derive(Debug, Clone)]
+pub struct StackInspector {
+    tx: mpsc::UnboundedSender<oneshot::Sender<Vec<TunnelSnapshot>>>,
+}
+
+impl StackInspector {
+    /// Every active tunnel, as [`PrismStack::tunnel_states`]. `None` once the stack is gone.
+    pub async fn tunnel_states(&self) -> Option<Vec<TunnelSnapshot>> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(tx).ok()?;
+        rx.await.ok()
+    }
+
+    /// Socket state of one tunnel, as [`PrismStack::tunnel_state`].
+    pub async fn tunnel_state(&self, handle: SocketHandle) -> Option<tcp::State> {
+        let tunnels = self.tunnel_states().await?;
+        tunnels.into_iter().find(|tunnel| tunnel.handle == handle).map(|tunnel| tunnel.state)
+    }
+}
+
+/// Shuts down a stack that `run` owns, see [`PrismStack::close_handle`].
+#[derive(Debug, Clone)]
+pub struct CloseHandle {
+    tx: mpsc::UnboundedSender<(Duration, oneshot::Sender<StatsSnapshot>)>,
+}
+
+impl CloseHandle {
+    /// Closes the running stack as [`PrismStack::close`] does, then ends its `run`. Returns the
+    /// final counters once the tunnels are drained, `None` if the stack is already gone.
+    pub async fn close(&self, timeout: Duration) -> Option<StatsSnapshot> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send((timeout, tx)).ok()?;
+        rx.await.ok()
+    }
+}
+
+/// Feeds path MTUs learned outside the stack into it, see [`PrismStack::path_mtu_reporter`].
+#[derive(Debug, Clone)]
+pub struct PathMtuReporter {
+    tx: mpsc::UnboundedSender<(IpAddr, usize)>,
+}
+
+impl PathMtuReporter {
+    /// Clamps the MSS of new connections to `dst` so their segments fit `mtu`, for
+    /// `PATH_MTU_TTL`. Only ever lowers the clamp; an MTU below the family's minimum (576 for
+    /// IPv4, 1280 for IPv6) counts as that minimum. Returns `false` once the stack is gone.
+    pub fn report(&self, dst: IpAddr, mtu: usize) -> bool {
+        self.tx.send((dst, mtu)).is_ok()
+    }
+
+    /// Reports the MTU of an ICMP fragmentation needed / packet too big (see
+    /// [`packet_too_big`](crate::relay::packet_too_big)). Returns whether `icmp` was one.
+    pub fn report_icmp(&self, icmp: &[u8]) -> bool {
+        crate::relay::packet_too_big(icmp).is_some_and(|(dst, mtu)| self.report(dst, mtu.into()))
+    }
+}
+
+/// The relayer-facing half of a tunnel, as handed over by [`PrismStack::detach_tunnel`].
+///
+/// Only the channel association migrates: the client-facing TCP socket (sequence numbers,
+/// buffers, timers) always stays in the stack that accepted it. Data still sitting in either
+/// socket buffer is delivered by that socket, not by the channels.
+pub struct TunnelChannels {
+    pub target: SocketAddr,
+    /// Egress sender (client -> remote), as held by the stack
+    pub tx_to_remote: mpsc::Sender<Bytes>,
+    /// Ingress receiver (remote -> client), as held by the stack
+    pub rx_from_remote: mpsc::Receiver<Bytes>,
+}
+
+/// Why [`PrismStack::open_tunnel`] couldn't open a tunnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenTunnelError {
+    /// The flow already has a tunnel, with this socket
+    Exists(SocketHandle),
+    /// No tunnel request sender is set
+    NoRelayer,
+    /// The socket couldn't listen on the target endpoint (`setup_listen_failed`)
+    ListenFailed,
+    /// smoltcp's address table had no room for the target (`setup_addr_table_full`)
+    AddressTableFull,
+    /// The relayer's request channel was full or closed (`setup_request_rejected`)
+    Rejected,
+}
+
+impl std::fmt::Display for OpenTunnelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OpenTunnelError::Exists(handle) => write!(f, "flow already tunneled by socket {}", handle),
+            OpenTunnelError::NoRelayer => write!(f, "no tunnel request sender set"),
+            OpenTunnelError::ListenFailed => write!(f, "couldn't listen on the target endpoint"),
+            OpenTunnelError::AddressTableFull => write!(f, "interface address table full"),
+            OpenTunnelError::Rejected => write!(f, "relayer didn't take the tunnel request"),
+        }
+    }
+}
+
+impl std::error::Error for OpenTunnelError {}
+
+/// Ingress half of one tunnel (remote -> client), polled through `ingress_streams`.
+///
+/// Unlike a plain `ReceiverStream`, the receiver can be taken back out while the stream
+/// sits inside the `SelectAll` (see [`PrismStack::detach_tunnel`]), and the end of the
+/// channel is reported as a final `(handle, None)` so the stack can send a FIN.
+/// A detached stream ends without that marker.
+pub struct IngressStream {
+    handle: SocketHandle,
+    rx: Option<mpsc::Receiver<Bytes>>,
+    /// Last task that polled us, woken on detach so `SelectAll` drops the empty stream.
+    waker: Option<Waker>,
+}
+
+/// A SYN waiting for its Consistent handshake, with the channels of its tunnel.
+pub struct PendingSyn {
+    /// Correlation ID, see [`TunnelRequest::id`]
+    pub id: u64,
+    pub syn: HeldSyn,
+    pub tx_to_remote: mpsc::Sender<Bytes>,
+    pub rx_from_remote: mpsc::Receiver<Bytes>,
+}
+
+/// How a pending SYN is kept until it is re-injected (see `compact_pending_syns`).
+#[derive(Debug, Clone)]
+pub enum HeldSyn {
+    /// The trapped (MSS-clamped) packet itself
+    Packet(Bytes),
+    /// Enough to rebuild it
+    Summary(SynSummary),
+}
+
+impl HeldSyn {
+    /// Bytes held on top of the entry itself.
+    fn len(&self) -> usize {
+        match self {
+            HeldSyn::Packet(packet) => packet.len(),
+            HeldSyn::Summary(_) => 0,
         }
+    }
 
-        let handle = self.sockets.add(socket);
-        self.device.pending_packets.push_back(pkt);
-        self.active_ips.insert(handle, cidr);
+    /// The SYN of `flow` as trapped, rebuilt from the summary if need be.
+    fn to_packet(&self, flow: FlowKey) -> BytesMut {
+        match self {
+            HeldSyn::Packet(packet) => BytesMut::from(packet.as_ref()),
+            HeldSyn::Summary(summary) => {
+                let packet = summary.to_packet(flow.0, flow.1).expect("summarized flows have one address family");
+                BytesMut::from(&packet[..])
+            }
+        }
+    }
+}
 
-        if let Some(ref req_tx) = self.tunnel_req_tx {
-            let (tx_to_remote, rx_from_internal) = mpsc::channel::<Bytes>(1024);
-            let (tx_to_internal, rx_from_remote) = mpsc::channel::<Bytes>(1024);
+/// Frames an ingress message for a stack with `ingress_reorder` set: the sequence number
+/// (big-endian) followed by `data`.
+pub fn sequenced_chunk(seq: u64, data: &[u8]) -> Bytes {
+    let mut chunk = BytesMut::with_capacity(8 + data.len());
+    chunk.extend_from_slice(&seq.to_be_bytes());
+    chunk.extend_from_slice(data);
+    chunk.freeze()
+}
 
-            let request = TunnelRequest {
-                target: event.dst,
-                tx: tx_to_internal,
-                rx: rx_from_internal,
-                response_tx: None,
-            };
+/// Remote data that didn't fit into a tunnel's send buffer. The tunnel's ingress channel is
+/// parked alongside and only read again once `tail` is written.
+pub(crate) struct PendingIngress {
+    pub(crate) tail: Bytes,
+    pub(crate) rx: Option<mpsc::Receiver<Bytes>>,
+}
 
-            if let Err(_) = req_tx.try_send(request) {
-                self.active_ips.remove(&handle);
-                self.sockets.remove(handle);
-            } else {
-                self.active_tunnels.insert(handle, tx_to_remote);
-                self.ingress_streams.push(
-                    ReceiverStream::new(rx_from_remote).map(move |b| (handle, b)).boxed(),
-                );
+/// Waits for room on a channel (the Blind Relay, the tunnel requests); `None` without a
+/// channel or once it is closed.
+async fn reserve_slot<T>(tx: Option<mpsc::Sender<T>>) -> Option<mpsc::OwnedPermit<T>> {
+    tx?.reserve_owned().await.ok()
+}
+
+/// Hands a [`FlowPeek`] to the peek channel, or counts it as dropped.
+fn send_peek(tx: Option<&mpsc::Sender<FlowPeek>>, stats: &PrismStats, peek: FlowPeek) {
+    if tx.is_none_or(|tx| tx.try_send(peek).is_err()) {
+        PrismStats::bump(&stats.peeks_dropped);
+    }
+}
+
+/// Whether a socket in this state can still accept data for the client later on.
+fn may_become_writable(state: tcp::State) -> bool {
+    matches!(state, tcp::State::SynReceived | tcp::State::Established | tcp::State::CloseWait)
+}
+
+impl IngressStream {
+    fn new(handle: SocketHandle, rx: mpsc::Receiver<Bytes>) -> Self {
+        Self { handle, rx: Some(rx), waker: None }
+    }
+
+    fn detach(&mut self) -> Option<mpsc::Receiver<Bytes>> {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+        self.rx.take()
+    }
+}
+
+impl Stream for IngressStream {
+    type Item = (SocketHandle, Option<Bytes>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let Some(rx) = this.rx.as_mut() else {
+            return Poll::Ready(None);
+        };
+        if !this.waker.as_ref().is_some_and(|w| w.will_wake(cx.waker())) {
+            this.waker = Some(cx.waker().clone());
+        }
+        let handle = this.handle;
+        match rx.poll_recv(cx) {
+            Poll::Ready(Some(data)) => Poll::Ready(Some((handle, Some(data)))),
+            Poll::Ready(None) => {
+                // Remote closed its write half: report it once, then end
+                this.rx = None;
+                Poll::Ready(Some((handle, None)))
             }
+            Poll::Pending => Poll::Pending,
         }
     }
+}
 
-    fn handle_handshake_feedback(&mut self, target: SocketAddr, success: bool, rx_buf: usize, tx_buf: usize) {
-        if let Some((trap, tx_to_remote, rx_from_remote)) = self.pending_syns.remove(&target) {
-            if success {
-                debug!("Tunnel ready for {}. Releasing SYN.", target);
-                let mut socket = tcp::Socket::new(
-                    tcp::SocketBuffer::new(vec![0; rx_buf]),
-                    tcp::SocketBuffer::new(vec![0; tx_buf]),
-                );
-                socket.set_keep_alive(Some(Duration::from_secs(60).into()));
-                socket.set_nagle_enabled(false);
-
-                let endpoint = match target {
-                    std::net::SocketAddr::V4(addr) => smoltcp::wire::IpEndpoint::new(
-                        smoltcp::wire::IpAddress::Ipv4(Ipv4Address::from_bytes(&addr.ip().octets())),
-                        addr.port(),
-                    ),
-                    std::net::SocketAddr::V6(addr) => smoltcp::wire::IpEndpoint::new(
-                        smoltcp::wire::IpAddress::Ipv6(Ipv6Address::from_bytes(&addr.ip().octets())),
-                        addr.port(),
-                    ),
-                };
+/// Why a tunnel ended or was never opened (see [`TunnelEvent`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The client closed first.
+    ClientFin,
+    /// The remote side closed first (the relayer dropped its sender).
+    RemoteFin,
+    /// The socket died on a timer without a FIN or RST from either side.
+    IdleTimeout,
+    /// A limit was hit: `syn_rate_limit`, the relayer's request queue was full, or the
+    /// interface's address table (`setup_addr_table_full`).
+    Limit,
+    /// The client reset the connection, or the relayer refused a Consistent handshake.
+    Reset,
+    /// The relayer reset the connection through its [`TunnelAbort`].
+    Aborted,
+    /// The tunnel socket couldn't listen on the target endpoint (`setup_listen_failed`).
+    ListenFailed,
+    /// The stack is shutting down ([`PrismStack::close`]): new connections are refused, and
+    /// open ones are closed once drained or reset when time runs out.
+    Shutdown,
+}
 
-                if let Ok(_) = socket.listen(endpoint) {
-                    let handle = self.sockets.add(socket);
-                    self.active_tunnels.insert(handle, tx_to_remote);
-                    self.ingress_streams.push(
-                        ReceiverStream::new(rx_from_remote).map(move |b| (handle, b)).boxed(),
-                    );
-                    // Track IP for cleanup
-                    let cidr = match target {
-                        std::net::SocketAddr::V4(addr) => IpCidr::new(
-                            IpAddress::Ipv4(Ipv4Address::from_bytes(&addr.ip().octets())),
-                            32,
-                        ),
-                        std::net::SocketAddr::V6(addr) => IpCidr::new(
-                            IpAddress::Ipv6(Ipv6Address::from_bytes(&addr.ip().octets())),
-                            128,
-                        ),
-                    };
-                    self.active_ips.insert(handle, cidr);
-                    self.device.pending_packets.push_back(BytesMut::from(trap.packet.as_ref()));
+/// Tunnel lifecycle notifications, see [`PrismStack::set_event_sender`].
+///
+/// `id` is the connection's correlation ID, as in its [`TunnelRequest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TunnelEvent {
+    /// The tunnel was requested: its socket is set up, the client may not be connected yet.
+    Opened { id: u64, handle: SocketHandle, target: SocketAddr },
+    /// The client completed its handshake with the tunnel socket. A tunnel closed without
+    /// it never connected (a Fast handshake the client gave up on, `setup_half_open_timeout`).
+    Established { id: u64, handle: SocketHandle, target: SocketAddr },
+    /// `bytes_tx` is client -> remote, `bytes_rx` remote -> client.
+    Closed { id: u64, handle: SocketHandle, target: SocketAddr, bytes_tx: u64, bytes_rx: u64, reason: CloseReason },
+    Rejected { id: u64, target: SocketAddr, reason: CloseReason },
+}
+
+/// The start of a tunnel's client -> remote stream, see [`PrismStack::set_peek_sender`]. The
+/// same bytes are also forwarded on the tunnel's egress channel as usual.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlowPeek {
+    /// Correlation ID of the tunnel, as in its [`TunnelRequest`]
+    pub id: u64,
+    pub handle: SocketHandle,
+    pub target: SocketAddr,
+    /// The first `PrismConfig::peek_bytes` of client data, or what arrived of them before
+    /// the client's FIN or a `PEEK_WINDOW` pause
+    pub data: Bytes,
+}
+
+/// A tunnel whose relayer couldn't keep up with its egress channel, see
+/// [`PrismStack::tunnel_backpressure`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TunnelBackpressure {
+    pub handle: SocketHandle,
+    pub target: SocketAddr,
+    /// Times the egress pump found the channel full after it last had room.
+    pub events: u64,
+    /// Total time the channel stayed full, the current stretch included.
+    pub duration: Duration,
+}
+
+/// Token bucket for `syn_rate_limit`, one per destination IP, and for the ICMP errors the
+/// stack generates itself.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SynBucket {
+    tokens: f64,
+    updated: time::Instant,
+}
+
+impl SynBucket {
+    fn full(burst: u32, now: time::Instant) -> Self {
+        Self { tokens: burst as f64, updated: now }
+    }
+
+    /// Refills for the time elapsed since the last update, then takes a token if there is one.
+    fn try_take(&mut self, burst: u32, window: Duration, now: time::Instant) -> bool {
+        self.refill(burst, window, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn refill(&mut self, burst: u32, window: Duration, now: time::Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        let rate = burst as f64 / window.as_secs_f64().max(f64::EPSILON);
+        self.tokens = (self.tokens + elapsed * rate).min(burst as f64);
+        self.updated = now;
+    }
+}
+
+/// Token bucket for `rate_limit_bps`, one per tunnel. Tokens are bytes.
+#[derive(Debug)]
+pub(crate) struct ByteBucket {
+    tokens: f64,
+    /// Refill rate in bytes per second, also the bucket's size
+    rate: f64,
+    updated: time::Instant,
+}
+
+impl ByteBucket {
+    fn full(bps: u64, now: time::Instant) -> Self {
+        let rate = (bps as f64 / 8.0).max(1.0);
+        Self { tokens: rate, rate, updated: now }
+    }
+
+    /// Refills for the time elapsed since the last update and returns the whole bytes available.
+    fn available(&mut self, now: time::Instant) -> usize {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+        self.tokens as usize
+    }
+
+    fn take(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+
+    /// How long until `bytes` (capped at the bucket's size) are available.
+    fn wait_for(&self, bytes: usize) -> Duration {
+        let wanted = (bytes as f64).min(self.rate);
+        Duration::from_secs_f64(((wanted - self.tokens) / self.rate).max(0.0))
+    }
+}
+
+/// Ingress messages of one tunnel that arrived ahead of a missing one (`ingress_reorder`).
+#[derive(Debug, Default)]
+pub(crate) struct IngressReorder {
+    /// Sequence number of the next message for the socket
+    next: u64,
+    held: BTreeMap<u64, Bytes>,
+}
+
+/// Why a tunnel's ingress can't be put back in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ReorderError {
+    /// A message too short to carry a sequence number
+    Malformed { len: usize },
+    /// More than `limit` messages waiting for a missing one
+    Overflow { limit: usize },
+    /// The channel closed with messages still waiting
+    Gap { held: usize },
+}
+
+impl std::fmt::Display for ReorderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReorderError::Malformed { len } => write!(f, "{} byte ingress message has no sequence number", len),
+            ReorderError::Overflow { limit } => write!(f, "more than {} ingress messages out of order", limit),
+            ReorderError::Gap { held } => write!(f, "remote closed with {} ingress messages after a gap", held),
+        }
+    }
+}
+
+impl IngressReorder {
+    /// Takes a sequenced message. Returns its payload if it is the next one; later ones are
+    /// held (`Ok(None)`), as are duplicates, which are dropped.
+    fn accept(&mut self, message: Bytes, limit: usize) -> Result<Option<Bytes>, ReorderError> {
+        let Some(seq) = message.get(..8).map(|seq| u64::from_be_bytes(seq.try_into().unwrap())) else {
+            return Err(ReorderError::Malformed { len: message.len() });
+        };
+        if seq < self.next || self.held.contains_key(&seq) {
+            return Ok(None);
+        }
+        let data = message.slice(8..);
+        if seq == self.next {
+            self.next += 1;
+            return Ok(Some(data));
+        }
+        if self.held.len() >= limit {
+            return Err(ReorderError::Overflow { limit });
+        }
+        self.held.insert(seq, data);
+        Ok(None)
+    }
+
+    /// The held message that is next in line, if it arrived.
+    fn pop_next(&mut self) -> Option<Bytes> {
+        let data = self.held.remove(&self.next)?;
+        self.next += 1;
+        Some(data)
+    }
+
+    fn finish(&self) -> Result<(), ReorderError> {
+        match self.held.len() {
+            0 => Ok(()),
+            held => Err(ReorderError::Gap { held }),
+        }
+    }
+}
+
+/// An active tunnel: its egress channel and bookkeeping, the value of `active_tunnels`.
+#[derive(Debug)]
+pub struct TunnelState {
+    /// Egress channel to the relayer. `None` while detached (see `detach_tunnel`) and once
+    /// the client has sent its FIN (`client_fin`); the ingress side is handled via
+    /// `ingress_streams`
+    pub tx_to_remote: Option<mpsc::Sender<Bytes>>,
+    /// Correlation ID, see [`TunnelRequest::id`]
+    pub id: u64,
+    pub target: SocketAddr,
+    /// When the tunnel was registered
+    pub opened_at: time::Instant,
+    /// Last time data moved either way (registration until then)
+    pub last_activity: time::Instant,
+    /// Client -> remote bytes handed to the relayer
+    pub bytes_tx: u64,
+    /// Remote -> client bytes written to the socket
+    pub bytes_rx: u64,
+    /// Client-side 4-tuple, the key of this tunnel in `flow_index`.
+    pub(crate) flow: FlowKey,
+    /// Whether the opening bytes were already logged (`log_payload_prefix`).
+    pub(crate) prefix_logged: bool,
+    /// Client data collected for the `FlowPeek` while it is shorter than `peek_bytes`
+    pub(crate) peek: Option<BytesMut>,
+    /// The `FlowPeek` went out (or was dropped)
+    pub(crate) peek_done: bool,
+    /// Which side sent the first FIN, if any
+    pub(crate) first_fin: Option<CloseReason>,
+    /// Everything the client sent up to its FIN was handed over and the egress sender dropped:
+    /// the tunnel can't be attached to again
+    pub(crate) client_fin: bool,
+    /// The client sent a RST
+    pub(crate) reset: bool,
+    /// The client completed the handshake
+    pub(crate) established: bool,
+    /// Opened by `open_tunnel` and still waiting for the client's SYN, until this deadline.
+    /// Its socket isn't listening meanwhile.
+    pub(crate) awaiting_syn: Option<time::Instant>,
+    /// Its coalesced request never reached the relayer: reset, and already reported with
+    /// `TunnelEvent::Rejected` instead of `Closed`
+    pub(crate) rejected: bool,
+    /// Times the egress channel was found full (see [`TunnelBackpressure`])
+    pub(crate) backpressure_events: u64,
+    /// Since when the egress channel has been full, while it is
+    pub(crate) backpressured_since: Option<time::Instant>,
+    /// Time spent backpressured in stretches that already ended
+    pub(crate) backpressured_for: Duration,
+    /// Read budget of the socket (`rate_limit_bps`)
+    pub(crate) rate_bucket: Option<ByteBucket>,
+    /// Ingress messages held back by `ingress_reorder`
+    pub(crate) reorder: IngressReorder,
+}
+
+impl TunnelState {
+    fn new(id: u64, flow: FlowKey, tx_to_remote: mpsc::Sender<Bytes>, now: time::Instant) -> Self {
+        Self {
+            tx_to_remote: Some(tx_to_remote),
+            id,
+            target: flow.1,
+            opened_at: now,
+            last_activity: now,
+            bytes_tx: 0,
+            bytes_rx: 0,
+            flow,
+            prefix_logged: false,
+            peek: None,
+            peek_done: false,
+            first_fin: None,
+            client_fin: false,
+            reset: false,
+            established: false,
+            awaiting_syn: None,
+            rejected: false,
+            backpressure_events: 0,
+            backpressured_since: None,
+            backpressured_for: Duration::ZERO,
+            rate_bucket: None,
+            reorder: IngressReorder::default(),
+        }
+    }
+
+    /// Starts a backpressure stretch unless one is running. Returns whether it started one.
+    fn backpressure_started(&mut self, now: time::Instant) -> bool {
+        if self.backpressured_since.is_some() {
+            return false;
+        }
+        self.backpressured_since = Some(now);
+        self.backpressure_events += 1;
+        true
+    }
+
+    /// Ends the running backpressure stretch, if any.
+    fn backpressure_ended(&mut self, now: time::Instant) {
+        if let Some(since) = self.backpressured_since.take() {
+            self.backpressured_for += now - since;
+        }
+    }
+
+    fn backpressure_time(&self, now: time::Instant) -> Duration {
+        self.backpressured_for + self.backpressured_since.map_or(Duration::ZERO, |since| now - since)
+    }
+
+    fn close_reason(&self) -> CloseReason {
+        if self.reset {
+            CloseReason::Reset
+        } else {
+            self.first_fin.unwrap_or(CloseReason::IdleTimeout)
+        }
+    }
+
+    /// Returns the hex dump of the first `limit` bytes of `data`, but only for the
+    /// first chunk seen on this tunnel.
+    fn take_payload_prefix(&mut self, data: &[u8], limit: usize) -> Option<String> {
+        if limit == 0 || self.prefix_logged || data.is_empty() {
+            return None;
+        }
+        self.prefix_logged = true;
+        Some(data.iter().take(limit).map(|b| format!("{:02x}", b)).collect())
+    }
+
+    /// Ends the collection of this tunnel's `FlowPeek`, returning it unless it already went
+    /// out or nothing was collected.
+    fn take_peek(&mut self, handle: SocketHandle) -> Option<FlowPeek> {
+        self.peek_done = true;
+        let data = self.peek.take().filter(|peek| !peek.is_empty())?.freeze();
+        Some(FlowPeek { id: self.id, handle, target: self.target, data })
+    }
+}
+
+/// The virtual network stack structure.
+pub struct PrismStack {
+    pub iface: Interface,
+    pub sockets: SocketSet<'static>,
+    // Removed trap_rx channel, we handle it directly in loop
+    
+    /// Control channel to request new tunnels from the Relayer
+    pub tunnel_req_tx: Option<mpsc::Sender<TunnelRequest>>,
+    
+    /// Blind Relay channel for non-TCP packets (UDP, ICMP, etc.).
+    /// Each message is exactly one IP packet, so UDP datagram boundaries survive the relay.
+    pub blind_relay_tx: Option<mpsc::Sender<Bytes>>,
+    /// Reports the DNS queries handed to the Blind Relay (best effort, never blocks)
+    pub dns_query_tx: Option<mpsc::Sender<crate::relay::DnsQuery>>,
+    /// Control channel to request UDP tunnels (`udp_trap_ports`) from the Relayer
+    pub udp_tunnel_req_tx: Option<mpsc::Sender<UdpTunnelRequest>>,
+    /// Trapped UDP flows by 5-tuple
+    pub(crate) udp_tunnels: HashMap<FlowKey, UdpTunnel>,
+    /// Datagrams from the relayer for all UDP tunnels
+    pub(crate) udp_return_streams: SelectAll<UdpReturnStream>,
+
+    /// Optional lifecycle event channel (best effort, never blocks)
+    pub event_tx: Option<mpsc::Sender<TunnelEvent>>,
+    /// Optional channel for the first bytes of each tunnel (best effort, never blocks)
+    pub peek_tx: Option<mpsc::Sender<FlowPeek>>,
+    
+    /// Active tunnel sockets with their egress channel and bookkeeping
+    pub active_tunnels: HashMap<SocketHandle, TunnelState>,
+    /// Maps a client 4-tuple to its tunnel socket, to find the socket a TUN packet is for
+    pub(crate) flow_index: HashMap<FlowKey, SocketHandle>,
+    /// Maps a correlation ID to the flow of its tunnel or pending Consistent handshake, for
+    /// `TunnelAbort`
+    pub(crate) id_index: HashMap<u64, FlowKey>,
+    /// Tunnels the egress pump has to visit next: sockets that were handed packets,
+    /// plus those whose data didn't fit into the tunnel channel last time
+    pub(crate) dirty: HashSet<SocketHandle>,
+    
+    /// Aggregated stream of incoming data from all active tunnels
+    /// Yields: (SocketHandle, Data)
+    pub ingress_streams: SelectAll<IngressStream>,
+    /// Unwritten remote data per tunnel, see [`PendingIngress`]
+    pub(crate) pending_ingress: HashMap<SocketHandle, PendingIngress>,
+
+    /// The PHY device
+    pub device: PrismDevice,
+    /// Stack configuration
+    pub config: PrismConfig,
+    /// Pending SYNs waiting for tunnel confirmation (Consistent Mode), keyed by 4-tuple
+    /// so concurrent connections from one host to the same destination don't collide.
+    pub pending_syns: HashMap<FlowKey, PendingSyn>,
+    /// Tracks which IPs are registered for each socket handle (for cleanup on close)
+    pub active_ips: HashMap<SocketHandle, IpCidr>,
+    /// Set of all dynamically-registered IP CIDRs (to prevent re-adding)
+    pub registered_ips: HashSet<IpCidr>,
+    /// Internal feedback channel to receive signals from the async bridge tasks
+    pub feedback_tx: mpsc::Sender<(FlowKey, bool)>,
+    pub feedback_rx: mpsc::Receiver<(FlowKey, bool)>,
+    /// Tunnel IDs sent by [`TunnelAbort`]s
+    pub(crate) abort_tx: mpsc::UnboundedSender<u64>,
+    pub(crate) abort_rx: mpsc::UnboundedReceiver<u64>,
+    /// Path MTUs from [`PathMtuReporter`]s
+    pub(crate) path_mtu_tx: mpsc::UnboundedSender<(IpAddr, usize)>,
+    pub(crate) path_mtu_rx: mpsc::UnboundedReceiver<(IpAddr, usize)>,
+    /// Reported path MTU per destination, and when it stops applying (`PATH_MTU_TTL`)
+    pub(crate) path_mtus: HashMap<IpAddr, (usize, time::Instant)>,
+    /// Snapshot requests from [`StackInspector`]s
+    pub(crate) inspect_tx: mpsc::UnboundedSender<oneshot::Sender<Vec<TunnelSnapshot>>>,
+    pub(crate) inspect_rx: mpsc::UnboundedReceiver<oneshot::Sender<Vec<TunnelSnapshot>>>,
+    /// Shutdown requests from [`CloseHandle`]s, with the drain timeout
+    pub(crate) close_tx: mpsc::UnboundedSender<(Duration, oneshot::Sender<StatsSnapshot>)>,
+    pub(crate) close_rx: mpsc::UnboundedReceiver<(Duration, oneshot::Sender<StatsSnapshot>)>,
+    /// Runtime counters, shared with observers via [`PrismStack::stats`]
+    pub stats: Arc<PrismStats>,
+    /// Tunnel requests held back by `syn_coalesce_window`, per target, with their flush deadline
+    pub(crate) syn_batches: HashMap<SocketAddr, (time::Instant, Vec<TunnelRequest>)>,
+    /// `syn_rate_limit` buckets per destination IP, dropped again once refilled
+    pub(crate) syn_buckets: HashMap<IpAddr, SynBucket>,
+    /// Budget for the ICMP errors `relay_packet` sends back (`ICMP_ERROR_BURST`)
+    pub(crate) icmp_bucket: SynBucket,
+    /// When each recently admitted flow sent its first SYN, and its sequence number (kept for
+    /// `SYN_CACHE_TTL`)
+    pub(crate) syn_cache: HashMap<FlowKey, (time::Instant, u32)>,
+    /// Tunnels over their `rate_limit_bps` budget with data waiting, and when to read them again
+    pub(crate) throttled: HashMap<SocketHandle, time::Instant>,
+    /// Tunnels collecting a short `FlowPeek`, and when to send it as it is (`PEEK_WINDOW`)
+    pub(crate) peeking: HashMap<SocketHandle, time::Instant>,
+    /// Helper tasks go to `spawn_local` (set by [`PrismStack::run_on_current_thread`])
+    pub(crate) local_tasks: bool,
+    /// Blind Relay packets waiting for channel room (`BlindRelayPolicy::DropOldest`)
+    pub(crate) relay_backlog: VecDeque<Bytes>,
+    /// Tunnel requests waiting for room on the request channel (`TUNNEL_REQUEST_BACKLOG`)
+    pub(crate) request_backlog: VecDeque<TunnelRequest>,
+    /// Sockets whose SYN was re-injected since the last poll (`verify_reinjected_syns`)
+    pub(crate) unverified_syns: Vec<(SocketHandle, SocketAddr)>,
+    /// Tunnels whose client sent a RST since the last poll; smoltcp decides whether it was
+    /// in the window
+    pub(crate) client_resets: Vec<SocketHandle>,
+    /// SYNs waiting for admission (`admit_per_poll`), with the time they were trapped. The
+    /// event's own copy of the SYN is dropped, `admission_queue_cap` bounds the length.
+    pub(crate) admission_queue: VecDeque<(time::Instant, PrismTrap, BytesMut)>,
+    /// SYNs admitted since the last poll
+    pub(crate) admitted_this_poll: usize,
+    /// A SYN was already refused for lack of a tunnel request sender (the warning is logged once)
+    pub(crate) warned_missing_relayer: bool,
+    /// Correlation ID for the next trapped connection
+    pub(crate) next_tunnel_id: u64,
+    /// Slot of each socket in the set, recorded as it is added (see `index_sockets`)
+    pub(crate) socket_index: HashMap<SocketHandle, usize>,
+    /// Slots below `socket_slots` left free by removed sockets; the set refills the lowest first
+    pub(crate) free_slots: BTreeSet<usize>,
+    /// Length of the socket set's storage as far as the stack has seen it grow (sockets added
+    /// and removed through `sockets` directly may leave free slots it doesn't know about)
+    pub(crate) socket_slots: usize,
+    /// Set by [`PrismStack::close`]: new connections are refused with a RST
+    pub(crate) closing: bool,
+    /// Fragmented IPv4 TCP segments being put back together
+    pub(crate) ipv4_reassembly: crate::reassembly::Ipv4Reassembler,
+    /// Replaces the built-in `default_verdict` when set
+    pub(crate) classifier: Option<PacketClassifier>,
+    /// Last time `static_neighbors` were announced to smoltcp
+    pub(crate) neighbors_announced: time::Instant,
+}
+
+impl PrismStack {
+    /// Creates a new PrismStack instance with the given Device.
+    pub fn new(device: PrismDevice, config: PrismConfig) -> Self {
+        Self::new_with_sockets(device, config, SocketSet::new(vec![]))
+    }
+
+    /// Like [`PrismStack::new`], but adopts an externally built socket set, e.g. to run a
+    /// local DNS responder on the gateway IP next to the tunnels.
+    ///
+    /// Invariant: handles added from outside must never appear in `active_tunnels`; the
+    /// tunnel logic (egress pump, reaping) only touches handles it created, so external
+    /// sockets are polled by the interface but otherwise left alone. Note that every TCP
+    /// SYN is trapped unless `gateway_tcp_ports` lets it through, and that non-TCP traffic only
+    /// reaches smoltcp when no blind relay is set.
+    pub fn new_with_sockets(mut device: PrismDevice, config: PrismConfig, mut sockets: SocketSet<'static>) -> Self {
+        for issue in config.check() {
+            warn!("Config: {}", issue);
+        }
+        let stats = Arc::new(PrismStats::default());
+        device.stats = stats.clone();
+        // Must be set before the interface reads the device capabilities
+        device.egress_mtu = config.egress_mtu;
+        device.tx_batch = config.tx_batch.max(1);
+        let offload = config.offload_mode();
+        if cfg!(target_os = "linux") {
+            device.offload = offload;
+            device.vnet_hdr |= offload != OffloadMode::Off;
+        } else {
+            if offload != OffloadMode::Off || device.vnet_hdr {
+                warn!("vnet header offload is only supported on Linux, ignoring");
+            }
+            device.vnet_hdr = false;
+        }
+        let medium = device.capabilities().medium;
+        let mut rng = config.random_seed.map_or_else(StdRng::from_entropy, StdRng::seed_from_u64);
+        let hardware_addr = match medium {
+            // A configured MAC that isn't unicast was reported by `check` above
+            smoltcp::phy::Medium::Ethernet => match config.hardware_addr.filter(|addr| addr.is_unicast()) {
+                Some(addr) => HardwareAddress::Ethernet(addr),
+                None => {
+                    let mut bytes = [0u8; 6];
+                    rng.fill(&mut bytes);
+                    bytes[0] &= 0xfe; // Unicast
+                    bytes[0] |= 0x02; // Local
+                    HardwareAddress::Ethernet(EthernetAddress(bytes))
                 }
-            } else {
-                warn!("Tunnel failed for {}. Dropping SYN.", target);
+            },
+            smoltcp::phy::Medium::Ip => HardwareAddress::Ip,
+            _ => panic!("Unsupported medium"),
+        };
+
+        let mut iface_config = Config::new(hardware_addr);
+        iface_config.random_seed = rng.gen();
+
+        // Removed trap channel creation - no longer needed in Device
+
+        let mut iface = Interface::new(iface_config, &mut device, Instant::now());
+
+        // Configure IP addresses (virtual gateway IP)
+        // We generally pick a link-local or private IP that won't conflict
+        iface.update_ip_addrs(|ip_addrs| {
+            for cidr in config.ip_mode.gateways() {
+                ip_addrs.push(cidr).unwrap();
             }
+        });
+
+        // Configure default route to sink all traffic
+        // NOTE: add_default_ipv4_route requires Ipv4Address, not IpAddress enum
+        if config.ip_mode.serves(false) {
+            iface.routes_mut().add_default_ipv4_route(Ipv4Address::new(10, 11, 12, 1)).unwrap();
         }
+        if config.ip_mode.serves(true) {
+            iface.routes_mut().add_default_ipv6_route(Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 1)).unwrap();
+        }
+        // Static routes on top; unreachable ones and the overflow were reported by `check`
+        iface.routes_mut().update(|routes| {
+            for &(cidr, via) in config.routes.iter().filter(|(cidr, via)| route_is_reachable(*cidr, *via, config.ip_mode)) {
+                let route = Route { cidr, via_router: via, preferred_until: None, expires_at: None };
+                if routes.push(route).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let (feedback_tx, feedback_rx) = mpsc::channel(128);
+        let (abort_tx, abort_rx) = mpsc::unbounded_channel();
+        let (inspect_tx, inspect_rx) = mpsc::unbounded_channel();
+        let (close_tx, close_rx) = mpsc::unbounded_channel();
+        let (path_mtu_tx, path_mtu_rx) = mpsc::unbounded_channel();
+        let (socket_index, free_slots) = index_sockets(&mut sockets);
+        let socket_slots = socket_index.values().max().map_or(0, |slot| slot + 1);
+
+        let mut stack = Self {
+            iface,
+            sockets,
+            tunnel_req_tx: None,
+            blind_relay_tx: None,
+            dns_query_tx: None,
+            udp_tunnel_req_tx: None,
+            udp_tunnels: HashMap::new(),
+            udp_return_streams: SelectAll::new(),
+            event_tx: None,
+            peek_tx: None,
+            active_tunnels: HashMap::new(),
+            flow_index: HashMap::new(),
+            id_index: HashMap::new(),
+            dirty: HashSet::new(),
+            ingress_streams: SelectAll::new(),
+            pending_ingress: HashMap::new(),
+            device,
+            config,
+            pending_syns: HashMap::new(),
+            active_ips: HashMap::new(),
+            registered_ips: HashSet::new(),
+            feedback_tx,
+            feedback_rx,
+            abort_tx,
+            abort_rx,
+            inspect_tx,
+            inspect_rx,
+            close_tx,
+            close_rx,
+            path_mtu_tx,
+            path_mtu_rx,
+            path_mtus: HashMap::new(),
+            stats,
+            syn_batches: HashMap::new(),
+            syn_buckets: HashMap::new(),
+            icmp_bucket: SynBucket::full(ICMP_ERROR_BURST, time::Instant::now()),
+            syn_cache: HashMap::new(),
+            throttled: HashMap::new(),
+            peeking: HashMap::new(),
+            local_tasks: false,
+            relay_backlog: VecDeque::new(),
+            request_backlog: VecDeque::new(),
+            unverified_syns: Vec::new(),
+            client_resets: Vec::new(),
+            admission_queue: VecDeque::new(),
+            admitted_this_poll: 0,
+            warned_missing_relayer: false,
+            next_tunnel_id: 1,
+            socket_index,
+            free_slots,
+            socket_slots,
+            closing: false,
+            ipv4_reassembly: crate::reassembly::Ipv4Reassembler::default(),
+            classifier: None,
+            neighbors_announced: time::Instant::now(),
+        };
+        stack.announce_static_neighbors(time::Instant::now());
+        stack
+    }
+
+    pub fn set_tunnel_request_sender(&mut self, tx: mpsc::Sender<TunnelRequest>) {
+        self.tunnel_req_tx = Some(tx);
+    }
+
+    /// Creates the tunnel request channel, `control_channel_depth` deep, and sets its sender
+    /// as by `set_tunnel_request_sender`. Returns the relayer's end.
+    pub fn tunnel_request_channel(&mut self) -> mpsc::Receiver<TunnelRequest> {
+        let (tx, rx) = mpsc::channel(self.config.control_channel_depth.max(1));
+        self.set_tunnel_request_sender(tx);
+        rx
+    }
+
+    /// Emits a [`LatencySample`](crate::latency::LatencySample) for each TCP packet the stack
+    /// answers: from the client packet leaving `rx_queue` to the stack's next packet on that
+    /// flow reaching `tx_queue`. Samples are dropped while the channel is full.
+    #[cfg(feature = "trace-latency")]
+    pub fn set_latency_sender(&mut self, tx: mpsc::Sender<crate::latency::LatencySample>) {
+        self.device.latency = Some(crate::latency::LatencyTracer::new(tx));
+    }
+    
+    /// Unlike the TCP egress channels (a byte stream), the relay gets one `Bytes` per packet:
+    /// datagrams are never merged or split.
+    pub fn set_blind_relay_sender(&mut self, tx: mpsc::Sender<Bytes>) {
+        self.blind_relay_tx = Some(tx);
+    }
+
+    /// Creates the Blind Relay channel, `blind_relay_depth` deep, and sets its sender as by
+    /// `set_blind_relay_sender`. Returns the relay's end.
+    pub fn blind_relay_channel(&mut self) -> mpsc::Receiver<Bytes> {
+        let (tx, rx) = mpsc::channel(self.config.blind_relay_depth.max(1));
+        self.set_blind_relay_sender(tx);
+        rx
+    }
+
+    /// Reports the first question of each DNS query (UDP to port 53) sent to the Blind Relay,
+    /// before relaying it unchanged. Queries are dropped when the channel is full.
+    pub fn set_dns_query_sender(&mut self, tx: mpsc::Sender<crate::relay::DnsQuery>) {
+        self.dns_query_tx = Some(tx);
+    }
+
+    /// Requests a [`UdpTunnelRequest`] for each new UDP flow to `udp_trap_ports`. A full
+    /// channel drops the datagram; the flow asks again with its next one.
+    pub fn set_udp_tunnel_request_sender(&mut self, tx: mpsc::Sender<UdpTunnelRequest>) {
+        self.udp_tunnel_req_tx = Some(tx);
+    }
+
+    /// Replaces the built-in packet classification ([`default_verdict`](crate::trap::default_verdict))
+    /// with `classifier`, called once per IP packet from the TUN with the whole packet.
+    ///
+    /// Broadcast/multicast (`cast_policy`) is handled before, and IPv4 TCP fragments are
+    /// reassembled first. L2 frames (`Medium::Ethernet`) always go to smoltcp. The classifier
+    /// runs on the stack's loop, so it should be quick; a panic is caught, counted in
+    /// `classifier_panics`, and the packet then goes where `default_verdict` sends it.
+    pub fn set_classifier<F>(&mut self, classifier: F)
+    where
+        F: Fn(&[u8]) -> crate::trap::Verdict + Send + 'static,
+    {
+        self.classifier = Some(Box::new(classifier));
+    }
+
+    /// Subscribes to [`TunnelEvent`]s. Events are dropped when the channel is full.
+    pub fn set_event_sender(&mut self, tx: mpsc::Sender<TunnelEvent>) {
+        self.event_tx = Some(tx);
+    }
+
+    /// Reports the first `peek_bytes` of client data of every tunnel as a [`FlowPeek`], for
+    /// relayers that pick a route from them. The data is forwarded on the tunnel as usual, so
+    /// the relayer may see it there first. Peeks are dropped when the channel is full.
+    pub fn set_peek_sender(&mut self, tx: mpsc::Sender<FlowPeek>) {
+        self.peek_tx = Some(tx);
+    }
+
+    /// Unbinds the relayer channels from a tunnel so they can be attached elsewhere.
+    ///
+    /// The TCP socket stays in this stack: while unbound, client data is left in its receive
+    /// buffer (natural backpressure) and it is still cleaned up normally once it closes.
+    /// Use [`attach_tunnel`](Self::attach_tunnel) to give it new channels.
+    pub fn detach_tunnel(&mut self, handle: SocketHandle) -> Option<TunnelChannels> {
+        let tunnel = self.active_tunnels.get_mut(&handle)?;
+        let target = tunnel.target;
+        let tx_to_remote = tunnel.tx_to_remote.take()?;
+        let rx_from_remote = self
+            .ingress_streams
+            .iter_mut()
+            .find(|stream| stream.handle == handle && stream.rx.is_some())
+            .and_then(IngressStream::detach);
+        match rx_from_remote {
+            Some(rx_from_remote) => Some(TunnelChannels { target, tx_to_remote, rx_from_remote }),
+            None => {
+                // Ingress side already finished; nothing coherent to hand over.
+                if let Some(tunnel) = self.active_tunnels.get_mut(&handle) {
+                    tunnel.tx_to_remote = Some(tx_to_remote);
+                }
+                None
+            }
+        }
+    }
+
+    /// Binds relayer channels (typically from another stack's `detach_tunnel`) to a tunnel
+    /// in this stack that currently has none and goes to the same target. A tunnel whose
+    /// client already finished sending has no use for them. Returns the channels back on
+    /// failure.
+    pub fn attach_tunnel(&mut self, handle: SocketHandle, channels: TunnelChannels) -> Result<(), TunnelChannels> {
+        match self.active_tunnels.get_mut(&handle) {
+            Some(tunnel) if tunnel.tx_to_remote.is_none() && !tunnel.client_fin && tunnel.target == channels.target => {
+                tunnel.tx_to_remote = Some(channels.tx_to_remote);
+                self.ingress_streams.push(IngressStream::new(handle, channels.rx_from_remote));
+                // Client data may have queued up in the socket while detached
+                self.dirty.insert(handle);
+                Ok(())
+            }
+            _ => Err(channels),
+        }
+    }
+
+    /// Opens a tunnel for `source` -> `target` without a trapped SYN: registers the target
+    /// address, sets up a socket and hands the relayer its `TunnelRequest`, just like a Fast
+    /// handshake (whatever `handshake_mode`). No data flows until the client actually
+    /// connects: the socket only starts listening when the SYN of `source` arrives, so no
+    /// other client to `target` can take it. Without that SYN within `handshake_timeout` the
+    /// tunnel is dropped and reported as [`TunnelEvent::Rejected`]; the relayer can give up
+    /// on it sooner with the request's `abort`. Syn rate limits and `admit_per_poll` don't
+    /// apply.
+    pub fn open_tunnel(&mut self, target: SocketAddr, source: SocketAddr) -> Result<SocketHandle, OpenTunnelError> {
+        if let Some(&handle) = self.flow_index.get(&(source, target)) {
+            return Err(OpenTunnelError::Exists(handle));
+        }
+        if self.tunnel_req_tx.is_none() {
+            return Err(OpenTunnelError::NoRelayer);
+        }
+        // The socket listens later, but an endpoint it can't listen on is known now
+        if target.port() == 0 {
+            return Err(OpenTunnelError::ListenFailed);
+        }
+        let id = self.next_tunnel_id;
+        self.next_tunnel_id += 1;
+        let _span = tracing::debug_span!("tunnel", id).entered();
+        debug!("Opening tunnel {} -> {} without a SYN", source, target);
+        let cidr = self.register_target_ip(target).ok_or(OpenTunnelError::AddressTableFull)?;
+        let handle = self.open_fast_tunnel(id, (source, target), None, self.config.tcp_rx_buffer, self.config.tcp_tx_buffer, cidr)?;
+        if let Some(tunnel) = self.active_tunnels.get_mut(&handle) {
+            tunnel.awaiting_syn = Some(time::Instant::now() + self.config.handshake_timeout);
+        }
+        Ok(handle)
+    }
+
+    /// Returns true if at least one tunnel to `target` is open.
+    pub fn has_tunnel(&self, target: &SocketAddr) -> bool {
+        self.active_tunnels.values().any(|tunnel| tunnel.target == *target)
+    }
+
+    /// Number of open tunnels to `target` (including detached ones).
+    pub fn tunnel_count_for(&self, target: &SocketAddr) -> usize {
+        self.active_tunnels.values().filter(|tunnel| tunnel.target == *target).count()
+    }
+
+    /// Tunnels whose egress channel has been full at some point (the relayer read slower than
+    /// the client sent), the longest backpressured first. Meanwhile the data waits in the
+    /// socket and TCP flow control slows the client down, so these are the tunnels held back
+    /// by a slow relayer.
+    pub fn tunnel_backpressure(&self) -> Vec<TunnelBackpressure> {
+        let now = time::Instant::now();
+        let mut tunnels: Vec<_> = self
+            .active_tunnels
+            .iter()
+            .filter(|(_, tunnel)| tunnel.backpressure_events > 0)
+            .map(|(&handle, tunnel)| TunnelBackpressure {
+                handle,
+                target: tunnel.target,
+                events: tunnel.backpressure_events,
+                duration: tunnel.backpressure_time(now),
+            })
+            .collect();
+        tunnels.sort_by_key(|tunnel| std::cmp::Reverse(tunnel.duration));
+        tunnels
+    }
+
+    /// Socket state of the tunnel `handle`, `None` if it isn't an active tunnel.
+    pub fn tunnel_state(&self, handle: SocketHandle) -> Option<tcp::State> {
+        self.active_tunnels.contains_key(&handle).then(|| self.sockets.get::<tcp::Socket>(handle).state())
+    }
+
+    /// Every active tunnel with its socket state, oldest first. Walks all tunnels, so call it
+    /// when debugging, not per packet.
+    pub fn tunnel_states(&self) -> Vec<TunnelSnapshot> {
+        let mut tunnels: Vec<_> = self
+            .active_tunnels
+            .iter()
+            .map(|(&handle, tunnel)| TunnelSnapshot {
+                handle,
+                id: tunnel.id,
+                source: tunnel.flow.0,
+                target: tunnel.target,
+                state: self.sockets.get::<tcp::Socket>(handle).state(),
+            })
+            .collect();
+        tunnels.sort_by_key(|tunnel| tunnel.id);
+        tunnels
+    }
+
+    /// Returns a handle that can ask for [`tunnel_states`](Self::tunnel_states) while `run`
+    /// owns the stack.
+    pub fn inspector(&self) -> StackInspector {
+        StackInspector { tx: self.inspect_tx.clone() }
+    }
+
+    /// Returns a handle that closes the stack gracefully while `run` owns it (see
+    /// [`close`](Self::close)); `run` returns once the tunnels are drained.
+    pub fn close_handle(&self) -> CloseHandle {
+        CloseHandle { tx: self.close_tx.clone() }
+    }
+
+    /// A handle to report path MTUs, e.g. from the ICMP errors a relayer receives upstream.
+    /// Works before and while the stack runs; reports apply to SYNs trapped after them.
+    pub fn path_mtu_reporter(&self) -> PathMtuReporter {
+        PathMtuReporter { tx: self.path_mtu_tx.clone() }
+    }
+
+    /// Returns a handle to the stack's counters that stays valid after `run` consumes the stack.
+    pub fn stats(&self) -> Arc<PrismStats> {
+        self.stats.clone()
+    }
+
+    /// Current memory footprint estimate (see [`MemoryEstimate`]); also published as the
+    /// `memory_estimate_bytes` gauge. Walks every socket, so call it for monitoring, not per packet.
+    pub fn memory_estimate(&self) -> MemoryEstimate {
+        let socket_buffers = self
+            .sockets
+            .iter()
+            .filter_map(|(_, socket)| tcp::Socket::downcast(socket))
+            .map(|tcp| tcp.recv_capacity() + tcp.send_capacity())
+            .sum();
+        let held = self.pending_ingress.values().map(|pending| pending.tail.len()).sum::<usize>()
+            + self.pending_syns.values().map(|pending| pending.syn.len()).sum::<usize>()
+            + self.admission_queue.iter().map(|(_, _, pkt)| pkt.len()).sum::<usize>()
+            + self.relay_backlog.iter().map(Bytes::len).sum::<usize>();
+        let queued_messages: usize = self
+            .active_tunnels
+            .values()
+            .filter_map(|tunnel| tunnel.tx_to_remote.as_ref())
+            .map(|tx| tx.max_capacity() - tx.capacity())
+            .sum();
+        let queued_datagrams: usize =
+            self.udp_tunnels.values().map(|tunnel| tunnel.tx.max_capacity() - tunnel.tx.capacity()).sum();
+        let estimate = MemoryEstimate {
+            socket_buffers,
+            socket_slots: self.socket_slots * std::mem::size_of::<SocketStorage>(),
+            tx_pool: self.device.tx_pool.iter().map(BytesMut::capacity).sum(),
+            pending_packets: self.device.pending_packets.iter().map(BytesMut::len).sum(),
+            held,
+            channel_buffers: queued_messages * self.config.max_egress_chunk + queued_datagrams * self.config.egress_mtu,
+        };
+        PrismStats::set(&self.stats.memory_estimate_bytes, estimate.total());
+        estimate
+    }
+
+    /// Classifies and queues a packet as if it was read from the TUN (Trap / Stack / Blind
+    /// Relay). With [`PrismStack::poll_once`] this drives the stack without `run`.
+    pub fn inject(&mut self, packet: BytesMut) {
+        self.process_ingress_packet(packet);
+    }
+
+    /// One step of the run loop at `now`, for deterministic tests (see [`PrismDevice::loopback`]):
+    /// delivers remote data and handshake results that are already queued, polls smoltcp and
+    /// pumps egress. Timer-driven work (sweeps, SYN batches) only happens in `run`.
+    pub fn poll_once(&mut self, now: Instant) -> bool {
+        while let Ok((key, success)) = self.feedback_rx.try_recv() {
+            self.handle_handshake_feedback(key, success, self.config.tcp_rx_buffer, self.config.tcp_tx_buffer);
+        }
+        while let Ok(id) = self.abort_rx.try_recv() {
+            self.abort_tunnel(id);
+        }
+        while let Ok(reply) = self.inspect_rx.try_recv() {
+            let _ = reply.send(self.tunnel_states());
+        }
+        while let Ok((dst, mtu)) = self.path_mtu_rx.try_recv() {
+            self.record_path_mtu(dst, mtu, time::Instant::now());
+        }
+        while let Some((flow, id, payload)) = self.udp_return_streams.next().now_or_never().flatten() {
+            self.handle_udp_return(flow, id, payload);
+        }
+        while let Some(item) = self.ingress_streams.next().now_or_never().flatten() {
+            self.handle_ingress_batch(item);
+        }
+        self.poll_and_pump(now, false)
+    }
+
+    /// Polls smoltcp (packets, timers, state updates) and pumps egress (Socket -> Tunnel).
+    /// Returns whether the poll changed any socket's readiness.
+    fn poll_and_pump(&mut self, now: Instant, full_scan: bool) -> bool {
+        if !self.admission_queue.is_empty() {
+            self.admit_queued_syns(time::Instant::now());
+        }
+        if !self.relay_backlog.is_empty() {
+            self.flush_relay_backlog();
+        }
+        if !self.request_backlog.is_empty() {
+            self.flush_request_backlog();
+        }
+        let changed = self.iface.poll(now, &mut self.device, &mut self.sockets);
+        self.device.flush_tx();
+        self.admitted_this_poll = 0;
+        if !self.unverified_syns.is_empty() {
+            self.verify_reinjected_syns();
+        }
+        if !self.client_resets.is_empty() {
+            self.mark_client_resets();
+        }
+        PrismStats::bump(&self.stats.poll_iterations);
+        if changed {
+            PrismStats::bump(&self.stats.polls_with_changes);
+        }
+
+        // Only sockets that were handed packets can have new data or a new state. On L2 we
+        // can't attribute frames to flows, so any change means a full scan.
+        let l2_change = changed && !matches!(self.device.medium, smoltcp::phy::Medium::Ip);
+        if full_scan || l2_change || self.config.always_pump_egress {
+            self.pump_egress(true);
+        } else if !self.dirty.is_empty() {
+            self.pump_egress(false);
+        }
+        changed
+    }
+
+    /// Like [`PrismStack::run`], for one-stack-per-core setups pinned by the caller.
+    ///
+    /// All state (sockets, buffer pools, channels' receiving ends) is owned by the stack, so
+    /// nothing is shared across cores; this variant additionally keeps the stack's helper tasks
+    /// on the current thread via `spawn_local`. Must be awaited inside a `tokio::task::LocalSet`.
+    pub async fn run_on_current_thread(mut self) -> anyhow::Result<()> {
+        self.local_tasks = true;
+        self.run().await
+    }
+
+    /// Shuts the stack down gracefully, for rolling restarts, and returns its final counters.
+    ///
+    /// New connections are refused with a RST from now on (`syns_refused_closing`) and SYNs
+    /// waiting for admission are dropped. Pending Consistent handshakes are refused the same
+    /// way as the relayer answers them, and those still unanswered on return too. Every
+    /// tunnel gets a FIN once the remote data already handed to the stack is written to its
+    /// socket. Data keeps flowing both ways until the client closes its side as well.
+    /// Whatever is still open after `timeout` is reset. Stops early if the TUN link goes away
+    /// (`rx_queue` or `tx_queue` closes).
+    ///
+    /// This is for a stack that isn't running; a running one is closed with its
+    /// [`close_handle`](Self::close_handle).
+    pub async fn close(mut self, timeout: Duration) -> StatsSnapshot {
+        self.drain(timeout).await
+    }
+
+    /// The shutdown behind [`close`](Self::close) and [`CloseHandle::close`].
+    async fn drain(&mut self, timeout: Duration) -> StatsSnapshot {
+        let deadline = time::Instant::now() + timeout;
+        self.closing = true;
+        self.admission_queue.clear();
+        debug!("Closing stack, {} tunnels to drain", self.active_tunnels.len());
+
+        while !self.active_tunnels.is_empty() && !self.device.tx_closed {
+            while let Some(item) = self.ingress_streams.next().now_or_never().flatten() {
+                self.handle_ingress_batch(item);
+            }
+            self.close_drained_tunnels();
+            let mut changed = self.poll_and_pump(Instant::now(), true);
+            let mut repolls = 0;
+            while changed && repolls < MAX_REPOLLS {
+                changed = self.poll_and_pump(Instant::now(), false);
+                repolls += 1;
+            }
+            if self.active_tunnels.is_empty() {
+                break;
+            }
+
+            let poll_delay = self.iface.poll_delay(Instant::now(), &self.sockets).map(Duration::from);
+            tokio::select! {
+                res = self.device.rx_queue.recv() => match res {
+                    Some(pkt) => {
+                        self.process_ingress_packet(pkt);
+                        while let Ok(pkt) = self.device.rx_queue.try_recv() {
+                            self.process_ingress_packet(pkt);
+                        }
+                    }
+                    None => break,
+                },
+                Some(item) = self.ingress_streams.next() => {
+                    self.handle_ingress_batch(item);
+                }
+                Some(id) = self.abort_rx.recv() => {
+                    self.abort_tunnel(id);
+                }
+                Some((key, success)) = self.feedback_rx.recv() => {
+                    self.handle_handshake_feedback(key, success, self.config.tcp_rx_buffer, self.config.tcp_tx_buffer);
+                }
+                Some(reply) = self.inspect_rx.recv() => {
+                    let _ = reply.send(self.tunnel_states());
+                }
+                _ = time::sleep(poll_delay.unwrap_or(timeout)), if poll_delay.is_some() => {}
+                _ = time::sleep_until(deadline) => break,
+            }
+        }
+
+        let pending: Vec<FlowKey> = self.pending_syns.keys().copied().collect();
+        for key in pending {
+            if let Some(pending) = self.take_pending_syn(&key) {
+                self.refuse_pending_syn(key, pending, CloseReason::Shutdown);
+            }
+        }
+
+        if !self.active_tunnels.is_empty() {
+            debug!("Resetting {} tunnels still open after {:?}", self.active_tunnels.len(), timeout);
+            for (&handle, tunnel) in self.active_tunnels.iter_mut() {
+                tunnel.first_fin.get_or_insert(CloseReason::Shutdown);
+                self.sockets.get_mut::<tcp::Socket>(handle).abort();
+            }
+            self.poll_and_pump(Instant::now(), true);
+        }
+        self.stats.snapshot()
+    }
+
+    /// Sends a FIN on every tunnel whose socket may still send, once no remote data is
+    /// parked for it ([`close`](Self::close)).
+    fn close_drained_tunnels(&mut self) {
+        for (&handle, tunnel) in self.active_tunnels.iter_mut() {
+            if tunnel.awaiting_syn.take().is_some() {
+                // Its client never connected, there's nothing to drain
+                tunnel.first_fin.get_or_insert(CloseReason::Shutdown);
+                self.dirty.insert(handle);
+                continue;
+            }
+            let socket = self.sockets.get_mut::<tcp::Socket>(handle);
+            let open = matches!(socket.state(), tcp::State::Listen) || may_become_writable(socket.state());
+            if open && !self.pending_ingress.contains_key(&handle) {
+                tunnel.first_fin.get_or_insert(CloseReason::Shutdown);
+                socket.close();
+                self.dirty.insert(handle);
+            }
+        }
+    }
+
+    /// Runs the virtual stack poll loop (Event-Driven).
+    ///
+    /// Returns `Ok` once `rx_queue` closes or a [`CloseHandle`] closed the stack, or an error
+    /// when the TUN link failed
+    /// (`PrismDevice::link_error`) or `tx_queue` closed. The latter is noticed right away,
+    /// even while the stack has nothing to send.
+    pub async fn run(mut self) -> anyhow::Result<()> {
+        debug!("Prism Stack started (Event-Driven Mode).");
+
+        // Sockets that die on a timer (retransmit / keep-alive timeout) never show up in
+        // `dirty`, so sweep every tunnel once in a while.
+        let mut reap_timer = time::interval(TUNNEL_REAP_INTERVAL);
+        reap_timer.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+        loop {
+            let now = Instant::now();
+            
+            // 1. Calculate Poll Delay
+            // smoltcp tells us when it needs to be called next (e.g. retransmit timer)
+            let poll_delay = self.iface.poll_delay(now, &self.sockets).map(Duration::from);
+            let mut sweep = false;
+            let next_batch_flush = self.syn_batches.values().map(|(deadline, _)| *deadline).min();
+            let next_unthrottle = self.throttled.values().min().copied();
+            let next_peek = self.peeking.values().min().copied();
+            
+            // 2. Select on Events
+            tokio::select! {
+                // Event A: Network Packet from TUN
+                // We pull directly from device.rx_queue because device.receive() is now passive/dumb
+                // BATCHING: Try to consume up to `rx_batch` packets per wake-up to reduce context switching
+                res = self.device.rx_queue.recv() => {
+                    if let Some(pkt) = res {
+                        #[cfg(feature = "trace-latency")]
+                        if let Some(tracer) = self.device.latency.as_mut() {
+                            tracer.start_batch(tokio::time::Instant::now());
+                        }
+                        let mut count = 0;
+                        let mut current_pkt = Some(pkt);
+                        
+                        while let Some(pkt) = current_pkt {
+                            self.process_ingress_packet(pkt);
+                            
+                            count += 1;
+                            if count >= self.config.rx_batch.max(1) { break; }
+                            
+                            // Try get next without waiting
+                            match self.device.rx_queue.try_recv() {
+                                Ok(p) => current_pkt = Some(p),
+                                Err(_) => current_pkt = None,
+                            }
+                        }
+                    } else {
+                        debug!("Network Interface Closed.");
+                        break;
+                    }
+                },
+
+                // Event B: Data from Active Tunnels (Fan-in)
+                Some(item) = self.ingress_streams.next() => {
+                    self.handle_ingress_batch(item);
+                },
+
+                // Event C: Feedback from Consistent Handshake
+                Some((key, success)) = self.feedback_rx.recv() => {
+                     self.handle_handshake_feedback(key, success, self.config.tcp_rx_buffer, self.config.tcp_tx_buffer);
+                },
+
+                // Event D: Timer Expiry
+                // If poll_delay is None, we wait forever (for IO)
+                // If poll_delay is Some, we sleep until then
+                _ = async {
+                    if let Some(d) = poll_delay {
+                        time::sleep(d).await;
+                    } else {
+                        // If no delay, wait forever (future never completes, but select! waits for others)
+                        std::future::pending::<()>().await;
+                    }
+                } => {}
+
+                // Event E: Periodic sweep over all tunnels
+                _ = reap_timer.tick() => {
+                    sweep = true;
+                }
+
+                // Event F: A SYN coalescing window closed
+                _ = time::sleep_until(next_batch_flush.unwrap_or_else(time::Instant::now)), if next_batch_flush.is_some() => {
+                    self.flush_syn_batches(time::Instant::now());
+                }
+
+                // Event G: SYNs are waiting for admission, poll again soon to admit the next share
+                _ = tokio::task::yield_now(), if !self.admission_queue.is_empty() => {}
+
+                // Event H: The Blind Relay channel has room for the DropOldest ring
+                // (only clones the sender while the ring holds something)
+                Some(permit) = reserve_slot(self.blind_relay_tx.clone().filter(|_| !self.relay_backlog.is_empty())), if !self.relay_backlog.is_empty() => {
+                    if let Some(pkt) = self.relay_backlog.pop_front() {
+                        permit.send(pkt);
+                    }
+                    self.flush_relay_backlog();
+                }
+
+                // Event I: A throttled tunnel has earned enough budget to be read again
+                _ = time::sleep_until(next_unthrottle.unwrap_or_else(time::Instant::now)), if next_unthrottle.is_some() => {
+                    self.unthrottle(time::Instant::now());
+                }
+
+                // Event J: A relayer aborted its tunnel
+                Some(id) = self.abort_rx.recv() => {
+                    self.abort_tunnel(id);
+                }
+
+                // Event K: Someone asked for a snapshot of the tunnels
+                Some(reply) = self.inspect_rx.recv() => {
+                    let _ = reply.send(self.tunnel_states());
+                }
+
+                // Event L: A relayer sent a datagram back on a UDP tunnel (or closed it)
+                Some((flow, id, payload)) = self.udp_return_streams.next() => {
+                    self.handle_udp_return(flow, id, payload);
+                }
+
+                // Event M: The TUN writer is gone. Stop now rather than on the next packet
+                // emitted, which could be a SYN-ACK for a connection that can't go anywhere
+                _ = self.device.tx_queue.closed(), if !self.device.tx_closed => {
+                    self.device.tx_closed = true;
+                }
+
+                // Event N: Someone learned a smaller path MTU
+                Some((dst, mtu)) = self.path_mtu_rx.recv() => {
+                    self.record_path_mtu(dst, mtu, time::Instant::now());
+                }
+
+                // Event O: The request channel has room for a staged tunnel request
+                Some(permit) = reserve_slot(self.tunnel_req_tx.clone().filter(|_| !self.request_backlog.is_empty())), if !self.request_backlog.is_empty() => {
+                    if let Some(request) = self.request_backlog.pop_front() {
+                        permit.send(request);
+                    }
+                    self.flush_request_backlog();
+                }
+
+                // Event P: Someone closes the stack (rolling restart)
+                Some((timeout, reply)) = self.close_rx.recv() => {
+                    let _ = reply.send(self.drain(timeout).await);
+                    break;
+                }
+
+                // Event Q: A short peek waited long enough for more client data
+                _ = time::sleep_until(next_peek.unwrap_or_else(time::Instant::now)), if next_peek.is_some() => {
+                    self.flush_peeks(time::Instant::now());
+                }
+            }
+
+            if sweep {
+                self.sweep_syn_buckets(time::Instant::now());
+                self.expire_syn_cache(time::Instant::now());
+                self.expire_udp_tunnels(time::Instant::now());
+                self.path_mtus.retain(|_, (_, until)| *until > time::Instant::now());
+                if self.neighbors_announced.elapsed() >= STATIC_NEIGHBOR_REFRESH {
+                    self.announce_static_neighbors(time::Instant::now());
+                }
+                let expired = self.ipv4_reassembly.expire(time::Instant::now());
+                PrismStats::add(&self.stats.ipv4_fragments_dropped, expired);
+                self.compact_sockets();
+                self.memory_estimate();
+            }
+
+            // 3. Poll smoltcp (consumes pending_packets) and pump egress
+            // A change usually means the pump just queued data or acks on some sockets, so poll
+            // again right away instead of waiting for the next event. Stops as soon as a poll
+            // changes nothing, and after MAX_REPOLLS regardless.
+            let mut changed = self.poll_and_pump(Instant::now(), sweep);
+            let mut repolls = 0;
+            while changed && repolls < MAX_REPOLLS {
+                changed = self.poll_and_pump(Instant::now(), false);
+                repolls += 1;
+            }
+
+            // Nothing can leave any more: trapping and buffering on would only fill memory
+            if self.device.tx_closed {
+                break;
+            }
+        }
+
+        // The TUN bridge closed the link because the device failed
+        if let Some(e) = self.device.link_error.lock().unwrap().take() {
+            return Err(anyhow::Error::new(e).context("TUN link down"));
+        }
+        if self.device.tx_closed {
+            anyhow::bail!("TX queue closed: the TUN writer is gone");
+        }
+        Ok(())
+    }
+
+    /// Handles `first` plus whatever else is ready, up to `INGRESS_BATCH_SIZE` messages, so a
+    /// busy tunnel gets no more than its round-robin share of one wake-up.
+    fn handle_ingress_batch(&mut self, first: (SocketHandle, Option<Bytes>)) {
+        let mut item = Some(first);
+        let mut count = 0;
+        while let Some((handle, data)) = item {
+            self.handle_remote_data(handle, data);
+            count += 1;
+            if count >= INGRESS_BATCH_SIZE { break; }
+            item = self.ingress_streams.next().now_or_never().flatten();
+        }
+    }
+
+    /// Delivers remote data to the client socket; `None` means the remote finished sending.
+    fn handle_remote_data(&mut self, handle: SocketHandle, data: Option<Bytes>) {
+        let Some(tunnel) = self.active_tunnels.get_mut(&handle) else {
+            return;
+        };
+        let reordered = match (self.config.ingress_reorder, data) {
+            (None, data) => Ok(data),
+            (Some(_), None) => tunnel.reorder.finish().map(|()| None),
+            (Some(limit), Some(message)) => match tunnel.reorder.accept(message, limit) {
+                Ok(Some(data)) => Ok(Some(data)),
+                Ok(None) => return,
+                Err(err) => Err(err),
+            },
+        };
+        let data = match reordered {
+            Ok(data) => data,
+            Err(err) => return self.abort_unordered(handle, err),
+        };
+        let socket = self.sockets.get_mut::<tcp::Socket>(handle);
+        let Some(data) = data else {
+            // Half-close: FIN goes out after the queued data, client -> remote keeps flowing
+            debug!("Remote closed tunnel {:?}, sending FIN to client", handle);
+            socket.close();
+            if let Some(tunnel) = self.active_tunnels.get_mut(&handle) {
+                tunnel.first_fin.get_or_insert(CloseReason::RemoteFin);
+                tunnel.awaiting_syn = None;
+            }
+            self.dirty.insert(handle);
+            return;
+        };
+        // Held messages the new one was missing follow it, unless it fills the send buffer
+        let mut next = Some(data);
+        while let Some(data) = next {
+            if !self.deliver_remote_data(handle, &data) {
+                return;
+            }
+            next = self.active_tunnels.get_mut(&handle).and_then(|tunnel| tunnel.reorder.pop_next());
+        }
+    }
+
+    /// Resets the tunnel with correlation ID `id` on behalf of its relayer ([`TunnelAbort`]).
+    /// A Consistent handshake still pending is refused with a RST and reported as rejected.
+    fn abort_tunnel(&mut self, id: u64) {
+        let Some(&flow) = self.id_index.get(&id) else { return };
+        let _span = tracing::debug_span!("tunnel", id).entered();
+        // Still waiting for its Consistent handshake: the client hasn't even seen a SYN-ACK
+        if self.pending_syns.get(&flow).is_some_and(|pending| pending.id == id) {
+            debug!("Relayer aborted pending handshake to {}", flow.1);
+            let pending = self.take_pending_syn(&flow).expect("checked above");
+            PrismStats::bump(&self.stats.relayer_aborts);
+            self.refuse_pending_syn(flow, pending, CloseReason::Aborted);
+            return;
+        }
+        let Some(&handle) = self.flow_index.get(&flow) else { return };
+        let Some(tunnel) = self.active_tunnels.get_mut(&handle).filter(|tunnel| tunnel.id == id) else { return };
+        debug!("Relayer aborted tunnel {:?} to {}", handle, tunnel.target);
+        tunnel.first_fin.get_or_insert(CloseReason::Aborted);
+        tunnel.awaiting_syn = None;
+        self.sockets.get_mut::<tcp::Socket>(handle).abort();
+        PrismStats::bump(&self.stats.relayer_aborts);
+        self.dirty.insert(handle);
+    }
+
+    /// Resets a tunnel whose ingress can't be put back in order (`ingress_reorder`).
+    fn abort_unordered(&mut self, handle: SocketHandle, err: ReorderError) {
+        warn!("Tunnel {:?}: {}, resetting it", handle, err);
+        PrismStats::bump(&self.stats.ingress_reorder_aborts);
+        self.sockets.get_mut::<tcp::Socket>(handle).abort();
+        if let Some(tunnel) = self.active_tunnels.get_mut(&handle) {
+            tunnel.first_fin.get_or_insert(CloseReason::Reset);
+        }
+        self.dirty.insert(handle);
+    }
+
+    /// Writes remote data to the client socket, parking what doesn't fit. Returns whether all
+    /// of it was written.
+    fn deliver_remote_data(&mut self, handle: SocketHandle, data: &Bytes) -> bool {
+        let sent = self.write_remote_data(handle, data);
+        if sent < data.len() {
+            // Only a socket that is gone or done sending loses data: nothing after this point
+            // could reach the client in order anyway
+            let state = self.sockets.get::<tcp::Socket>(handle).state();
+            if !may_become_writable(state) {
+                warn!("Tunnel {:?} can no longer send to the client ({}), dropped {} bytes", handle, state, data.len() - sent);
+                PrismStats::add(&self.stats.bytes_from_remote_undeliverable, data.len() - sent);
+                return false;
+            }
+            // Socket buffer full: keep the rest (a cheap slice) and stop reading this tunnel's
+            // channel until it is written, so the relayer sees backpressure instead of loss
+            let rx = self
+                .ingress_streams
+                .iter_mut()
+                .find(|stream| stream.handle == handle && stream.rx.is_some())
+                .and_then(IngressStream::detach);
+            self.pending_ingress.insert(handle, PendingIngress { tail: data.slice(sent..), rx });
+            return false;
+        }
+        true
+    }
+
+    /// Queues remote data on the client socket; returns how many bytes fit.
+    fn write_remote_data(&mut self, handle: SocketHandle, data: &[u8]) -> usize {
+        let socket = self.sockets.get_mut::<tcp::Socket>(handle);
+        // The only copy on this path: straight from the relayer's `Bytes` into the ring buffer
+        let sent = socket.send_slice(data).unwrap_or(0);
+        if let Some(tunnel) = self.active_tunnels.get_mut(&handle) {
+            tunnel.bytes_rx += sent as u64;
+            if sent > 0 {
+                tunnel.last_activity = time::Instant::now();
+            }
+            let counter = if tunnel.target.is_ipv4() { &self.stats.bytes_from_remote_v4 } else { &self.stats.bytes_from_remote_v6 };
+            PrismStats::add(counter, sent);
+        }
+        sent
+    }
+
+    /// Writes as much of a parked tail as fits and resumes the ingress channel once it is gone.
+    fn drain_pending_ingress(&mut self, handle: SocketHandle) {
+        let Some(mut pending) = self.pending_ingress.remove(&handle) else { return };
+        let sent = self.write_remote_data(handle, &pending.tail);
+        if sent < pending.tail.len() {
+            pending.tail = pending.tail.slice(sent..);
+            self.pending_ingress.insert(handle, pending);
+            return;
+        }
+        // Held messages already in line go before anything new from the channel
+        while let Some(data) = self.active_tunnels.get_mut(&handle).and_then(|tunnel| tunnel.reorder.pop_next()) {
+            let sent = self.write_remote_data(handle, &data);
+            if sent < data.len() {
+                self.pending_ingress.insert(handle, PendingIngress { tail: data.slice(sent..), rx: pending.rx });
+                return;
+            }
+        }
+        if let Some(rx) = pending.rx {
+            self.ingress_streams.push(IngressStream::new(handle, rx));
+        }
+    }
+
+    /// Moves client data from tunnel sockets into their channels and reaps closed sockets.
+    /// Visits every tunnel if `all` is set, otherwise only the `dirty` ones.
+    fn pump_egress(&mut self, all: bool) {
+        let handles: Vec<SocketHandle> = if all {
+            self.dirty.clear();
+            self.active_tunnels.keys().copied().collect()
+        } else {
+            self.dirty.drain().collect()
+        };
+        let mut sockets_to_remove = Vec::new();
+        
+        for handle in handles {
+            // Client ACKs free send buffer space, so parked remote data goes first
+            self.drain_pending_ingress(handle);
+            let Some(tunnel) = self.active_tunnels.get_mut(&handle) else { continue };
+            PrismStats::bump(&self.stats.egress_sockets_visited);
+            // Not listening yet (`open_tunnel`): nothing to do before its deadline
+            if let Some(deadline) = tunnel.awaiting_syn {
+                if time::Instant::now() >= deadline {
+                    debug!("Tunnel {:?}: no SYN from {} in time, dropping it", handle, tunnel.flow.0);
+                    tunnel.awaiting_syn = None;
+                    tunnel.rejected = true;
+                    let (id, target) = (tunnel.id, tunnel.target);
+                    PrismStats::bump(&self.stats.setup_half_open_timeout);
+                    self.emit(TunnelEvent::Rejected { id, target, reason: CloseReason::IdleTimeout });
+                    sockets_to_remove.push(handle);
+                }
+                continue;
+            }
+            let state = self.sockets.get::<tcp::Socket>(handle).state();
+            if !tunnel.established && !matches!(state, tcp::State::Listen | tcp::State::SynReceived | tcp::State::Closed) {
+                tunnel.established = true;
+                let (id, target) = (tunnel.id, tunnel.target);
+                self.emit(TunnelEvent::Established { id, handle, target });
+            }
+            let Some(tunnel) = self.active_tunnels.get_mut(&handle) else { continue };
+            let socket = self.sockets.get_mut::<tcp::Socket>(handle);
+
+            // Closed: reset, aborted or timed out, nothing more to read. TimeWait: the client's
+            // data and FIN may have arrived together, so it is only removed here once there's
+            // nowhere to hand that data to; otherwise after the read loop below
+            let detached = tunnel.tx_to_remote.is_none();
+            if socket.state() == tcp::State::Closed || (socket.state() == tcp::State::TimeWait && detached) {
+                sockets_to_remove.push(handle);
+                continue;
+            }
+
+            // Detached tunnels keep their data in the socket until re-attached. The sender is
+            // taken out for the loop below, so the permits don't borrow `tunnel`
+            let Some(tx_to_remote) = tunnel.tx_to_remote.take() else { continue };
+            if tunnel.backpressured_since.is_some() && tx_to_remote.capacity() > 0 {
+                tunnel.backpressure_ended(time::Instant::now());
+            }
+
+            // Ingress (Socket -> Tunnel) (Data FROM Client TO Remote)
+            let max_chunk = self.config.max_egress_chunk.max(1);
+            while socket.can_recv() {
+                // Over the rate limit: leave the data in the socket and come back once the
+                // bucket holds what is waiting (or is full)
+                let mut budget = max_chunk;
+                if let Some(bucket) = tunnel.rate_bucket.as_mut() {
+                    let now = time::Instant::now();
+                    budget = budget.min(bucket.available(now));
+                    if budget == 0 {
+                        let wake = now + bucket.wait_for(socket.recv_queue().min(max_chunk));
+                        if self.throttled.insert(handle, wake).is_none() {
+                            PrismStats::bump(&self.stats.egress_rate_limited);
+                        }
+                        break;
+                    }
+                }
+                // Reserve before reading: on a full channel the data stays in the socket (TCP
+                // flow control slows the client down) instead of being read and lost
+                let permit = match tx_to_remote.try_reserve() {
+                    Ok(permit) => permit,
+                    Err(e) => {
+                        if matches!(e, mpsc::error::TrySendError::Full(())) && tunnel.backpressure_started(time::Instant::now()) {
+                            PrismStats::bump(&self.stats.egress_backpressure_events);
+                        }
+                        self.dirty.insert(handle);
+                        break;
+                    }
+                };
+                let data = match socket.recv(|buf| {
+                    let n = buf.len().min(budget);
+                    (n, Bytes::copy_from_slice(&buf[..n]))
+                }) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        // The state check after the loop decides whether it goes
+                        debug!("Tunnel {:?} stopped reading in {}: {:?}", handle, socket.state(), e);
+                        break;
+                    }
+                };
+                if data.is_empty() { break; }
+                let len = data.len();
+                if let Some(prefix) = tunnel.take_payload_prefix(&data, self.config.log_payload_prefix) {
+                    debug!("Tunnel {:?} -> {} opening bytes: {}", handle, tunnel.target, prefix);
+                }
+                let peek_bytes = self.config.peek_bytes;
+                if self.peek_tx.is_some() && peek_bytes > 0 && !tunnel.peek_done {
+                    if tunnel.peek.is_none() && len >= peek_bytes {
+                        // All in the first chunk: a view of it, not a copy
+                        tunnel.peek_done = true;
+                        let peek = FlowPeek { id: tunnel.id, handle, target: tunnel.target, data: data.slice(..peek_bytes) };
+                        send_peek(self.peek_tx.as_ref(), &self.stats, peek);
+                    } else {
+                        let peek = tunnel.peek.get_or_insert_with(|| BytesMut::with_capacity(peek_bytes));
+                        peek.extend_from_slice(&data[..len.min(peek_bytes - peek.len())]);
+                        if peek.len() < peek_bytes {
+                            self.peeking.entry(handle).or_insert_with(|| time::Instant::now() + PEEK_WINDOW);
+                        } else if let Some(peek) = tunnel.take_peek(handle) {
+                            self.peeking.remove(&handle);
+                            send_peek(self.peek_tx.as_ref(), &self.stats, peek);
+                        }
+                    }
+                }
+                permit.send(data);
+                let counter = if tunnel.target.is_ipv4() { &self.stats.bytes_to_remote_v4 } else { &self.stats.bytes_to_remote_v6 };
+                PrismStats::add(counter, len);
+                tunnel.bytes_tx += len as u64;
+                tunnel.last_activity = time::Instant::now();
+                if let Some(bucket) = tunnel.rate_bucket.as_mut() {
+                    bucket.take(len);
+                }
+            }
+
+            // Client FIN: once everything the client sent has been handed over, drop the egress
+            // sender so the relayer can half-close upstream. The socket itself stays (remote ->
+            // client keeps flowing) until it reaches Closed/TimeWait and is removed above.
+            let client_done = matches!(socket.state(), tcp::State::CloseWait | tcp::State::LastAck | tcp::State::Closing);
+            let finished = client_done || matches!(socket.state(), tcp::State::Closed | tcp::State::TimeWait);
+            if finished && !socket.can_recv() && tunnel.peek.is_some() {
+                // No more client data coming: the peek is as long as it gets
+                self.peeking.remove(&handle);
+                if let Some(peek) = tunnel.take_peek(handle) {
+                    send_peek(self.peek_tx.as_ref(), &self.stats, peek);
+                }
+            }
+            if client_done && !socket.can_recv() {
+                debug!("Client finished sending on tunnel {:?}, closing egress channel", handle);
+                // Closing means both FINs crossed; the remote's would have been seen first
+                tunnel.first_fin.get_or_insert(CloseReason::ClientFin);
+                tunnel.client_fin = true;
+            } else if matches!(socket.state(), tcp::State::Closed | tcp::State::TimeWait) && !socket.can_recv() {
+                // Done with everything the client sent: remove it now, not on the next visit
+                sockets_to_remove.push(handle);
+            } else {
+                tunnel.tx_to_remote = Some(tx_to_remote);
+            }
+        }
+        
+        for handle in sockets_to_remove {
+            // Drop the tx sender — this causes the remote rx to close,
+            // which in turn ends the IngressStream in ingress_streams (SelectAll auto-removes ended streams).
+            // The ingress side is cut here too, so a reused handle never gets stale remote data.
+            let tunnel = self.active_tunnels.remove(&handle);
+            self.pending_ingress.remove(&handle);
+            self.throttled.remove(&handle);
+            self.peeking.remove(&handle);
+            for stream in self.ingress_streams.iter_mut().filter(|stream| stream.handle == handle) {
+                stream.detach();
+            }
+            if let Some(tunnel) = tunnel {
+                let _span = tracing::debug_span!("tunnel", id = tunnel.id).entered();
+                debug!("Tunnel {:?} to {} closed ({:?})", handle, tunnel.target, tunnel.close_reason());
+                self.flow_index.remove(&tunnel.flow);
+                self.id_index.remove(&tunnel.id);
+                #[cfg(feature = "trace-latency")]
+                if let Some(tracer) = self.device.latency.as_mut() {
+                    tracer.forget(&tunnel.flow);
+                }
+                if !tunnel.rejected {
+                    if !tunnel.established && !tunnel.reset {
+                        PrismStats::bump(&self.stats.setup_half_open_timeout);
+                    }
+                    self.emit(TunnelEvent::Closed {
+                        id: tunnel.id,
+                        handle,
+                        target: tunnel.target,
+                        bytes_tx: tunnel.bytes_tx,
+                        bytes_rx: tunnel.bytes_rx,
+                        reason: tunnel.close_reason(),
+                    });
+                }
+            }
+            
+            // Clean up dynamically-registered IP address to prevent ip_addrs table leak
+            if let Some(cidr) = self.active_ips.remove(&handle) {
+                self.release_target_ip(cidr);
+            }
+            
+            self.remove_socket(handle);
+        }
+    }
+
+    /// Classifies a single packet from the TUN and routes it (Trap / Stack / Blind Relay).
+    fn process_ingress_packet(&mut self, mut pkt: BytesMut) {
+        #[cfg(target_os = "linux")]
+        if self.device.vnet_hdr && !crate::offload::finish_virtio_rx(&mut pkt) {
+            debug!("Dropping malformed vnet packet ({} bytes)", pkt.len());
+            return;
+        }
+        #[cfg(feature = "trace-latency")]
+        if let Some(tracer) = self.device.latency.as_mut() {
+            tracer.ingress(&pkt);
+        }
+
+        // PROTOCOL CLASSIFICATION
+        // We only intercept TCP. Everything else goes to Blind Relay.
+        let pkt_type = if matches!(self.device.medium, smoltcp::phy::Medium::Ip) {
+            // A family the stack doesn't serve has no gateway to answer it, not even with an ICMP
+            if let Some(version @ (4 | 6)) = pkt.first().map(|b| b >> 4) {
+                if !self.config.ip_mode.serves(version == 6) {
+                    debug!("Dropping IPv{} packet, not served in {:?}", version, self.config.ip_mode);
+                    PrismStats::bump(&self.stats.other_family_dropped);
+                    return;
+                }
+            }
+            // Broadcast/Multicast never gets trapped, the policy decides where it goes.
+            if let Some(dst) = crate::trap::destination_ip(&pkt) {
+                if self.is_broadcast_or_multicast(dst) {
+                    match self.config.cast_policy {
+                        CastPolicy::Drop => debug!("Dropping broadcast/multicast packet to {}", dst),
+                        CastPolicy::Relay => self.relay_packet(pkt),
+                        CastPolicy::Pass => self.queue_for_stack(pkt),
+                    }
+                    return;
+                }
+            }
+            // A fragmented TCP segment is classified once whole, as if it arrived in one piece
+            if crate::trap::is_ipv4_tcp_fragment(&pkt) {
+                match self.ipv4_reassembly.push(&pkt, time::Instant::now()) {
+                    crate::reassembly::Reassembly::Complete(whole) => pkt = whole,
+                    crate::reassembly::Reassembly::Pending => return,
+                    crate::reassembly::Reassembly::Dropped => {
+                        debug!("Dropping IPv4 TCP fragment ({} bytes)", pkt.len());
+                        PrismStats::bump(&self.stats.ipv4_fragments_dropped);
+                        return;
+                    }
+                }
+            }
+            if let Some(classifier) = &self.classifier {
+                let verdict = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| classifier(&pkt)))
+                    .unwrap_or_else(|_| {
+                        warn!("Packet classifier panicked, using the default verdict");
+                        PrismStats::bump(&self.stats.classifier_panics);
+                        crate::trap::default_verdict(&pkt)
+                    });
+                match verdict {
+                    crate::trap::Verdict::Trap => {}
+                    crate::trap::Verdict::BlindRelay => {
+                        self.relay_packet(pkt);
+                        return;
+                    }
+                    crate::trap::Verdict::Stack => {
+                        self.queue_for_stack(pkt);
+                        return;
+                    }
+                    crate::trap::Verdict::Drop => {
+                        debug!("Classifier dropped a packet ({} bytes)", pkt.len());
+                        PrismStats::bump(&self.stats.classifier_dropped);
+                        return;
+                    }
+                }
+            }
+            crate::trap::get_packet_type(&pkt)
+        } else {
+            // L2 Frames: For now treat as "Unknown/Other" -> Blind Relay if we wanted L2 bridge
+            // But smoltcp stack expects IP.
+            // Let's just pass to stack if we are unsure, or drop?
+            // For now, pass to stack so it might answer ARP?
+            // Actually, ARP is L2, so get_packet_type might return Unknown.
+            crate::trap::PacketType::Unknown
+        };
+
+        match pkt_type {
+            crate::trap::PacketType::Tcp => {
+                if self.config.drop_invalid_flags && crate::trap::has_invalid_tcp_flags(&pkt) {
+                    debug!("Dropping TCP segment with invalid flags: {:?}", crate::trap::tcp_flow(&pkt));
+                    PrismStats::bump(&self.stats.invalid_tcp_flags_dropped);
+                    return;
+                }
+                if let Some(cidrs) = &self.config.trap_cidrs {
+                    let dst = crate::trap::destination_ip(&pkt).map(IpAddress::from);
+                    let in_scope = dst.is_some_and(|dst| cidrs.iter().any(|cidr| cidr.contains_addr(&dst)));
+                    if !in_scope {
+                        // Only a SYN is worth an ICMP; later segments of the flow are dropped
+                        let is_syn = crate::trap::tcp_flags(&pkt).is_some_and(|flags| flags & 0x12 == 0x02);
+                        match self.config.default_action {
+                            DefaultAction::Trap => {}
+                            DefaultAction::Reject if is_syn => {
+                                debug!("Rejecting TCP to {:?}, outside trap_cidrs", dst);
+                                PrismStats::bump(&self.stats.out_of_scope_tcp_rejected);
+                                if let Some(reply) = crate::relay::icmp_net_unreachable(&pkt, gateway_for(&pkt)) {
+                                    self.device.transmit_packet(&reply);
+                                }
+                                return;
+                            }
+                            DefaultAction::Reject | DefaultAction::Drop => {
+                                debug!("Dropping TCP to {:?}, outside trap_cidrs", dst);
+                                PrismStats::bump(&self.stats.out_of_scope_tcp_dropped);
+                                return;
+                            }
+                        }
+                    }
+                }
+                if let Some(ports) = &self.config.trap_ports {
+                    if crate::trap::tcp_flow(&pkt).is_some_and(|(_, dst)| !ports.contains(dst.port())) {
+                        self.relay_packet(pkt);
+                        return;
+                    }
+                }
+                // TCP: Check for SYN Trap
+                if let Some(event) = crate::trap::inspect_packet_with_clamp(&pkt, || self.mss_clamp_for(&pkt)) {
+                    // smoltcp gets the clamped SYN, not the original
+                    let pkt = BytesMut::from(event.packet.as_ref());
+                    // Two copies: the clamped SYN, and smoltcp's own
+                    PrismStats::add(&self.stats.trap_copies, 2);
+                    PrismStats::add(&self.stats.trap_bytes_copied, event.packet.len() + pkt.len());
+                    self.handle_trap(event, pkt, self.config.tcp_rx_buffer, self.config.tcp_tx_buffer);
+                } else {
+                    // TCP Data/ACK -> Stack
+                    let flow = crate::trap::tcp_flow(&pkt);
+                    if let (Some(flags), Some((src, dst))) = (crate::trap::tcp_flags(&pkt), flow) {
+                        let tracked = self.flow_index.contains_key(&(src, dst)) || self.is_gateway_address(dst.ip());
+                        if !crate::trap::is_trackable(flags, tracked) {
+                            debug!("Dropping stray TCP reply {} -> {} (flags {:#04x})", src, dst, flags);
+                            PrismStats::bump(&self.stats.stray_tcp_dropped);
+                            return;
+                        }
+                    }
+                    if let Some(&handle) = flow.and_then(|flow| self.flow_index.get(&flow)) {
+                        self.dirty.insert(handle);
+                        if crate::trap::tcp_flags(&pkt).is_some_and(|flags| flags & 0x04 != 0) {
+                            self.client_resets.push(handle);
+                        }
+                    }
+                    self.queue_for_stack(pkt);
+                }
+            }
+            // The gateway is us: smoltcp answers pings to it, and whatever listens there gets
+            // the rest (or it's refused with an ICMP), relay or not
+            crate::trap::PacketType::Other if self.is_to_gateway(&pkt) => self.queue_for_stack(pkt),
+            crate::trap::PacketType::Other => {
+                if let Some(pkt) = self.trap_udp(pkt) {
+                    self.relay_packet(pkt);
+                }
+            }
+            crate::trap::PacketType::Unknown => {
+                 // Debug log to catch IPv6 parsing failures
+                 if !pkt.is_empty() {
+                     let ver = pkt[0] >> 4;
+                     if ver == 6 {
+                         tracing::warn!("IPv6 Packet failed classification! Len: {}", pkt.len());
+                         PrismStats::bump(&self.stats.ipv6_classification_failures);
+                     }
+                 }
+                 self.queue_for_stack(pkt);
+            }
+        }
+    }
+
+    /// Refuses a SYN trapped before `set_tunnel_request_sender` was called (`missing_relayer`).
+    fn refuse_without_relayer(&mut self, event: &crate::trap::TrapEvent, pkt: &[u8]) {
+        if !self.warned_missing_relayer {
+            warn!("No tunnel request sender set, refusing trapped connections (first: {} -> {})", event.src, event.dst);
+            self.warned_missing_relayer = true;
+        }
+        PrismStats::bump(&self.stats.setup_request_rejected);
+        self.emit(TunnelEvent::Rejected { id: event.id, target: event.dst, reason: CloseReason::Reset });
+        if self.config.missing_relayer == MissingRelayerPolicy::Reset {
+            if let Some(rst) = crate::trap::build_rst_reply(pkt) {
+                self.device.transmit_packet(&rst);
+            }
+        }
+    }
+
+    /// Steers a datagram to `udp_trap_ports` into its flow's UDP tunnel, requesting the tunnel
+    /// first if the flow is new. Gives back what isn't trapped.
+    fn trap_udp(&mut self, pkt: BytesMut) -> Option<BytesMut> {
+        let (Some(ports), Some(req_tx)) = (&self.config.udp_trap_ports, &self.udp_tunnel_req_tx) else {
+            return Some(pkt);
+        };
+        let Some((source, target, payload)) = crate::trap::udp_datagram(&pkt) else { return Some(pkt) };
+        if !ports.contains(target.port()) {
+            return Some(pkt);
+        }
+        let flow = (source, target);
+        let now = time::Instant::now();
+        if !self.udp_tunnels.contains_key(&flow) {
+            if self.udp_tunnels.len() >= self.config.max_udp_tunnels {
+                debug!("Too many UDP tunnels, relaying datagram {} -> {}", source, target);
+                PrismStats::bump(&self.stats.udp_tunnels_over_limit);
+                return Some(pkt);
+            }
+            let (tx_to_remote, rx_from_client) = self.tunnel_channel();
+            let (tx_to_client, rx_from_remote) = self.tunnel_channel();
+            let id = self.next_tunnel_id;
+            let request = UdpTunnelRequest { id, source, target, rx: rx_from_client, tx: tx_to_client };
+            if req_tx.try_send(request).is_err() {
+                debug!("UDP tunnel request channel full or closed, dropping datagram {} -> {}", source, target);
+                PrismStats::bump(&self.stats.udp_tunnel_datagrams_dropped);
+                return None;
+            }
+            self.next_tunnel_id += 1;
+            debug!(id, "UDP tunnel {} -> {}", source, target);
+            PrismStats::bump(&self.stats.udp_tunnels_opened);
+            let datagrams = futures::stream::unfold(rx_from_remote, |mut rx| async move {
+                rx.recv().await.map(|payload| (payload, rx))
+            });
+            let stream = datagrams
+                .map(move |payload| (flow, id, Some(payload)))
+                .chain(futures::stream::once(async move { (flow, id, None) }));
+            self.udp_return_streams.push(stream.boxed());
+            self.udp_tunnels.insert(flow, UdpTunnel { id, tx: tx_to_remote, last_activity: now });
+        }
+        let tunnel = self.udp_tunnels.get_mut(&flow)?;
+        tunnel.last_activity = now;
+        match tunnel.tx.try_send(pkt.freeze().slice(payload)) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => PrismStats::bump(&self.stats.udp_tunnel_datagrams_dropped),
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                // The relayer is done with the flow; its next datagram asks for a new tunnel
+                PrismStats::bump(&self.stats.udp_tunnel_datagrams_dropped);
+                self.udp_tunnels.remove(&flow);
+            }
+        }
+        None
+    }
+
+    /// Sends a datagram from the relayer back to the client of UDP tunnel `id`, or forgets the
+    /// tunnel once the relayer dropped its sender (`payload` is `None`). Datagrams for tunnels
+    /// that expired or were replaced by a newer one on the same flow are dropped.
+    fn handle_udp_return(&mut self, flow: FlowKey, id: u64, payload: Option<Bytes>) {
+        let Some(tunnel) = self.udp_tunnels.get_mut(&flow).filter(|tunnel| tunnel.id == id) else {
+            if payload.is_some() {
+                debug!(id, "Dropping UDP datagram for closed tunnel {} -> {}", flow.0, flow.1);
+                PrismStats::bump(&self.stats.udp_tunnel_datagrams_dropped);
+            }
+            return;
+        };
+        let Some(payload) = payload else {
+            debug!(id, "UDP tunnel {} -> {} closed by the relayer", flow.0, flow.1);
+            self.udp_tunnels.remove(&flow);
+            return;
+        };
+        tunnel.last_activity = time::Instant::now();
+        match crate::relay::build_udp(flow.1, flow.0, &payload) {
+            Some(datagram) if datagram.len() <= self.device.mtu => self.device.transmit_packet(&datagram),
+            _ => {
+                debug!(id, "Dropping oversize UDP datagram of {} bytes for {}", payload.len(), flow.0);
+                PrismStats::bump(&self.stats.udp_tunnel_datagrams_dropped);
+            }
+        }
+    }
+
+    /// Closes UDP tunnels idle for `UDP_TUNNEL_IDLE_TIMEOUT`. Dropping the sender ends the
+    /// relayer's `rx`; its return stream is ignored from then on.
+    fn expire_udp_tunnels(&mut self, now: time::Instant) {
+        self.udp_tunnels.retain(|flow, tunnel| {
+            let active = now.duration_since(tunnel.last_activity) < UDP_TUNNEL_IDLE_TIMEOUT;
+            if !active {
+                debug!(id = tunnel.id, "UDP tunnel {} -> {} idle, closing", flow.0, flow.1);
+            }
+            active
+        });
+    }
+
+    /// Sends a non-TCP packet to the Blind Relay (or lets smoltcp reject it if no relay is set).
+    fn relay_packet(&mut self, mut pkt: BytesMut) {
+        // One message per datagram: cut link-layer padding so the message ends where the IP packet does
+        if let Some(len) = crate::trap::ip_packet_len(&pkt) {
+            pkt.truncate(len);
+        }
+        // [Added] Check packet size to prevent huge UDP packets from blocking physical NIC
+        if pkt.len() > self.config.egress_mtu {
+            debug!("Dropping oversize packet: {} > {}", pkt.len(), self.config.egress_mtu);
+            PrismStats::bump(&self.stats.blind_relay_oversize_dropped);
+            // Drop directly, do not put into blind_relay_tx
+            if self.config.oversize_policy == OversizePolicy::Reject && self.take_icmp_token() {
+                let mtu = self.config.egress_mtu.min(u16::MAX as usize) as u16;
+                if let Some(reply) = crate::relay::icmp_packet_too_big(&pkt, gateway_for(&pkt), mtu) {
+                    self.device.transmit_packet(&reply);
+                }
+            }
+        } else {
+            // UDP/ICMP/Gre etc. -> Blind Relay
+            if self.blind_relay_tx.is_some() {
+                if let Some(tx) = &self.dns_query_tx {
+                    if let Some(query) = crate::relay::dns_query(&pkt) {
+                        debug!("DNS query {} ({}) from {}", query.name, query.qtype, query.client);
+                        let _ = tx.try_send(query);
+                    }
+                }
+                // Never wait here, whatever the policy: that would block the main loop
+                self.send_to_blind_relay(pkt.freeze());
+            } else if self.config.reject_unsupported
+                && !crate::trap::destination_ip(&pkt).is_some_and(|ip| self.is_gateway_address(ip))
+            {
+                if !self.take_icmp_token() {
+                    debug!("Not answering {} byte packet, ICMP error budget spent", pkt.len());
+                } else if let Some(reply) = crate::relay::icmp_port_unreachable(&pkt) {
+                    self.device.transmit_packet(&reply);
+                }
+            } else {
+                // If no relay configured, drop or let stack reject it (ICMP Unreachable)
+                // Letting stack see it might generate "Port Unreachable", which is good.
+                self.queue_for_stack(pkt);
+            }
+        }
+    }
+
+    /// Hands smoltcp an announcement of each `static_neighbors` entry (Ethernet only), which
+    /// fills its neighbor cache as if the neighbor had answered. Invalid entries were reported
+    /// by `check`.
+    fn announce_static_neighbors(&mut self, now: time::Instant) {
+        self.neighbors_announced = now;
+        if !matches!(self.device.medium, smoltcp::phy::Medium::Ethernet) {
+            return;
+        }
+        let HardwareAddress::Ethernet(ours) = self.iface.hardware_addr() else { return };
+        for &(ip, mac) in self.config.static_neighbors.iter().filter(|&&(ip, mac)| self.config.is_valid_neighbor(ip, mac)) {
+            let Some(gateway) = self.config.ip_mode.gateways().find(|gateway| gateway.contains_addr(&ip)) else { continue };
+            if let Some(frame) = crate::relay::neighbor_announcement(ip, mac, (gateway.address(), ours)) {
+                self.device.pending_packets.push_back(BytesMut::from(&frame[..]));
+            }
+        }
+    }
+
+    /// Queues a packet for smoltcp's next poll, within `pending_packets_cap`.
+    fn queue_for_stack(&mut self, pkt: BytesMut) {
+        let queue = &mut self.device.pending_packets;
+        if queue.len() >= self.config.pending_packets_cap.max(1) {
+            let oldest = match self.config.pending_overflow {
+                PendingOverflowPolicy::DropNewest => None,
+                // SYNs are left alone: the queued ones may be re-injected for a live socket
+                PendingOverflowPolicy::DropOldest => {
+                    queue
+                        .iter()
+                        .take(PENDING_DROP_SCAN)
+                        .position(|queued| crate::trap::tcp_flags(queued).is_none_or(|flags| flags & 0x02 == 0))
+                }
+            };
+            PrismStats::bump(&self.stats.pending_overflow_dropped);
+            match oldest {
+                Some(index) => {
+                    debug!("Pending packet queue full, dropping its oldest packet");
+                    queue.remove(index);
+                }
+                None => {
+                    debug!("Pending packet queue full, dropping a packet ({} bytes)", pkt.len());
+                    return;
+                }
+            }
+        }
+        queue.push_back(pkt);
+    }
+
+    /// Queues a trapped SYN for the freshly listening `handle`. It bypasses
+    /// `pending_packets_cap`: the socket is committed, and would wait in Listen for nothing.
+    fn reinject_syn(&mut self, handle: SocketHandle, target: SocketAddr, pkt: BytesMut) {
+        self.device.pending_packets.push_back(pkt);
+        if self.config.verify_reinjected_syns {
+            self.unverified_syns.push((handle, target));
+        }
+    }
+
+    /// Lets the socket of a tunnel from `open_tunnel` listen, now that the SYN of its client
+    /// is here, and hands it that SYN.
+    fn accept_awaited_syn(&mut self, handle: SocketHandle, flow: FlowKey, pkt: BytesMut) {
+        let Some(tunnel) = self.active_tunnels.get_mut(&handle) else { return };
+        tunnel.awaiting_syn = None;
+        let id = tunnel.id;
+        if let Err(e) = self.sockets.get_mut::<tcp::Socket>(handle).listen(listen_endpoint(flow.1)) {
+            warn!("Failed to listen: {}", e);
+            PrismStats::bump(&self.stats.setup_listen_failed);
+            tunnel.rejected = true;
+            self.dirty.insert(handle);
+            self.emit(TunnelEvent::Rejected { id, target: flow.1, reason: CloseReason::ListenFailed });
+            return;
+        }
+        self.remember_syn(flow, &pkt, time::Instant::now());
+        self.reinject_syn(handle, flow.1, pkt);
+        self.dirty.insert(handle);
+    }
+
+    /// Flags re-injected SYNs that smoltcp didn't accept (`verify_reinjected_syns`). Runs
+    /// right after a poll, which always drains `pending_packets`.
+    fn verify_reinjected_syns(&mut self) {
+        for (handle, target) in std::mem::take(&mut self.unverified_syns) {
+            // Sockets whose request was rejected are already gone
+            if !self.active_ips.contains_key(&handle) {
+                continue;
+            }
+            if self.sockets.get::<tcp::Socket>(handle).state() == tcp::State::Listen {
+                warn!("Re-injected SYN for {} was rejected by smoltcp", target);
+                PrismStats::bump(&self.stats.reinjected_syns_rejected);
+            }
+        }
+    }
+
+    /// Marks the tunnels a client RST closed. Runs right after a poll, which always drains
+    /// `pending_packets`: a RST outside the receive window left its socket open.
+    fn mark_client_resets(&mut self) {
+        for handle in std::mem::take(&mut self.client_resets) {
+            let Some(tunnel) = self.active_tunnels.get_mut(&handle) else { continue };
+            if self.sockets.get::<tcp::Socket>(handle).state() == tcp::State::Closed {
+                tunnel.reset = true;
+            }
+        }
+    }
+
+    fn send_to_blind_relay(&mut self, pkt: Bytes) {
+        let Some(ref relay) = self.blind_relay_tx else { return };
+        match self.config.blind_relay_policy {
+            BlindRelayPolicy::DropOnFull => {
+                if relay.try_send(pkt).is_err() {
+                    PrismStats::bump(&self.stats.blind_relay_dropped_full);
+                }
+            }
+            BlindRelayPolicy::DropOldest => {
+                self.relay_backlog.push_back(pkt);
+                if self.relay_backlog.len() > BLIND_RELAY_BACKLOG {
+                    self.relay_backlog.pop_front();
+                    PrismStats::bump(&self.stats.blind_relay_dropped_oldest);
+                }
+                self.flush_relay_backlog();
+            }
+            BlindRelayPolicy::Block => match relay.try_send(pkt) {
+                Ok(()) => {}
+                Err(mpsc::error::TrySendError::Full(pkt)) => {
+                    let relay = relay.clone();
+                    let stats = self.stats.clone();
+                    self.spawn_helper(async move {
+                        if relay.send(pkt).await.is_err() {
+                            PrismStats::bump(&stats.blind_relay_dropped_blocked);
+                        }
+                    });
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => {
+                    PrismStats::bump(&self.stats.blind_relay_dropped_blocked);
+                }
+            },
+        }
+    }
+
+    /// Sends as much of the `DropOldest` ring as the Blind Relay channel takes right now.
+    fn flush_relay_backlog(&mut self) {
+        let Some(ref relay) = self.blind_relay_tx else { return };
+        while let Some(pkt) = self.relay_backlog.pop_front() {
+            match relay.try_send(pkt) {
+                Ok(()) => {}
+                Err(mpsc::error::TrySendError::Full(pkt)) => {
+                    self.relay_backlog.push_front(pkt);
+                    break;
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => {
+                    PrismStats::add(&self.stats.blind_relay_dropped_oldest, self.relay_backlog.len() + 1);
+                    self.relay_backlog.clear();
+                }
+            }
+        }
+    }
+
+    /// Runs a helper task next to the stack (`spawn_local` under [`PrismStack::run_on_current_thread`]).
+    fn spawn_helper<F>(&self, task: F)
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        if self.local_tasks {
+            tokio::task::spawn_local(task);
+        } else {
+            tokio::spawn(task);
+        }
+    }
+
+    /// Returns true for a packet to one of the gateway's own addresses (not a trapped
+    /// destination IP), which smoltcp handles itself.
+    fn is_to_gateway(&self, pkt: &[u8]) -> bool {
+        crate::trap::destination_ip(pkt).is_some_and(|dst| self.is_gateway_address(dst))
+    }
+
+    /// Takes a token for an ICMP error from `icmp_bucket`. Without one the packet that would
+    /// have caused it is dropped unanswered, so a flood can't be turned back on its source.
+    fn take_icmp_token(&mut self) -> bool {
+        self.icmp_bucket.try_take(ICMP_ERROR_BURST, ICMP_ERROR_WINDOW, time::Instant::now())
+    }
+
+    /// Returns true for the gateway's own addresses, as opposed to trapped destination IPs.
+    fn is_gateway_address(&self, ip: IpAddr) -> bool {
+        let ip = IpAddress::from(ip);
+        self.iface.ip_addrs().iter().any(|cidr| cidr.address() == ip && !self.registered_ips.contains(cidr))
+    }
+
+    /// Returns true if `dst` is a multicast address, the limited broadcast address,
+    /// or the directed broadcast of one of the interface's IPv4 subnets.
+    fn is_broadcast_or_multicast(&self, dst: IpAddr) -> bool {
+        match dst {
+            IpAddr::V4(addr) => {
+                if addr.is_broadcast() || addr.is_multicast() {
+                    return true;
+                }
+                let addr = Ipv4Address::from_bytes(&addr.octets());
+                self.iface.ip_addrs().iter().any(|cidr| match cidr {
+                    IpCidr::Ipv4(v4) => v4.broadcast() == Some(addr),
+                    _ => false,
+                })
+            }
+            IpAddr::V6(addr) => addr.is_multicast(),
+        }
+    }
+
+    // Helper to handle Trap Logic
+    fn handle_trap(&mut self, mut event: crate::trap::TrapEvent, pkt: BytesMut, rx_buf_size: usize, tx_buf_size: usize) {
+        if let Some(ports) = &self.config.gateway_tcp_ports {
+            if self.is_gateway_address(event.dst.ip()) {
+                if ports.contains(event.dst.port()) {
+                    self.queue_for_stack(pkt);
+                } else {
+                    debug!("Refusing SYN {} -> {}, a gateway port", event.src, event.dst);
+                    PrismStats::bump(&self.stats.gateway_syns_refused);
+                    if let Some(rst) = crate::trap::build_rst_reply(&pkt) {
+                        self.device.transmit_packet(&rst);
+                    }
+                }
+                return;
+            }
+        }
+        debug!("Trapped SYN for target: {}", event.dst);
+        if crate::trap::tcp_payload_len(&pkt).is_some_and(|len| len > 0) {
+            PrismStats::bump(&self.stats.syns_with_data);
+        }
+
+        if self.is_syn_retransmit((event.src, event.dst), time::Instant::now()) {
+            if self.config.duplicate_syns == DuplicateSynPolicy::Restart
+                && self.restart_handshake((event.src, event.dst), &pkt, time::Instant::now())
+            {
+                debug!("New SYN for {} -> {}, restarting its handshake", event.src, event.dst);
+                PrismStats::bump(&self.stats.handshakes_restarted);
+                return;
+            }
+            debug!("Ignoring SYN retransmit for {} -> {}", event.src, event.dst);
+            PrismStats::bump(&self.stats.duplicate_syns_suppressed);
+            // Fast mode already has a socket that answers it again; a Consistent SYN is still held
+            if let Some(&handle) = self.flow_index.get(&(event.src, event.dst)) {
+                self.queue_for_stack(pkt);
+                self.dirty.insert(handle);
+            }
+            return;
+        }
+
+        // The client of a tunnel opened with `open_tunnel` connecting at last
+        if let Some(&handle) = self.flow_index.get(&(event.src, event.dst)) {
+            if self.active_tunnels.get(&handle).is_some_and(|tunnel| tunnel.awaiting_syn.is_some()) {
+                self.accept_awaited_syn(handle, (event.src, event.dst), pkt);
+                return;
+            }
+        }
+
+        // A new connection: from here on everything about it carries its correlation ID
+        event.id = self.next_tunnel_id;
+        self.next_tunnel_id += 1;
+        let _span = tracing::debug_span!("tunnel", id = event.id).entered();
+
+        if self.closing {
+            debug!("Refusing SYN {} -> {}, shutting down", event.src, event.dst);
+            PrismStats::bump(&self.stats.syns_refused_closing);
+            if let Some(rst) = crate::trap::build_rst_reply(&pkt) {
+                self.device.transmit_packet(&rst);
+            }
+            self.emit(TunnelEvent::Rejected { id: event.id, target: event.dst, reason: CloseReason::Shutdown });
+            return;
+        }
+
+        if let Some((burst, window)) = self.config.syn_rate_limit {
+            let now = time::Instant::now();
+            let bucket = self.syn_buckets.entry(event.dst.ip()).or_insert_with(|| SynBucket::full(burst, now));
+            if !bucket.try_take(burst, window, now) {
+                debug!("Rate limiting SYN {} -> {}", event.src, event.dst);
+                PrismStats::bump(&self.stats.rate_limited);
+                self.emit(TunnelEvent::Rejected { id: event.id, target: event.dst, reason: CloseReason::Limit });
+                return;
+            }
+        }
+
+        if let Some(limit) = self.config.admit_per_poll {
+            // Once anything is queued, newcomers line up behind it
+            if self.admitted_this_poll >= limit || !self.admission_queue.is_empty() {
+                if self.admission_queue.len() >= self.config.admission_queue_cap {
+                    debug!("Refusing SYN {} -> {}, admission queue full", event.src, event.dst);
+                    PrismStats::bump(&self.stats.syns_queue_dropped);
+                    if let Some(rst) = crate::trap::build_rst_reply(&pkt) {
+                        self.device.transmit_packet(&rst);
+                    }
+                    self.emit(TunnelEvent::Rejected { id: event.id, target: event.dst, reason: CloseReason::Limit });
+                    return;
+                }
+                debug!("Queueing SYN {} -> {} for admission", event.src, event.dst);
+                PrismStats::bump(&self.stats.syns_queued);
+                self.remember_syn((event.src, event.dst), &pkt, time::Instant::now());
+                // `pkt` is all admission needs: don't hold the clamped SYN twice
+                event.packet = Bytes::new();
+                self.admission_queue.push_back((time::Instant::now(), event, pkt));
+                return;
+            }
+            self.admitted_this_poll += 1;
+        }
+        self.admit_syn(event, pkt, rx_buf_size, tx_buf_size);
+    }
+
+    /// Admits queued SYNs up to what's left of this poll's `admit_per_poll` budget, dropping
+    /// the ones that waited longer than `handshake_timeout`.
+    fn admit_queued_syns(&mut self, now: time::Instant) {
+        let limit = self.config.admit_per_poll.unwrap_or(usize::MAX);
+        while let Some((trapped_at, _, _)) = self.admission_queue.front() {
+            if now.duration_since(*trapped_at) >= self.config.handshake_timeout {
+                let (_, event, _) = self.admission_queue.pop_front().unwrap();
+                let _span = tracing::debug_span!("tunnel", id = event.id).entered();
+                debug!("SYN {} -> {} expired in the admission queue", event.src, event.dst);
+                PrismStats::bump(&self.stats.syns_queue_expired);
+                self.forget_syn(&(event.src, event.dst));
+                self.emit(TunnelEvent::Rejected { id: event.id, target: event.dst, reason: CloseReason::Limit });
+                continue;
+            }
+            if self.admitted_this_poll >= limit {
+                break;
+            }
+            let (_, event, pkt) = self.admission_queue.pop_front().unwrap();
+            self.admitted_this_poll += 1;
+            let _span = tracing::debug_span!("tunnel", id = event.id).entered();
+            self.admit_syn(event, pkt, self.config.tcp_rx_buffer, self.config.tcp_tx_buffer);
+        }
+    }
+
+    /// Sets up the tunnel for a SYN that passed the retransmit, rate and admission checks.
+    fn admit_syn(&mut self, event: crate::trap::TrapEvent, pkt: BytesMut, rx_buf_size: usize, tx_buf_size: usize) {
+        let flow = (event.src, event.dst);
+        if self.tunnel_req_tx.is_none() {
+            self.forget_syn(&flow);
+            self.refuse_without_relayer(&event, &pkt);
+            return;
+        }
+
+        // Register IP to Interface (needed for both modes)
+        let Some(cidr) = self.register_target_ip(event.dst) else {
+            self.forget_syn(&flow);
+            if let Some(rst) = crate::trap::build_rst_reply(&pkt) {
+                self.device.transmit_packet(&rst);
+            }
+            self.emit(TunnelEvent::Rejected { id: event.id, target: event.dst, reason: CloseReason::Limit });
+            return;
+        };
+
+        self.remember_syn(flow, &pkt, time::Instant::now());
+        // Dispatch to handshake mode
+        if self.config.handshake_mode == HandshakeMode::Consistent {
+            self.initiate_consistent_handshake(event, pkt);
+        } else {
+            self.initiate_fast_handshake(event, pkt, rx_buf_size, tx_buf_size, cidr);
+        }
+    }
+
+    /// Adds `target`'s address to the interface, so smoltcp accepts traffic for it. `None`
+    /// (counted in `setup_addr_table_full`) if smoltcp's address table has no room left.
+    fn register_target_ip(&mut self, target: SocketAddr) -> Option<IpCidr> {
+        let cidr = host_cidr(target);
+        if !self.registered_ips.contains(&cidr) {
+            let mut pushed = false;
+            self.iface.update_ip_addrs(|ip_addrs| {
+                pushed = ip_addrs.push(cidr).is_ok();
+            });
+            if !pushed {
+                warn!("Interface address table full, can't take {}", target);
+                PrismStats::bump(&self.stats.setup_addr_table_full);
+                return None;
+            }
+            self.registered_ips.insert(cidr);
+        }
+        Some(cidr)
+    }
+
+    /// Takes `cidr` back off the interface once no tunnel socket or pending Consistent
+    /// handshake needs it. Addresses the stack didn't add itself are left alone.
+    fn release_target_ip(&mut self, cidr: IpCidr) {
+        let in_use = self.active_ips.values().any(|active| *active == cidr)
+            || self.pending_syns.keys().any(|(_, target)| host_cidr(*target) == cidr);
+        if in_use || !self.registered_ips.remove(&cidr) {
+            return;
+        }
+        self.iface.update_ip_addrs(|ip_addrs| {
+            ip_addrs.retain(|addr| *addr != cidr);
+        });
+    }
+
+    fn initiate_consistent_handshake(&mut self, event: crate::trap::TrapEvent, pkt: BytesMut) {
+        let key = (event.src, event.dst);
+        debug!("Consistent Handshake: Buffering SYN for {}", event.dst);
+        
+        if self.tunnel_req_tx.is_some() {
+            let (tx_to_remote, rx_from_internal) = self.tunnel_channel();
+            let (tx_to_internal, rx_from_remote) = self.tunnel_channel();
+            let (resp_tx, resp_rx) = oneshot::channel();
+
+            let request = TunnelRequest {
+                id: event.id,
+                target: event.dst,
+                source: event.src,
+                tx: tx_to_internal,
+                rx: rx_from_internal,
+                response_tx: Some(resp_tx),
+                abort: TunnelAbort { id: event.id, tx: self.abort_tx.clone() },
+                coalesced: Vec::new(),
+            };
+
+            if let Err(e) = self.submit_tunnel_request(request) {
+                error!("Failed to request tunnel (Consistent): {}", e);
+                PrismStats::bump(&self.stats.setup_request_rejected);
+                self.release_target_ip(host_cidr(event.dst));
+                self.forget_syn(&key);
+                self.emit(TunnelEvent::Rejected { id: event.id, target: event.dst, reason: CloseReason::Limit });
+            } else {
+                 let summary = self.config.compact_pending_syns.then(|| SynSummary::from_packet(&pkt)).flatten();
+                 let syn = summary.map_or_else(|| HeldSyn::Packet(pkt.freeze()), HeldSyn::Summary);
+                 self.pending_syns.insert(key, PendingSyn { id: event.id, syn, tx_to_remote, rx_from_remote });
+                 self.id_index.insert(event.id, key);
+                 
+                 // Spawn wait task with timeout to prevent memory leak
+                 let feedback_tx = self.feedback_tx.clone();
+                 let target = event.dst;
+                 let handshake_timeout = self.config.handshake_timeout;
+                 let stats = self.stats.clone();
+                 let waiter = async move {
+                      let success = match tokio::time::timeout(
+                          handshake_timeout,
+                          resp_rx,
+                      ).await {
+                          Ok(Ok(val)) => val,
+                          Ok(Err(_)) => false,
+                          Err(_) => {
+                              tracing::warn!("Consistent Handshake timeout for {}", target);
+                              PrismStats::bump(&stats.setup_handshake_timeout);
+                              feedback_tx.send((key, false)).await.ok();
+                              return;
+                          }
+                      };
+                      if !success {
+                          PrismStats::bump(&stats.setup_upstream_failed);
+                      }
+                      let _ = feedback_tx.send((key, success)).await;
+                 };
+                 self.spawn_helper(waiter);
+            }
+        }
+    }
+
+    /// Builds a tunnel socket with the configured timers.
+    ///
+    /// Out-of-order client segments are kept in the rx buffer, so `rx_buf_size` bounds the
+    /// reordered bytes; the number of holes is smoltcp's compile-time assembler size (see the
+    /// `deep-reorder` feature).
+    fn make_socket(&self, rx_buf_size: usize, tx_buf_size: usize) -> tcp::Socket<'static> {
+        let allocator = &self.device.allocator;
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(allocator.socket_buffer(rx_buf_size)),
+            tcp::SocketBuffer::new(allocator.socket_buffer(tx_buf_size)),
+        );
+        socket.set_keep_alive(self.config.keep_alive.map(Into::into));
+        socket.set_timeout(self.config.timeout.map(Into::into));
+        socket.set_ack_delay(self.config.ack_delay.map(Into::into));
+        socket.set_nagle_enabled(false);
+        socket
+    }
+
+    fn initiate_fast_handshake(&mut self, event: crate::trap::TrapEvent, pkt: BytesMut, rx_buf_size: usize, tx_buf_size: usize, cidr: IpCidr) {
+        let _ = self.open_fast_tunnel(event.id, (event.src, event.dst), Some(pkt), rx_buf_size, tx_buf_size, cidr);
+    }
+
+    /// Sets up a socket for `flow` and requests the tunnel from the relayer. With `syn` the
+    /// socket listens and gets it; without, it waits for its client's SYN to start listening
+    /// (`open_tunnel`).
+    fn open_fast_tunnel(
+        &mut self,
+        id: u64,
+        flow: FlowKey,
+        syn: Option<BytesMut>,
+        rx_buf_size: usize,
+        tx_buf_size: usize,
+        cidr: IpCidr,
+    ) -> Result<SocketHandle, OpenTunnelError> {
+        let (source, target) = flow;
+        let mut socket = self.make_socket(rx_buf_size, tx_buf_size);
+
+        // Listening before the SYN is there would take one from any client to `target`
+        let listening = if syn.is_some() { socket.listen(listen_endpoint(target)) } else { Ok(()) };
+        if let Err(e) = listening {
+            warn!("Failed to listen: {}", e);
+            PrismStats::bump(&self.stats.setup_listen_failed);
+            self.release_target_ip(cidr);
+            self.forget_syn(&flow);
+            self.emit(TunnelEvent::Rejected { id, target, reason: CloseReason::ListenFailed });
+            return Err(OpenTunnelError::ListenFailed);
+        }
+
+        let handle = self.add_socket(socket);
+        if let Some(syn) = syn {
+            self.reinject_syn(handle, target, syn);
+        }
+        self.active_ips.insert(handle, cidr);
+
+        let (tx_to_remote, rx_from_internal) = self.tunnel_channel();
+        let (tx_to_internal, rx_from_remote) = self.tunnel_channel();
+
+        let request = TunnelRequest {
+            id,
+            target,
+            source,
+            tx: tx_to_internal,
+            rx: rx_from_internal,
+            response_tx: None,
+            abort: TunnelAbort { id, tx: self.abort_tx.clone() },
+            coalesced: Vec::new(),
+        };
+
+        if self.submit_tunnel_request(request).is_err() {
+            PrismStats::bump(&self.stats.setup_request_rejected);
+            self.active_ips.remove(&handle);
+            self.release_target_ip(cidr);
+            self.forget_syn(&flow);
+            self.remove_socket(handle);
+            self.emit(TunnelEvent::Rejected { id, target, reason: CloseReason::Limit });
+            return Err(OpenTunnelError::Rejected);
+        }
+        self.register_tunnel(id, handle, flow, tx_to_remote, rx_from_remote);
+        Ok(handle)
+    }
+
+    /// Records a path MTU for `dst` (see [`PathMtuReporter::report`]).
+    fn record_path_mtu(&mut self, dst: IpAddr, mtu: usize, now: time::Instant) {
+        let mtu = mtu.max(if dst.is_ipv6() { 1280 } else { 576 });
+        let known = self.path_mtus.get(&dst).filter(|(_, until)| *until > now).map(|&(mtu, _)| mtu);
+        if mtu >= known.unwrap_or(self.config.egress_mtu) {
+            return;
+        }
+        if self.path_mtus.len() >= PATH_MTU_CACHE_SIZE && known.is_none() {
+            debug!("Path MTU cache full, ignoring {} for {}", mtu, dst);
+            return;
+        }
+        debug!("Path MTU to {} is {}", dst, mtu);
+        self.path_mtus.insert(dst, (mtu, now + PATH_MTU_TTL));
+    }
+
+    /// The MSS clamp for a SYN: the configured one, lowered to a reported path MTU of its
+    /// destination.
+    fn mss_clamp_for(&self, syn: &[u8]) -> MssClamp {
+        let clamp = self.config.mss_clamp();
+        let path_mtu = crate::trap::destination_ip(syn)
+            .and_then(|dst| self.path_mtus.get(&dst))
+            .filter(|(_, until)| *until > time::Instant::now());
+        match path_mtu {
+            Some(&(mtu, _)) => {
+                let path = MssClamp::for_mtu(mtu);
+                MssClamp { v4: clamp.v4.min(path.v4), v6: clamp.v6.min(path.v6) }
+            }
+            None => clamp,
+        }
+    }
+
+    /// Tells whether a trapped SYN repeats one admitted (or queued for admission) within
+    /// `SYN_CACHE_TTL`. A flow still waiting for its Consistent handshake always counts as a
+    /// repeat.
+    fn is_syn_retransmit(&self, flow: FlowKey, now: time::Instant) -> bool {
+        self.pending_syns.contains_key(&flow)
+            || self.syn_cache.get(&flow).is_some_and(|&(seen, _)| now.duration_since(seen) < SYN_CACHE_TTL)
+    }
+
+    /// Records the SYN of a flow being admitted, so its retransmits aren't trapped again. A
+    /// SYN refused on the way is never recorded, or forgotten again (`forget_syn`): its
+    /// retransmits get another chance.
+    fn remember_syn(&mut self, flow: FlowKey, syn: &[u8], now: time::Instant) {
+        if !self.is_syn_retransmit(flow, now) {
+            self.syn_cache.insert(flow, (now, crate::trap::tcp_seq(syn).unwrap_or_default()));
+        }
+    }
+
+    fn forget_syn(&mut self, flow: &FlowKey) {
+        self.syn_cache.remove(flow);
+    }
+
+    /// Restarts the unfinished handshake of `flow` with `syn`, unless it carries the sequence
+    /// number already seen (`DuplicateSynPolicy::Restart`). Returns whether it did.
+    fn restart_handshake(&mut self, flow: FlowKey, syn: &BytesMut, now: time::Instant) -> bool {
+        let Some(seq) = crate::trap::tcp_seq(syn) else { return false };
+        if let Some(pending) = self.pending_syns.get_mut(&flow) {
+            let held = match &pending.syn {
+                HeldSyn::Packet(packet) => crate::trap::tcp_seq(packet),
+                HeldSyn::Summary(summary) => Some(summary.seq),
+            };
+            if held == Some(seq) {
+                return false;
+            }
+            let summary = self.config.compact_pending_syns.then(|| SynSummary::from_packet(syn)).flatten();
+            pending.syn = summary.map_or_else(|| HeldSyn::Packet(Bytes::copy_from_slice(syn)), HeldSyn::Summary);
+            return true;
+        }
+
+        let Some(&handle) = self.flow_index.get(&flow) else { return false };
+        if self.syn_cache.get(&flow).is_some_and(|&(_, seen)| seen == seq) {
+            return false;
+        }
+        let socket = self.sockets.get_mut::<tcp::Socket>(handle);
+        let Some(local) = socket.local_endpoint().filter(|_| socket.state() == tcp::State::SynReceived) else {
+            return false;
+        };
+        // Back to Listen without a RST: the client already gave up on the old attempt
+        socket.abort();
+        if socket.listen(local).is_err() {
+            return false;
+        }
+        self.syn_cache.insert(flow, (now, seq));
+        self.reinject_syn(handle, flow.1, syn.clone());
+        self.dirty.insert(handle);
+        true
+    }
+
+    fn expire_syn_cache(&mut self, now: time::Instant) {
+        self.syn_cache.retain(|_, (seen, _)| now.duration_since(*seen) < SYN_CACHE_TTL);
+    }
+
+    /// Adds a socket, keeping track of how far the set's storage has grown.
+    fn add_socket(&mut self, socket: tcp::Socket<'static>) -> SocketHandle {
+        let handle = self.sockets.add(socket);
+        let slot = self.free_slots.pop_first().unwrap_or(self.socket_slots);
+        self.socket_index.insert(handle, slot);
+        self.socket_slots = self.socket_slots.max(slot + 1);
+        handle
+    }
+
+    /// Removes a socket, leaving its slot free for the next one.
+    fn remove_socket(&mut self, handle: SocketHandle) {
+        self.sockets.remove(handle);
+        if let Some(slot) = self.socket_index.remove(&handle) {
+            self.free_slots.insert(slot);
+        }
+    }
+
+    /// Moves the live sockets into a set just long enough to hold them, once the highest one
+    /// sits well below the set's length (`SOCKET_COMPACT_RATIO`). Sockets keep their slot, so
+    /// every handle stays valid, including those of sockets passed to `new_with_sockets`; the
+    /// storage can only shrink down to the highest live socket.
+    fn compact_sockets(&mut self) {
+        let used = self.socket_index.values().max().map_or(0, |slot| slot + 1);
+        self.socket_slots = self.socket_slots.max(used);
+        if self.socket_slots < SOCKET_COMPACT_MIN_SLOTS || used * SOCKET_COMPACT_RATIO > self.socket_slots {
+            return;
+        }
+
+        // Only the socket types this crate enables can be moved (see `add_any_socket`)
+        if !self.sockets.iter().all(|(_, socket)| matches!(socket, Socket::Tcp(_) | Socket::Udp(_))) {
+            return;
+        }
+
+        let mut old = std::mem::replace(&mut self.sockets, SocketSet::new(Vec::with_capacity(used)));
+        let handles: Vec<SocketHandle> = old.iter().map(|(handle, _)| handle).collect();
+        // A new set fills its lowest free slot first: free slots below a socket get a
+        // placeholder until the socket's own handle comes up, and the socket takes its place
+        let mut placeholders = Vec::new();
+        self.socket_index.clear();
+        self.free_slots.clear();
+        let mut filled = 0;
+        for handle in handles {
+            loop {
+                let placeholder = self.sockets.add(placeholder_socket());
+                if placeholder == handle {
+                    self.sockets.remove(placeholder);
+                    break;
+                }
+                placeholders.push(placeholder);
+                self.free_slots.insert(filled);
+                filled += 1;
+            }
+            let moved = add_any_socket(&mut self.sockets, old.remove(handle));
+            debug_assert_eq!(moved, handle);
+            self.socket_index.insert(handle, filled);
+            filled += 1;
+        }
+        for placeholder in placeholders {
+            self.sockets.remove(placeholder);
+        }
+        debug!("Compacted socket set from {} to {} slots", self.socket_slots, filled);
+        self.socket_slots = filled;
+        PrismStats::bump(&self.stats.socket_set_compactions);
+    }
+
+    /// Queues the throttled tunnels whose wait is over for the next egress pump.
+    fn unthrottle(&mut self, now: time::Instant) {
+        self.throttled.retain(|handle, wake| {
+            if *wake > now {
+                return true;
+            }
+            self.dirty.insert(*handle);
+            false
+        });
+    }
+
+    /// Sends the peeks of tunnels whose client paused before filling `peek_bytes`.
+    fn flush_peeks(&mut self, now: time::Instant) {
+        let due: Vec<SocketHandle> = self.peeking.iter().filter(|(_, until)| **until <= now).map(|(&handle, _)| handle).collect();
+        for handle in due {
+            self.peeking.remove(&handle);
+            if let Some(peek) = self.active_tunnels.get_mut(&handle).and_then(|tunnel| tunnel.take_peek(handle)) {
+                send_peek(self.peek_tx.as_ref(), &self.stats, peek);
+            }
+        }
+    }
+
+    /// Forgets rate-limit buckets that have refilled completely; they'd start full anyway.
+    fn sweep_syn_buckets(&mut self, now: time::Instant) {
+        let Some((burst, window)) = self.config.syn_rate_limit else {
+            self.syn_buckets.clear();
+            return;
+        };
+        self.syn_buckets.retain(|_, bucket| {
+            bucket.refill(burst, window, now);
+            bucket.tokens < burst as f64
+        });
+    }
+
+    /// One of the two channels of a new tunnel (TCP or UDP), `tunnel_channel_depth` deep.
+    fn tunnel_channel(&self) -> (mpsc::Sender<Bytes>, mpsc::Receiver<Bytes>) {
+        mpsc::channel(self.config.tunnel_channel_depth.max(1))
+    }
+
+    /// Starts tracking a tunnel socket and its relayer channels.
+    fn register_tunnel(&mut self, id: u64, handle: SocketHandle, flow: FlowKey, tx_to_remote: mpsc::Sender<Bytes>, rx_from_remote: mpsc::Receiver<Bytes>) {
+        let now = time::Instant::now();
+        let mut tunnel = TunnelState::new(id, flow, tx_to_remote, now);
+        tunnel.rate_bucket = self.config.rate_limit_bps.map(|bps| ByteBucket::full(bps, now));
+        self.active_tunnels.insert(handle, tunnel);
+        self.flow_index.insert(flow, handle);
+        self.id_index.insert(id, flow);
+        self.ingress_streams.push(IngressStream::new(handle, rx_from_remote));
+        let opened = if flow.1.is_ipv4() { &self.stats.tunnels_opened_v4 } else { &self.stats.tunnels_opened_v6 };
+        PrismStats::bump(opened);
+        self.emit(TunnelEvent::Opened { id, handle, target: flow.1 });
+    }
+
+    /// Sends a lifecycle event if anyone listens; drops it rather than wait.
+    fn emit(&self, event: TunnelEvent) {
+        if let Some(ref tx) = self.event_tx {
+            let _ = tx.try_send(event);
+        }
+    }
+
+    /// Hands a request to the relayer, parks it in its target's coalescing batch, or returns
+    /// an error (dropping the request, which closes its channels) when the relayer can't take
+    /// it. Batched requests are accepted unconditionally; if the relayer can't take the batch
+    /// when it is flushed, its streams are rejected then (see `flush_syn_batches`).
+    fn submit_tunnel_request(&mut self, request: TunnelRequest) -> Result<(), mpsc::error::TrySendError<()>> {
+        use mpsc::error::TrySendError;
+        if self.tunnel_req_tx.is_none() {
+            return Err(TrySendError::Closed(()));
+        }
+        let Some(window) = self.config.syn_coalesce_window else {
+            return self.send_or_stage_request(request);
+        };
+        self.syn_batches
+            .entry(request.target)
+            .or_insert_with(|| (time::Instant::now() + window, Vec::new()))
+            .1
+            .push(request);
+        Ok(())
+    }
+
+    /// Sends every batch whose coalescing window has closed by `now` as one request.
+    ///
+    /// A batch the relayer can't take fails each of its streams as `admit_syn` would have:
+    /// Fast-mode sockets, already answering the client, are reset and reported as rejected.
+    /// Consistent-mode streams fail through their dropped handshake (`setup_upstream_failed`).
+    fn flush_syn_batches(&mut self, now: time::Instant) {
+        let due: Vec<SocketAddr> = self
+            .syn_batches
+            .iter()
+            .filter(|(_, (deadline, _))| *deadline <= now)
+            .map(|(target, _)| *target)
+            .collect();
+        for target in due {
+            let Some((_, mut requests)) = self.syn_batches.remove(&target) else { continue };
+            // Requests carry no handle: a failed batch finds its Fast sockets by flow
+            let fast: Vec<(u64, FlowKey)> = requests
+                .iter()
+                .filter(|req| req.response_tx.is_none())
+                .map(|req| (req.id, (req.source, req.target)))
+                .collect();
+            let mut request = requests.remove(0);
+            request.coalesced = requests;
+            debug!("Requesting tunnel to {} for {} coalesced streams", target, request.coalesced.len() + 1);
+            if let Err(e) = self.send_or_stage_request(request) {
+                error!("Failed to request coalesced tunnel to {}: {}", target, e);
+                for (id, flow) in fast {
+                    self.reject_unsent(id, flow);
+                }
+            }
+        }
+    }
+
+    /// Resets the Fast-mode tunnel of a request the relayer never got.
+    fn reject_unsent(&mut self, id: u64, flow: FlowKey) {
+        PrismStats::bump(&self.stats.setup_request_rejected);
+        self.emit(TunnelEvent::Rejected { id, target: flow.1, reason: CloseReason::Limit });
+        self.forget_syn(&flow);
+        let Some(&handle) = self.flow_index.get(&flow) else { return };
+        let Some(tunnel) = self.active_tunnels.get_mut(&handle).filter(|tunnel| tunnel.id == id) else { return };
+        tunnel.rejected = true;
+        self.sockets.get_mut::<tcp::Socket>(handle).abort();
+        self.dirty.insert(handle);
+    }
+
+    /// Sends a request to the relayer, or stages it behind the ones already waiting while
+    /// the channel is full. `Full` only once `TUNNEL_REQUEST_BACKLOG` is full as well.
+    fn send_or_stage_request(&mut self, request: TunnelRequest) -> Result<(), mpsc::error::TrySendError<()>> {
+        use mpsc::error::TrySendError;
+        let Some(ref req_tx) = self.tunnel_req_tx else {
+            return Err(TrySendError::Closed(()));
+        };
+        // Behind a backlog, sending right away would overtake the staged requests
+        let request = if self.request_backlog.is_empty() {
+            match req_tx.try_send(request) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(request)) => request,
+                Err(TrySendError::Closed(_)) => return Err(TrySendError::Closed(())),
+            }
+        } else if req_tx.is_closed() {
+            return Err(TrySendError::Closed(()));
+        } else {
+            request
+        };
+        if self.request_backlog.len() >= TUNNEL_REQUEST_BACKLOG {
+            return Err(TrySendError::Full(()));
+        }
+        debug!("Request channel full, staging the tunnel request to {}", request.target);
+        PrismStats::bump(&self.stats.tunnel_requests_staged);
+        self.request_backlog.push_back(request);
+        Ok(())
+    }
+
+    /// Sends as many staged tunnel requests as the request channel takes right now. If it
+    /// closed, the staged requests are dropped: their Fast-mode sockets are reset and reported
+    /// as rejected, their Consistent handshakes fail as the response channels close.
+    fn flush_request_backlog(&mut self) {
+        let Some(ref req_tx) = self.tunnel_req_tx else { return };
+        let mut dropped = Vec::new();
+        while let Some(request) = self.request_backlog.pop_front() {
+            match req_tx.try_send(request) {
+                Ok(()) => {}
+                Err(mpsc::error::TrySendError::Full(request)) => {
+                    self.request_backlog.push_front(request);
+                    break;
+                }
+                Err(mpsc::error::TrySendError::Closed(request)) => {
+                    PrismStats::add(&self.stats.tunnel_requests_staged_dropped, self.request_backlog.len() + 1);
+                    dropped.push(request);
+                    dropped.extend(self.request_backlog.drain(..));
+                }
+            }
+        }
+        let fast: Vec<(u64, FlowKey)> = dropped
+            .iter()
+            .flat_map(|request| std::iter::once(request).chain(&request.coalesced))
+            .filter(|req| req.response_tx.is_none())
+            .map(|req| (req.id, (req.source, req.target)))
+            .collect();
+        for (id, flow) in fast {
+            self.reject_unsent(id, flow);
+        }
+    }
+
+    /// Removes the pending Consistent handshake of `key`, if any.
+    fn take_pending_syn(&mut self, key: &FlowKey) -> Option<PendingSyn> {
+        let pending = self.pending_syns.remove(key)?;
+        self.id_index.remove(&pending.id);
+        Some(pending)
+    }
+
+    /// Gives up on a pending Consistent handshake that was taken out of `pending_syns`: the
+    /// client's SYN is answered with a RST and the connection reported as rejected.
+    fn refuse_pending_syn(&mut self, key: FlowKey, pending: PendingSyn, reason: CloseReason) {
+        if let Some(rst) = crate::trap::build_rst_reply(&pending.syn.to_packet(key)) {
+            self.device.transmit_packet(&rst);
+        }
+        self.release_target_ip(host_cidr(key.1));
+        self.forget_syn(&key);
+        self.emit(TunnelEvent::Rejected { id: pending.id, target: key.1, reason });
+    }
+
+    fn handle_handshake_feedback(&mut self, key: FlowKey, success: bool, rx_buf: usize, tx_buf: usize) {
+        let target = key.1;
+        if self.closing {
+            // Too late for a new tunnel, whatever the relayer says
+            if let Some(pending) = self.take_pending_syn(&key) {
+                let _span = tracing::debug_span!("tunnel", id = pending.id).entered();
+                debug!("Refusing handshake for {}, shutting down", target);
+                self.refuse_pending_syn(key, pending, CloseReason::Shutdown);
+            }
+            return;
+        }
+        if let Some(PendingSyn { id, syn, tx_to_remote, rx_from_remote }) = self.take_pending_syn(&key) {
+            let _span = tracing::debug_span!("tunnel", id).entered();
+            if success {
+                debug!("Tunnel ready for {}. Releasing SYN.", target);
+                let mut socket = self.make_socket(rx_buf, tx_buf);
+                let packet = syn.to_packet(key);
+                if socket.listen(listen_endpoint(target)).is_ok() {
+                    let handle = self.add_socket(socket);
+                    self.register_tunnel(id, handle, key, tx_to_remote, rx_from_remote);
+                    // Track IP for cleanup
+                    self.active_ips.insert(handle, host_cidr(target));
+                    self.reinject_syn(handle, target, packet);
+                } else {
+                    warn!("Failed to listen on {}", target);
+                    PrismStats::bump(&self.stats.setup_listen_failed);
+                    self.release_target_ip(host_cidr(target));
+                    self.forget_syn(&key);
+                    self.emit(TunnelEvent::Rejected { id, target, reason: CloseReason::ListenFailed });
+                }
+            } else {
+                warn!("Tunnel failed for {}. Dropping SYN.", target);
+                self.release_target_ip(host_cidr(target));
+                self.forget_syn(&key);
+                self.emit(TunnelEvent::Rejected { id, target, reason: CloseReason::Reset });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::phy::{ChecksumCapabilities, DeviceCapabilities, Medium};
+    use smoltcp::socket::udp;
+    use std::collections::VecDeque;
+    use smoltcp::wire::{Icmpv4Message, Icmpv4Packet, Icmpv4Repr, IpProtocol, Ipv4Packet, Ipv4Repr, Ipv6Packet, Ipv6Repr, TcpControl, TcpPacket, TcpRepr, TcpSeqNumber, UdpPacket, UdpRepr};
+
+    fn test_stack(config: PrismConfig) -> PrismStack {
+        let (_os_tx, os_rx) = mpsc::channel(16);
+        let (tun_tx, _tun_rx) = mpsc::channel(16);
+        let device = PrismDevice::new(os_rx, tun_tx, 1500, Medium::Ip);
+        PrismStack::new(device, config)
+    }
+
+    fn test_stack_with_tun(config: PrismConfig) -> (PrismStack, mpsc::Receiver<Bytes>) {
+        let (_os_tx, os_rx) = mpsc::channel(16);
+        let (tun_tx, tun_rx) = mpsc::channel(4096);
+        let device = PrismDevice::new(os_rx, tun_tx, 1500, Medium::Ip);
+        (PrismStack::new(device, config), tun_rx)
+    }
+
+    /// In-memory link for [`TestClient`]: `rx` is what the client receives, `tx` what it sent.
+    #[derive(Default)]
+    struct Wire {
+        rx: VecDeque<Vec<u8>>,
+        tx: VecDeque<Vec<u8>>,
+    }
+
+    struct WireRx(Vec<u8>);
+    struct WireTx<'a>(&'a mut VecDeque<Vec<u8>>);
+
+    impl smoltcp::phy::RxToken for WireRx {
+        fn consume<R, F: FnOnce(&mut [u8]) -> R>(mut self, f: F) -> R {
+            f(&mut self.0)
+        }
+    }
+
+    impl smoltcp::phy::TxToken for WireTx<'_> {
+        fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F) -> R {
+            let mut buf = vec![0; len];
+            let result = f(&mut buf);
+            self.0.push_back(buf);
+            result
+        }
+    }
+
+    impl Device for Wire {
+        type RxToken<'a> = WireRx;
+        type TxToken<'a> = WireTx<'a>;
+
+        fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+            let pkt = self.rx.pop_front()?;
+            Some((WireRx(pkt), WireTx(&mut self.tx)))
+        }
+
+        fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+            Some(WireTx(&mut self.tx))
+        }
+
+        fn capabilities(&self) -> DeviceCapabilities {
+            let mut caps = DeviceCapabilities::default();
+            caps.max_transmission_unit = 1500;
+            caps.medium = Medium::Ip;
+            caps
+        }
+    }
+
+    /// A smoltcp TCP client at 10.11.12.2 talking to a stack through its TUN channels.
+    struct TestClient {
+        iface: Interface,
+        sockets: SocketSet<'static>,
+        wire: Wire,
+        handle: SocketHandle,
+    }
+
+    impl TestClient {
+        /// Opens a connection from 10.11.12.2:`src_port` to 10.11.12.9:`dst_port`, a trapped
+        /// destination on the client's own subnet (the gateway's ports refuse SYNs).
+        fn connect(src_port: u16, dst_port: u16) -> Self {
+            Self::connect_between(IpCidr::new(IpAddress::v4(10, 11, 12, 2), 24), IpAddress::v4(10, 11, 12, 9), src_port, dst_port)
+        }
+
+        /// Same as [`connect`](Self::connect) over IPv6: fd00::2 to fd00::9.
+        fn connect_v6(src_port: u16, dst_port: u16) -> Self {
+            let local = IpCidr::new(IpAddress::v6(0xfd00, 0, 0, 0, 0, 0, 0, 2), 64);
+            Self::connect_between(local, IpAddress::v6(0xfd00, 0, 0, 0, 0, 0, 0, 9), src_port, dst_port)
+        }
+
+        fn connect_between(local: IpCidr, remote: IpAddress, src_port: u16, dst_port: u16) -> Self {
+            let mut wire = Wire::default();
+            let mut iface = Interface::new(Config::new(HardwareAddress::Ip), &mut wire, Instant::from_millis(0));
+            iface.update_ip_addrs(|addrs| addrs.push(local).unwrap());
+            let mut sockets = SocketSet::new(vec![]);
+            let socket = tcp::Socket::new(
+                tcp::SocketBuffer::new(vec![0; 512 * 1024]),
+                tcp::SocketBuffer::new(vec![0; 512 * 1024]),
+            );
+            let handle = sockets.add(socket);
+            sockets
+                .get_mut::<tcp::Socket>(handle)
+                .connect(iface.context(), (remote, dst_port), src_port)
+                .unwrap();
+            Self { iface, sockets, wire, handle }
+        }
+
+        fn socket(&mut self) -> &mut tcp::Socket<'static> {
+            self.sockets.get_mut::<tcp::Socket>(self.handle)
+        }
+
+        /// Shuttles packets between the client and `stack` until both sides go quiet.
+        /// The stack's egress pump only runs if `pump` is set.
+        fn exchange(&mut self, stack: &mut PrismStack, tun_rx: &mut mpsc::Receiver<Bytes>, pump: bool) {
+            let now = Instant::from_millis(0);
+            for _ in 0..10_000 {
+                self.iface.poll(now, &mut self.wire, &mut self.sockets);
+                let mut moved = false;
+                while let Some(pkt) = self.wire.tx.pop_front() {
+                    stack.process_ingress_packet(BytesMut::from(&pkt[..]));
+                    moved = true;
+                }
+                stack.iface.poll(now, &mut stack.device, &mut stack.sockets);
+                stack.mark_client_resets();
+                if pump {
+                    stack.pump_egress(false);
+                }
+                while let Ok(pkt) = tun_rx.try_recv() {
+                    self.wire.rx.push_back(pkt.to_vec());
+                    moved = true;
+                }
+                if !moved {
+                    return;
+                }
+            }
+            panic!("client and stack never went quiet");
+        }
+    }
+
+    /// Builds an IPv4 UDP datagram from 10.11.12.2:5353 to `dst:port`.
+    fn build_udp_v4(dst: [u8; 4], dst_port: u16, payload: &[u8]) -> BytesMut {
+        let src_addr = Ipv4Address::new(10, 11, 12, 2);
+        let dst_addr = Ipv4Address::from_bytes(&dst);
+        let caps = ChecksumCapabilities::default();
+        let ip_repr = Ipv4Repr {
+            src_addr,
+            dst_addr,
+            next_header: IpProtocol::Udp,
+            payload_len: 8 + payload.len(),
+            hop_limit: 64,
+        };
+        let udp_repr = UdpRepr { src_port: 5353, dst_port };
+        let mut buf = vec![0u8; 20 + 8 + payload.len()];
+        let mut ip = Ipv4Packet::new_unchecked(&mut buf);
+        ip_repr.emit(&mut ip, &caps);
+        let mut udp = UdpPacket::new_unchecked(ip.payload_mut());
+        udp_repr.emit(
+            &mut udp,
+            &src_addr.into(),
+            &dst_addr.into(),
+            payload.len(),
+            |p| p.copy_from_slice(payload),
+            &caps,
+        );
+        BytesMut::from(&buf[..])
+    }
+
+    /// Builds an IPv4 ICMP echo request from 10.11.12.2 to `dst`.
+    fn build_ping_v4(dst: [u8; 4]) -> BytesMut {
+        let src_addr = Ipv4Address::new(10, 11, 12, 2);
+        let caps = ChecksumCapabilities::default();
+        let icmp_repr = Icmpv4Repr::EchoRequest { ident: 7, seq_no: 1, data: b"ping" };
+        let ip_repr = Ipv4Repr {
+            src_addr,
+            dst_addr: Ipv4Address::from_bytes(&dst),
+            next_header: IpProtocol::Icmp,
+            payload_len: icmp_repr.buffer_len(),
+            hop_limit: 64,
+        };
+        let mut buf = vec![0u8; 20 + icmp_repr.buffer_len()];
+        let mut ip = Ipv4Packet::new_unchecked(&mut buf);
+        ip_repr.emit(&mut ip, &caps);
+        icmp_repr.emit(&mut Icmpv4Packet::new_unchecked(ip.payload_mut()), &caps);
+        BytesMut::from(&buf[..])
+    }
+
+    /// Builds an IPv4 TCP SYN from 10.11.12.2:`src_port` to `dst:dst_port`.
+    fn build_syn_v4(src_port: u16, dst: [u8; 4], dst_port: u16) -> BytesMut {
+        build_tcp_v4(src_port, dst, dst_port, TcpControl::Syn, None, &[])
+    }
+
+    /// Builds an IPv4 TCP segment from 10.11.12.2:`src_port` to `dst:dst_port`.
+    fn build_tcp_v4(
+        src_port: u16,
+        dst: [u8; 4],
+        dst_port: u16,
+        control: TcpControl,
+        ack_number: Option<TcpSeqNumber>,
+        payload: &[u8],
+    ) -> BytesMut {
+        build_tcp_v4_seq(src_port, dst, dst_port, control, TcpSeqNumber(1000), ack_number, payload)
+    }
+
+    /// Same as [`build_tcp_v4`] with an explicit sequence number.
+    fn build_tcp_v4_seq(
+        src_port: u16,
+        dst: [u8; 4],
+        dst_port: u16,
+        control: TcpControl,
+        seq_number: TcpSeqNumber,
+        ack_number: Option<TcpSeqNumber>,
+        payload: &[u8],
+    ) -> BytesMut {
+        let src_addr = Ipv4Address::new(10, 11, 12, 2);
+        let dst_addr = Ipv4Address::from_bytes(&dst);
+        let caps = ChecksumCapabilities::default();
+        let tcp_repr = TcpRepr {
+            src_port,
+            dst_port,
+            control,
+            seq_number,
+            ack_number,
+            window_len: 65535,
+            window_scale: None,
+            max_seg_size: (control == TcpControl::Syn).then_some(1460),
+            sack_permitted: false,
+            sack_ranges: [None; 3],
+            payload,
+        };
+        let ip_repr = Ipv4Repr {
+            src_addr,
+            dst_addr,
+            next_header: IpProtocol::Tcp,
+            payload_len: tcp_repr.buffer_len(),
+            hop_limit: 64,
+        };
+        let mut buf = vec![0u8; 20 + tcp_repr.buffer_len()];
+        let mut ip = Ipv4Packet::new_unchecked(&mut buf);
+        ip_repr.emit(&mut ip, &caps);
+        let mut tcp = TcpPacket::new_unchecked(ip.payload_mut());
+        tcp_repr.emit(&mut tcp, &src_addr.into(), &dst_addr.into(), &caps);
+        BytesMut::from(&buf[..])
+    }
+
+    /// Builds an IPv6 TCP SYN from fd00::2:`src_port` to `dst:dst_port`.
+    fn build_syn_v6(src_port: u16, dst: Ipv6Address, dst_port: u16) -> BytesMut {
+        let src_addr = Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+        let caps = ChecksumCapabilities::default();
+        let tcp_repr = TcpRepr {
+            src_port,
+            dst_port,
+            control: TcpControl::Syn,
+            seq_number: TcpSeqNumber(1000),
+            ack_number: None,
+            window_len: 65535,
+            window_scale: None,
+            max_seg_size: Some(1440),
+            sack_permitted: false,
+            sack_ranges: [None; 3],
+            payload: &[],
+        };
+        let ip_repr = Ipv6Repr { src_addr, dst_addr: dst, next_header: IpProtocol::Tcp, payload_len: tcp_repr.buffer_len(), hop_limit: 64 };
+        let mut buf = vec![0u8; 40 + tcp_repr.buffer_len()];
+        let mut ip = Ipv6Packet::new_unchecked(&mut buf);
+        ip_repr.emit(&mut ip);
+        let mut tcp = TcpPacket::new_unchecked(ip.payload_mut());
+        tcp_repr.emit(&mut tcp, &src_addr.into(), &dst.into(), &caps);
+        BytesMut::from(&buf[..])
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_consistent_handshake_times_out() {
+        let timeout = Duration::from_secs(5);
+        let mut stack = test_stack(PrismConfig {
+            handshake_mode: HandshakeMode::Consistent,
+            handshake_timeout: timeout,
+            ..Default::default()
+        });
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+
+        stack.process_ingress_packet(build_syn_v4(40000, [1, 2, 3, 4], 443));
+        assert_eq!(stack.pending_syns.len(), 1);
+        // The relayer holds the request but never answers
+        let _req = req_rx.recv().await.unwrap();
+
+        time::advance(timeout + Duration::from_millis(1)).await;
+        let (key, success) = stack.feedback_rx.recv().await.unwrap();
+        assert!(!success);
+        stack.handle_handshake_feedback(key, success, 1024, 1024);
+        assert!(stack.pending_syns.is_empty());
+        assert!(stack.sockets.iter().next().is_none());
+        let stats = stack.stats().snapshot();
+        assert_eq!(stats.setup_handshake_timeout, 1);
+        assert_eq!(stats.setup_upstream_failed, 0);
+    }
+
+    #[tokio::test]
+    async fn test_poll_reports_changes_until_settled() {
+        let (mut stack, _tun_rx) = test_stack_with_tun(PrismConfig::default());
+        let (req_tx, _req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        assert!(!stack.poll_once(Instant::from_millis(0)));
+
+        stack.inject(build_syn_v4(40000, [10, 11, 12, 9], 8080));
+        assert!(stack.poll_once(Instant::from_millis(0)));
+        assert!(!stack.poll_once(Instant::from_millis(0)));
+        let stats = stack.stats().snapshot();
+        assert_eq!(stats.poll_iterations, 3);
+        assert_eq!(stats.polls_with_changes, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_run_loop_does_not_spin() {
+        let (os_tx, os_rx) = mpsc::channel(16);
+        let (tun_tx, _tun_rx) = mpsc::channel(16);
+        let stack = PrismStack::new(PrismDevice::new(os_rx, tun_tx, 1500, Medium::Ip), PrismConfig::default());
+        let stats = stack.stats();
+        let task = tokio::spawn(stack.run());
+
+        time::sleep(Duration::from_secs(5)).await;
+        // One poll per reap tick, nothing in between
+        let polls = stats.snapshot().poll_iterations;
+        assert!(polls <= 6, "idle loop polled {polls} times");
+        assert_eq!(stats.snapshot().polls_with_changes, 0);
+
+        drop(os_tx);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rx_batch_bounds_packets_per_wake_up() {
+        async fn polls_for(rx_batch: usize) -> u64 {
+            let (os_tx, os_rx) = mpsc::channel(16);
+            let (tun_tx, _tun_rx) = mpsc::channel(16);
+            let config = PrismConfig { rx_batch, ..Default::default() };
+            let mut stack = PrismStack::new(PrismDevice::new(os_rx, tun_tx, 1500, Medium::Ip), config);
+            let (relay_tx, relay_rx) = mpsc::channel(16);
+            stack.set_blind_relay_sender(relay_tx);
+            let stats = stack.stats();
+            for _ in 0..6 {
+                os_tx.send(build_udp_v4([1, 2, 3, 4], 53, b"q")).await.unwrap();
+            }
+            let task = tokio::spawn(stack.run());
+            time::sleep(Duration::from_millis(10)).await;
+            assert_eq!(relay_rx.len(), 6);
+            let polls = stats.snapshot().poll_iterations;
+            drop(os_tx);
+            task.await.unwrap().unwrap();
+            polls
+        }
+
+        // The whole burst in one wake-up, or a poll after every two packets
+        let (one_batch, three_batches) = (polls_for(BATCH_SIZE).await, polls_for(2).await);
+        assert_eq!(three_batches, one_batch + 2, "{one_batch} vs {three_batches} polls");
+    }
+
+    #[cfg(feature = "trace-latency")]
+    #[tokio::test(start_paused = true)]
+    async fn test_latency_sample_per_answered_packet() {
+        let (os_tx, os_rx) = mpsc::channel(16);
+        let (tun_tx, mut tun_rx) = mpsc::channel(16);
+        let mut stack = PrismStack::new(PrismDevice::new(os_rx, tun_tx, 1500, Medium::Ip), PrismConfig::default());
+        let (lat_tx, mut lat_rx) = mpsc::channel(4);
+        stack.set_latency_sender(lat_tx);
+        let task = tokio::spawn(stack.run());
+
+        // Refused with a RST: one sample for the SYN, none for the UDP packet nobody answers
+        os_tx.send(build_udp_v4([10, 11, 12, 1], 53, b"q")).await.unwrap();
+        os_tx.send(build_syn_v4(40000, [10, 11, 12, 1], 8080)).await.unwrap();
+        tun_rx.recv().await.unwrap();
+        let sample = lat_rx.recv().await.unwrap();
+        assert_eq!(sample.flow, ("10.11.12.2:40000".parse().unwrap(), "10.11.12.1:8080".parse().unwrap()));
+        assert_eq!(sample.dwell(), Duration::ZERO);
+        assert!(lat_rx.try_recv().is_err());
+
+        drop(os_tx);
+        task.await.unwrap().unwrap();
+    }
+
+    #[cfg(feature = "trace-latency")]
+    #[tokio::test(start_paused = true)]
+    async fn test_latency_tracer_forgets_unanswered_packets() {
+        let (lat_tx, _lat_rx) = mpsc::channel(4);
+        let mut tracer = crate::latency::LatencyTracer::new(lat_tx);
+        tracer.start_batch(tokio::time::Instant::now());
+
+        // Nothing answers a bare ACK or a RST
+        tracer.ingress(&build_tcp_v4(40000, [1, 2, 3, 4], 443, TcpControl::None, Some(TcpSeqNumber(1)), &[]));
+        tracer.ingress(&build_tcp_v4(40000, [1, 2, 3, 4], 443, TcpControl::Rst, None, &[]));
+        assert!(tracer.pending.is_empty());
+
+        // A closed tunnel forgets its flow, and so does time
+        let data = build_tcp_v4(40000, [1, 2, 3, 4], 443, TcpControl::None, Some(TcpSeqNumber(1)), b"x");
+        tracer.ingress(&data);
+        assert_eq!(tracer.pending.len(), 1);
+        tracer.forget(&crate::trap::tcp_flow(&data).unwrap());
+        assert!(tracer.pending.is_empty());
+        tracer.ingress(&data);
+        time::advance(crate::constants::LATENCY_TRACE_TTL).await;
+        tracer.start_batch(tokio::time::Instant::now());
+        assert!(tracer.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rejected_reinjected_syn_is_flagged() {
+        let (mut stack, _tun_rx) = test_stack_with_tun(PrismConfig { verify_reinjected_syns: true, ..Default::default() });
+        let (req_tx, _req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+
+        stack.inject(build_syn_v4(40000, [10, 11, 12, 9], 8080));
+        stack.poll_once(Instant::from_millis(0));
+        assert_eq!(stack.stats().snapshot().reinjected_syns_rejected, 0);
+
+        // Corrupt the TCP checksum: smoltcp drops the SYN, the socket stays in Listen
+        let mut syn = build_syn_v4(40001, [10, 11, 12, 9], 8080);
+        syn[20 + 16] ^= 0xff;
+        stack.inject(syn);
+        stack.poll_once(Instant::from_millis(0));
+        assert_eq!(stack.stats().snapshot().reinjected_syns_rejected, 1);
+        assert!(stack.unverified_syns.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_request_carries_source() {
+        for mode in [HandshakeMode::Fast, HandshakeMode::Consistent] {
+            let mut stack = test_stack(PrismConfig { handshake_mode: mode, ..Default::default() });
+            let (req_tx, mut req_rx) = mpsc::channel(16);
+            stack.set_tunnel_request_sender(req_tx);
+            stack.process_ingress_packet(build_syn_v4(40000, [1, 2, 3, 4], 443));
+            let req = req_rx.try_recv().unwrap();
+            assert_eq!(req.source, "10.11.12.2:40000".parse().unwrap());
+            assert_eq!(req.target, "1.2.3.4:443".parse().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_syn_burst_is_admitted_over_several_polls() {
+        let (mut stack, _tun_rx) = test_stack_with_tun(PrismConfig { admit_per_poll: Some(50), ..Default::default() });
+        let (req_tx, mut req_rx) = mpsc::channel(1024);
+        stack.set_tunnel_request_sender(req_tx);
+
+        for i in 0..500 {
+            stack.inject(build_syn_v4(10000 + i, [1, 2, 3, 4], 443));
+        }
+        // The first 50 use up the budget of the upcoming poll, the rest go 50 per poll
+        let mut admitted = vec![std::iter::from_fn(|| req_rx.try_recv().ok()).count()];
+        for _ in 0..10 {
+            stack.poll_once(Instant::from_millis(0));
+            admitted.push(std::iter::from_fn(|| req_rx.try_recv().ok()).count());
+        }
+        assert_eq!(admitted, [50, 0, 50, 50, 50, 50, 50, 50, 50, 50, 50]);
+        assert!(stack.admission_queue.is_empty());
+        assert_eq!(stack.stats().snapshot().syns_queued, 450);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_queued_syn_expires_after_handshake_timeout() {
+        let mut stack = test_stack(PrismConfig {
+            admit_per_poll: Some(1),
+            handshake_timeout: Duration::from_secs(1),
+            ..Default::default()
+        });
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        stack.inject(build_syn_v4(40000, [1, 2, 3, 4], 443));
+        stack.inject(build_syn_v4(40001, [1, 2, 3, 4], 443));
+        assert_eq!(stack.admission_queue.len(), 1);
+
+        time::advance(Duration::from_secs(2)).await;
+        stack.poll_once(Instant::from_millis(0));
+        assert!(stack.admission_queue.is_empty());
+        assert_eq!(stack.stats().snapshot().syns_queue_expired, 1);
+        assert_eq!(req_rx.try_recv().unwrap().source, "10.11.12.2:40000".parse().unwrap());
+        assert!(req_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_full_admission_queue_refuses_syns() {
+        let config = PrismConfig { admit_per_poll: Some(1), admission_queue_cap: 2, ..Default::default() };
+        let mut stack = PrismStack::new(PrismDevice::loopback(1500, Medium::Ip), config);
+        let (req_tx, _req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+
+        // One admitted, two queued, the last one refused
+        for port in 40000..40004 {
+            stack.inject(build_syn_v4(port, [1, 2, 3, 4], 443));
+        }
+        assert_eq!(stack.admission_queue.len(), 2);
+        assert!(stack.admission_queue.iter().all(|(_, event, _)| event.packet.is_empty()));
+        let sent = stack.device.take_transmitted();
+        assert_eq!(sent.len(), 1);
+        let ip = Ipv4Packet::new_checked(&sent[0][..]).unwrap();
+        let tcp = TcpPacket::new_checked(ip.payload()).unwrap();
+        assert!(tcp.rst());
+        assert_eq!(tcp.dst_port(), 40003);
+        let stats = stack.stats().snapshot();
+        assert_eq!((stats.syns_queued, stats.syns_queue_dropped), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn test_blind_relay_policies_on_full_channel() {
+        use crate::constants::BLIND_RELAY_BACKLOG;
+        let datagram = |i: usize| build_udp_v4([1, 2, 3, 4], 53, &i.to_be_bytes());
+
+        let mut stack = test_stack(PrismConfig::default());
+        let (relay_tx, mut relay_rx) = mpsc::channel(1);
+        stack.set_blind_relay_sender(relay_tx);
+        for i in 0..3 {
+            stack.inject(datagram(i));
+        }
+        assert_eq!(relay_rx.try_recv().unwrap(), datagram(0).freeze());
+        assert!(relay_rx.try_recv().is_err());
+        assert_eq!(stack.stats().snapshot().blind_relay_dropped_full, 2);
+
+        // One packet in the channel, the ring overflows by one: datagram 1 goes
+        let mut stack = test_stack(PrismConfig { blind_relay_policy: BlindRelayPolicy::DropOldest, ..Default::default() });
+        let (relay_tx, mut relay_rx) = mpsc::channel(1);
+        stack.set_blind_relay_sender(relay_tx);
+        for i in 0..BLIND_RELAY_BACKLOG + 2 {
+            stack.inject(datagram(i));
+        }
+        assert_eq!(stack.stats().snapshot().blind_relay_dropped_oldest, 1);
+        let mut received = Vec::new();
+        while let Ok(pkt) = relay_rx.try_recv() {
+            received.push(pkt);
+            stack.poll_once(Instant::from_millis(0));
+        }
+        let expected: Vec<Bytes> =
+            std::iter::once(0).chain(2..BLIND_RELAY_BACKLOG + 2).map(|i| datagram(i).freeze()).collect();
+        assert_eq!(received, expected);
+
+        let mut stack = test_stack(PrismConfig { blind_relay_policy: BlindRelayPolicy::Block, ..Default::default() });
+        let (relay_tx, mut relay_rx) = mpsc::channel(1);
+        stack.set_blind_relay_sender(relay_tx);
+        for i in 0..3 {
+            stack.inject(datagram(i));
+        }
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            received.push(relay_rx.recv().await.unwrap());
+        }
+        received.sort();
+        let mut expected: Vec<Bytes> = (0..3).map(|i| datagram(i).freeze()).collect();
+        expected.sort();
+        assert_eq!(received, expected);
+        drop(relay_rx);
+        stack.inject(datagram(3));
+        let stats = stack.stats().snapshot();
+        assert_eq!(stats.blind_relay_dropped_blocked, 1);
+        assert_eq!(stats.blind_relay_dropped_full, 0);
+    }
+
+    #[tokio::test]
+    async fn test_memory_estimate_matches_known_sizes() {
+        let mut stack = test_stack(PrismConfig::default());
+        let (req_tx, _req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        assert_eq!(stack.memory_estimate(), MemoryEstimate::default());
+
+        let syn = build_syn_v4(40000, [1, 2, 3, 4], 443);
+        stack.inject(syn.clone());
+        stack.inject(build_syn_v4(40001, [1, 2, 3, 4], 443));
+        stack.active_tunnels.values().find_map(|tunnel| tunnel.tx_to_remote.as_ref()).unwrap().try_send(Bytes::from_static(b"x")).unwrap();
+        stack.device.tx_pool.push(BytesMut::with_capacity(4096));
+
+        let estimate = stack.memory_estimate();
+        assert_eq!(estimate.socket_buffers, 2 * (TCP_RX_BUFFER_SIZE + TCP_TX_BUFFER_SIZE));
+        assert!(estimate.tx_pool >= 4096);
+        assert_eq!(estimate.pending_packets, 2 * syn.len());
+        assert_eq!(estimate.held, 0);
+        assert_eq!(estimate.channel_buffers, stack.config.max_egress_chunk);
+        assert_eq!(estimate.socket_slots, 2 * std::mem::size_of::<SocketStorage>());
+        let expected = 2 * (TCP_RX_BUFFER_SIZE + TCP_TX_BUFFER_SIZE)
+            + estimate.socket_slots
+            + 4096
+            + 2 * syn.len()
+            + stack.config.max_egress_chunk;
+        assert!(estimate.total() >= expected && estimate.total() < expected + 4096);
+        assert_eq!(stack.stats().snapshot().memory_estimate_bytes, estimate.total() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_socket_set_is_compacted_after_spike() {
+        let mut stack = test_stack(PrismConfig::default());
+        let (req_tx, _req_rx) = mpsc::channel(1024);
+        stack.set_tunnel_request_sender(req_tx);
+        let slot_size = std::mem::size_of::<SocketStorage>();
+
+        let spike = 2 * SOCKET_COMPACT_MIN_SLOTS as u16;
+        for port in 0..spike {
+            stack.inject(build_syn_v4(20000 + port, [1, 2, 3, 4], 443));
+        }
+        stack.poll_once(Instant::now());
+        assert_eq!(stack.memory_estimate().socket_slots, spike as usize * slot_size);
+
+        // All but the tunnels in slots 0 and 3 go away; their slots stay allocated until a sweep
+        let mut handles: Vec<SocketHandle> = stack.active_tunnels.keys().copied().collect();
+        handles.sort();
+        let survivors = [handles[0], handles[3]];
+        for &handle in handles.iter().filter(|handle| !survivors.contains(handle)) {
+            stack.sockets.get_mut::<tcp::Socket>(handle).abort();
+        }
+        stack.pump_egress(true);
+        assert_eq!(stack.active_tunnels.len(), 2);
+        assert_eq!(stack.memory_estimate().socket_slots, spike as usize * slot_size);
+
+        stack.compact_sockets();
+        assert_eq!(stack.memory_estimate().socket_slots, 4 * slot_size);
+        assert_eq!(stack.stats().snapshot().socket_set_compactions, 1);
+        // The survivors are untouched under their old handles
+        for survivor in survivors {
+            assert!(stack.sockets.get::<tcp::Socket>(survivor).is_open());
+            assert!(stack.active_tunnels.contains_key(&survivor));
+        }
+        assert_eq!(stack.sockets.iter().map(|(handle, _)| handle).collect::<Vec<_>>(), survivors);
+
+        // Nothing left to reclaim; new tunnels take the free slots first
+        stack.compact_sockets();
+        assert_eq!(stack.stats().snapshot().socket_set_compactions, 1);
+        for port in 0..3 {
+            stack.inject(build_syn_v4(30000 + port, [1, 2, 3, 4], 443));
+        }
+        stack.poll_once(Instant::now());
+        assert_eq!(stack.memory_estimate().socket_slots, 5 * slot_size);
+    }
+
+    #[tokio::test]
+    async fn test_full_tx_queue_drops_and_closed_tx_queue_stops_run() {
+        let (os_tx, os_rx) = mpsc::channel(16);
+        let (tun_tx, mut tun_rx) = mpsc::channel(1);
+        let stack = PrismStack::new(PrismDevice::new(os_rx, tun_tx, 1500, Medium::Ip), PrismConfig::default());
+        let stats = stack.stats();
+        let run = tokio::spawn(stack.run());
+
+        // The writer is alive but behind: the second echo reply doesn't fit and is dropped
+        os_tx.send(build_ping_v4([10, 11, 12, 1])).await.unwrap();
+        os_tx.send(build_ping_v4([10, 11, 12, 1])).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while stats.snapshot().tx_queue_full == 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(tun_rx.recv().await.is_some());
+        assert!(!run.is_finished());
+
+        // The writer is gone: the next packet the stack emits ends `run`
+        drop(tun_rx);
+        os_tx.send(build_ping_v4([10, 11, 12, 1])).await.unwrap();
+        let err = tokio::time::timeout(Duration::from_secs(5), run).await.unwrap().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "TX queue closed: the TUN writer is gone");
+        assert_eq!(stats.snapshot().tx_queue_full, 1);
+    }
+
+    #[tokio::test]
+    async fn test_stray_tcp_replies_are_dropped() {
+        let mut stack = test_stack(PrismConfig::default());
+        let (req_tx, _req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        stack.inject(build_syn_v4(40000, [1, 2, 3, 4], 443));
+        stack.device.pending_packets.clear();
+
+        // Replies to nothing we know of
+        stack.inject(build_tcp_v4(40001, [5, 6, 7, 8], 443, TcpControl::Rst, None, &[]));
+        stack.inject(build_tcp_v4(40001, [5, 6, 7, 8], 443, TcpControl::Syn, Some(TcpSeqNumber(1)), &[]));
+        assert!(stack.device.pending_packets.is_empty());
+        assert_eq!(stack.stats().snapshot().stray_tcp_dropped, 2);
+
+        // Unknown ACKs still reach smoltcp (which resets them), as do RSTs for a known flow
+        // or the gateway itself
+        stack.inject(build_tcp_v4(40001, [5, 6, 7, 8], 443, TcpControl::None, Some(TcpSeqNumber(1)), &[]));
+        stack.inject(build_tcp_v4(40000, [1, 2, 3, 4], 443, TcpControl::Rst, None, &[]));
+        stack.inject(build_tcp_v4(40002, [10, 11, 12, 1], 8080, TcpControl::Rst, None, &[]));
+        assert_eq!(stack.device.pending_packets.len(), 3);
+        assert_eq!(stack.stats().snapshot().stray_tcp_dropped, 2);
+    }
+
+    #[tokio::test]
+    async fn test_syn_without_relayer_is_refused() {
+        let mut stack = PrismStack::new(PrismDevice::loopback(1500, Medium::Ip), PrismConfig::default());
+        stack.inject(build_syn_v4(40000, [1, 2, 3, 4], 443));
+        stack.inject(build_syn_v4(40001, [1, 2, 3, 4], 443));
+        assert!(stack.sockets.iter().next().is_none());
+        assert!(stack.device.pending_packets.is_empty());
+        let sent = stack.device.take_transmitted();
+        assert_eq!(sent.len(), 2);
+        let ip = Ipv4Packet::new_checked(&sent[0][..]).unwrap();
+        let tcp = TcpPacket::new_checked(ip.payload()).unwrap();
+        assert!(tcp.rst());
+        assert_eq!((tcp.src_port(), tcp.dst_port()), (443, 40000));
+        assert!(stack.warned_missing_relayer);
+        assert_eq!(stack.stats().snapshot().setup_request_rejected, 2);
+
+        let config = PrismConfig { missing_relayer: MissingRelayerPolicy::Drop, ..Default::default() };
+        let mut stack = PrismStack::new(PrismDevice::loopback(1500, Medium::Ip), config);
+        stack.inject(build_syn_v4(40000, [1, 2, 3, 4], 443));
+        assert!(stack.sockets.iter().next().is_none());
+        assert!(stack.device.take_transmitted().is_empty());
+        assert_eq!(stack.stats().snapshot().setup_request_rejected, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retransmit_of_a_refused_syn_is_trapped_again() {
+        // The relayer shows up between the client's first SYN and its retransmit
+        let config = PrismConfig { missing_relayer: MissingRelayerPolicy::Drop, ..Default::default() };
+        let mut stack = PrismStack::new(PrismDevice::loopback(1500, Medium::Ip), config);
+        stack.inject(build_syn_v4(40000, [1, 2, 3, 4], 443));
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        time::advance(Duration::from_secs(1)).await;
+        stack.inject(build_syn_v4(40000, [1, 2, 3, 4], 443));
+        assert_eq!(req_rx.try_recv().unwrap().target, "1.2.3.4:443".parse().unwrap());
+        assert_eq!(stack.stats().snapshot().duplicate_syns_suppressed, 0);
+
+        // A rate-limited SYN is retried once the bucket refills, not after the cache expires
+        let mut stack = test_stack(PrismConfig { syn_rate_limit: Some((1, Duration::from_secs(1))), ..Default::default() });
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        stack.process_ingress_packet(build_syn_v4(40000, [1, 2, 3, 4], 443));
+        stack.process_ingress_packet(build_syn_v4(40001, [1, 2, 3, 4], 443));
+        assert!(req_rx.try_recv().is_ok() && req_rx.try_recv().is_err());
+        time::advance(Duration::from_secs(1)).await;
+        stack.process_ingress_packet(build_syn_v4(40001, [1, 2, 3, 4], 443));
+        assert_eq!(req_rx.try_recv().unwrap().source, "10.11.12.2:40001".parse().unwrap());
+        let snapshot = stack.stats().snapshot();
+        assert_eq!((snapshot.rate_limited, snapshot.duplicate_syns_suppressed), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn test_setup_failures_counted_by_cause() {
+        // Relayer gone: the request can't be sent
+        let mut stack = test_stack(PrismConfig::default());
+        let (req_tx, req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        drop(req_rx);
+        stack.process_ingress_packet(build_syn_v4(40000, [1, 2, 3, 4], 443));
+        assert_eq!(stack.stats().snapshot().setup_request_rejected, 1);
+
+        // Port 0 can't be listened on
+        let (req_tx, _req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        stack.set_event_sender(event_tx);
+        stack.process_ingress_packet(build_syn_v4(40001, [1, 2, 3, 4], 0));
+        assert_eq!(stack.stats().snapshot().setup_listen_failed, 1);
+        assert!(matches!(event_rx.try_recv().unwrap(), TunnelEvent::Rejected { reason: CloseReason::ListenFailed, .. }));
+
+        // Likewise once a Consistent handshake is accepted
+        let mut stack = test_stack(PrismConfig { handshake_mode: HandshakeMode::Consistent, ..Default::default() });
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        stack.set_event_sender(event_tx);
+        stack.process_ingress_packet(build_syn_v4(40001, [1, 2, 3, 4], 0));
+        req_rx.recv().await.unwrap().response_tx.take().unwrap().send(true).unwrap();
+        let (key, success) = stack.feedback_rx.recv().await.unwrap();
+        stack.handle_handshake_feedback(key, success, 1024, 1024);
+        assert_eq!(stack.stats().snapshot().setup_listen_failed, 1);
+        let target = "1.2.3.4:0".parse().unwrap();
+        assert!(matches!(
+            event_rx.try_recv().unwrap(),
+            TunnelEvent::Rejected { target: t, reason: CloseReason::ListenFailed, .. } if t == target
+        ));
+
+        // The relayer refuses a Consistent handshake
+        let mut stack = test_stack(PrismConfig { handshake_mode: HandshakeMode::Consistent, ..Default::default() });
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        stack.process_ingress_packet(build_syn_v4(40000, [1, 2, 3, 4], 443));
+        req_rx.recv().await.unwrap().response_tx.take().unwrap().send(false).unwrap();
+        let (key, success) = stack.feedback_rx.recv().await.unwrap();
+        stack.handle_handshake_feedback(key, success, 1024, 1024);
+        let stats = stack.stats().snapshot();
+        assert_eq!(stats.setup_upstream_failed, 1);
+        assert_eq!(stats.setup_handshake_timeout, 0);
+
+        // The client never ACKs the SYN-ACK
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig { timeout: Some(Duration::from_secs(1)), ..Default::default() });
+        let (req_tx, _req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        stack.set_event_sender(event_tx);
+        stack.process_ingress_packet(build_syn_v4(40000, [10, 11, 12, 9], 8080));
+        stack.iface.poll(Instant::from_millis(0), &mut stack.device, &mut stack.sockets);
+        assert!(tun_rx.try_recv().is_ok());
+        stack.pump_egress(true);
+        stack.iface.poll(Instant::from_millis(2000), &mut stack.device, &mut stack.sockets);
+        stack.pump_egress(true);
+        assert!(stack.active_tunnels.is_empty());
+        assert_eq!(stack.stats().snapshot().setup_half_open_timeout, 1);
+        // Requested, but never established
+        assert!(matches!(event_rx.try_recv().unwrap(), TunnelEvent::Opened { .. }));
+        assert!(matches!(event_rx.try_recv().unwrap(), TunnelEvent::Closed { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_compact_pending_syn_is_rebuilt_on_success() {
+        let mut held = Vec::new();
+        for compact_pending_syns in [false, true] {
+            let config = PrismConfig { handshake_mode: HandshakeMode::Consistent, compact_pending_syns, ..Default::default() };
+            let (mut stack, mut tun_rx) = test_stack_with_tun(config);
+            let (req_tx, mut req_rx) = mpsc::channel(4);
+            stack.set_tunnel_request_sender(req_tx);
+
+            let mut client = TestClient::connect(40000, 8080);
+            client.exchange(&mut stack, &mut tun_rx, true);
+            let pending = stack.pending_syns.values().next().unwrap();
+            assert_eq!(matches!(pending.syn, HeldSyn::Summary(_)), compact_pending_syns);
+            held.push(stack.memory_estimate().held);
+
+            // The relayer accepts: the (rebuilt) SYN opens the connection as the original would
+            let mut request = req_rx.try_recv().unwrap();
+            request.response_tx.take().unwrap().send(true).unwrap();
+            let (key, success) = stack.feedback_rx.recv().await.unwrap();
+            stack.handle_handshake_feedback(key, success, 64 * 1024, 64 * 1024);
+            client.exchange(&mut stack, &mut tun_rx, true);
+            assert_eq!(client.socket().state(), tcp::State::Established);
+            client.socket().send_slice(b"hello").unwrap();
+            client.exchange(&mut stack, &mut tun_rx, true);
+            assert_eq!(request.rx.try_recv().unwrap(), Bytes::from_static(b"hello"));
+        }
+        // The whole SYN (IP and TCP headers with options) against nothing
+        assert!(held[0] >= 44, "{:?}", held);
+        assert_eq!(held[1], 0);
+    }
+
+    #[tokio::test]
+    async fn test_consistent_syn_retransmit_suppressed() {
+        let mut stack = test_stack(PrismConfig {
+            handshake_mode: HandshakeMode::Consistent,
+            ..Default::default()
+        });
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+
+        let syn = build_syn_v4(40000, [1, 2, 3, 4], 443);
+        stack.process_ingress_packet(syn.clone());
+        stack.process_ingress_packet(syn);
+
+        assert!(req_rx.try_recv().is_ok());
+        assert!(req_rx.try_recv().is_err());
+        assert_eq!(stack.pending_syns.len(), 1);
+        assert_eq!(stack.stats().snapshot().duplicate_syns_suppressed, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fast_syn_retransmit_goes_to_existing_socket() {
+        let mut stack = test_stack(PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        let syn = build_syn_v4(40000, [1, 2, 3, 4], 443);
+
+        stack.process_ingress_packet(syn.clone());
+        let handle = *stack.active_tunnels.keys().next().unwrap();
+        time::advance(Duration::from_secs(1)).await;
+        stack.process_ingress_packet(syn.clone());
+
+        assert!(req_rx.try_recv().is_ok());
+        assert!(req_rx.try_recv().is_err());
+        assert_eq!(stack.active_tunnels.len(), 1);
+        assert_eq!(stack.device.pending_packets.len(), 2);
+        assert!(stack.dirty.contains(&handle));
+        assert_eq!(stack.stats().snapshot().duplicate_syns_suppressed, 1);
+
+        // Once the entry expired the same 4-tuple is a new connection
+        time::advance(SYN_CACHE_TTL).await;
+        stack.expire_syn_cache(time::Instant::now());
+        assert!(stack.syn_cache.is_empty());
+        stack.process_ingress_packet(syn);
+        assert!(req_rx.try_recv().is_ok());
+        assert_eq!(stack.active_tunnels.len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_second_syn_to_a_half_open_flow() {
+        let syn_from = |seq| build_tcp_v4_seq(40000, [10, 11, 12, 9], 8080, TcpControl::Syn, TcpSeqNumber(seq), None, &[]);
+        // The SYN-ACKs the client got, by the sequence number they acknowledge
+        let acked = |tun_rx: &mut mpsc::Receiver<Bytes>| {
+            std::iter::from_fn(|| tun_rx.try_recv().ok())
+                .map(|pkt| {
+                    let ip = Ipv4Packet::new_checked(&pkt[..]).unwrap();
+                    TcpPacket::new_checked(ip.payload()).unwrap().ack_number().0
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Coalesced, the socket ignores the new SYN; restarted, it answers it
+        for (policy, answered) in [(DuplicateSynPolicy::Coalesce, &[][..]), (DuplicateSynPolicy::Restart, &[5001][..])] {
+            let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig { duplicate_syns: policy, ..Default::default() });
+            let (req_tx, mut req_rx) = mpsc::channel(16);
+            stack.set_tunnel_request_sender(req_tx);
+
+            stack.process_ingress_packet(syn_from(1000));
+            stack.poll_once(Instant::now());
+            assert_eq!(acked(&mut tun_rx), [1001]);
+
+            // The same SYN again is a retransmit either way
+            time::advance(Duration::from_millis(1)).await;
+            stack.process_ingress_packet(syn_from(1000));
+            stack.poll_once(Instant::now());
+            assert!(acked(&mut tun_rx).iter().all(|&ack| ack == 1001));
+
+            // A new attempt from the same port 1ms later: still one socket and one tunnel
+            time::advance(Duration::from_millis(1)).await;
+            stack.process_ingress_packet(syn_from(5000));
+            stack.poll_once(Instant::now());
+            assert_eq!(acked(&mut tun_rx), answered);
+            assert!(req_rx.try_recv().is_ok());
+            assert!(req_rx.try_recv().is_err());
+            assert_eq!(stack.active_tunnels.len(), 1);
+            let stats = stack.stats().snapshot();
+            let restarted = (policy == DuplicateSynPolicy::Restart) as u64;
+            assert_eq!((stats.duplicate_syns_suppressed, stats.handshakes_restarted), (2 - restarted, restarted));
+        }
+
+        // A held Consistent SYN is swapped for the new one
+        let mut stack = test_stack(PrismConfig {
+            handshake_mode: HandshakeMode::Consistent,
+            duplicate_syns: DuplicateSynPolicy::Restart,
+            ..Default::default()
+        });
+        let (req_tx, _req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        stack.process_ingress_packet(syn_from(1000));
+        stack.process_ingress_packet(syn_from(5000));
+        let held = &stack.pending_syns.values().next().unwrap().syn;
+        assert!(matches!(held, HeldSyn::Packet(packet) if crate::trap::tcp_seq(packet) == Some(5000)));
+    }
+
+    #[tokio::test]
+    async fn test_egress_pump_visits_only_dirty_tunnels() {
+        let mut stack = test_stack(PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(64);
+        stack.set_tunnel_request_sender(req_tx);
+
+        for port in 40000..40032 {
+            stack.process_ingress_packet(build_syn_v4(port, [1, 2, 3, 4], 443));
+        }
+        let mut relayer = Vec::new();
+        while let Ok(req) = req_rx.try_recv() {
+            relayer.push(req);
+        }
+        assert_eq!(stack.active_tunnels.len(), 32);
+        assert_eq!(stack.flow_index.len(), 32);
+
+        // A segment for one known flow marks just that tunnel; unknown flows mark nothing
+        stack.process_ingress_packet(build_tcp_v4(40007, [1, 2, 3, 4], 443, TcpControl::None, Some(TcpSeqNumber(1)), b"x"));
+        stack.process_ingress_packet(build_tcp_v4(50000, [1, 2, 3, 4], 443, TcpControl::None, Some(TcpSeqNumber(1)), b"x"));
+        let flow = ("10.11.12.2:40007".parse().unwrap(), "1.2.3.4:443".parse().unwrap());
+        assert_eq!(stack.dirty, HashSet::from([stack.flow_index[&flow]]));
+
+        stack.pump_egress(false);
+        assert_eq!(stack.stats().snapshot().egress_sockets_visited, 1);
+        assert!(stack.dirty.is_empty());
+
+        // The periodic sweep still visits everything
+        stack.pump_egress(true);
+        assert_eq!(stack.stats().snapshot().egress_sockets_visited, 33);
+    }
+
+    #[tokio::test]
+    async fn test_egress_is_chunked_to_max_egress_chunk() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig { max_egress_chunk: 16 * 1024, ..Default::default() });
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+
+        let mut client = TestClient::connect(40000, 8080);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        assert_eq!(client.socket().state(), tcp::State::Established);
+        let mut relayer = req_rx.try_recv().unwrap();
+
+        // Let 100KB pile up in the stack's socket before the pump sees any of it
+        let data: Vec<u8> = (0..100 * 1024).map(|i| (i % 251) as u8).collect();
+        assert_eq!(client.socket().send_slice(&data).unwrap(), data.len());
+        client.exchange(&mut stack, &mut tun_rx, false);
+        stack.pump_egress(true);
+
+        let mut received = Vec::new();
+        let mut sizes = Vec::new();
+        while let Ok(chunk) = relayer.rx.try_recv() {
+            sizes.push(chunk.len());
+            received.extend_from_slice(&chunk);
+        }
+        assert_eq!(received, data);
+        assert!(sizes.iter().all(|&n| n <= 16 * 1024), "{:?}", sizes);
+        assert!(sizes.len() >= 7);
+    }
+
+    #[tokio::test]
+    async fn test_remote_close_sends_fin_to_client() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+
+        let mut client = TestClient::connect(40000, 8080);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        let relayer = req_rx.try_recv().unwrap();
+        let handle = *stack.active_tunnels.keys().next().unwrap();
+
+        // Remote sends a last message and closes its write half
+        relayer.tx.send(Bytes::from_static(b"bye")).await.unwrap();
+        drop(relayer.tx);
+        let (h, data) = stack.ingress_streams.next().await.unwrap();
+        stack.handle_remote_data(h, data);
+        let (h, data) = stack.ingress_streams.next().await.unwrap();
+        assert_eq!((h, data.clone()), (handle, None));
+        stack.handle_remote_data(h, data);
+        client.exchange(&mut stack, &mut tun_rx, true);
+
+        let server_state = stack.sockets.get::<tcp::Socket>(handle).state();
+        assert!(matches!(server_state, tcp::State::FinWait1 | tcp::State::FinWait2), "{}", server_state);
+        assert_eq!(client.socket().state(), tcp::State::CloseWait);
+        let mut buf = [0u8; 8];
+        assert_eq!(client.socket().recv_slice(&mut buf).unwrap(), 3);
+
+        // Client -> remote still drains after the half-close
+        let mut relayer_rx = relayer.rx;
+        client.socket().send_slice(b"late").unwrap();
+        client.exchange(&mut stack, &mut tun_rx, true);
+        assert_eq!(relayer_rx.try_recv().unwrap(), Bytes::from_static(b"late"));
+    }
+
+    #[tokio::test]
+    async fn test_closing_sockets_are_removed_in_the_same_pump() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+
+        // Remote closes first, then the client's last data arrives with its FIN: the socket
+        // is in TimeWait with the data still unread
+        let mut client = TestClient::connect(40000, 8080);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        let relayer = req_rx.try_recv().unwrap();
+        let handle = *stack.active_tunnels.keys().next().unwrap();
+        stack.handle_remote_data(handle, None);
+        client.exchange(&mut stack, &mut tun_rx, false);
+        client.socket().send_slice(b"last words").unwrap();
+        client.socket().close();
+        client.exchange(&mut stack, &mut tun_rx, false);
+        assert_eq!(stack.sockets.get::<tcp::Socket>(handle).state(), tcp::State::TimeWait);
+
+        let mut relayer_rx = relayer.rx;
+        stack.pump_egress(true);
+        assert_eq!(relayer_rx.try_recv().unwrap(), Bytes::from_static(b"last words"));
+        assert!(stack.active_tunnels.is_empty());
+
+        // Data followed by a reset: nothing to deliver, gone on the first visit
+        let mut client = TestClient::connect(40001, 8080);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        let _relayer = req_rx.try_recv().unwrap();
+        client.socket().send_slice(b"never mind").unwrap();
+        client.exchange(&mut stack, &mut tun_rx, false);
+        client.socket().abort();
+        client.exchange(&mut stack, &mut tun_rx, false);
+        stack.pump_egress(true);
+        assert!(stack.active_tunnels.is_empty());
+        assert!(stack.sockets.iter().next().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_relayer_abort_resets_fast_tunnel() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        stack.set_event_sender(event_tx);
+
+        let mut client = TestClient::connect(40000, 8080);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        assert_eq!(client.socket().state(), tcp::State::Established);
+        let request = req_rx.try_recv().unwrap();
+        let handle = *stack.active_tunnels.keys().next().unwrap();
+        assert!(matches!(event_rx.try_recv().unwrap(), TunnelEvent::Opened { .. }));
+        assert!(matches!(event_rx.try_recv().unwrap(), TunnelEvent::Established { .. }));
+
+        // The target turned out to be unreachable: RST, not the FIN of a dropped `tx`
+        request.abort.abort();
+        stack.poll_once(Instant::from_millis(0));
+        client.exchange(&mut stack, &mut tun_rx, true);
+        assert_eq!(client.socket().state(), tcp::State::Closed);
+        assert!(stack.active_tunnels.is_empty());
+        assert!(matches!(
+            event_rx.try_recv().unwrap(),
+            TunnelEvent::Closed { handle: h, reason: CloseReason::Aborted, .. } if h == handle
+        ));
+        assert_eq!(stack.stats().snapshot().relayer_aborts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_relayer_abort_refuses_pending_consistent_handshake() {
+        let config = PrismConfig { handshake_mode: HandshakeMode::Consistent, ..Default::default() };
+        let (mut stack, mut tun_rx) = test_stack_with_tun(config);
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        stack.set_event_sender(event_tx);
+
+        let mut client = TestClient::connect(40000, 8080);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        let mut request = req_rx.try_recv().unwrap();
+        assert_eq!(stack.pending_syns.len(), 1);
+
+        // The relayer gives up, and its late answer comes after the abort
+        request.abort.abort();
+        request.response_tx.take().unwrap().send(true).unwrap();
+        stack.poll_once(Instant::from_millis(0));
+        assert!(stack.pending_syns.is_empty());
+        let (key, success) = stack.feedback_rx.recv().await.unwrap();
+        stack.handle_handshake_feedback(key, success, 4096, 4096);
+        assert!(stack.active_tunnels.is_empty());
+        assert!(stack.id_index.is_empty() && stack.registered_ips.is_empty());
+
+        client.exchange(&mut stack, &mut tun_rx, true);
+        assert_eq!(client.socket().state(), tcp::State::Closed);
+        assert_eq!(
+            event_rx.try_recv().unwrap(),
+            TunnelEvent::Rejected { id: request.id, target: request.target, reason: CloseReason::Aborted }
+        );
+        assert!(event_rx.try_recv().is_err());
+        assert_eq!(stack.stats().snapshot().relayer_aborts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_flag_segments_are_dropped() {
+        let mut stack = test_stack(PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+
+        // SYN+FIN, SYN+RST, NULL, XMAS
+        for (port, flags) in (40000..).zip([0x03, 0x06, 0x00, 0x29]) {
+            let mut pkt = build_syn_v4(port, [1, 2, 3, 4], 443);
+            pkt[20 + 13] = flags;
+            stack.process_ingress_packet(pkt);
+        }
+
+        assert!(req_rx.try_recv().is_err());
+        assert!(stack.active_tunnels.is_empty());
+        assert!(stack.device.pending_packets.is_empty());
+        assert_eq!(stack.stats().snapshot().invalid_tcp_flags_dropped, 4);
+
+        // With the check off, SYN+FIN is trapped like before
+        let mut stack = test_stack(PrismConfig { drop_invalid_flags: false, ..Default::default() });
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        let mut pkt = build_syn_v4(40000, [1, 2, 3, 4], 443);
+        pkt[20 + 13] = 0x03;
+        stack.process_ingress_packet(pkt);
+        assert!(req_rx.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_remote_data_beyond_send_buffer_is_not_lost() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+
+        let mut client = TestClient::connect(40000, 8080);
+        client.socket().set_ack_delay(None);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        let relayer = req_rx.try_recv().unwrap();
+
+        // 40 x 64KB overflows the 2MB send buffer
+        let chunks: Vec<Bytes> = (0..40u8).map(|i| Bytes::from(vec![i; 64 * 1024])).collect();
+        for chunk in &chunks {
+            relayer.tx.send(chunk.clone()).await.unwrap();
+        }
+        let expected: Vec<u8> = chunks.concat();
+
+        let mut received = Vec::new();
+        let mut parked = false;
+        while received.len() < expected.len() {
+            while let Ok(Some((handle, data))) = time::timeout(Duration::from_millis(10), stack.ingress_streams.next()).await {
+                stack.handle_remote_data(handle, data);
+            }
+            parked |= !stack.pending_ingress.is_empty();
+            client.exchange(&mut stack, &mut tun_rx, true);
+            let mut buf = vec![0; 512 * 1024];
+            while let Ok(n @ 1..) = client.socket().recv_slice(&mut buf) {
+                received.extend_from_slice(&buf[..n]);
+            }
+        }
+        assert!(parked);
+        assert!(received == expected);
+        assert!(stack.pending_ingress.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_remote_data_for_a_reset_client_is_counted() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+
+        let mut client = TestClient::connect(40000, 8080);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        let _relayer = req_rx.try_recv().unwrap();
+        let handle = *stack.active_tunnels.keys().next().unwrap();
+
+        stack.handle_remote_data(handle, Some(Bytes::from_static(b"in time")));
+        assert!(stack.pending_ingress.is_empty());
+        stack.sockets.get_mut::<tcp::Socket>(handle).abort();
+        stack.handle_remote_data(handle, Some(Bytes::from_static(b"too late")));
+        assert!(stack.pending_ingress.is_empty());
+        let stats = stack.stats().snapshot();
+        assert_eq!((stats.bytes_from_remote_v4, stats.bytes_from_remote_undeliverable), (7, 8));
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_states_on_demand() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+        let inspector = stack.inspector();
+
+        let mut client = TestClient::connect(40000, 8080);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        let relayer = req_rx.try_recv().unwrap();
+        let handle = *stack.active_tunnels.keys().next().unwrap();
+        assert_eq!(stack.tunnel_state(handle), Some(tcp::State::Established));
+        assert_eq!(stack.tunnel_states(), vec![TunnelSnapshot {
+            handle,
+            id: relayer.id,
+            source: "10.11.12.2:40000".parse().unwrap(),
+            target: "10.11.12.9:8080".parse().unwrap(),
+            state: tcp::State::Established,
+        }]);
+
+        // Through the inspector, answered by the stack's loop
+        client.socket().close();
+        client.exchange(&mut stack, &mut tun_rx, true);
+        let asked = tokio::spawn(async move { inspector.tunnel_state(handle).await });
+        tokio::task::yield_now().await;
+        stack.poll_once(Instant::from_millis(0));
+        assert_eq!(asked.await.unwrap(), Some(tcp::State::CloseWait));
+
+        let inspector = stack.inspector();
+        drop(stack);
+        assert_eq!(inspector.tunnel_states().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_reordered_ingress_is_written_in_order() {
+        // A small send buffer, so a message released from the reorder buffer gets parked too
+        let config = PrismConfig { ingress_reorder: Some(4), tcp_tx_buffer: 4096, ..Default::default() };
+        let (mut stack, mut tun_rx) = test_stack_with_tun(config);
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+
+        let mut client = TestClient::connect(40000, 8080);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        let relayer = req_rx.try_recv().unwrap();
+
+        let chunks: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 3000]).collect();
+        // 1 is duplicated, 0 arrives last and releases everything held
+        for seq in [2, 1, 3, 1, 0] {
+            relayer.tx.send(sequenced_chunk(seq, &chunks[seq as usize])).await.unwrap();
+        }
+        let mut received = Vec::new();
+        let mut parked = false;
+        while received.len() < 4 * 3000 {
+            while let Ok(Some((handle, data))) = time::timeout(Duration::from_millis(10), stack.ingress_streams.next()).await {
+                stack.handle_remote_data(handle, data);
+            }
+            parked |= !stack.pending_ingress.is_empty();
+            client.exchange(&mut stack, &mut tun_rx, true);
+            let mut buf = vec![0; 16 * 1024];
+            while let Ok(n @ 1..) = client.socket().recv_slice(&mut buf) {
+                received.extend_from_slice(&buf[..n]);
+            }
+        }
+        assert!(parked);
+        assert!(received == chunks.concat());
+
+        // The remote closes with a message missing: reset instead of a FIN after a hole
+        relayer.tx.send(sequenced_chunk(5, b"after a gap")).await.unwrap();
+        drop(relayer.tx);
+        while let Ok(Some((handle, data))) = time::timeout(Duration::from_millis(10), stack.ingress_streams.next()).await {
+            stack.handle_remote_data(handle, data);
+        }
+        client.exchange(&mut stack, &mut tun_rx, true);
+        assert_eq!(client.socket().state(), tcp::State::Closed);
+        assert_eq!(stack.stats().snapshot().ingress_reorder_aborts, 1);
+        assert!(stack.active_tunnels.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reorder_overflow_and_malformed_messages_reset_the_tunnel() {
+        let config = PrismConfig { ingress_reorder: Some(2), ..Default::default() };
+        let (mut stack, mut tun_rx) = test_stack_with_tun(config);
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+
+        let mut clients = [TestClient::connect(40000, 8080), TestClient::connect(40001, 8080)];
+        let mut relayers = Vec::new();
+        for client in &mut clients {
+            client.exchange(&mut stack, &mut tun_rx, true);
+            relayers.push(req_rx.try_recv().unwrap());
+        }
+        // Three messages waiting for 0 on one tunnel, no sequence number on the other
+        for seq in 1..=3 {
+            relayers[0].tx.send(sequenced_chunk(seq, b"early")).await.unwrap();
+        }
+        relayers[1].tx.send(Bytes::from_static(b"raw")).await.unwrap();
+        while let Ok(Some((handle, data))) = time::timeout(Duration::from_millis(10), stack.ingress_streams.next()).await {
+            stack.handle_remote_data(handle, data);
+        }
+        // Both sockets were aborted and reaped (the test clients share one wire, so only the
+        // stack's side is checked)
+        clients[0].exchange(&mut stack, &mut tun_rx, true);
+        assert!(stack.active_tunnels.is_empty());
+        assert_eq!(stack.stats().snapshot().ingress_reorder_aborts, 2);
+    }
+
+    #[tokio::test]
+    async fn test_ingress_batch_is_shared_round_robin() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(8);
+        stack.set_tunnel_request_sender(req_tx);
+
+        let mut relayers = Vec::new();
+        for port in 40000..40005 {
+            let mut client = TestClient::connect(port, 8080);
+            client.exchange(&mut stack, &mut tun_rx, false);
+            relayers.push(req_rx.try_recv().unwrap());
+        }
+        // The first tunnel floods, the others have one message each
+        for _ in 0..100 {
+            relayers[0].tx.send(Bytes::from_static(&[0; 1024])).await.unwrap();
+        }
+        for relayer in &relayers[1..] {
+            relayer.tx.send(Bytes::from_static(b"hi")).await.unwrap();
+        }
+
+        let first = stack.ingress_streams.next().await.unwrap();
+        stack.handle_ingress_batch(first);
+        assert!(stack.active_tunnels.values().all(|tunnel| tunnel.bytes_rx > 0));
+        let total: u64 = stack.active_tunnels.values().map(|tunnel| tunnel.bytes_rx).sum();
+        assert_eq!(total, (INGRESS_BATCH_SIZE as u64 - 4) * 1024 + 4 * 2);
+    }
+
+    #[tokio::test]
+    async fn test_out_of_order_segments_reach_egress_in_order() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+        let now = Instant::from_millis(0);
+
+        stack.process_ingress_packet(build_syn_v4(40000, [10, 11, 12, 9], 8080));
+        stack.iface.poll(now, &mut stack.device, &mut stack.sockets);
+        let mut relayer = req_rx.try_recv().unwrap();
+        let syn_ack = tun_rx.try_recv().unwrap();
+        let ip = Ipv4Packet::new_checked(&syn_ack[..]).unwrap();
+        let ack = Some(TcpPacket::new_checked(ip.payload()).unwrap().seq_number() + 1);
+
+        // Handshake completes with seq 1001; then the second half overtakes the first
+        let segments = [(1001, &b""[..]), (1007, b"world"), (1004, b"lo "), (1001, b"hel")];
+        for (seq, payload) in segments {
+            let segment = build_tcp_v4_seq(40000, [10, 11, 12, 9], 8080, TcpControl::None, TcpSeqNumber(seq), ack, payload);
+            stack.process_ingress_packet(segment);
+            stack.iface.poll(now, &mut stack.device, &mut stack.sockets);
+            stack.pump_egress(false);
+            if seq != 1001 {
+                assert!(relayer.rx.try_recv().is_err());
+            }
+        }
+
+        let mut received = Vec::new();
+        while let Ok(data) = relayer.rx.try_recv() {
+            received.extend_from_slice(&data);
+        }
+        assert_eq!(received, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn test_client_fin_closes_egress_channel() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+
+        let mut client = TestClient::connect(40000, 8080);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        let mut relayer = req_rx.try_recv().unwrap();
+        let handle = *stack.active_tunnels.keys().next().unwrap();
+
+        client.socket().send_slice(b"last words").unwrap();
+        client.socket().close();
+        client.exchange(&mut stack, &mut tun_rx, true);
+
+        // Pending data is delivered first, then the channel ends
+        assert_eq!(relayer.rx.recv().await.unwrap(), Bytes::from_static(b"last words"));
+        assert!(relayer.rx.recv().await.is_none());
+        assert_eq!(stack.sockets.get::<tcp::Socket>(handle).state(), tcp::State::CloseWait);
+
+        // Finished isn't detached: other channels can't be attached
+        assert!(stack.active_tunnels[&handle].client_fin);
+        let (tx_to_remote, _rx) = mpsc::channel(1);
+        let (_tx, rx_from_remote) = mpsc::channel(1);
+        let channels = TunnelChannels { target: "10.11.12.9:8080".parse().unwrap(), tx_to_remote, rx_from_remote };
+        assert!(stack.attach_tunnel(handle, channels).is_err());
+
+        // Remote -> client still works on the half-closed tunnel
+        relayer.tx.send(Bytes::from_static(b"reply")).await.unwrap();
+        let (h, data) = stack.ingress_streams.next().await.unwrap();
+        stack.handle_remote_data(h, data);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        let mut buf = [0u8; 8];
+        assert_eq!(client.socket().recv_slice(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"reply");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_syn_burst_is_coalesced_into_one_request() {
+        let mut stack = test_stack(PrismConfig {
+            syn_coalesce_window: Some(Duration::from_millis(20)),
+            ..Default::default()
+        });
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+
+        for port in 40000..40006 {
+            stack.process_ingress_packet(build_syn_v4(port, [1, 2, 3, 4], 443));
+        }
+        stack.process_ingress_packet(build_syn_v4(40006, [5, 6, 7, 8], 443));
+        // Sockets answer right away (Fast mode); only the requests wait
+        assert_eq!(stack.active_tunnels.len(), 7);
+        assert!(req_rx.try_recv().is_err());
+
+        time::advance(Duration::from_millis(10)).await;
+        stack.flush_syn_batches(time::Instant::now());
+        assert!(req_rx.try_recv().is_err());
+
+        time::advance(Duration::from_millis(10)).await;
+        stack.flush_syn_batches(time::Instant::now());
+        let mut requests: Vec<_> = std::iter::from_fn(|| req_rx.try_recv().ok()).collect();
+        requests.sort_by_key(|req| req.coalesced.len());
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].target, "5.6.7.8:443".parse().unwrap());
+        assert!(requests[0].coalesced.is_empty());
+        let burst = &requests[1];
+        assert_eq!(burst.coalesced.len(), 5);
+        assert!(burst.coalesced.iter().all(|req| req.target == burst.target && req.coalesced.is_empty()));
+        assert!(stack.syn_batches.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rejected_syn_batch_resets_its_streams() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig {
+            syn_coalesce_window: Some(Duration::from_millis(20)),
+            ..Default::default()
+        });
+        let (req_tx, req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        stack.set_event_sender(event_tx);
+
+        for port in 40000..40003 {
+            stack.process_ingress_packet(build_syn_v4(port, [1, 2, 3, 4], 443));
+        }
+        stack.poll_once(Instant::from_millis(0));
+        for _ in 0..3 {
+            let syn_ack = tun_rx.try_recv().unwrap();
+            assert_eq!(crate::trap::tcp_flags(&syn_ack).unwrap() & 0x12, 0x12);
+        }
+
+        // The relayer is gone by the time the window closes
+        drop(req_rx);
+        time::advance(Duration::from_millis(20)).await;
+        stack.flush_syn_batches(time::Instant::now());
+        assert_eq!(stack.stats().snapshot().setup_request_rejected, 3);
+        let events: Vec<_> = std::iter::from_fn(|| event_rx.try_recv().ok()).collect();
+        let rejected = events.iter().filter(|event| matches!(event, TunnelEvent::Rejected { reason: CloseReason::Limit, .. }));
+        assert_eq!(rejected.count(), 3);
+
+        // Each client is reset and its socket goes, without a Closed event
+        stack.iface.poll(Instant::from_millis(1), &mut stack.device, &mut stack.sockets);
+        for _ in 0..3 {
+            let rst = tun_rx.try_recv().unwrap();
+            assert_ne!(crate::trap::tcp_flags(&rst).unwrap() & 0x04, 0);
+        }
+        stack.pump_egress(true);
+        assert!(stack.active_tunnels.is_empty());
+        assert!(stack.flow_index.is_empty() && stack.registered_ips.is_empty());
+        assert!(event_rx.try_recv().is_err());
+        assert_eq!(stack.stats().snapshot().setup_half_open_timeout, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_syn_flood_is_rate_limited_per_destination() {
+        let mut stack = test_stack(PrismConfig {
+            syn_rate_limit: Some((10, Duration::from_secs(1))),
+            ..Default::default()
+        });
+        let (req_tx, mut req_rx) = mpsc::channel(256);
+        stack.set_tunnel_request_sender(req_tx);
+
+        for port in 0..100 {
+            stack.process_ingress_packet(build_syn_v4(30000 + port, [1, 2, 3, 4], 443));
+        }
+        // Another destination has its own bucket
+        stack.process_ingress_packet(build_syn_v4(40000, [5, 6, 7, 8], 443));
+
+        let requests: Vec<_> = std::iter::from_fn(|| req_rx.try_recv().ok()).collect();
+        assert_eq!(requests.len(), 11);
+        assert_eq!(stack.stats().snapshot().rate_limited, 90);
+
+        // Half a window refills half the bucket
+        time::advance(Duration::from_millis(500)).await;
+        for port in 0..10 {
+            stack.process_ingress_packet(build_syn_v4(31000 + port, [1, 2, 3, 4], 443));
+        }
+        assert_eq!(std::iter::from_fn(|| req_rx.try_recv().ok()).count(), 5);
+
+        // Refilled buckets are swept
+        time::advance(Duration::from_secs(1)).await;
+        stack.sweep_syn_buckets(time::Instant::now());
+        assert!(stack.syn_buckets.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_close_drains_tunnels_and_refuses_new_ones() {
+        let (os_tx, os_rx) = mpsc::channel(64);
+        let (tun_tx, mut tun_rx) = mpsc::channel(4096);
+        let mut stack = PrismStack::new(PrismDevice::new(os_rx, tun_tx, 1500, Medium::Ip), PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        stack.set_event_sender(event_tx);
+
+        let mut client = TestClient::connect(40000, 8080);
+        client.socket().set_ack_delay(None);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        let mut relayer = req_rx.try_recv().unwrap();
+        relayer.tx.send(Bytes::from_static(b"last reply")).await.unwrap();
+
+        let closing = tokio::spawn(stack.close(Duration::from_secs(5)));
+        os_tx.send(build_syn_v4(40001, [10, 11, 12, 9], 8080)).await.unwrap();
+        // The client reads what was in flight, sees the FIN, and closes after one more request
+        let mut received = Vec::new();
+        let mut sent = false;
+        while !closing.is_finished() {
+            client.iface.poll(Instant::from_millis(0), &mut client.wire, &mut client.sockets);
+            while let Some(pkt) = client.wire.tx.pop_front() {
+                os_tx.send(BytesMut::from(&pkt[..])).await.unwrap();
+            }
+            while let Ok(pkt) = tun_rx.try_recv() {
+                client.wire.rx.push_back(pkt.to_vec());
+            }
+            let mut buf = [0u8; 64];
+            while let Ok(n @ 1..) = client.socket().recv_slice(&mut buf) {
+                received.extend_from_slice(&buf[..n]);
+            }
+            if client.socket().state() == tcp::State::CloseWait && !sent {
+                client.socket().send_slice(b"one more").unwrap();
+                client.socket().close();
+                sent = true;
+            }
+            time::sleep(Duration::from_millis(1)).await;
+        }
+        let stats = closing.await.unwrap();
+
+        assert_eq!(received, b"last reply");
+        assert_eq!(relayer.rx.recv().await.unwrap(), Bytes::from_static(b"one more"));
+        assert!(relayer.rx.recv().await.is_none());
+        assert_eq!(stats.syns_refused_closing, 1);
+        assert!(matches!(event_rx.try_recv().unwrap(), TunnelEvent::Opened { .. }));
+        assert!(matches!(event_rx.try_recv().unwrap(), TunnelEvent::Established { .. }));
+        assert!(matches!(event_rx.try_recv().unwrap(), TunnelEvent::Rejected { reason: CloseReason::Shutdown, .. }));
+        assert!(matches!(event_rx.try_recv().unwrap(), TunnelEvent::Closed { reason: CloseReason::Shutdown, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_close_handle_drains_a_running_stack() {
+        let (os_tx, os_rx) = mpsc::channel(64);
+        let (tun_tx, mut tun_rx) = mpsc::channel(4096);
+        let mut stack = PrismStack::new(PrismDevice::new(os_rx, tun_tx, 1500, Medium::Ip), PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+        let handle = stack.close_handle();
+        let running = tokio::spawn(stack.run());
+
+        // One round of packets between the client and the running stack
+        async fn shuttle(client: &mut TestClient, os_tx: &mpsc::Sender<BytesMut>, tun_rx: &mut mpsc::Receiver<Bytes>) {
+            client.iface.poll(Instant::from_millis(0), &mut client.wire, &mut client.sockets);
+            while let Some(pkt) = client.wire.tx.pop_front() {
+                os_tx.send(BytesMut::from(&pkt[..])).await.unwrap();
+            }
+            while let Ok(pkt) = tun_rx.try_recv() {
+                client.wire.rx.push_back(pkt.to_vec());
+            }
+            time::sleep(Duration::from_millis(1)).await;
+        }
+        let mut client = TestClient::connect(40000, 8080);
+        while client.socket().state() != tcp::State::Established {
+            shuttle(&mut client, &os_tx, &mut tun_rx).await;
+        }
+        let mut relayer = req_rx.recv().await.unwrap();
+
+        let closing = tokio::spawn(async move { handle.close(Duration::from_secs(5)).await });
+        while !closing.is_finished() {
+            shuttle(&mut client, &os_tx, &mut tun_rx).await;
+            if client.socket().state() == tcp::State::CloseWait {
+                client.socket().close();
+            }
+        }
+        let stats = closing.await.unwrap().unwrap();
+        assert_eq!(stats.tunnels_opened_v4, 1);
+        assert!(relayer.rx.recv().await.is_none());
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_close_resets_what_is_left_after_the_timeout() {
+        let (_os_tx, os_rx) = mpsc::channel(16);
+        let (tun_tx, mut tun_rx) = mpsc::channel(4096);
+        let mut stack = PrismStack::new(PrismDevice::new(os_rx, tun_tx, 1500, Medium::Ip), PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+        let mut client = TestClient::connect(40000, 8080);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        let _relayer = req_rx.try_recv().unwrap();
+
+        // The client never answers the FIN
+        let started = time::Instant::now();
+        stack.close(Duration::from_secs(3)).await;
+        assert_eq!(started.elapsed(), Duration::from_secs(3));
+        let mut last = None;
+        while let Ok(pkt) = tun_rx.try_recv() {
+            last = Some(pkt);
+        }
+        let last = last.unwrap();
+        let ip = Ipv4Packet::new_checked(&last[..]).unwrap();
+        assert!(TcpPacket::new_checked(ip.payload()).unwrap().rst());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_close_refuses_pending_consistent_handshakes() {
+        let (_os_tx, os_rx) = mpsc::channel(16);
+        let (tun_tx, mut tun_rx) = mpsc::channel(4096);
+        let config = PrismConfig { handshake_mode: HandshakeMode::Consistent, ..Default::default() };
+        let mut stack = PrismStack::new(PrismDevice::new(os_rx, tun_tx, 1500, Medium::Ip), config);
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        stack.set_event_sender(event_tx);
+
+        // One tunnel keeps the drain going, its client never answers the FIN
+        let mut client = TestClient::connect(40000, 8080);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        req_rx.recv().await.unwrap().response_tx.take().unwrap().send(true).unwrap();
+        let (key, success) = stack.feedback_rx.recv().await.unwrap();
+        stack.handle_handshake_feedback(key, success, 4096, 4096);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        assert_eq!(client.socket().state(), tcp::State::Established);
+        stack.process_ingress_packet(build_syn_v4(40001, [10, 11, 12, 9], 8080));
+        stack.process_ingress_packet(build_syn_v4(40002, [10, 11, 12, 9], 8080));
+        let mut answered = req_rx.try_recv().unwrap();
+        let _unanswered = req_rx.try_recv().unwrap();
+        while event_rx.try_recv().is_ok() {}
+
+        /// The client port of the next RST the stack sends
+        async fn next_rst(tun_rx: &mut mpsc::Receiver<Bytes>) -> u16 {
+            loop {
+                let pkt = tun_rx.recv().await.unwrap();
+                if crate::trap::tcp_flags(&pkt).is_some_and(|flags| flags & 0x04 != 0) {
+                    return crate::trap::tcp_flow(&pkt).unwrap().1.port();
+                }
+            }
+        }
+        let started = time::Instant::now();
+        let closing = tokio::spawn(stack.close(Duration::from_secs(3)));
+        // A handshake the relayer accepts while closing is refused right away...
+        answered.response_tx.take().unwrap().send(true).unwrap();
+        assert_eq!(next_rst(&mut tun_rx).await, 40001);
+        assert!(started.elapsed() < Duration::from_secs(3));
+        // ...and one it never answers when the stack is done
+        closing.await.unwrap();
+        let mut ports = vec![next_rst(&mut tun_rx).await, next_rst(&mut tun_rx).await];
+        ports.sort();
+        assert_eq!(ports, [40000, 40002]);
+
+        let rejected: Vec<_> = std::iter::from_fn(|| event_rx.try_recv().ok())
+            .filter_map(|event| match event {
+                TunnelEvent::Rejected { id, reason: CloseReason::Shutdown, .. } => Some(id),
+                TunnelEvent::Closed { .. } => None,
+                event => panic!("unexpected {:?}", event),
+            })
+            .collect();
+        assert_eq!(rejected, [2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_first_bytes_are_peeked() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig { peek_bytes: 5, ..Default::default() });
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+        let (peek_tx, mut peek_rx) = mpsc::channel(4);
+        stack.set_peek_sender(peek_tx);
+
+        let mut client = TestClient::connect(40000, 8080);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        let mut relayer = req_rx.try_recv().unwrap();
+        let handle = *stack.active_tunnels.keys().next().unwrap();
+        // The stack delays its ACK, which Nagle would wait for before sending more
+        client.socket().set_nagle_enabled(false);
+        client.socket().send_slice(b"hello world").unwrap();
+        client.exchange(&mut stack, &mut tun_rx, true);
+
+        // The relayer still gets every byte, once
+        let peek = peek_rx.try_recv().unwrap();
+        let target = "10.11.12.9:8080".parse().unwrap();
+        assert_eq!(peek, FlowPeek { id: relayer.id, handle, target, data: Bytes::from_static(b"hello") });
+        assert_eq!(relayer.rx.recv().await.unwrap(), Bytes::from_static(b"hello world"));
+
+        // One peek per tunnel
+        client.socket().send_slice(b"again").unwrap();
+        client.exchange(&mut stack, &mut tun_rx, true);
+        assert_eq!(relayer.rx.recv().await.unwrap(), Bytes::from_static(b"again"));
+        assert!(peek_rx.try_recv().is_err());
+        assert_eq!(stack.stats().snapshot().peeks_dropped, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_peek_spans_chunks() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig { peek_bytes: 8, ..Default::default() });
+        let (req_tx, _req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+        let (peek_tx, mut peek_rx) = mpsc::channel(4);
+        stack.set_peek_sender(peek_tx);
+        /// Sends `data` on a fresh exchange, so the stack reads it as a chunk of its own
+        fn send(client: &mut TestClient, stack: &mut PrismStack, tun_rx: &mut mpsc::Receiver<Bytes>, data: &[u8]) {
+            client.socket().send_slice(data).unwrap();
+            client.exchange(stack, tun_rx, true);
+        }
+
+        // Collected over two chunks until `peek_bytes` are there
+        let mut client = TestClient::connect(40000, 8080);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        client.socket().set_nagle_enabled(false);
+        send(&mut client, &mut stack, &mut tun_rx, b"hel");
+        assert!(peek_rx.try_recv().is_err());
+        send(&mut client, &mut stack, &mut tun_rx, b"lo world");
+        assert_eq!(peek_rx.try_recv().unwrap().data, Bytes::from_static(b"hello wo"));
+        assert!(stack.peeking.is_empty());
+
+        // A client that pauses gets a short peek after `PEEK_WINDOW`
+        let mut client = TestClient::connect(40001, 8080);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        send(&mut client, &mut stack, &mut tun_rx, b"hi");
+        stack.flush_peeks(time::Instant::now());
+        assert!(peek_rx.try_recv().is_err());
+        time::advance(PEEK_WINDOW).await;
+        stack.flush_peeks(time::Instant::now());
+        assert_eq!(peek_rx.try_recv().unwrap().data, Bytes::from_static(b"hi"));
+        send(&mut client, &mut stack, &mut tun_rx, b" there");
+        assert!(peek_rx.try_recv().is_err());
+
+        // So does one that sends its FIN, right away
+        let mut client = TestClient::connect(40002, 8080);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        client.socket().send_slice(b"bye").unwrap();
+        client.socket().close();
+        client.exchange(&mut stack, &mut tun_rx, true);
+        assert_eq!(peek_rx.try_recv().unwrap().data, Bytes::from_static(b"bye"));
+        assert!(stack.peeking.is_empty());
+        assert_eq!(stack.stats().snapshot().peeks_dropped, 0);
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_lifecycle_events() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        stack.set_event_sender(event_tx);
+        let target: SocketAddr = "10.11.12.9:8080".parse().unwrap();
+
+        let mut client = TestClient::connect(40000, 8080);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        let mut relayer = req_rx.try_recv().unwrap();
+        let handle = *stack.active_tunnels.keys().next().unwrap();
+        // The request and every event of the connection carry the same correlation ID
+        let id = relayer.id;
+        assert_eq!(event_rx.try_recv().unwrap(), TunnelEvent::Opened { id, handle, target });
+        // The client's ACK completed the handshake in the same exchange
+        assert_eq!(event_rx.try_recv().unwrap(), TunnelEvent::Established { id, handle, target });
+
+        // The clock never moves here, so a delayed ACK would never leave
+        client.socket().set_ack_delay(None);
+        client.socket().send_slice(b"ping").unwrap();
+        client.socket().close();
+        client.exchange(&mut stack, &mut tun_rx, true);
+        assert_eq!(relayer.rx.recv().await.unwrap(), Bytes::from_static(b"ping"));
+
+        relayer.tx.send(Bytes::from_static(b"pong!")).await.unwrap();
+        drop(relayer.tx);
+        while let Ok(Some((h, data))) = time::timeout(Duration::from_millis(10), stack.ingress_streams.next()).await {
+            stack.handle_remote_data(h, data);
+        }
+        client.exchange(&mut stack, &mut tun_rx, true);
+        assert!(!stack.active_tunnels.contains_key(&handle));
+        assert_eq!(
+            event_rx.try_recv().unwrap(),
+            TunnelEvent::Closed { id, handle, target, bytes_tx: 4, bytes_rx: 5, reason: CloseReason::ClientFin }
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_slow_relayer_backpressure_accumulates() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig { max_egress_chunk: 16, ..Default::default() });
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+
+        let mut client = TestClient::connect(40000, 8080);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        let mut relayer = req_rx.try_recv().unwrap();
+        let handle = *stack.active_tunnels.keys().next().unwrap();
+        assert!(stack.tunnel_backpressure().is_empty());
+
+        // 1250 chunks of 16 bytes for a 1024 message channel nobody reads
+        let data: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+        client.socket().set_ack_delay(None);
+        client.socket().send_slice(&data).unwrap();
+        client.exchange(&mut stack, &mut tun_rx, true);
+        let bp = stack.tunnel_backpressure();
+        assert_eq!(bp.len(), 1);
+        assert_eq!((bp[0].handle, bp[0].events), (handle, 1));
+
+        // The relayer catches up after 50ms: the stretch ends and the rest is sent
+        time::advance(Duration::from_millis(50)).await;
+        let mut received = Vec::new();
+        while let Ok(chunk) = relayer.rx.try_recv() {
+            received.extend_from_slice(&chunk);
+        }
+        stack.pump_egress(false);
+        while let Ok(chunk) = relayer.rx.try_recv() {
+            received.extend_from_slice(&chunk);
+        }
+        // Nothing was lost to the full channel
+        assert_eq!(received, data);
+        assert_eq!(stack.tunnel_backpressure()[0].duration, Duration::from_millis(50));
+
+        // A second stretch adds up, counted while it lasts
+        client.socket().send_slice(&data).unwrap();
+        client.exchange(&mut stack, &mut tun_rx, true);
+        time::advance(Duration::from_millis(30)).await;
+        let bp = stack.tunnel_backpressure()[0];
+        assert_eq!(bp.events, 2);
+        assert_eq!(bp.duration, Duration::from_millis(80));
+        assert_eq!(stack.stats().snapshot().egress_backpressure_events, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tunnel_state_tracks_activity() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+
+        let opened_at = time::Instant::now();
+        let mut client = TestClient::connect(40000, 8080);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        let mut relayer = req_rx.try_recv().unwrap();
+        let handle = *stack.active_tunnels.keys().next().unwrap();
+        let tunnel = &stack.active_tunnels[&handle];
+        assert_eq!((tunnel.id, tunnel.target), (relayer.id, relayer.target));
+        assert_eq!((tunnel.opened_at, tunnel.last_activity), (opened_at, opened_at));
+        assert!(tunnel.tx_to_remote.is_some());
+
+        // Client -> remote
+        time::advance(Duration::from_secs(5)).await;
+        client.socket().send_slice(b"hello").unwrap();
+        client.exchange(&mut stack, &mut tun_rx, true);
+        assert_eq!(relayer.rx.try_recv().unwrap(), Bytes::from_static(b"hello"));
+        let tunnel = &stack.active_tunnels[&handle];
+        assert_eq!((tunnel.bytes_tx, tunnel.bytes_rx), (5, 0));
+        assert_eq!(tunnel.last_activity, opened_at + Duration::from_secs(5));
+
+        // Remote -> client
+        time::advance(Duration::from_secs(5)).await;
+        relayer.tx.try_send(Bytes::from_static(b"world!")).unwrap();
+        stack.poll_once(Instant::now());
+        let tunnel = &stack.active_tunnels[&handle];
+        assert_eq!((tunnel.bytes_tx, tunnel.bytes_rx), (5, 6));
+        assert_eq!(tunnel.last_activity, opened_at + Duration::from_secs(10));
+        assert_eq!(tunnel.opened_at, opened_at);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limited_tunnel_is_paced() {
+        // 10 KB/s
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig { rate_limit_bps: Some(80_000), ..Default::default() });
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        stack.set_event_sender(event_tx);
+
+        let mut client = TestClient::connect(40000, 8080);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        let mut relayer = req_rx.try_recv().unwrap();
+        let handle = *stack.active_tunnels.keys().next().unwrap();
+        let mut received = Vec::new();
+        let mut drain = |relayer: &mut TunnelRequest| {
+            while let Ok(chunk) = relayer.rx.try_recv() {
+                received.extend_from_slice(&chunk);
+            }
+            received.len()
+        };
+
+        // The full bucket lets one second's worth through, the rest waits in the socket
+        let data: Vec<u8> = (0..25_000).map(|i| (i % 251) as u8).collect();
+        client.socket().send_slice(&data).unwrap();
+        client.exchange(&mut stack, &mut tun_rx, true);
+        assert_eq!(drain(&mut relayer), 10_000);
+        assert_eq!(stack.stats().snapshot().egress_rate_limited, 1);
+        // Woken once a full bucket's worth is back
+        assert_eq!(stack.throttled[&handle], time::Instant::now() + Duration::from_secs(1));
+
+        time::advance(Duration::from_millis(500)).await;
+        stack.unthrottle(time::Instant::now());
+        assert!(!stack.dirty.contains(&handle));
+
+        time::advance(Duration::from_millis(500)).await;
+        stack.unthrottle(time::Instant::now());
+        stack.pump_egress(false);
+        assert_eq!(drain(&mut relayer), 20_000);
+        // Only the last 5000 bytes to wait for now
+        assert_eq!(stack.throttled[&handle], time::Instant::now() + Duration::from_millis(500));
+
+        time::advance(Duration::from_millis(500)).await;
+        stack.unthrottle(time::Instant::now());
+        stack.pump_egress(false);
+        assert_eq!(drain(&mut relayer), 25_000);
+        assert!(stack.throttled.is_empty());
+        assert_eq!(received, data);
+
+        // The totals come with the close event
+        client.socket().abort();
+        client.exchange(&mut stack, &mut tun_rx, true);
+        let _opened = event_rx.try_recv().unwrap();
+        let _established = event_rx.try_recv().unwrap();
+        assert!(matches!(event_rx.try_recv().unwrap(), TunnelEvent::Closed { bytes_tx: 25_000, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_reset_and_rejected_events() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig {
+            syn_rate_limit: Some((1, Duration::from_secs(1))),
+            ..Default::default()
+        });
+        let (req_tx, _req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        stack.set_event_sender(event_tx);
+
+        let mut client = TestClient::connect(40000, 8080);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        let handle = *stack.active_tunnels.keys().next().unwrap();
+        assert!(matches!(event_rx.try_recv().unwrap(), TunnelEvent::Opened { .. }));
+        assert!(matches!(event_rx.try_recv().unwrap(), TunnelEvent::Established { .. }));
+
+        // smoltcp ignores a RST outside the receive window, and so does the close reason
+        stack.inject(build_tcp_v4_seq(40000, [10, 11, 12, 9], 8080, TcpControl::Rst, TcpSeqNumber(0x4000_0000), None, &[]));
+        stack.poll_once(Instant::now());
+        assert_eq!(stack.sockets.get::<tcp::Socket>(handle).state(), tcp::State::Established);
+        assert!(!stack.active_tunnels[&handle].reset);
+
+        client.socket().abort();
+        client.exchange(&mut stack, &mut tun_rx, true);
+        assert!(matches!(
+            event_rx.try_recv().unwrap(),
+            TunnelEvent::Closed { handle: h, reason: CloseReason::Reset, .. } if h == handle
+        ));
+
+        // The bucket for 10.11.12.9 is empty now; the refused connection still got its own ID
+        stack.process_ingress_packet(build_syn_v4(40001, [10, 11, 12, 9], 8080));
+        assert_eq!(
+            event_rx.try_recv().unwrap(),
+            TunnelEvent::Rejected { id: 2, target: "10.11.12.9:8080".parse().unwrap(), reason: CloseReason::Limit }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stats_split_by_ip_version() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+
+        let mut v4 = TestClient::connect(40000, 8080);
+        v4.exchange(&mut stack, &mut tun_rx, true);
+        let mut v6 = TestClient::connect_v6(40001, 8080);
+        v6.exchange(&mut stack, &mut tun_rx, true);
+        let relayer_v4 = req_rx.try_recv().unwrap();
+        let relayer_v6 = req_rx.try_recv().unwrap();
+        assert!(relayer_v6.target.is_ipv6());
+
+        v4.socket().send_slice(&[0; 100]).unwrap();
+        v6.socket().send_slice(&[0; 60]).unwrap();
+        v4.exchange(&mut stack, &mut tun_rx, true);
+        v6.exchange(&mut stack, &mut tun_rx, true);
+        relayer_v4.tx.send(Bytes::from_static(b"v4 reply")).await.unwrap();
+        relayer_v6.tx.send(Bytes::from_static(b"six")).await.unwrap();
+        for _ in 0..2 {
+            let (handle, data) = stack.ingress_streams.next().await.unwrap();
+            stack.handle_remote_data(handle, data);
+        }
+
+        let stats = stack.stats().snapshot();
+        assert_eq!((stats.tunnels_opened_v4, stats.tunnels_opened_v6), (1, 1));
+        assert_eq!((stats.bytes_to_remote_v4, stats.bytes_to_remote_v6), (100, 60));
+        assert_eq!((stats.bytes_from_remote_v4, stats.bytes_from_remote_v6), (8, 3));
+    }
+
+    #[test]
+    fn test_buffers_come_from_allocator_hook() {
+        use crate::buffer::BufferAllocator;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[derive(Default)]
+        struct Counting {
+            socket_bytes: AtomicUsize,
+            arenas: AtomicUsize,
+        }
+        impl BufferAllocator for Counting {
+            fn socket_buffer(&self, len: usize) -> Vec<u8> {
+                self.socket_bytes.fetch_add(len, Ordering::Relaxed);
+                vec![0; len]
+            }
+            fn tx_arena(&self, capacity: usize) -> BytesMut {
+                self.arenas.fetch_add(1, Ordering::Relaxed);
+                BytesMut::with_capacity(capacity)
+            }
+        }
+
+        let counting = Arc::new(Counting::default());
+        let (_os_tx, os_rx) = mpsc::channel(16);
+        let (tun_tx, mut tun_rx) = mpsc::channel(16);
+        let device = PrismDevice::new(os_rx, tun_tx, 1500, Medium::Ip).with_allocator(counting.clone());
+        let mut stack = PrismStack::new(device, PrismConfig::default());
+        let (req_tx, _req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+
+        stack.process_ingress_packet(build_syn_v4(40000, [10, 11, 12, 9], 8080));
+        assert_eq!(counting.socket_bytes.load(Ordering::Relaxed), TCP_RX_BUFFER_SIZE + TCP_TX_BUFFER_SIZE);
+        stack.iface.poll(Instant::from_millis(0), &mut stack.device, &mut stack.sockets);
+        assert!(tun_rx.try_recv().is_ok());
+        assert_eq!(counting.arenas.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_channel_depths() {
+        let config = PrismConfig { tunnel_channel_depth: 2, control_channel_depth: 3, blind_relay_depth: 0, ..Default::default() };
+        assert_eq!(config.check(), vec![ConfigIssue::ZeroChannelDepth { name: "blind_relay_depth" }]);
+        let mut stack = test_stack(config);
+        let mut req_rx = stack.tunnel_request_channel();
+        let _relay_rx = stack.blind_relay_channel();
+        assert_eq!(stack.tunnel_req_tx.as_ref().unwrap().max_capacity(), 3);
+        assert_eq!(stack.blind_relay_tx.as_ref().unwrap().max_capacity(), 1);
+
+        // Both channels of a tunnel
+        stack.process_ingress_packet(build_syn_v4(40000, [1, 2, 3, 4], 443));
+        let request = req_rx.try_recv().unwrap();
+        assert_eq!(request.tx.max_capacity(), 2);
+        let tunnel = stack.active_tunnels.values().next().unwrap();
+        assert_eq!(tunnel.tx_to_remote.as_ref().unwrap().max_capacity(), 2);
+    }
+
+    #[test]
+    fn test_static_neighbors() {
+        use smoltcp::wire::{EthernetFrame, EthernetProtocol, EthernetRepr, Icmpv6Packet, Icmpv6Repr};
+        let ours = EthernetAddress([0x02, 0x11, 0x22, 0x33, 0x44, 0x55]);
+        let peer = EthernetAddress([0x02, 0xaa, 0xbb, 0xcc, 0xdd, 0xee]);
+        // The pings come from another MAC, as if routed: only the static entry knows `peer`
+        let frame = |ethertype, ip: &[u8]| {
+            let mut buf = vec![0u8; 14 + ip.len()];
+            let mut frame = EthernetFrame::new_unchecked(&mut buf);
+            EthernetRepr { src_addr: EthernetAddress([0x02, 0, 0, 0, 0, 0x99]), dst_addr: ours, ethertype }.emit(&mut frame);
+            frame.payload_mut().copy_from_slice(ip);
+            BytesMut::from(&buf[..])
+        };
+        let ping_v6 = {
+            let (src, dst) = (Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 2), Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 1));
+            let icmp = Icmpv6Repr::EchoRequest { ident: 7, seq_no: 1, data: b"ping" };
+            let ip_repr = Ipv6Repr { src_addr: src, dst_addr: dst, next_header: IpProtocol::Icmpv6, payload_len: icmp.buffer_len(), hop_limit: 64 };
+            let mut buf = vec![0u8; 40 + icmp.buffer_len()];
+            let mut packet = Ipv6Packet::new_unchecked(&mut buf);
+            ip_repr.emit(&mut packet);
+            icmp.emit(&src.into(), &dst.into(), &mut Icmpv6Packet::new_unchecked(packet.payload_mut()), &ChecksumCapabilities::default());
+            buf
+        };
+        /// Destination MAC and type of each frame the stack answers `ping` with
+        fn answers(stack: &mut PrismStack, ping: BytesMut) -> Vec<(EthernetAddress, EthernetProtocol)> {
+            stack.inject(ping);
+            stack.poll_once(Instant::from_millis(0));
+            let sent = stack.device.take_transmitted();
+            sent.iter().map(|f| EthernetFrame::new_checked(&f[..]).unwrap()).map(|f| (f.dst_addr(), f.ethertype())).collect()
+        }
+
+        let neighbors = vec![
+            (IpAddress::v4(10, 11, 12, 2), peer),
+            (IpAddress::v6(0xfd00, 0, 0, 0, 0, 0, 0, 2), peer),
+        ];
+        let config = PrismConfig { hardware_addr: Some(ours), static_neighbors: neighbors.clone(), ..Default::default() };
+        assert!(config.check().is_empty());
+        let mut stack = PrismStack::new(PrismDevice::loopback(1500, Medium::Ethernet), config);
+        stack.poll_once(Instant::from_millis(0));
+        assert!(stack.device.take_transmitted().is_empty());
+        assert_eq!(answers(&mut stack, frame(EthernetProtocol::Ipv4, &build_ping_v4([10, 11, 12, 1]))), [(peer, EthernetProtocol::Ipv4)]);
+        assert_eq!(answers(&mut stack, frame(EthernetProtocol::Ipv6, &ping_v6)), [(peer, EthernetProtocol::Ipv6)]);
+
+        // Without them, the stack has to ask for the MAC first
+        let mut stack = PrismStack::new(PrismDevice::loopback(1500, Medium::Ethernet), PrismConfig { hardware_addr: Some(ours), ..Default::default() });
+        let asked = answers(&mut stack, frame(EthernetProtocol::Ipv4, &build_ping_v4([10, 11, 12, 1])));
+        assert_eq!(asked, [(EthernetAddress::BROADCAST, EthernetProtocol::Arp)]);
+
+        // Ignored on an IP device, which has no link layer
+        let stack = PrismStack::new(PrismDevice::loopback(1500, Medium::Ip), PrismConfig { static_neighbors: neighbors, ..Default::default() });
+        assert!(stack.device.pending_packets.is_empty());
+
+        // Off the gateway subnets, the gateway itself, or not unicast
+        let multicast = EthernetAddress([0x01, 0x00, 0x5e, 0x00, 0x00, 0x01]);
+        let invalid = vec![
+            (IpAddress::v4(192, 168, 1, 1), peer),
+            (IpAddress::v4(10, 11, 12, 1), peer),
+            (IpAddress::v4(10, 11, 12, 3), multicast),
+        ];
+        let config = PrismConfig { static_neighbors: invalid.clone(), ..Default::default() };
+        let issues: Vec<_> = invalid.into_iter().map(|(ip, mac)| ConfigIssue::StaticNeighborInvalid { ip, mac }).collect();
+        assert_eq!(config.check(), issues);
+    }
+
+    #[test]
+    fn test_fixed_hardware_addr() {
+        let mac = EthernetAddress([0x02, 0x11, 0x22, 0x33, 0x44, 0x55]);
+        let config = PrismConfig { hardware_addr: Some(mac), ..Default::default() };
+        let stack = PrismStack::new(PrismDevice::loopback(1500, Medium::Ethernet), config.clone());
+        assert_eq!(stack.iface.hardware_addr(), HardwareAddress::Ethernet(mac));
+
+        // Ignored on an IP device: smoltcp would refuse an Ethernet address there
+        PrismStack::new(PrismDevice::loopback(1500, Medium::Ip), config);
+
+        // A multicast address is reported and replaced by a random unicast one
+        let multicast = EthernetAddress([0x01, 0x00, 0x5e, 0x00, 0x00, 0x01]);
+        let config = PrismConfig { hardware_addr: Some(multicast), ..Default::default() };
+        assert_eq!(config.check(), vec![ConfigIssue::HardwareAddrNotUnicast { addr: multicast }]);
+        let stack = PrismStack::new(PrismDevice::loopback(1500, Medium::Ethernet), config);
+        let HardwareAddress::Ethernet(random) = stack.iface.hardware_addr() else { panic!() };
+        assert!(random.is_unicast() && random != multicast);
+    }
+
+    #[test]
+    fn test_random_seed_makes_stacks_reproducible() {
+        let syn_ack_seq = |random_seed| {
+            let config = PrismConfig { random_seed, ..Default::default() };
+            let (mut stack, mut tun_rx) = test_stack_with_tun(config);
+            let (req_tx, _req_rx) = mpsc::channel(4);
+            stack.set_tunnel_request_sender(req_tx);
+            stack.process_ingress_packet(build_syn_v4(40000, [10, 11, 12, 9], 8080));
+            stack.poll_once(Instant::from_millis(0));
+            let syn_ack = tun_rx.try_recv().unwrap();
+            let ip = Ipv4Packet::new_checked(&syn_ack[..]).unwrap();
+            TcpPacket::new_checked(ip.payload()).unwrap().seq_number()
+        };
+        assert_eq!(syn_ack_seq(Some(7)), syn_ack_seq(Some(7)));
+        assert_ne!(syn_ack_seq(Some(7)), syn_ack_seq(Some(8)));
+
+        let mac = |random_seed| {
+            let config = PrismConfig { random_seed, ..Default::default() };
+            PrismStack::new(PrismDevice::loopback(1500, Medium::Ethernet), config).iface.hardware_addr()
+        };
+        assert_eq!(mac(Some(7)), mac(Some(7)));
+        assert_ne!(mac(Some(7)), mac(Some(8)));
+    }
+
+    #[test]
+    fn test_static_routes() {
+        let routes_of = |stack: &mut PrismStack| {
+            let mut table = Vec::new();
+            stack.iface.routes_mut().update(|routes| table = routes.iter().map(|r| (r.cidr, r.via_router)).collect());
+            table
+        };
+        let v4_default = (IpCidr::new(IpAddress::v4(0, 0, 0, 0), 0), IpAddress::v4(10, 11, 12, 1));
+        let v6_default = (IpCidr::new(IpAddress::v6(0, 0, 0, 0, 0, 0, 0, 0), 0), IpAddress::v6(0xfd00, 0, 0, 0, 0, 0, 0, 1));
+
+        // No routes: only the default sink
+        let mut stack = test_stack(PrismConfig::default());
+        assert_eq!(routes_of(&mut stack), vec![v4_default, v6_default]);
+
+        let lan = (IpCidr::new(IpAddress::v4(192, 168, 0, 0), 16), IpAddress::v4(10, 11, 12, 254));
+        let lan6 = (IpCidr::new(IpAddress::v6(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0), 32), IpAddress::v6(0xfd00, 0, 0, 0, 0, 0, 0, 0xfe));
+        let off_subnet = (IpCidr::new(IpAddress::v4(172, 16, 0, 0), 12), IpAddress::v4(172, 16, 0, 1));
+        let wrong_family = (IpCidr::new(IpAddress::v4(192, 0, 2, 0), 24), IpAddress::v6(0xfd00, 0, 0, 0, 0, 0, 0, 0xfe));
+        let config = PrismConfig { routes: vec![lan, off_subnet, wrong_family, lan6], ..Default::default() };
+        assert_eq!(
+            config.check(),
+            vec![
+                ConfigIssue::RouteNexthopUnreachable { cidr: off_subnet.0, via: off_subnet.1 },
+                ConfigIssue::RouteNexthopUnreachable { cidr: wrong_family.0, via: wrong_family.1 },
+            ]
+        );
+        let mut stack = PrismStack::new(PrismDevice::loopback(1500, Medium::Ip), config);
+        assert_eq!(routes_of(&mut stack), vec![v4_default, v6_default, lan, lan6]);
+
+        // Past the table's capacity the remaining routes are reported and skipped
+        let routes: Vec<_> = (0..MAX_ROUTES as u8)
+            .map(|i| (IpCidr::new(IpAddress::v4(192, 168, i, 0), 24), IpAddress::v4(10, 11, 12, 254)))
+            .collect();
+        let config = PrismConfig { routes: routes.clone(), ..Default::default() };
+        let fits = MAX_ROUTES - 2;
+        let expected: Vec<_> = routes[fits..]
+            .iter()
+            .map(|&(cidr, via)| ConfigIssue::RouteTableFull { cidr, via })
+            .collect();
+        assert_eq!(config.check(), expected);
+        let mut stack = PrismStack::new(PrismDevice::loopback(1500, Medium::Ip), config);
+        assert_eq!(routes_of(&mut stack)[2..], routes[..fits]);
+    }
+
+    #[test]
+    fn test_single_stack_modes() {
+        let v6_target = Ipv6Address::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        let mut stack = test_stack(PrismConfig { ip_mode: IpMode::V4Only, ..Default::default() });
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        stack.device.pending_packets.clear();
+
+        // Only the IPv4 gateway and default route are installed
+        assert_eq!(stack.iface.ip_addrs(), &GATEWAY_CIDRS[..1]);
+        let mut routes = Vec::new();
+        stack.iface.routes_mut().update(|table| routes = table.iter().map(|r| r.cidr).collect());
+        assert_eq!(routes, vec![IpCidr::new(IpAddress::v4(0, 0, 0, 0), 0)]);
+
+        // A trapped IPv6 SYN goes nowhere, not even to smoltcp
+        stack.process_ingress_packet(build_syn_v6(40000, v6_target, 443));
+        assert!(req_rx.try_recv().is_err());
+        assert!(stack.device.pending_packets.is_empty() && stack.active_tunnels.is_empty());
+        assert_eq!(stack.stats().snapshot().other_family_dropped, 1);
+        stack.process_ingress_packet(build_syn_v4(40000, [1, 2, 3, 4], 443));
+        assert!(req_rx.try_recv().is_ok());
+
+        // And the other way around
+        let mut stack = test_stack(PrismConfig { ip_mode: IpMode::V6Only, ..Default::default() });
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        assert_eq!(stack.iface.ip_addrs(), &GATEWAY_CIDRS[1..]);
+        stack.process_ingress_packet(build_syn_v4(40000, [1, 2, 3, 4], 443));
+        assert!(req_rx.try_recv().is_err());
+        stack.process_ingress_packet(build_syn_v6(40000, v6_target, 443));
+        assert!(req_rx.try_recv().is_ok());
+
+        // The IPv6 MSS clamp doesn't matter to a V4Only stack
+        let config = PrismConfig { egress_mtu: 1300, mss_clamp_v6: Some(1280), ..Default::default() };
+        assert_eq!(config.check().len(), 1);
+        assert!(PrismConfig { ip_mode: IpMode::V4Only, ..config }.check().is_empty());
+    }
+
+    #[test]
+    fn test_tunnel_requests_wait_out_a_full_channel() {
+        use crate::constants::TUNNEL_REQUEST_BACKLOG;
+        let mut stack = test_stack(PrismConfig { tcp_rx_buffer: 4096, tcp_tx_buffer: 4096, ..Default::default() });
+        let (req_tx, mut req_rx) = mpsc::channel(1);
+        stack.set_tunnel_request_sender(req_tx);
+
+        // One request fits the channel, the rest of the burst is staged rather than refused
+        for port in 0..TUNNEL_REQUEST_BACKLOG as u16 + 2 {
+            stack.process_ingress_packet(build_syn_v4(40000 + port, [1, 2, 3, 4], 443));
+        }
+        let stats = stack.stats().snapshot();
+        assert_eq!(stats.tunnel_requests_staged, TUNNEL_REQUEST_BACKLOG as u64);
+        assert_eq!(stats.setup_request_rejected, 1);
+        assert_eq!(stack.active_tunnels.len(), TUNNEL_REQUEST_BACKLOG + 1);
+
+        // Sent in order as the relayer catches up
+        for port in 0..3 {
+            assert_eq!(req_rx.try_recv().unwrap().source.port(), 40000 + port);
+            assert!(req_rx.try_recv().is_err());
+            stack.poll_once(Instant::from_millis(0));
+        }
+        assert_eq!(stack.request_backlog.len(), TUNNEL_REQUEST_BACKLOG - 3);
+
+        // A relayer that went away takes the staged requests with it
+        drop(req_rx);
+        stack.poll_once(Instant::from_millis(0));
+        assert!(stack.request_backlog.is_empty());
+        assert_eq!(stack.stats().snapshot().tunnel_requests_staged_dropped, TUNNEL_REQUEST_BACKLOG as u64 - 3);
+    }
+
+    #[tokio::test]
+    async fn test_staged_requests_are_rejected_when_the_channel_closes() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig::default());
+        let (req_tx, req_rx) = mpsc::channel(1);
+        stack.set_tunnel_request_sender(req_tx);
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        stack.set_event_sender(event_tx);
+
+        // The first request fills the channel, the other two are staged
+        for port in 40000..40003 {
+            stack.process_ingress_packet(build_syn_v4(port, [1, 2, 3, 4], 443));
+        }
+        stack.poll_once(Instant::from_millis(0));
+        assert_eq!(stack.request_backlog.len(), 2);
+        while tun_rx.try_recv().is_ok() {}
+        while event_rx.try_recv().is_ok() {}
+
+        drop(req_rx);
+        stack.flush_request_backlog();
+        assert!(stack.request_backlog.is_empty());
+        let rejected: Vec<_> = std::iter::from_fn(|| event_rx.try_recv().ok())
+            .map(|event| match event {
+                TunnelEvent::Rejected { id, reason: CloseReason::Limit, .. } => id,
+                event => panic!("unexpected {:?}", event),
+            })
+            .collect();
+        assert_eq!(rejected, [2, 3]);
+
+        // Their clients are reset and the sockets go; the one the relayer got stays
+        stack.iface.poll(Instant::from_millis(1), &mut stack.device, &mut stack.sockets);
+        for _ in 0..2 {
+            let rst = tun_rx.try_recv().unwrap();
+            assert_ne!(crate::trap::tcp_flags(&rst).unwrap() & 0x04, 0);
+        }
+        assert!(tun_rx.try_recv().is_err());
+        stack.pump_egress(true);
+        assert_eq!(stack.active_tunnels.len(), 1);
+        assert_eq!(stack.active_tunnels.values().next().unwrap().id, 1);
+    }
+
+    #[test]
+    fn test_pending_packets_cap() {
+        let gateway = |payload: &[u8]| build_udp_v4([10, 11, 12, 1], 53, payload);
+        for policy in [PendingOverflowPolicy::DropNewest, PendingOverflowPolicy::DropOldest] {
+            let config = PrismConfig { pending_packets_cap: 2, pending_overflow: policy, ..Default::default() };
+            let mut stack = test_stack(config);
+            let (req_tx, mut req_rx) = mpsc::channel(16);
+            stack.set_tunnel_request_sender(req_tx);
+            stack.device.pending_packets.clear();
+            stack.process_ingress_packet(gateway(b"1"));
+            stack.process_ingress_packet(gateway(b"2"));
+
+            // A trapped SYN gets past the cap, its socket is already listening for it
+            stack.process_ingress_packet(build_syn_v4(40000, [1, 2, 3, 4], 443));
+            assert!(req_rx.try_recv().is_ok());
+            assert_eq!(stack.device.pending_packets.len(), 3);
+            assert_eq!(stack.stats().snapshot().pending_overflow_dropped, 0);
+
+            stack.process_ingress_packet(gateway(b"3"));
+            assert_eq!(stack.stats().snapshot().pending_overflow_dropped, 1);
+            // The datagram's payload, or S for the SYN
+            let queued: Vec<_> =
+                stack.device.pending_packets.iter().map(|pkt| crate::trap::tcp_flags(pkt).map_or(pkt[28], |_| b'S')).collect();
+            match policy {
+                PendingOverflowPolicy::DropNewest => assert_eq!(queued, b"12S"),
+                // The oldest datagram goes, not the SYN queued after it
+                PendingOverflowPolicy::DropOldest => assert_eq!(queued, b"2S3"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_pending_drop_oldest_scan_is_bounded() {
+        let config = PrismConfig {
+            pending_packets_cap: PENDING_DROP_SCAN + 1,
+            pending_overflow: PendingOverflowPolicy::DropOldest,
+            ..Default::default()
+        };
+        let mut stack = test_stack(config);
+        stack.device.pending_packets.clear();
+        for port in 0..PENDING_DROP_SCAN as u16 {
+            stack.device.pending_packets.push_back(build_syn_v4(40000 + port, [1, 2, 3, 4], 443));
+        }
+        stack.device.pending_packets.push_back(build_udp_v4([10, 11, 12, 1], 53, b"1"));
+
+        // The datagram is past the scanned SYNs, so the new packet goes instead
+        stack.queue_for_stack(build_udp_v4([10, 11, 12, 1], 53, b"2"));
+        assert_eq!(stack.stats().snapshot().pending_overflow_dropped, 1);
+        assert_eq!(stack.device.pending_packets.len(), PENDING_DROP_SCAN + 1);
+        assert_eq!(stack.device.pending_packets.back().unwrap()[28], b'1');
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reported_path_mtu_lowers_the_mss_clamp() {
+        let mut stack = test_stack(PrismConfig { egress_mtu: 1500, ..Default::default() });
+        let (req_tx, _req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        let v6_target = Ipv6Address::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        /// MSS of the SYN handed to smoltcp
+        fn trapped_mss(stack: &mut PrismStack, syn: BytesMut) -> u16 {
+            stack.device.pending_packets.clear();
+            stack.process_ingress_packet(syn);
+            let syn = stack.device.pending_packets.pop_back().unwrap();
+            SynSummary::from_packet(&syn).unwrap().max_seg_size.unwrap()
+        }
+        assert_eq!(trapped_mss(&mut stack, build_syn_v4(40000, [1, 2, 3, 4], 443)), 1460);
+
+        let reporter = stack.path_mtu_reporter();
+        assert!(reporter.report("1.2.3.4".parse().unwrap(), 1400));
+        assert!(reporter.report("2001:db8::1".parse().unwrap(), 1400));
+        // Only ever lowered, and never below the family's minimum
+        assert!(reporter.report("1.2.3.4".parse().unwrap(), 1450));
+        assert!(reporter.report("5.6.7.8".parse().unwrap(), 100));
+        let ptb = crate::relay::icmp_packet_too_big(&build_udp_v4([9, 9, 9, 9], 443, &[0; 1400]), "192.0.2.1".parse().unwrap(), 1300);
+        assert!(reporter.report_icmp(&ptb.unwrap()));
+        assert!(!reporter.report_icmp(&build_udp_v4([9, 9, 9, 9], 443, b"not icmp")));
+        stack.poll_once(Instant::now());
+
+        // Per family: 40 bytes of IPv4 and TCP headers, 60 of IPv6 and TCP
+        assert_eq!(trapped_mss(&mut stack, build_syn_v4(40001, [1, 2, 3, 4], 443)), 1360);
+        assert_eq!(trapped_mss(&mut stack, build_syn_v6(40002, v6_target, 443)), 1340);
+        assert_eq!(trapped_mss(&mut stack, build_syn_v4(40003, [5, 6, 7, 8], 443)), 536);
+        assert_eq!(trapped_mss(&mut stack, build_syn_v4(40004, [9, 9, 9, 9], 443)), 1260);
+        assert_eq!(trapped_mss(&mut stack, build_syn_v4(40005, [8, 8, 8, 8], 443)), 1460);
+
+        // Until the report ages out
+        time::advance(PATH_MTU_TTL).await;
+        assert_eq!(trapped_mss(&mut stack, build_syn_v4(40006, [1, 2, 3, 4], 443)), 1460);
+    }
+
+    #[test]
+    fn test_config_check_mtu_vs_mss_clamp() {
+        let clamp = 1280;
+        let check = |egress_mtu| PrismConfig {
+            egress_mtu,
+            mss_clamp_v4: Some(clamp as u16),
+            mss_clamp_v6: Some(clamp as u16),
+            ..Default::default()
+        }
+        .check();
+
+        assert!(check(1500).is_empty());
+        assert!(check(clamp + 60).is_empty());
+        // Room for IPv4 headers but not IPv6 ones
+        assert_eq!(
+            check(clamp + 40),
+            vec![ConfigIssue::MtuBelowMssClamp { ipv6: true, required: clamp + 60, egress_mtu: clamp + 40 }]
+        );
+        assert_eq!(check(clamp).len(), 2);
+
+        // Clamps derived from egress_mtu always fit
+        for egress_mtu in [576, 1280, 1500, 9000] {
+            assert!(PrismConfig { egress_mtu, ..Default::default() }.check().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_reinjected_syn_carries_family_clamp() {
+        let mut stack = test_stack(PrismConfig { egress_mtu: 1400, ..Default::default() });
+        let (req_tx, _req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        stack.inject(build_syn_v4(40000, [1, 2, 3, 4], 443));
+        let syn = stack.device.pending_packets.pop_front().unwrap();
+        let ip = Ipv4Packet::new_checked(&syn[..]).unwrap();
+        let tcp = TcpPacket::new_checked(ip.payload()).unwrap();
+        assert!(tcp.verify_checksum(&ip.src_addr().into(), &ip.dst_addr().into()));
+        let repr = TcpRepr::parse(&tcp, &ip.src_addr().into(), &ip.dst_addr().into(), &ChecksumCapabilities::default()).unwrap();
+        assert_eq!(repr.max_seg_size, Some(1360));
+    }
+
+    #[test]
+    fn test_socket_timers_follow_config() {
+        let mut stack = test_stack(PrismConfig {
+            keep_alive: None,
+            timeout: Some(Duration::from_secs(90)),
+            ack_delay: None,
+            ..Default::default()
+        });
+        let (req_tx, _req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        stack.process_ingress_packet(build_syn_v4(40000, [1, 2, 3, 4], 443));
+
+        let handle = *stack.active_tunnels.keys().next().unwrap();
+        let socket = stack.sockets.get::<tcp::Socket>(handle);
+        assert_eq!(socket.keep_alive(), None);
+        assert_eq!(socket.timeout(), Some(Duration::from_secs(90).into()));
+        assert_eq!(socket.ack_delay(), None);
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_counts_per_target() {
+        let mut stack = test_stack(PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+
+        stack.process_ingress_packet(build_syn_v4(40000, [1, 2, 3, 4], 443));
+        stack.process_ingress_packet(build_syn_v4(40001, [1, 2, 3, 4], 443));
+        stack.process_ingress_packet(build_syn_v4(40002, [5, 6, 7, 8], 80));
+        let _relayer: Vec<_> = std::iter::from_fn(|| req_rx.try_recv().ok()).collect();
+
+        let a: SocketAddr = "1.2.3.4:443".parse().unwrap();
+        let b: SocketAddr = "5.6.7.8:80".parse().unwrap();
+        let c: SocketAddr = "1.2.3.4:80".parse().unwrap();
+        assert!(stack.has_tunnel(&a));
+        assert!(stack.has_tunnel(&b));
+        assert!(!stack.has_tunnel(&c));
+        assert_eq!(stack.tunnel_count_for(&a), 2);
+        assert_eq!(stack.tunnel_count_for(&b), 1);
+        assert_eq!(stack.tunnel_count_for(&c), 0);
+    }
+
+    #[tokio::test]
+    async fn test_consistent_flows_to_same_dst_are_distinct() {
+        let mut stack = test_stack(PrismConfig {
+            handshake_mode: HandshakeMode::Consistent,
+            ..Default::default()
+        });
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+
+        stack.process_ingress_packet(build_syn_v4(40000, [1, 2, 3, 4], 443));
+        stack.process_ingress_packet(build_syn_v4(40001, [1, 2, 3, 4], 443));
+
+        assert_eq!(stack.pending_syns.len(), 2);
+        assert!(req_rx.try_recv().is_ok());
+        assert!(req_rx.try_recv().is_ok());
+        assert_eq!(stack.stats().snapshot().duplicate_syns_suppressed, 0);
+
+        // Releasing one flow leaves the other pending
+        let dst: SocketAddr = "1.2.3.4:443".parse().unwrap();
+        let first = ("10.11.12.2:40000".parse().unwrap(), dst);
+        stack.handle_handshake_feedback(first, true, 1024, 1024);
+        assert_eq!(stack.pending_syns.len(), 1);
+        assert!(stack.pending_syns.contains_key(&("10.11.12.2:40001".parse().unwrap(), dst)));
+        assert_eq!(stack.active_tunnels.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_channels_move_between_stacks() {
+        let syn = build_syn_v4(40000, [1, 2, 3, 4], 443);
+
+        let mut stack_a = test_stack(PrismConfig::default());
+        let (req_tx_a, mut req_rx_a) = mpsc::channel(16);
+        stack_a.set_tunnel_request_sender(req_tx_a);
+        stack_a.process_ingress_packet(syn.clone());
+        let mut relayer_a = req_rx_a.try_recv().unwrap();
+        let handle_a = *stack_a.active_tunnels.keys().next().unwrap();
+
+        let mut stack_b = test_stack(PrismConfig::default());
+        let (req_tx_b, _req_rx_b) = mpsc::channel(16);
+        stack_b.set_tunnel_request_sender(req_tx_b);
+        stack_b.process_ingress_packet(syn);
+        let handle_b = *stack_b.active_tunnels.keys().next().unwrap();
+
+        // B must be unbound before it can adopt A's channels
+        let channels = stack_a.detach_tunnel(handle_a).unwrap();
+        let channels = stack_b.attach_tunnel(handle_b, channels).unwrap_err();
+        assert!(stack_b.detach_tunnel(handle_b).is_some());
+        // ...and only if they go to the same target
+        let mut channels = channels;
+        channels.target = "5.6.7.8:443".parse().unwrap();
+        let mut channels = stack_b.attach_tunnel(handle_b, channels).unwrap_err();
+        channels.target = "1.2.3.4:443".parse().unwrap();
+        assert!(stack_b.attach_tunnel(handle_b, channels).is_ok());
+        assert_eq!(stack_b.active_tunnels[&handle_b].target, "1.2.3.4:443".parse().unwrap());
+
+        // A keeps its socket, but no longer has a data path or an ingress stream
+        assert!(stack_a.active_tunnels[&handle_a].tx_to_remote.is_none());
+        assert!(stack_a.ingress_streams.next().await.is_none());
+
+        // Remote -> client now lands on B's socket
+        relayer_a.tx.send(Bytes::from_static(b"hello")).await.unwrap();
+        let (handle, data) = stack_b.ingress_streams.next().await.unwrap();
+        assert_eq!(handle, handle_b);
+        assert_eq!(data, Some(Bytes::from_static(b"hello")));
+
+        // Client -> remote from B reaches A's relayer
+        let tx = stack_b.active_tunnels[&handle_b].tx_to_remote.as_ref().unwrap();
+        tx.try_send(Bytes::from_static(b"world")).unwrap();
+        assert_eq!(relayer_a.rx.recv().await.unwrap(), Bytes::from_static(b"world"));
+    }
+
+    #[tokio::test]
+    async fn test_run_on_local_set() {
+        let (os_tx, os_rx) = mpsc::channel(16);
+        let (tun_tx, mut tun_rx) = mpsc::channel(16);
+        let device = PrismDevice::new(os_rx, tun_tx, 1500, Medium::Ip);
+        let mut stack = PrismStack::new(device, PrismConfig {
+            handshake_mode: HandshakeMode::Consistent,
+            ..Default::default()
+        });
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+
+        let local = tokio::task::LocalSet::new();
+        local.run_until(async move {
+            let run = tokio::task::spawn_local(stack.run_on_current_thread());
+            os_tx.send(build_syn_v4(40000, [10, 11, 12, 9], 8080)).await.unwrap();
+
+            // The handshake waiter runs on the LocalSet too
+            let mut req = req_rx.recv().await.unwrap();
+            req.response_tx.take().unwrap().send(true).unwrap();
+            let syn_ack = tun_rx.recv().await.unwrap();
+            assert_eq!(crate::trap::tcp_flags(&syn_ack).unwrap() & 0x12, 0x12);
+
+            drop(os_tx);
+            run.await.unwrap().unwrap();
+        }).await;
+    }
+
+    #[test]
+    fn test_payload_prefix_logged_once() {
+        let flow: FlowKey = ("10.11.12.2:40000".parse().unwrap(), "1.2.3.4:443".parse().unwrap());
+        let (tx, _rx) = mpsc::channel(1);
+        let mut meta = TunnelState::new(1, flow, tx.clone(), time::Instant::now());
+        assert_eq!(
+            meta.take_payload_prefix(b"\x16\x03\x01\x02\x00", 3).as_deref(),
+            Some("160301")
+        );
+        // Later segments of the same tunnel are never logged
+        assert_eq!(meta.take_payload_prefix(b"GET / HTTP/1.1", 3), None);
+
+        // Disabled (the production default)
+        let mut meta = TunnelState::new(1, flow, tx, time::Instant::now());
+        assert_eq!(meta.take_payload_prefix(b"GET", 0), None);
+        assert!(!meta.prefix_logged);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_linux_offload_still_enables_offload() {
+        assert_eq!(PrismConfig { linux_offload: true, ..Default::default() }.offload_mode(), OffloadMode::Gso);
+        let config = PrismConfig { linux_offload: true, offload: OffloadMode::Checksum, ..Default::default() };
+        assert_eq!(config.offload_mode(), OffloadMode::Checksum);
+        assert_eq!(PrismConfig::default().offload_mode(), OffloadMode::Off);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_offload_vnet_header_both_directions() {
+        use crate::constants::VIRTIO_NET_HDR_SIZE;
+        use crate::offload::{prepend_virtio_hdr_none, VirtioNetHdr, VIRTIO_NET_HDR_F_NEEDS_CSUM};
+        use smoltcp::phy::TxToken;
+
+        let (_os_tx, os_rx) = mpsc::channel(16);
+        let (tun_tx, mut tun_rx) = mpsc::channel(16);
+        let device = PrismDevice::new(os_rx, tun_tx, 1500, Medium::Ip);
+        let mut stack = PrismStack::new(device, PrismConfig { offload: OffloadMode::Checksum, ..Default::default() });
+        let (relay_tx, mut relay_rx) = mpsc::channel(16);
+        stack.set_blind_relay_sender(relay_tx);
+
+        // RX: the header is stripped before classification
+        let udp = build_udp_v4([1, 2, 3, 4], 53, b"query");
+        stack.process_ingress_packet(prepend_virtio_hdr_none(&udp));
+        assert_eq!(relay_rx.try_recv().unwrap(), udp.freeze());
+
+        // TX: the device reserves and fills the header in front of what smoltcp writes
+        let tcp = build_syn_v4(40000, [1, 2, 3, 4], 443);
+        let token = stack.device.transmit(Instant::from_millis(0)).unwrap();
+        token.consume(tcp.len(), |buf| buf.copy_from_slice(&tcp));
+        let sent = tun_rx.try_recv().unwrap();
+        assert_eq!(sent.len(), VIRTIO_NET_HDR_SIZE + tcp.len());
+        let hdr = VirtioNetHdr::parse(&sent).unwrap();
+        assert_eq!(hdr.flags, VIRTIO_NET_HDR_F_NEEDS_CSUM);
+        assert_eq!(hdr.csum_start, 20);
+        assert!(!stack.device.capabilities().checksum.tcp.tx());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_vnet_framed_syn_is_trapped() {
+        use crate::offload::prepend_virtio_hdr_none;
+
+        let (_os_tx, os_rx) = mpsc::channel(16);
+        let (tun_tx, _tun_rx) = mpsc::channel(16);
+        let device = PrismDevice::new(os_rx, tun_tx, 1500, Medium::Ip).with_vnet_hdr(true);
+        let mut stack = PrismStack::new(device, PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+
+        let syn = build_syn_v4(40000, [1, 2, 3, 4], 443);
+        stack.process_ingress_packet(prepend_virtio_hdr_none(&syn));
+
+        assert_eq!(req_rx.try_recv().unwrap().target, "1.2.3.4:443".parse().unwrap());
+        assert_eq!(stack.active_tunnels.len(), 1);
+        // Checksum offload stays off: smoltcp still computes TX checksums
+        assert!(stack.device.capabilities().checksum.tcp.tx());
+    }
+
+    #[test]
+    fn test_multicast_udp_follows_cast_policy() {
+        let pkt = build_udp_v4([224, 0, 0, 251], 5353, b"mdns");
+
+        let mut stack = test_stack(PrismConfig { cast_policy: CastPolicy::Relay, ..Default::default() });
+        let (relay_tx, mut relay_rx) = mpsc::channel(16);
+        stack.set_blind_relay_sender(relay_tx);
+        stack.process_ingress_packet(pkt.clone());
+        assert_eq!(relay_rx.try_recv().unwrap(), pkt.clone().freeze());
+
+        let mut stack = test_stack(PrismConfig { cast_policy: CastPolicy::Drop, ..Default::default() });
+        let (relay_tx, mut relay_rx) = mpsc::channel(16);
+        stack.set_blind_relay_sender(relay_tx);
+        stack.process_ingress_packet(pkt);
+        assert!(relay_rx.try_recv().is_err());
+        assert!(stack.device.pending_packets.is_empty());
+    }
+
+    #[test]
+    fn test_broadcast_follows_cast_policy() {
+        let mut stack = test_stack(PrismConfig { cast_policy: CastPolicy::Pass, ..Default::default() });
+        let (relay_tx, mut relay_rx) = mpsc::channel(16);
+        stack.set_blind_relay_sender(relay_tx);
+
+        // Limited broadcast and the gateway subnet's directed broadcast
+        stack.process_ingress_packet(build_udp_v4([255, 255, 255, 255], 67, b"dhcp"));
+        stack.process_ingress_packet(build_udp_v4([10, 11, 12, 255], 137, b"nbns"));
+        assert!(relay_rx.try_recv().is_err());
+        assert_eq!(stack.device.pending_packets.len(), 2);
+
+        let mut stack = test_stack(PrismConfig { cast_policy: CastPolicy::Drop, ..Default::default() });
+        stack.process_ingress_packet(build_udp_v4([10, 11, 12, 255], 137, b"nbns"));
+        assert!(stack.device.pending_packets.is_empty());
+    }
+
+    #[test]
+    fn test_ipv6_classification_failures_counted() {
+        let mut stack = test_stack(PrismConfig::default());
+
+        // IPv6 header announcing 100 bytes of payload that never came
+        let mut pkt = BytesMut::zeroed(40);
+        pkt[0] = 0x60;
+        pkt[4..6].copy_from_slice(&100u16.to_be_bytes());
+        pkt[6] = 6;
+        stack.process_ingress_packet(pkt);
+
+        assert_eq!(stack.stats().snapshot().ipv6_classification_failures, 1);
+    }
+
+    #[test]
+    fn test_udp_relay_keeps_datagram_boundaries() {
+        let mut stack = test_stack(PrismConfig::default());
+        let (relay_tx, mut relay_rx) = mpsc::channel(16);
+        stack.set_blind_relay_sender(relay_tx);
+
+        let first = build_udp_v4([8, 8, 8, 8], 53, b"first");
+        let second = build_udp_v4([8, 8, 8, 8], 53, b"second datagram");
+        let mut padded = second.clone();
+        padded.extend_from_slice(&[0; 6]);
+        stack.process_ingress_packet(first.clone());
+        stack.process_ingress_packet(padded);
+
+        assert_eq!(relay_rx.try_recv().unwrap(), first.freeze());
+        assert_eq!(relay_rx.try_recv().unwrap(), second.freeze());
+        assert!(relay_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_dns_queries_are_reported_and_relayed() {
+        let mut stack = test_stack(PrismConfig::default());
+        let (relay_tx, mut relay_rx) = mpsc::channel(16);
+        stack.set_blind_relay_sender(relay_tx);
+        let (dns_tx, mut dns_rx) = mpsc::channel(1);
+        stack.set_dns_query_sender(dns_tx);
+
+        let mut msg = vec![0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        msg.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        let query = build_udp_v4([8, 8, 8, 8], 53, &msg);
+        stack.process_ingress_packet(query.clone());
+        // Malformed or not DNS: relayed all the same, and a full channel doesn't hold anything up
+        stack.process_ingress_packet(build_udp_v4([8, 8, 8, 8], 53, b"junk"));
+        stack.process_ingress_packet(query.clone());
+        stack.process_ingress_packet(build_udp_v4([8, 8, 8, 8], 443, &msg));
+
+        let reported = dns_rx.try_recv().unwrap();
+        assert_eq!((reported.name.as_str(), reported.qtype), ("example.com", 1));
+        assert_eq!(reported.client, "10.11.12.2:5353".parse().unwrap());
+        assert!(dns_rx.try_recv().is_err());
+        assert_eq!(relay_rx.try_recv().unwrap(), query.freeze());
+        assert_eq!(relay_rx.len(), 3);
+    }
+
+    #[test]
+    fn test_oversize_packets_never_reach_the_relay() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig { egress_mtu: 1280, ..Default::default() });
+        let (relay_tx, mut relay_rx) = mpsc::channel(16);
+        stack.set_blind_relay_sender(relay_tx);
+
+        let fits = build_udp_v4([8, 8, 8, 8], 443, &[0; 1280 - 28]);
+        stack.process_ingress_packet(fits.clone());
+        stack.process_ingress_packet(build_udp_v4([8, 8, 8, 8], 443, &[0; 1280 - 27]));
+        assert_eq!(relay_rx.try_recv().unwrap(), fits.freeze());
+        assert!(relay_rx.try_recv().is_err());
+        assert!(tun_rx.try_recv().is_err());
+        assert_eq!(stack.stats().snapshot().blind_relay_oversize_dropped, 1);
+
+        stack.config.oversize_policy = OversizePolicy::Reject;
+        stack.process_ingress_packet(build_udp_v4([8, 8, 8, 8], 443, &[0; 1400]));
+        assert!(relay_rx.try_recv().is_err());
+        let error = tun_rx.try_recv().unwrap();
+        let ip = Ipv4Packet::new_checked(&error[..]).unwrap();
+        assert_eq!((ip.src_addr(), ip.dst_addr()), (Ipv4Address::new(10, 11, 12, 1), Ipv4Address::new(10, 11, 12, 2)));
+        assert_eq!(&ip.payload()[..2], &[3, 4]);
+        assert_eq!(&ip.payload()[6..8], &1280u16.to_be_bytes());
+        assert_eq!(stack.stats().snapshot().blind_relay_oversize_dropped, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_icmp_errors_are_rate_limited() {
+        let config = PrismConfig { reject_unsupported: true, ..Default::default() };
+        let (mut stack, mut tun_rx) = test_stack_with_tun(config);
+
+        for _ in 0..ICMP_ERROR_BURST + 4 {
+            stack.process_ingress_packet(build_udp_v4([8, 8, 8, 8], 443, b"x"));
+        }
+        let mut replies = 0;
+        while tun_rx.try_recv().is_ok() {
+            replies += 1;
+        }
+        assert_eq!(replies, ICMP_ERROR_BURST);
+
+        // The budget refills over the window, and oversize rejections draw from it too
+        time::advance(ICMP_ERROR_WINDOW / ICMP_ERROR_BURST).await;
+        stack.config.oversize_policy = OversizePolicy::Reject;
+        stack.process_ingress_packet(build_udp_v4([8, 8, 8, 8], 443, &[0; 1500]));
+        stack.process_ingress_packet(build_udp_v4([8, 8, 8, 8], 443, b"x"));
+        let error = tun_rx.try_recv().unwrap();
+        assert_eq!(&Ipv4Packet::new_checked(&error[..]).unwrap().payload()[..2], &[3, 4]);
+        assert!(tun_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_udp_flows_to_trapped_ports_are_steered() {
+        let config = PrismConfig { udp_trap_ports: Some(PortSet::new().with_port(443)), ..Default::default() };
+        let (mut stack, mut tun_rx) = test_stack_with_tun(config);
+        let (relay_tx, mut relay_rx) = mpsc::channel(16);
+        stack.set_blind_relay_sender(relay_tx);
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_udp_tunnel_request_sender(req_tx);
+
+        stack.process_ingress_packet(build_udp_v4([8, 8, 8, 8], 443, b"initial"));
+        stack.process_ingress_packet(build_udp_v4([8, 8, 8, 8], 443, b"handshake"));
+        let dns = build_udp_v4([8, 8, 8, 8], 53, b"query");
+        stack.process_ingress_packet(dns.clone());
+
+        // One tunnel per flow, carrying bare payloads; other ports still go to the relay
+        let mut request = req_rx.try_recv().unwrap();
+        assert!(req_rx.try_recv().is_err());
+        assert_eq!(request.source, "10.11.12.2:5353".parse().unwrap());
+        assert_eq!(request.target, "8.8.8.8:443".parse().unwrap());
+        assert_eq!(request.rx.try_recv().unwrap(), Bytes::from_static(b"initial"));
+        assert_eq!(request.rx.try_recv().unwrap(), Bytes::from_static(b"handshake"));
+        assert_eq!(relay_rx.try_recv().unwrap(), dns.freeze());
+        assert!(relay_rx.try_recv().is_err());
+
+        // Replies come back as datagrams from the target
+        request.tx.try_send(Bytes::from_static(b"reply")).unwrap();
+        stack.poll_once(Instant::now());
+        let reply = tun_rx.try_recv().unwrap();
+        let (src, dst, payload) = crate::trap::udp_datagram(&reply).unwrap();
+        assert_eq!((src, dst), (request.target, request.source));
+        assert_eq!(&reply[payload], b"reply");
+        let ip = Ipv4Packet::new_checked(&reply[..]).unwrap();
+        let udp = UdpPacket::new_checked(ip.payload()).unwrap();
+        assert!(udp.verify_checksum(&ip.src_addr().into(), &ip.dst_addr().into()));
+
+        // Once the relayer lets go, the next datagram asks for a new tunnel
+        drop(request);
+        stack.poll_once(Instant::now());
+        assert!(stack.udp_tunnels.is_empty());
+        stack.process_ingress_packet(build_udp_v4([8, 8, 8, 8], 443, b"again"));
+        let request = req_rx.try_recv().unwrap();
+        assert_eq!(request.id, 2);
+        let stats = stack.stats().snapshot();
+        assert_eq!((stats.udp_tunnels_opened, stats.udp_tunnel_datagrams_dropped), (2, 0));
+
+        // Idle tunnels are closed by the sweep
+        stack.expire_udp_tunnels(time::Instant::now() + UDP_TUNNEL_IDLE_TIMEOUT);
+        assert!(stack.udp_tunnels.is_empty());
+    }
+
+    #[test]
+    fn test_udp_tunnels_are_bounded() {
+        let config = PrismConfig { udp_trap_ports: Some(PortSet::new().with_port(443)), max_udp_tunnels: 1, ..Default::default() };
+        let (mut stack, mut tun_rx) = test_stack_with_tun(config);
+        let (relay_tx, mut relay_rx) = mpsc::channel(16);
+        stack.set_blind_relay_sender(relay_tx);
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_udp_tunnel_request_sender(req_tx);
+
+        // The second flow finds the limit reached and goes to the relay
+        stack.process_ingress_packet(build_udp_v4([8, 8, 8, 8], 443, b"first"));
+        let second = build_udp_v4([9, 9, 9, 9], 443, b"second");
+        stack.process_ingress_packet(second.clone());
+        let request = req_rx.try_recv().unwrap();
+        assert!(req_rx.try_recv().is_err());
+        assert_eq!(relay_rx.try_recv().unwrap(), second.freeze());
+        assert_eq!(stack.stats().snapshot().udp_tunnels_over_limit, 1);
+
+        // Its queued datagram counts as channel memory
+        assert_eq!(stack.memory_estimate().channel_buffers, stack.config.egress_mtu);
+
+        // A reply after the tunnel expired is dropped and counted
+        stack.expire_udp_tunnels(time::Instant::now() + UDP_TUNNEL_IDLE_TIMEOUT);
+        request.tx.try_send(Bytes::from_static(b"late")).unwrap();
+        stack.poll_once(Instant::now());
+        assert!(tun_rx.try_recv().is_err());
+        assert_eq!(stack.stats().snapshot().udp_tunnel_datagrams_dropped, 1);
+    }
+
+    #[test]
+    fn test_reject_unsupported_answers_with_icmp() {
+        let config = PrismConfig { reject_unsupported: true, ..Default::default() };
+        let mut stack = PrismStack::new(PrismDevice::loopback(1500, Medium::Ip), config);
+
+        let udp = build_udp_v4([8, 8, 8, 8], 53, b"query");
+        stack.process_ingress_packet(udp.clone());
+        assert!(stack.device.pending_packets.is_empty());
+        let sent = stack.device.take_transmitted();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0], crate::relay::icmp_port_unreachable(&udp).unwrap());
+
+        // The gateway answers for itself
+        stack.process_ingress_packet(build_udp_v4([10, 11, 12, 1], 53, b"query"));
+        assert_eq!(stack.device.pending_packets.len(), 1);
+        assert!(stack.device.take_transmitted().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_external_socket_slots_are_indexed() {
+        let (_os_tx, os_rx) = mpsc::channel(16);
+        let (tun_tx, _tun_rx) = mpsc::channel(16);
+        let device = PrismDevice::new(os_rx, tun_tx, 1500, Medium::Ip);
+        // Slots 0 and 2 taken, slot 1 free
+        let mut sockets = SocketSet::new(vec![]);
+        let first = sockets.add(placeholder_socket());
+        let hole = sockets.add(placeholder_socket());
+        let third = sockets.add(placeholder_socket());
+        sockets.remove(hole);
+        let mut stack = PrismStack::new_with_sockets(device, PrismConfig::default(), sockets);
+        assert_eq!(stack.socket_index, HashMap::from([(first, 0), (third, 2)]));
+        assert_eq!(stack.free_slots, BTreeSet::from([1]));
+        assert_eq!(stack.sockets.iter().count(), 2);
+
+        // The first tunnel fills the hole, the next one grows the set
+        let (req_tx, _req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        stack.inject(build_syn_v4(40000, [1, 2, 3, 4], 443));
+        stack.inject(build_syn_v4(40001, [1, 2, 3, 4], 443));
+        stack.poll_once(Instant::now());
+        let mut slots: Vec<usize> = stack.active_tunnels.keys().map(|handle| stack.socket_index[handle]).collect();
+        slots.sort();
+        assert_eq!(slots, [1, 3]);
+        assert!(stack.active_tunnels.contains_key(&hole));
+        assert_eq!(stack.memory_estimate().socket_slots, 4 * std::mem::size_of::<SocketStorage>());
+    }
+
+    #[test]
+    fn test_external_sockets_are_served() {
+        let (_os_tx, os_rx) = mpsc::channel(16);
+        let (tun_tx, _tun_rx) = mpsc::channel(16);
+        let device = PrismDevice::new(os_rx, tun_tx, 1500, Medium::Ip);
+        let mut sockets = SocketSet::new(vec![]);
+        let mut dns = udp::Socket::new(
+            udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 4], vec![0; 1024]),
+            udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 4], vec![0; 1024]),
+        );
+        dns.bind(53).unwrap();
+        let dns = sockets.add(dns);
+        let mut stack = PrismStack::new_with_sockets(device, PrismConfig::default(), sockets);
+        let (relay_tx, mut relay_rx) = mpsc::channel(16);
+        stack.set_blind_relay_sender(relay_tx);
+
+        // Relay or not, UDP to the gateway goes to smoltcp
+        stack.process_ingress_packet(build_udp_v4([10, 11, 12, 1], 53, b"query"));
+        assert!(relay_rx.try_recv().is_err());
+        stack.iface.poll(Instant::from_millis(0), &mut stack.device, &mut stack.sockets);
+        stack.pump_egress(true);
+
+        let (data, meta) = stack.sockets.get_mut::<udp::Socket>(dns).recv().unwrap();
+        assert_eq!(data, b"query");
+        assert_eq!(meta.endpoint.port, 5353);
+        assert!(stack.active_tunnels.is_empty());
+    }
+
+    #[test]
+    fn test_tx_batch_keeps_order_and_flushes_every_poll() {
+        let (_os_tx, os_rx) = mpsc::channel(16);
+        let (tun_tx, mut tun_rx) = mpsc::channel(16);
+        let mut device = PrismDevice::new(os_rx, tun_tx, 1500, Medium::Ip);
+        device.tx_batch = 3;
+        for i in 0..5u8 {
+            device.transmit_packet(&[i; 20]);
+        }
+        let received: Vec<u8> = std::iter::from_fn(|| tun_rx.try_recv().ok()).map(|pkt| pkt[0]).collect();
+        assert_eq!(received, [0, 1, 2]);
+        device.flush_tx();
+        let received: Vec<u8> = std::iter::from_fn(|| tun_rx.try_recv().ok()).map(|pkt| pkt[0]).collect();
+        assert_eq!(received, [3, 4]);
+
+        // A partial batch doesn't outlive the poll that produced it
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig { tx_batch: 64, ..Default::default() });
+        stack.inject(build_ping_v4([10, 11, 12, 1]));
+        stack.inject(build_ping_v4([10, 11, 12, 1]));
+        stack.poll_once(Instant::from_millis(0));
+        assert_eq!(std::iter::from_fn(|| tun_rx.try_recv().ok()).count(), 2);
+        assert!(stack.device.tx_staged.is_empty());
+    }
+
+    #[test]
+    fn test_gateway_ping_is_answered_locally() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig::default());
+        let (relay_tx, mut relay_rx) = mpsc::channel(16);
+        stack.set_blind_relay_sender(relay_tx);
+
+        stack.process_ingress_packet(build_ping_v4([10, 11, 12, 1]));
+        stack.iface.poll(Instant::from_millis(0), &mut stack.device, &mut stack.sockets);
+        assert!(relay_rx.try_recv().is_err());
+        let reply = tun_rx.try_recv().unwrap();
+        let ip = Ipv4Packet::new_checked(&reply[..]).unwrap();
+        assert_eq!(ip.src_addr(), Ipv4Address::new(10, 11, 12, 1));
+        let icmp = Icmpv4Packet::new_checked(ip.payload()).unwrap();
+        assert_eq!(icmp.msg_type(), Icmpv4Message::EchoReply);
+        assert_eq!(icmp.data(), b"ping");
+
+        // Pings to anywhere else are still relayed
+        stack.process_ingress_packet(build_ping_v4([8, 8, 8, 8]));
+        assert!(relay_rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_trap_ports_relay_other_tcp() {
+        let mut stack = test_stack(PrismConfig {
+            trap_ports: Some(PortSet::new().with_port(443).with_range(8000..=8999)),
+            ..Default::default()
+        });
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        let (relay_tx, mut relay_rx) = mpsc::channel(16);
+        stack.set_blind_relay_sender(relay_tx);
+
+        let ssh = build_syn_v4(40000, [1, 2, 3, 4], 22);
+        stack.process_ingress_packet(ssh.clone());
+        assert_eq!(relay_rx.try_recv().unwrap(), ssh.freeze());
+        assert!(req_rx.try_recv().is_err());
+
+        stack.process_ingress_packet(build_syn_v4(40001, [1, 2, 3, 4], 443));
+        stack.process_ingress_packet(build_syn_v4(40002, [1, 2, 3, 4], 8080));
+        assert_eq!(std::iter::from_fn(|| req_rx.try_recv().ok()).count(), 2);
+        assert!(relay_rx.try_recv().is_err());
+
+        // Without a relay smoltcp sees the SYN (and answers with a RST)
+        let mut stack = test_stack(PrismConfig { trap_ports: Some(PortSet::new().with_port(443)), ..Default::default() });
+        stack.process_ingress_packet(build_syn_v4(40000, [1, 2, 3, 4], 22));
+        assert_eq!(stack.device.pending_packets.len(), 1);
+        assert!(stack.active_tunnels.is_empty());
+    }
+
+    #[test]
+    fn test_classifier_steers_packets() {
+        use crate::trap::Verdict;
+        let mut stack = test_stack(PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        let (relay_tx, mut relay_rx) = mpsc::channel(16);
+        stack.set_blind_relay_sender(relay_tx);
+        stack.set_classifier(|pkt| match crate::trap::tcp_flow(pkt).map(|(_, dst)| dst.port()) {
+            Some(22) => Verdict::BlindRelay,
+            Some(25) => Verdict::Drop,
+            Some(80) => Verdict::Stack,
+            Some(666) => panic!("classifier bug"),
+            _ => Verdict::Trap,
+        });
+        stack.device.pending_packets.clear();
+
+        let ssh = build_syn_v4(40000, [1, 2, 3, 4], 22);
+        stack.process_ingress_packet(ssh.clone());
+        assert_eq!(relay_rx.try_recv().unwrap(), ssh.freeze());
+        stack.process_ingress_packet(build_syn_v4(40001, [1, 2, 3, 4], 25));
+        stack.process_ingress_packet(build_syn_v4(40002, [1, 2, 3, 4], 80));
+        assert_eq!(stack.device.pending_packets.len(), 1);
+        assert!(req_rx.try_recv().is_err() && relay_rx.try_recv().is_err());
+
+        // Trap, and a panic, both fall back to the built-in handling
+        stack.process_ingress_packet(build_syn_v4(40003, [1, 2, 3, 4], 443));
+        stack.process_ingress_packet(build_syn_v4(40004, [1, 2, 3, 4], 666));
+        assert_eq!(std::iter::from_fn(|| req_rx.try_recv().ok()).count(), 2);
+        let stats = stack.stats().snapshot();
+        assert_eq!((stats.classifier_dropped, stats.classifier_panics), (1, 1));
+    }
+
+    #[test]
+    fn test_trap_cidrs_drop_tcp_to_other_destinations() {
+        let mut stack = test_stack(PrismConfig {
+            trap_cidrs: Some(vec![IpCidr::new(IpAddress::v4(1, 2, 3, 0), 24)]),
+            ..Default::default()
+        });
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        let (relay_tx, mut relay_rx) = mpsc::channel(16);
+        stack.set_blind_relay_sender(relay_tx);
+
+        stack.process_ingress_packet(build_syn_v4(40000, [1, 2, 3, 4], 443));
+        assert!(req_rx.try_recv().is_ok());
+
+        // Outside the scope: not trapped, relayed, or handed to smoltcp to answer
+        stack.device.pending_packets.clear();
+        for dst in [[5, 6, 7, 8], [10, 11, 12, 1]] {
+            stack.process_ingress_packet(build_syn_v4(40001, dst, 443));
+        }
+        assert!(req_rx.try_recv().is_err());
+        assert!(relay_rx.try_recv().is_err());
+        assert!(stack.device.pending_packets.is_empty());
+        assert_eq!(stack.active_tunnels.len(), 1);
+        assert!(!stack.registered_ips.contains(&IpCidr::new(IpAddress::v4(5, 6, 7, 8), 32)));
+        assert_eq!(stack.stats().snapshot().out_of_scope_tcp_dropped, 2);
+
+        // Only TCP is scoped
+        stack.process_ingress_packet(build_udp_v4([5, 6, 7, 8], 53, b"dns"));
+        assert!(relay_rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_default_action_for_tcp_outside_trap_cidrs() {
+        let config = |default_action| PrismConfig {
+            trap_cidrs: Some(vec![IpCidr::new(IpAddress::v4(1, 2, 3, 0), 24)]),
+            default_action,
+            ..Default::default()
+        };
+
+        // Reject: a network unreachable from the gateway, quoting the SYN
+        let (mut stack, mut tun_rx) = test_stack_with_tun(config(DefaultAction::Reject));
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        stack.device.pending_packets.clear();
+        let syn = build_syn_v4(40000, [5, 6, 7, 8], 443);
+        stack.process_ingress_packet(syn.clone());
+        assert!(req_rx.try_recv().is_err());
+        assert!(stack.device.pending_packets.is_empty());
+        let reply = tun_rx.try_recv().unwrap();
+        let ip = Ipv4Packet::new_checked(&reply[..]).unwrap();
+        assert_eq!((ip.src_addr(), ip.dst_addr()), (Ipv4Address::new(10, 11, 12, 1), Ipv4Address::new(10, 11, 12, 2)));
+        let icmp = Icmpv4Packet::new_checked(ip.payload()).unwrap();
+        assert_eq!((icmp.msg_type(), icmp.msg_code()), (Icmpv4Message::DstUnreachable, 0));
+        assert_eq!(&icmp.data()[..syn.len()], &syn[..]);
+        let snapshot = stack.stats().snapshot();
+        assert_eq!((snapshot.out_of_scope_tcp_rejected, snapshot.out_of_scope_tcp_dropped), (1, 0));
+
+        // Only SYNs are answered: the rest of the flow is dropped without an ICMP each
+        let ack = build_tcp_v4(40000, [5, 6, 7, 8], 443, TcpControl::None, Some(TcpSeqNumber(1)), b"data");
+        stack.process_ingress_packet(ack);
+        assert!(tun_rx.try_recv().is_err());
+        assert!(stack.device.pending_packets.is_empty());
+        let snapshot = stack.stats().snapshot();
+        assert_eq!((snapshot.out_of_scope_tcp_rejected, snapshot.out_of_scope_tcp_dropped), (1, 1));
+
+        // Trap: handled like any destination
+        let mut stack = test_stack(config(DefaultAction::Trap));
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        stack.process_ingress_packet(build_syn_v4(40000, [5, 6, 7, 8], 443));
+        assert_eq!(req_rx.try_recv().unwrap().target, "5.6.7.8:443".parse().unwrap());
+        let snapshot = stack.stats().snapshot();
+        assert_eq!((snapshot.out_of_scope_tcp_rejected, snapshot.out_of_scope_tcp_dropped), (0, 0));
+    }
+
+    #[test]
+    fn test_trap_copies_counted_per_syn() {
+        const SYNS: u16 = 5;
+        let mut stack = test_stack(PrismConfig::default());
+        let (req_tx, _req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+
+        let len = build_syn_v4(40000, [1, 2, 3, 4], 443).len();
+        for i in 0..SYNS {
+            stack.process_ingress_packet(build_syn_v4(40000 + i, [1, 2, 3, 4], 443));
+        }
+        let snapshot = stack.stats().snapshot();
+        assert_eq!(snapshot.trap_copies, 2 * SYNS as u64);
+        assert_eq!(snapshot.trap_bytes_copied, 2 * (SYNS as usize * len) as u64);
+
+        // Segments that aren't a SYN are never copied
+        let ack = build_tcp_v4(40000, [1, 2, 3, 4], 443, TcpControl::None, Some(TcpSeqNumber(1)), b"data");
+        stack.process_ingress_packet(ack);
+        assert_eq!(stack.stats().snapshot().trap_copies, 2 * SYNS as u64);
+    }
+
+    #[test]
+    fn test_fragmented_syn_is_trapped_once_whole() {
+        use crate::constants::IPV4_REASSEMBLY_TIMEOUT;
+        let mut stack = test_stack(PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+
+        // Split after the first 8 bytes of the TCP header
+        let syn = build_syn_v4(40000, [1, 2, 3, 4], 443);
+        let fragment = |range: std::ops::Range<usize>, more: bool| {
+            let mut pkt = BytesMut::from(&syn[..20]);
+            pkt.extend_from_slice(&syn[20 + range.start..20 + range.end]);
+            let mut ip = Ipv4Packet::new_unchecked(&mut pkt[..]);
+            ip.set_total_len((20 + range.len()) as u16);
+            ip.set_ident(0x1234);
+            ip.set_more_frags(more);
+            ip.set_frag_offset(range.start as u16);
+            ip.fill_checksum();
+            pkt
+        };
+        let first = fragment(0..8, true);
+        let second = fragment(8..syn.len() - 20, false);
+
+        // The second fragment arrives first: no tunnel until both are in
+        stack.process_ingress_packet(second);
+        assert!(req_rx.try_recv().is_err());
+        assert!(stack.device.pending_packets.is_empty());
+        stack.process_ingress_packet(first);
+        let request = req_rx.try_recv().unwrap();
+        assert_eq!(request.target, "1.2.3.4:443".parse().unwrap());
+        assert_eq!(stack.stats().snapshot().ipv4_fragments_dropped, 0);
+
+        // A lone fragment is forgotten after a while
+        stack.process_ingress_packet(fragment(0..8, true));
+        assert_eq!(stack.ipv4_reassembly.expire(time::Instant::now() + IPV4_REASSEMBLY_TIMEOUT), 1);
+    }
+
+    #[test]
+    fn test_gateway_syns_are_refused_or_passed() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig {
+            gateway_tcp_ports: Some(PortSet::new().with_port(53)),
+            ..Default::default()
+        });
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        stack.device.pending_packets.clear();
+
+        // Refused right away, without a tunnel
+        stack.process_ingress_packet(build_syn_v4(40000, [10, 11, 12, 1], 8080));
+        assert!(req_rx.try_recv().is_err());
+        assert!(stack.active_tunnels.is_empty() && stack.pending_syns.is_empty());
+        assert!(stack.device.pending_packets.is_empty());
+        let rst = tun_rx.try_recv().unwrap();
+        let ip = Ipv4Packet::new_checked(&rst[..]).unwrap();
+        assert_eq!(ip.src_addr(), Ipv4Address::new(10, 11, 12, 1));
+        let tcp = TcpPacket::new_checked(ip.payload()).unwrap();
+        assert!(tcp.rst() && tcp.ack());
+        assert_eq!((tcp.src_port(), tcp.dst_port()), (8080, 40000));
+        assert_eq!(stack.stats().snapshot().gateway_syns_refused, 1);
+
+        // A listed port is smoltcp's, other destinations are still trapped
+        stack.process_ingress_packet(build_syn_v4(40001, [10, 11, 12, 1], 53));
+        assert_eq!(stack.device.pending_packets.len(), 1);
+        assert!(req_rx.try_recv().is_err());
+        stack.process_ingress_packet(build_syn_v4(40002, [1, 2, 3, 4], 443));
+        assert!(req_rx.try_recv().is_ok());
+        assert_eq!(stack.stats().snapshot().gateway_syns_refused, 1);
+    }
+
+    #[test]
+    fn test_target_addresses_are_bounded_and_released() {
+        use crate::constants::MAX_ADDRS;
+
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(16);
+        stack.set_tunnel_request_sender(req_tx);
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        stack.set_event_sender(event_tx);
+
+        // By default a SYN to the gateway is refused rather than tunneled back to it
+        stack.process_ingress_packet(build_syn_v4(40000, [10, 11, 12, 1], 8080));
+        assert!(req_rx.try_recv().is_err());
+        assert_eq!(stack.stats().snapshot().gateway_syns_refused, 1);
+        tun_rx.try_recv().unwrap();
+
+        // Every destination the table holds completes its handshake; two tunnels share one
+        let free = MAX_ADDRS - GATEWAY_CIDRS.len();
+        for i in 0..free as u8 {
+            stack.process_ingress_packet(build_syn_v4(40000, [1, 2, 3, i], 443));
+        }
+        stack.process_ingress_packet(build_syn_v4(40001, [1, 2, 3, 0], 443));
+        stack.iface.poll(Instant::from_millis(0), &mut stack.device, &mut stack.sockets);
+        for _ in 0..=free {
+            let syn_ack = tun_rx.try_recv().unwrap();
+            assert_eq!(crate::trap::tcp_flags(&syn_ack).unwrap() & 0x12, 0x12);
+        }
+        assert_eq!(stack.registered_ips.len(), free);
+
+        // One more is refused with a RST instead of being recorded without an address
+        stack.process_ingress_packet(build_syn_v4(40000, [5, 6, 7, 8], 443));
+        assert!(!stack.registered_ips.contains(&IpCidr::new(IpAddress::v4(5, 6, 7, 8), 32)));
+        assert_eq!(stack.active_tunnels.len(), free + 1);
+        let rst = tun_rx.try_recv().unwrap();
+        assert_ne!(crate::trap::tcp_flags(&rst).unwrap() & 0x04, 0);
+        assert_eq!(stack.stats().snapshot().setup_addr_table_full, 1);
+        let events: Vec<_> = std::iter::from_fn(|| event_rx.try_recv().ok()).collect();
+        assert!(matches!(events.last().unwrap(), TunnelEvent::Rejected { reason: CloseReason::Limit, .. }));
+
+        // A shared address stays while one of its tunnels is open
+        let shared = IpCidr::new(IpAddress::v4(1, 2, 3, 0), 32);
+        for (port, kept) in [(40000, true), (40001, false)] {
+            let flow = (SocketAddr::from(([10, 11, 12, 2], port)), SocketAddr::from(([1, 2, 3, 0], 443)));
+            let id = stack.active_tunnels[&stack.flow_index[&flow]].id;
+            stack.abort_tunnel(id);
+            stack.pump_egress(true);
+            assert_eq!(stack.registered_ips.contains(&shared), kept);
+            assert_eq!(stack.iface.ip_addrs().contains(&shared), kept);
+        }
+
+        // ... which makes room for the next destination
+        stack.process_ingress_packet(build_syn_v4(40002, [5, 6, 7, 8], 443));
+        assert!(stack.registered_ips.contains(&IpCidr::new(IpAddress::v4(5, 6, 7, 8), 32)));
+    }
+
+    #[tokio::test]
+    async fn test_syn_data_is_delivered_once() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+
+        // A TFO SYN: the trap keeps the data, smoltcp leaves it unacknowledged
+        let syn = build_tcp_v4(40000, [10, 11, 12, 9], 8080, TcpControl::Syn, None, b"early data");
+        assert_eq!(crate::trap::tcp_payload_len(&syn), Some(10));
+        stack.process_ingress_packet(syn);
+        let mut relayer = req_rx.try_recv().unwrap();
+        stack.poll_once(Instant::from_millis(0));
+        let syn_ack = tun_rx.try_recv().unwrap();
+        let ip = Ipv4Packet::new_checked(&syn_ack[..]).unwrap();
+        let tcp = TcpPacket::new_checked(ip.payload()).unwrap();
+        assert!(tcp.syn() && tcp.ack());
+        assert_eq!(tcp.ack_number(), TcpSeqNumber(1001));
+        assert_eq!(stack.stats().snapshot().syns_with_data, 1);
+
+        // So the client sends it again after the handshake, and the relayer sees it once
+        let data = build_tcp_v4_seq(
+            40000, [10, 11, 12, 9], 8080, TcpControl::Psh, TcpSeqNumber(1001),
+            Some(tcp.seq_number() + 1), b"early data",
+        );
+        stack.process_ingress_packet(data);
+        stack.poll_once(Instant::from_millis(0));
+        stack.pump_egress(false);
+        assert_eq!(relayer.rx.try_recv().unwrap(), Bytes::from_static(b"early data"));
+        assert!(relayer.rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_open_tunnel_without_syn() {
+        let (mut stack, mut tun_rx) = test_stack_with_tun(PrismConfig::default());
+        let target: SocketAddr = "10.11.12.9:8080".parse().unwrap();
+        let source: SocketAddr = "10.11.12.2:40000".parse().unwrap();
+        assert_eq!(stack.open_tunnel(target, source), Err(OpenTunnelError::NoRelayer));
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+
+        // Requested and registered right away, with nothing sent to the client
+        let handle = stack.open_tunnel(target, source).unwrap();
+        let mut relayer = req_rx.try_recv().unwrap();
+        assert_eq!((relayer.target, relayer.source), (target, source));
+        assert_eq!(stack.active_tunnels[&handle].id, relayer.id);
+        assert_eq!(stack.sockets.get::<tcp::Socket>(handle).state(), tcp::State::Closed);
+        assert!(stack.device.pending_packets.is_empty());
+        assert_eq!(stack.open_tunnel(target, source), Err(OpenTunnelError::Exists(handle)));
+
+        // Another client of the same target gets a tunnel of its own, not this socket
+        let mut other = TestClient::connect(40001, 8080);
+        other.exchange(&mut stack, &mut tun_rx, true);
+        assert_eq!(other.socket().state(), tcp::State::Established);
+        let other_relayer = req_rx.try_recv().unwrap();
+        assert_eq!(other_relayer.source, "10.11.12.2:40001".parse().unwrap());
+        assert_eq!(stack.active_tunnels.len(), 2);
+        assert_eq!(stack.sockets.get::<tcp::Socket>(handle).state(), tcp::State::Closed);
+
+        // The client's SYN lands on that socket instead of opening a second tunnel
+        let mut client = TestClient::connect(40000, 8080);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        assert!(req_rx.try_recv().is_err());
+        assert_eq!(stack.active_tunnels.len(), 2);
+        assert_eq!(stack.sockets.get::<tcp::Socket>(handle).state(), tcp::State::Established);
+
+        client.socket().send_slice(b"hello").unwrap();
+        client.exchange(&mut stack, &mut tun_rx, true);
+        assert_eq!(relayer.rx.recv().await.unwrap(), Bytes::from_static(b"hello"));
+        relayer.tx.send(Bytes::from_static(b"world")).await.unwrap();
+        let (h, data) = stack.ingress_streams.next().await.unwrap();
+        stack.handle_remote_data(h, data);
+        client.exchange(&mut stack, &mut tun_rx, true);
+        let mut buf = [0u8; 8];
+        assert_eq!(client.socket().recv_slice(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"world");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_open_tunnel_expires_without_syn() {
+        let mut stack = test_stack(PrismConfig::default());
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        stack.set_tunnel_request_sender(req_tx);
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        stack.set_event_sender(event_tx);
+        let target: SocketAddr = "1.2.3.4:443".parse().unwrap();
+        assert_eq!(stack.open_tunnel("1.2.3.4:0".parse().unwrap(), "10.11.12.2:40000".parse().unwrap()), Err(OpenTunnelError::ListenFailed));
+        let handle = stack.open_tunnel(target, "10.11.12.2:40000".parse().unwrap()).unwrap();
+        let mut relayer = req_rx.try_recv().unwrap();
+        assert!(matches!(event_rx.try_recv().unwrap(), TunnelEvent::Opened { .. }));
+
+        time::advance(stack.config.handshake_timeout - Duration::from_millis(1)).await;
+        stack.pump_egress(true);
+        assert!(stack.active_tunnels.contains_key(&handle));
+
+        // Dropped once the deadline passes, as rejected rather than closed
+        time::advance(Duration::from_millis(1)).await;
+        stack.pump_egress(true);
+        assert!(stack.active_tunnels.is_empty());
+        assert!(stack.flow_index.is_empty() && stack.id_index.is_empty() && stack.registered_ips.is_empty());
+        assert_eq!(
+            event_rx.try_recv().unwrap(),
+            TunnelEvent::Rejected { id: relayer.id, target, reason: CloseReason::IdleTimeout }
+        );
+        assert!(event_rx.try_recv().is_err());
+        assert!(relayer.rx.recv().await.is_none());
+        assert_eq!(stack.stats().snapshot().setup_half_open_timeout, 1);
+    }
+
+    #[test]
+    fn test_unicast_udp_still_relayed() {
+        let mut stack = test_stack(PrismConfig { cast_policy: CastPolicy::Drop, ..Default::default() });
+        let (relay_tx, mut relay_rx) = mpsc::channel(16);
+        stack.set_blind_relay_sender(relay_tx);
+        stack.process_ingress_packet(build_udp_v4([8, 8, 8, 8], 53, b"dns"));
+        assert!(relay_rx.try_recv().is_ok());
     }
 }
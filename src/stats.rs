@@ -0,0 +1,216 @@
+//! Runtime counters for the Prism Stack.
+//!
+//! Counters are plain atomics so they can be bumped from the poll loop (and the device)
+//! without locking, and read from any thread through a shared `Arc<PrismStats>`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+macro_rules! define_stats {
+    ($($(#[$doc:meta])* $name:ident),* $(,)?) => {
+        /// Live counters, updated with `Ordering::Relaxed`.
+        #[derive(Debug, Default)]
+        pub struct PrismStats {
+            $($(#[$doc])* pub $name: AtomicU64,)*
+        }
+
+        /// A point-in-time copy of [`PrismStats`].
+        #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+        pub struct StatsSnapshot {
+            $($(#[$doc])* pub $name: u64,)*
+        }
+
+        impl PrismStats {
+            /// Reads every counter into a [`StatsSnapshot`].
+            pub fn snapshot(&self) -> StatsSnapshot {
+                StatsSnapshot {
+                    $($name: self.$name.load(Ordering::Relaxed),)*
+                }
+            }
+        }
+    };
+}
+
+define_stats! {
+    /// SYN retransmits recognized by the SYN cache (or a pending Consistent handshake).
+    duplicate_syns_suppressed,
+    /// Unfinished handshakes restarted by a SYN with a new sequence number
+    /// (`DuplicateSynPolicy::Restart`).
+    handshakes_restarted,
+    /// smoltcp `iface.poll` calls made by the run loop (and `poll_once`).
+    poll_iterations,
+    /// Polls that reported a socket readiness change; each one may trigger an inline re-poll.
+    polls_with_changes,
+    /// Re-injected SYNs that left their socket in Listen (`verify_reinjected_syns`).
+    reinjected_syns_rejected,
+    /// SYNs that waited in the `admit_per_poll` admission queue.
+    syns_queued,
+    /// Queued SYNs dropped after waiting longer than `handshake_timeout`.
+    syns_queue_expired,
+    /// SYNs refused with a RST because the admission queue was full (`admission_queue_cap`).
+    syns_queue_dropped,
+    /// Blind Relay packets dropped on a full channel (`BlindRelayPolicy::DropOnFull`).
+    blind_relay_dropped_full,
+    /// Blind Relay packets pushed out of the ring (`BlindRelayPolicy::DropOldest`).
+    blind_relay_dropped_oldest,
+    /// Blind Relay packets lost to a closed channel (`BlindRelayPolicy::Block`).
+    blind_relay_dropped_blocked,
+    /// Non-TCP packets larger than `egress_mtu`, never relayed (see `OversizePolicy`).
+    blind_relay_oversize_dropped,
+    /// UDP tunnels requested for flows to `udp_trap_ports`.
+    udp_tunnels_opened,
+    /// Datagrams lost on a UDP tunnel: either way for a full or closed channel (or request
+    /// channel), too large for the TUN MTU on the way back, or sent back after the tunnel
+    /// was closed.
+    udp_tunnel_datagrams_dropped,
+    /// Datagrams of new UDP flows relayed because `max_udp_tunnels` were already open.
+    udp_tunnels_over_limit,
+    /// Last [`MemoryEstimate::total`] in bytes, refreshed on every tunnel sweep and by
+    /// `PrismStack::memory_estimate`. A gauge: it goes down as well as up.
+    memory_estimate_bytes,
+    /// Packets the stack emitted but dropped because `tx_queue` was full (the TUN writer
+    /// falls behind). A closed `tx_queue` stops the stack instead.
+    tx_queue_full,
+    /// Packets carved from a pooled TX buffer (`TX_POOL_CAPACITY`)...
+    tx_pool_hits,
+    /// ...and packets that needed a fresh `TX_ARENA_SIZE` arena: the pool was empty, or its
+    /// next remainder was too small for the packet (which then stays pooled).
+    tx_pool_misses,
+    /// Arena remainders put back into the pool after a packet was carved off...
+    tx_pool_recycled,
+    /// ...and ones dropped, below `TX_POOL_RECYCLE_THRESHOLD` or with the pool at
+    /// `TX_POOL_MAX_SIZE`. Their memory is freed with the packets still sharing it.
+    tx_pool_discarded,
+    /// Buffers allocated to copy trapped SYNs: one for the MSS clamp, one for the packet
+    /// handed to smoltcp. Non-SYN TCP isn't copied.
+    trap_copies,
+    /// Bytes copied into those buffers.
+    trap_bytes_copied,
+    /// Times the socket set was shrunk after a connection spike (`SOCKET_COMPACT_RATIO`).
+    socket_set_compactions,
+    /// Tunnel sockets visited by the egress pump. Grows with activity, not with tunnel count.
+    egress_sockets_visited,
+    /// Times a tunnel's egress channel was found full, all tunnels together. Per tunnel (and
+    /// with the time spent full) in `PrismStack::tunnel_backpressure`.
+    egress_backpressure_events,
+    /// Times a tunnel had data waiting but was over its `rate_limit_bps` budget, so its
+    /// socket was left unread until the bucket refilled.
+    egress_rate_limited,
+    /// First-bytes peeks lost to a full or closed peek channel (`PrismStack::set_peek_sender`).
+    peeks_dropped,
+    /// Tunnels reset by their relayer (`TunnelAbort`), pending Consistent handshakes included.
+    relayer_aborts,
+    /// Tunnels reset because their ingress couldn't be put back in order (`ingress_reorder`).
+    ingress_reorder_aborts,
+    /// TCP segments dropped for impossible flag combinations (`drop_invalid_flags`).
+    invalid_tcp_flags_dropped,
+    /// TCP segments dropped for a destination outside `trap_cidrs` (`DefaultAction::Drop`, or
+    /// any but a SYN with `DefaultAction::Reject`).
+    out_of_scope_tcp_dropped,
+    /// SYNs answered with an ICMP network unreachable for a destination outside `trap_cidrs`
+    /// (`DefaultAction::Reject`).
+    out_of_scope_tcp_rejected,
+    /// SYNs to a gateway address refused with a RST (not on `gateway_tcp_ports`).
+    gateway_syns_refused,
+    /// SYNs refused with a RST while the stack was closing (`PrismStack::close`).
+    syns_refused_closing,
+    /// Trapped SYNs carrying data (TCP Fast Open). smoltcp doesn't accept it, so the client
+    /// sends it again once the handshake completes.
+    syns_with_data,
+    /// RSTs and SYN-ACKs dropped for not belonging to any flow or gateway address.
+    stray_tcp_dropped,
+    /// IPv6 packets whose fixed header didn't parse (truncated, or a payload length past the
+    /// end of the buffer). They are handed to smoltcp, which drops them.
+    ipv6_classification_failures,
+    /// Packets for smoltcp dropped at `pending_packets_cap` (see `PendingOverflowPolicy`).
+    pending_overflow_dropped,
+    /// Packets of an address family the stack doesn't serve (`PrismConfig::ip_mode`).
+    other_family_dropped,
+    /// IPv4 TCP fragments dropped: malformed, over `IPV4_REASSEMBLY_MAX_PACKETS`, or (one per
+    /// packet) still incomplete after `IPV4_REASSEMBLY_TIMEOUT`.
+    ipv4_fragments_dropped,
+    /// Packets the classifier (`PrismStack::set_classifier`) answered with `Verdict::Drop`.
+    classifier_dropped,
+    /// Packets whose classifier panicked. They are handled as `default_verdict` decides.
+    classifier_panics,
+    /// SYNs dropped by `syn_rate_limit`.
+    rate_limited,
+    /// Tunnels opened to IPv4 targets.
+    tunnels_opened_v4,
+    /// Tunnels opened to IPv6 targets.
+    tunnels_opened_v6,
+    /// Client -> remote bytes handed to IPv4 tunnels.
+    bytes_to_remote_v4,
+    /// Client -> remote bytes handed to IPv6 tunnels.
+    bytes_to_remote_v6,
+    /// Remote -> client bytes accepted from IPv4 tunnels.
+    bytes_from_remote_v4,
+    /// Remote -> client bytes accepted from IPv6 tunnels.
+    bytes_from_remote_v6,
+    /// Remote -> client bytes that arrived after the client socket stopped sending for good
+    /// (reset, or closed by the client). A full send buffer parks data instead.
+    bytes_from_remote_undeliverable,
+    /// Setup failure: the relayer's request channel was full (and `TUNNEL_REQUEST_BACKLOG`
+    /// too), closed or never set.
+    setup_request_rejected,
+    /// Tunnel requests held back because the relayer's request channel was full.
+    tunnel_requests_staged,
+    /// Staged tunnel requests lost when the request channel closed.
+    tunnel_requests_staged_dropped,
+    /// Setup failure: the tunnel socket couldn't listen on the target endpoint.
+    setup_listen_failed,
+    /// Setup failure: smoltcp's interface address table (`IFACE_MAX_ADDR_COUNT`, see the
+    /// crate's Cargo.toml) had no room for the target address.
+    setup_addr_table_full,
+    /// Setup failure: the relayer didn't answer a Consistent handshake within `handshake_timeout`.
+    setup_handshake_timeout,
+    /// Setup failure: the relayer answered a Consistent handshake with failure (or dropped it).
+    setup_upstream_failed,
+    /// Setup failure: the tunnel socket closed before the client completed the handshake.
+    setup_half_open_timeout,
+}
+
+impl PrismStats {
+    /// Increments a counter by one.
+    #[inline]
+    pub(crate) fn bump(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Adds `n` to a counter.
+    #[inline]
+    pub(crate) fn add(counter: &AtomicU64, n: usize) {
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Overwrites a gauge.
+    #[inline]
+    pub(crate) fn set(gauge: &AtomicU64, n: usize) {
+        gauge.store(n as u64, Ordering::Relaxed);
+    }
+}
+
+/// Memory held by a stack, in bytes, computed from known buffer sizes and counts. Allocator
+/// overhead and smoltcp's own per-socket state aren't included.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryEstimate {
+    /// RX + TX buffers of all tunnel sockets
+    pub socket_buffers: usize,
+    /// Slots of the socket set, live or free: it only shrinks when compacted
+    pub socket_slots: usize,
+    /// Arenas kept in the device's TX pool
+    pub tx_pool: usize,
+    /// Packets queued for smoltcp's next poll
+    pub pending_packets: usize,
+    /// Data the stack holds outside smoltcp: parked remote data, SYNs waiting for a
+    /// handshake or admission, the Blind Relay ring
+    pub held: usize,
+    /// Upper bound for client data queued in tunnel egress channels (queued messages x
+    /// `max_egress_chunk`, and queued UDP tunnel datagrams x `egress_mtu`)
+    pub channel_buffers: usize,
+}
+
+impl MemoryEstimate {
+    pub fn total(&self) -> usize {
+        self.socket_buffers + self.socket_slots + self.tx_pool + self.pending_packets + self.held + self.channel_buffers
+    }
+}
@@ -1,31 +1,91 @@
 use smoltcp::wire::{IpProtocol, Ipv4Packet, TcpPacket, Ipv6Packet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::net::{IpAddr, SocketAddr};
+use std::ops::Range;
 use bytes::Bytes;
-use crate::constants::DEFAULT_MSS_CLAMP;
+use crate::constants::{DEFAULT_MSS_CLAMP, IPV6_MAX_EXT_HEADERS};
 
 #[derive(Debug, Clone)]
 pub struct PrismTrap {
+    /// Client address (inside the TUN namespace)
+    pub src: SocketAddr,
     pub dst: SocketAddr,
     pub packet: Bytes,
+    /// Correlation ID of the connection, unique per stack. Assigned by the stack when it
+    /// takes the SYN (`inspect_packet` leaves it 0), then carried by its `TunnelRequest`
+    /// and `TunnelEvent`s.
+    pub id: u64,
 }
 
 pub type TrapEvent = PrismTrap;
 
+/// MSS clamp per address family. A full segment carries 40 (IPv4) or 60 (IPv6) bytes of
+/// headers, so the same path MTU allows a 20 bytes larger MSS over IPv4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MssClamp {
+    pub v4: u16,
+    pub v6: u16,
+}
+
+impl MssClamp {
+    /// The largest MSS per family whose segments (IP + TCP headers included) fit `mtu`.
+    pub fn for_mtu(mtu: usize) -> Self {
+        let mss = |headers: usize| mtu.saturating_sub(headers).min(u16::MAX as usize) as u16;
+        Self { v4: mss(40), v6: mss(60) }
+    }
+}
+
+impl Default for MssClamp {
+    /// `DEFAULT_MSS_CLAMP` for both families.
+    fn default() -> Self {
+        Self { v4: DEFAULT_MSS_CLAMP, v6: DEFAULT_MSS_CLAMP }
+    }
+}
+
 pub enum PacketType {
     Tcp,
     Other, // UDP, ICMP, etc.
     Unknown, // Not IP
 }
 
+/// Where the stack sends an IP packet from the TUN, as decided by a packet classifier (see
+/// `PrismStack::set_classifier`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Prism's own handling: TCP is trapped (or fed to its flow's socket), UDP to
+    /// `udp_trap_ports` goes into a UDP tunnel, pings to the gateway are answered, and
+    /// anything else goes to the Blind Relay.
+    Trap,
+    /// Straight to the Blind Relay, TCP included.
+    BlindRelay,
+    /// Straight to smoltcp, which answers it or drops it. Non-TCP packets to the gateway
+    /// itself get this with `Trap` too.
+    Stack,
+    /// Dropped (and counted in `classifier_dropped`).
+    Drop,
+}
+
+/// The built-in classifier: whatever parses as IP is trapped, the rest goes to smoltcp.
+pub fn default_verdict(buffer: &[u8]) -> Verdict {
+    match get_packet_type(buffer) {
+        PacketType::Tcp | PacketType::Other => Verdict::Trap,
+        PacketType::Unknown => Verdict::Stack,
+    }
+}
+
 /// Inspects the packet to determine if it is TCP or something else.
 pub fn get_packet_type(buffer: &[u8]) -> PacketType {
-    if buffer.len() < 1 { return PacketType::Unknown; }
+    if buffer.is_empty() { return PacketType::Unknown; }
     
     let version = buffer[0] >> 4;
     match version {
         4 => {
             if let Ok(ip) = Ipv4Packet::new_checked(buffer) {
-                if ip.next_header() == IpProtocol::Tcp {
+                // Fragments are TCP only if the TCP header is all there: non-first ones carry
+                // none, and a first one may be cut before its end
+                let header_len = ip.header_len() as usize;
+                if ip.next_header() == IpProtocol::Tcp && ip.frag_offset() == 0 && header_len + 20 <= buffer.len() {
                     return PacketType::Tcp;
                 }
                 return PacketType::Other;
@@ -33,10 +93,15 @@ pub fn get_packet_type(buffer: &[u8]) -> PacketType {
             PacketType::Unknown
         }
         6 => {
-            if let Ok(_) = Ipv6Packet::new_checked(buffer) {
+            if Ipv6Packet::new_checked(buffer).is_ok() {
                 // Elegant IPv6 Extension Header Skipping
-                if let Ok((next_proto, _offset)) = skip_ipv6_headers(buffer) {
-                     if next_proto == IpProtocol::Tcp {
+                // smoltcp can't reassemble IPv6, so every fragment, first one included, goes to
+                // the blind relay together with the rest of its datagram.
+                if ipv6_fragment_ident(buffer).is_some() {
+                    return PacketType::Other;
+                }
+                if let Ok((next_proto, offset)) = skip_ipv6_headers(buffer) {
+                     if next_proto == IpProtocol::Tcp && offset + 20 <= buffer.len() {
                          return PacketType::Tcp;
                      }
                 }
@@ -49,12 +114,295 @@ pub fn get_packet_type(buffer: &[u8]) -> PacketType {
     }
 }
 
-fn skip_ipv6_headers(buffer: &[u8]) -> Result<(IpProtocol, usize), ()> {
+/// Whether `buffer` is a fragment of an IPv4 TCP segment (MF set or a non-zero offset).
+pub fn is_ipv4_tcp_fragment(buffer: &[u8]) -> bool {
+    buffer.first().is_some_and(|b| b >> 4 == 4)
+        && Ipv4Packet::new_checked(buffer)
+            .is_ok_and(|ip| ip.next_header() == IpProtocol::Tcp && (ip.more_frags() || ip.frag_offset() != 0))
+}
+
+/// Returns the destination IP address of an IPv4/IPv6 packet.
+pub fn destination_ip(buffer: &[u8]) -> Option<IpAddr> {
+    match buffer.first()? >> 4 {
+        4 => Ipv4Packet::new_checked(buffer).ok().map(|ip| IpAddr::V4(ip.dst_addr().into())),
+        6 => Ipv6Packet::new_checked(buffer).ok().map(|ip| IpAddr::V6(ip.dst_addr().into())),
+        _ => None,
+    }
+}
+
+/// Returns the length of an IPv4/IPv6 packet according to its header, excluding any trailing padding.
+pub fn ip_packet_len(buffer: &[u8]) -> Option<usize> {
+    match buffer.first()? >> 4 {
+        4 => Ipv4Packet::new_checked(buffer).ok().map(|ip| ip.total_len() as usize),
+        6 => Ipv6Packet::new_checked(buffer).ok().map(|ip| ip.total_len()),
+        _ => None,
+    }
+}
+
+/// Returns `(source, destination)` of a TCP segment, i.e. the flow it belongs to.
+pub fn tcp_flow(buffer: &[u8]) -> Option<(SocketAddr, SocketAddr)> {
+    let (src_ip, dst_ip, offset) = locate_tcp(buffer)?;
+    let ports = buffer.get(offset..offset + 4)?;
+    Some((
+        SocketAddr::new(src_ip, u16::from_be_bytes([ports[0], ports[1]])),
+        SocketAddr::new(dst_ip, u16::from_be_bytes([ports[2], ports[3]])),
+    ))
+}
+
+/// Hashes the flow a packet belongs to: its addresses and, for TCP and UDP, its ports. Both
+/// directions of a flow hash the same. Only the first fragment of a datagram carries its ports,
+/// so every fragment hashes by its addresses and fragment ID instead, keeping all the pieces of
+/// one datagram together. Anything that isn't IP hashes to 0.
+pub fn flow_hash(buffer: &[u8]) -> u64 {
+    let (src, dst, proto, offset, fragment) = match buffer.first().map(|b| b >> 4) {
+        Some(4) => {
+            let Ok(ip) = Ipv4Packet::new_checked(buffer) else { return 0 };
+            let fragment = (ip.more_frags() || ip.frag_offset() != 0).then_some(u32::from(ip.ident()));
+            let offset = Some(ip.header_len() as usize);
+            (IpAddr::V4(ip.src_addr().into()), IpAddr::V4(ip.dst_addr().into()), ip.next_header(), offset, fragment)
+        }
+        Some(6) => {
+            let Ok(ip) = Ipv6Packet::new_checked(buffer) else { return 0 };
+            let (proto, offset) = skip_ipv6_headers(buffer).map_or((IpProtocol::Ipv6NoNxt, None), |(p, o)| (p, Some(o)));
+            let fragment = ipv6_fragment_ident(buffer);
+            (IpAddr::V6(ip.src_addr().into()), IpAddr::V6(ip.dst_addr().into()), proto, offset, fragment)
+        }
+        _ => return 0,
+    };
+    let mut hasher = DefaultHasher::new();
+    if let Some(ident) = fragment {
+        (src.min(dst), src.max(dst), ident).hash(&mut hasher);
+        return hasher.finish();
+    }
+    let ports = match proto {
+        IpProtocol::Tcp | IpProtocol::Udp => offset.and_then(|offset| buffer.get(offset..offset + 4)),
+        _ => None,
+    };
+    let (src_port, dst_port) = ports.map_or((0, 0), |p| (u16::from_be_bytes([p[0], p[1]]), u16::from_be_bytes([p[2], p[3]])));
+    let (a, b) = (SocketAddr::new(src, src_port), SocketAddr::new(dst, dst_port));
+    (a.min(b), a.max(b)).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Returns the payload length of a TCP segment (past its options, up to the IP length).
+pub fn tcp_payload_len(buffer: &[u8]) -> Option<usize> {
+    let (_, _, offset) = locate_tcp(buffer)?;
+    let header_len = usize::from(buffer.get(offset + 12)? >> 4) * 4;
+    ip_packet_len(buffer)?.checked_sub(offset + header_len)
+}
+
+/// Returns the flags byte (FIN, SYN, RST, PSH, ACK, URG, ECE, CWR) of a TCP segment.
+pub fn tcp_flags(buffer: &[u8]) -> Option<u8> {
+    let (_, _, offset) = locate_tcp(buffer)?;
+    buffer.get(offset + 13).copied()
+}
+
+/// Returns the sequence number of a TCP segment.
+pub fn tcp_seq(buffer: &[u8]) -> Option<u32> {
+    let (_, _, offset) = locate_tcp(buffer)?;
+    let seq = buffer.get(offset + 4..offset + 8)?;
+    Some(u32::from_be_bytes([seq[0], seq[1], seq[2], seq[3]]))
+}
+
+/// Returns true for flag combinations no real TCP stack sends: SYN+FIN, SYN+RST,
+/// no flags at all (NULL scan) and FIN+PSH+URG (XMAS scan).
+pub fn has_invalid_tcp_flags(buffer: &[u8]) -> bool {
+    const FIN: u8 = 0x01;
+    const SYN: u8 = 0x02;
+    const RST: u8 = 0x04;
+    const PSH: u8 = 0x08;
+    const URG: u8 = 0x20;
+
+    let Some(flags) = tcp_flags(buffer) else {
+        return false;
+    };
+    let flags = flags & 0x3F; // ignore ECE/CWR
+    flags == 0
+        || flags & (SYN | FIN) == SYN | FIN
+        || flags & (SYN | RST) == SYN | RST
+        || flags & (FIN | PSH | URG) == FIN | PSH | URG
+}
+
+/// Whether a TCP segment that isn't trapped should reach smoltcp. RSTs and SYN-ACKs answer
+/// something sent from this side, so without a `tracked` destination (a known flow or the
+/// gateway's own address) they are strays from another stack on the TUN and get dropped.
+/// Anything else goes through: smoltcp resets unknown ACKs and data, which the client wants.
+pub fn is_trackable(flags: u8, tracked: bool) -> bool {
+    const SYN: u8 = 0x02;
+    const RST: u8 = 0x04;
+    const ACK: u8 = 0x10;
+
+    let reply = flags & RST != 0 || flags & (SYN | ACK) == SYN | ACK;
+    tracked || !reply
+}
+
+/// Builds the RST+ACK refusing a TCP segment (normally a trapped SYN), addressed back to its
+/// sender. `None` if `buffer` isn't a TCP segment.
+pub fn build_rst_reply(buffer: &[u8]) -> Option<Vec<u8>> {
+    use smoltcp::wire::{TcpControl, TcpRepr, TcpSeqNumber};
+
+    let (src, dst, offset) = locate_tcp(buffer)?;
+    let tcp = TcpPacket::new_checked(&buffer[offset..]).ok()?;
+    // SYN and FIN take a sequence number each
+    let seq_len = tcp.payload().len() + tcp.syn() as usize + tcp.fin() as usize;
+    let rst = TcpRepr {
+        src_port: tcp.dst_port(),
+        dst_port: tcp.src_port(),
+        control: TcpControl::Rst,
+        seq_number: TcpSeqNumber(0),
+        ack_number: Some(tcp.seq_number() + seq_len),
+        window_len: 0,
+        window_scale: None,
+        max_seg_size: None,
+        sack_permitted: false,
+        sack_ranges: [None; 3],
+        payload: &[],
+    };
+    emit_tcp(dst, src, &rst)
+}
+
+/// Serializes a TCP segment into a fresh IP packet, with checksums. `None` if the addresses
+/// are of different families.
+fn emit_tcp(src: IpAddr, dst: IpAddr, repr: &smoltcp::wire::TcpRepr) -> Option<Vec<u8>> {
+    use smoltcp::phy::ChecksumCapabilities;
+    use smoltcp::wire::{IpAddress, Ipv4Repr, Ipv6Repr};
+
+    let caps = ChecksumCapabilities::default();
+    let (src, dst) = (IpAddress::from(src), IpAddress::from(dst));
+    let mut packet;
+    let tcp_offset = match (src, dst) {
+        (IpAddress::Ipv4(src_addr), IpAddress::Ipv4(dst_addr)) => {
+            let ip = Ipv4Repr { src_addr, dst_addr, next_header: IpProtocol::Tcp, payload_len: repr.buffer_len(), hop_limit: 64 };
+            packet = vec![0u8; ip.buffer_len() + repr.buffer_len()];
+            ip.emit(&mut Ipv4Packet::new_unchecked(&mut packet[..]), &caps);
+            ip.buffer_len()
+        }
+        (IpAddress::Ipv6(src_addr), IpAddress::Ipv6(dst_addr)) => {
+            let ip = Ipv6Repr { src_addr, dst_addr, next_header: IpProtocol::Tcp, payload_len: repr.buffer_len(), hop_limit: 64 };
+            packet = vec![0u8; ip.buffer_len() + repr.buffer_len()];
+            ip.emit(&mut Ipv6Packet::new_unchecked(&mut packet[..]));
+            ip.buffer_len()
+        }
+        _ => return None,
+    };
+    repr.emit(&mut TcpPacket::new_unchecked(&mut packet[tcp_offset..]), &src, &dst, &caps);
+    Some(packet)
+}
+
+/// What smoltcp takes from a SYN to open a connection. Together with the flow's addresses it
+/// rebuilds an equivalent SYN ([`to_packet`](Self::to_packet)), so a SYN waiting for its
+/// tunnel doesn't have to be kept whole.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SynSummary {
+    pub seq: u32,
+    pub window_len: u16,
+    pub window_scale: Option<u8>,
+    pub max_seg_size: Option<u16>,
+    pub sack_permitted: bool,
+}
+
+impl SynSummary {
+    /// Summarizes a SYN. `None` for anything the summary can't carry: not a SYN, a bad
+    /// checksum (smoltcp would drop the SYN, so the rebuilt one mustn't pass), or data on
+    /// the SYN.
+    pub fn from_packet(buffer: &[u8]) -> Option<Self> {
+        use smoltcp::phy::ChecksumCapabilities;
+        use smoltcp::wire::{TcpControl, TcpRepr};
+
+        let (src, dst, offset) = locate_tcp(buffer)?;
+        let tcp = TcpPacket::new_checked(&buffer[offset..]).ok()?;
+        let repr = TcpRepr::parse(&tcp, &src.into(), &dst.into(), &ChecksumCapabilities::default()).ok()?;
+        if repr.control != TcpControl::Syn || repr.ack_number.is_some() || !repr.payload.is_empty() {
+            return None;
+        }
+        Some(Self {
+            seq: repr.seq_number.0 as u32,
+            window_len: repr.window_len,
+            window_scale: repr.window_scale,
+            max_seg_size: repr.max_seg_size,
+            sack_permitted: repr.sack_permitted,
+        })
+    }
+
+    /// The SYN from `src` to `dst` this summary stands for.
+    pub fn to_packet(&self, src: SocketAddr, dst: SocketAddr) -> Option<Vec<u8>> {
+        use smoltcp::wire::{TcpControl, TcpRepr, TcpSeqNumber};
+
+        let syn = TcpRepr {
+            src_port: src.port(),
+            dst_port: dst.port(),
+            control: TcpControl::Syn,
+            seq_number: TcpSeqNumber(self.seq as i32),
+            ack_number: None,
+            window_len: self.window_len,
+            window_scale: self.window_scale,
+            max_seg_size: self.max_seg_size,
+            sack_permitted: self.sack_permitted,
+            sack_ranges: [None; 3],
+            payload: &[],
+        };
+        emit_tcp(src.ip(), dst.ip(), &syn)
+    }
+}
+
+/// Returns the addresses of a TCP packet and the offset of its TCP header.
+fn locate_tcp(buffer: &[u8]) -> Option<(IpAddr, IpAddr, usize)> {
+    match buffer.first()? >> 4 {
+        4 => {
+            let ip = Ipv4Packet::new_checked(buffer).ok()?;
+            if ip.next_header() != IpProtocol::Tcp || ip.frag_offset() != 0 { return None; }
+            Some((IpAddr::V4(ip.src_addr().into()), IpAddr::V4(ip.dst_addr().into()), ip.header_len() as usize))
+        }
+        6 => {
+            let ip = Ipv6Packet::new_checked(buffer).ok()?;
+            let (next_proto, offset) = skip_ipv6_headers(buffer).ok()?;
+            if next_proto != IpProtocol::Tcp { return None; }
+            Some((IpAddr::V6(ip.src_addr().into()), IpAddr::V6(ip.dst_addr().into()), offset))
+        }
+        _ => None,
+    }
+}
+
+/// Client and server of a UDP datagram, and where its payload sits in `buffer`. Fragments
+/// carry no (or an incomplete) datagram and aren't recognised.
+pub fn udp_datagram(buffer: &[u8]) -> Option<(SocketAddr, SocketAddr, Range<usize>)> {
+    let (src, dst, offset) = match buffer.first()? >> 4 {
+        4 => {
+            let ip = Ipv4Packet::new_checked(buffer).ok()?;
+            if ip.next_header() != IpProtocol::Udp || ip.frag_offset() != 0 || ip.more_frags() {
+                return None;
+            }
+            (IpAddr::V4(ip.src_addr().into()), IpAddr::V4(ip.dst_addr().into()), ip.header_len() as usize)
+        }
+        6 => {
+            let ip = Ipv6Packet::new_checked(buffer).ok()?;
+            // A first fragment has the UDP header, but not the whole datagram
+            if ipv6_fragment_ident(buffer).is_some() {
+                return None;
+            }
+            let (IpProtocol::Udp, offset) = skip_ipv6_headers(buffer).ok()? else { return None };
+            (IpAddr::V6(ip.src_addr().into()), IpAddr::V6(ip.dst_addr().into()), offset)
+        }
+        _ => return None,
+    };
+    let end = ip_packet_len(buffer)?.min(buffer.len());
+    let header = buffer.get(offset..end)?.get(..8)?;
+    let src_port = u16::from_be_bytes([header[0], header[1]]);
+    let dst_port = u16::from_be_bytes([header[2], header[3]]);
+    Some((SocketAddr::new(src, src_port), SocketAddr::new(dst, dst_port), offset + 8..end))
+}
+
+/// Walks the IPv6 extension header chain and returns the upper-layer protocol and its offset.
+///
+/// For a non-first fragment (fragment offset != 0) there is no upper-layer header in the
+/// packet, so the walk stops at the Fragment header and returns `IpProtocol::Ipv6Frag`.
+pub(crate) fn skip_ipv6_headers(buffer: &[u8]) -> Result<(IpProtocol, usize), ()> {
     if buffer.len() < 40 { return Err(()); }
     let mut next_header = IpProtocol::from(buffer[6]); // Next Header field in IPv6 fixed header
     let mut offset = 40;
     
-    for _ in 0..10 {
+    // One pass per extension header, plus one to look at what follows the last
+    for _ in 0..=IPV6_MAX_EXT_HEADERS {
         if next_header == IpProtocol::Tcp {
             return Ok((next_header, offset));
         }
@@ -65,6 +413,12 @@ fn skip_ipv6_headers(buffer: &[u8]) -> Result<(IpProtocol, usize), ()> {
                 let next_proto = IpProtocol::from(buffer[offset]);
                 
                 let hdr_len = if next_header == IpProtocol::Ipv6Frag {
+                    if offset + 8 > buffer.len() { return Err(()); }
+                    // Fragment Offset is the upper 13 bits of bytes 2..4
+                    let frag_offset = u16::from_be_bytes([buffer[offset + 2], buffer[offset + 3]]) >> 3;
+                    if frag_offset != 0 {
+                        return Ok((IpProtocol::Ipv6Frag, offset));
+                    }
                     8
                 } else {
                     (buffer[offset + 1] as usize + 1) * 8
@@ -80,8 +434,36 @@ fn skip_ipv6_headers(buffer: &[u8]) -> Result<(IpProtocol, usize), ()> {
     Err(())
 }
 
-/// Inspects a raw packet buffer to detect TCP SYN segments.
+/// Returns the Identification of an IPv6 packet's Fragment header, if its extension header
+/// chain has one (first fragment or not).
+pub(crate) fn ipv6_fragment_ident(buffer: &[u8]) -> Option<u32> {
+    let mut next_header = IpProtocol::from(*buffer.get(6)?);
+    let mut offset = 40;
+    for _ in 0..IPV6_MAX_EXT_HEADERS {
+        match next_header {
+            IpProtocol::Ipv6Frag => {
+                let ident = buffer.get(offset + 4..offset + 8)?;
+                return Some(u32::from_be_bytes([ident[0], ident[1], ident[2], ident[3]]));
+            }
+            IpProtocol::HopByHop | IpProtocol::Ipv6Route | IpProtocol::Ipv6Opts => {
+                next_header = IpProtocol::from(*buffer.get(offset)?);
+                offset += (usize::from(*buffer.get(offset + 1)?) + 1) * 8;
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Inspects a raw packet buffer to detect TCP SYN segments, clamping their MSS to
+/// `DEFAULT_MSS_CLAMP`.
 pub fn inspect_packet(buffer: &[u8]) -> Option<PrismTrap> {
+    inspect_packet_with_clamp(buffer, MssClamp::default)
+}
+
+/// Like [`inspect_packet`], with the MSS clamp of each address family. `clamp` is only called
+/// for a SYN, the rest of the traffic doesn't pay for it.
+pub fn inspect_packet_with_clamp(buffer: &[u8], clamp: impl FnOnce() -> MssClamp) -> Option<PrismTrap> {
     // Basic length check
     if buffer.len() < 20 {
         return None;
@@ -89,41 +471,56 @@ pub fn inspect_packet(buffer: &[u8]) -> Option<PrismTrap> {
 
     let version = buffer[0] >> 4;
     match version {
-        4 => inspect_ipv4(buffer),
-        6 => inspect_ipv6(buffer),
+        4 => inspect_ipv4(buffer, clamp),
+        6 => inspect_ipv6(buffer, clamp),
         _ => None,
     }
 }
 
-fn inspect_ipv4(buffer: &[u8]) -> Option<PrismTrap> {
+fn inspect_ipv4(buffer: &[u8], clamp: impl FnOnce() -> MssClamp) -> Option<PrismTrap> {
     let ipv4_packet = Ipv4Packet::new_checked(buffer).ok()?;
-    if ipv4_packet.next_header() != IpProtocol::Tcp {
+    // A non-first fragment's payload isn't a TCP header, whatever it looks like
+    if ipv4_packet.next_header() != IpProtocol::Tcp || ipv4_packet.frag_offset() != 0 {
         return None;
     }
 
+    let src_addr = IpAddr::V4(ipv4_packet.src_addr().into());
     let dst_addr = IpAddr::V4(ipv4_packet.dst_addr().into());
     let payload = ipv4_packet.payload();
 
-    inspect_tcp(payload, dst_addr, buffer)
+    inspect_tcp(payload, src_addr, dst_addr, buffer, clamp)
 }
 
-fn inspect_ipv6(buffer: &[u8]) -> Option<PrismTrap> {
+fn inspect_ipv6(buffer: &[u8], clamp: impl FnOnce() -> MssClamp) -> Option<PrismTrap> {
     let ipv6_packet = Ipv6Packet::new_checked(buffer).ok()?;
+    // Fragments are never trapped, see `get_packet_type`
+    if ipv6_fragment_ident(buffer).is_some() {
+        return None;
+    }
     
     // Header Skipping Logic
     if let Ok((proto, offset)) = skip_ipv6_headers(buffer) {
         if proto == IpProtocol::Tcp {
-             if offset > buffer.len() { return None; }
+             // Bail out on a truncated TCP header (e.g. a first fragment split mid-header)
+             if offset + 20 > buffer.len() { return None; }
              let payload = &buffer[offset..];
+             let src_addr = IpAddr::V6(ipv6_packet.src_addr().into());
              let dst_addr = IpAddr::V6(ipv6_packet.dst_addr().into());
-             return inspect_tcp(payload, dst_addr, buffer);
+             return inspect_tcp(payload, src_addr, dst_addr, buffer, clamp);
         }
     }
 
     None
 }
 
-fn inspect_tcp(_buffer: &[u8], dst_ip: IpAddr, original_packet: &[u8]) -> Option<PrismTrap> {
+fn inspect_tcp(tcp: &[u8], src_ip: IpAddr, dst_ip: IpAddr, original_packet: &[u8], clamp: impl FnOnce() -> MssClamp) -> Option<PrismTrap> {
+    // Only a SYN is worth the copy below: data and ACKs go to smoltcp as they are
+    let tcp = TcpPacket::new_checked(tcp).ok()?;
+    if !tcp.syn() || tcp.ack() {
+        return None;
+    }
+    let clamp = clamp();
+
     // We need to modify the MSS option if present (MSS Clamping)
     // But original_packet is &[u8] which is immutable.
     // However, PrismTrap stores a Bytes, which owns the data.
@@ -135,6 +532,7 @@ fn inspect_tcp(_buffer: &[u8], dst_ip: IpAddr, original_packet: &[u8]) -> Option
     
     // To modify, we need to clone to a mutable buffer first.
     // This is the "Trap" path (SYN only), so copying is acceptable (low frequency).
+    // The stack counts these copies (`trap_copies`, `trap_bytes_copied`).
     
     let mut modified_packet = original_packet.to_vec();
     
@@ -145,77 +543,65 @@ fn inspect_tcp(_buffer: &[u8], dst_ip: IpAddr, original_packet: &[u8]) -> Option
     match version {
         4 => {
             if let Ok(mut ip) = Ipv4Packet::new_checked(&mut modified_packet) {
-                let src_addr = ip.src_addr();
-                let dst_addr = ip.dst_addr();
-                
                 // Re-borrow payload after inner scope
                 let payload = ip.payload_mut();
                 
                 // 1. Check flags & get port
                 let mut should_clamp = false;
+                let mut src_port = 0;
                 let mut dst_port = 0;
                 if let Ok(tcp) = TcpPacket::new_checked(&payload) {
                      if tcp.syn() && !tcp.ack() {
                          should_clamp = true;
+                         src_port = tcp.src_port();
                          dst_port = tcp.dst_port();
                      }
                 }
                 
                 if should_clamp {
-                    // 2. Clamp MSS on raw payload
-                    clamp_mss_raw(payload);
-                    
-                    // 3. Re-calculate checksums
-                    if let Ok(mut tcp) = TcpPacket::new_checked(payload) {
-                        tcp.fill_checksum(&src_addr.into(), &dst_addr.into());
-                    }
-                    ip.fill_checksum();
-                    
+                    // 2. Clamp MSS on raw payload (the IP header doesn't change)
+                    clamp_mss_raw(payload, clamp.v4);
+
                     let event = PrismTrap {
+                        src: SocketAddr::new(src_ip, src_port),
                         dst: SocketAddr::new(dst_ip, dst_port),
                         packet: Bytes::from(modified_packet),
+                        id: 0,
                     };
                     return Some(event);
                 }
             }
         },
-        6 => {
-             if let Ok(_) = Ipv6Packet::new_checked(&mut modified_packet) {
-                 // IPv6 Extension Header Skipping to find TCP payload
-                 if let Ok((proto, offset)) = skip_ipv6_headers(&modified_packet) {
-                     if proto == IpProtocol::Tcp && offset < modified_packet.len() {
-                         let tcp_payload = &modified_packet[offset..];
-                         
-                         // 1. Check SYN flag & get port
-                         let mut should_clamp = false;
-                         let mut dst_port = 0;
-                         if let Ok(tcp) = TcpPacket::new_checked(tcp_payload) {
-                             if tcp.syn() && !tcp.ack() {
-                                 should_clamp = true;
-                                 dst_port = tcp.dst_port();
-                             }
-                         }
-                         
-                         if should_clamp {
-                             // 2. Clamp MSS on TCP payload (mutable slice)
-                             let tcp_payload_mut = &mut modified_packet[offset..];
-                             clamp_mss_raw(tcp_payload_mut);
-                             
-                             // 3. Re-calculate TCP checksum (IPv6 has no IP checksum)
-                             let src_addr = Ipv6Packet::new_checked(&modified_packet).unwrap().src_addr();
-                             let dst_addr_smol = Ipv6Packet::new_checked(&modified_packet).unwrap().dst_addr();
-                             let tcp_payload_mut = &mut modified_packet[offset..];
-                             if let Ok(mut tcp) = TcpPacket::new_checked(tcp_payload_mut) {
-                                 tcp.fill_checksum(&src_addr.into(), &dst_addr_smol.into());
-                             }
-                             
-                             let event = PrismTrap {
-                                 dst: SocketAddr::new(dst_ip, dst_port),
-                                 packet: Bytes::from(modified_packet),
-                             };
-                             return Some(event);
+        6 if Ipv6Packet::new_checked(&mut modified_packet).is_ok() => {
+             // IPv6 Extension Header Skipping to find TCP payload
+             if let Ok((proto, offset)) = skip_ipv6_headers(&modified_packet) {
+                 if proto == IpProtocol::Tcp && offset < modified_packet.len() {
+                     let tcp_payload = &modified_packet[offset..];
+                     
+                     // 1. Check SYN flag & get port
+                     let mut should_clamp = false;
+                     let mut src_port = 0;
+                     let mut dst_port = 0;
+                     if let Ok(tcp) = TcpPacket::new_checked(tcp_payload) {
+                         if tcp.syn() && !tcp.ack() {
+                             should_clamp = true;
+                             src_port = tcp.src_port();
+                             dst_port = tcp.dst_port();
                          }
                      }
+                     
+                     if should_clamp {
+                         // 2. Clamp MSS on TCP payload (mutable slice)
+                         clamp_mss_raw(&mut modified_packet[offset..], clamp.v6);
+
+                         let event = PrismTrap {
+                             src: SocketAddr::new(src_ip, src_port),
+                             dst: SocketAddr::new(dst_ip, dst_port),
+                             packet: Bytes::from(modified_packet),
+                             id: 0,
+                         };
+                         return Some(event);
+                     }
                  }
              }
         },
@@ -225,10 +611,9 @@ fn inspect_tcp(_buffer: &[u8], dst_ip: IpAddr, original_packet: &[u8]) -> Option
     None
 }
 
-/// Clamps the MSS option in a TCP packet to a safe value (e.g. 1280)
-// Removed old clamp_mss function to avoid confusion and unused code warnings
-// Fixed signature to take raw buffer
-fn clamp_mss_raw(buffer: &mut [u8]) {
+/// Lowers the MSS option of a TCP segment to `clamp` and patches the checksum for just that
+/// change (RFC 1624), so a segment that arrived with a bad checksum still has one.
+fn clamp_mss_raw(buffer: &mut [u8], clamp: u16) {
     if buffer.len() < 20 { return; }
     let data_offset = ((buffer[12] >> 4) * 4) as usize;
     if data_offset < 20 || data_offset > buffer.len() { return; }
@@ -238,21 +623,33 @@ fn clamp_mss_raw(buffer: &mut [u8]) {
     let mut i = 0;
     while i < options.len() {
         let kind = options[i];
-        if kind == 0 || kind == 1 { // EOL or NOP
+        // Whatever follows EOL is padding, not options (smoltcp stops there too)
+        if kind == 0 { break; }
+        if kind == 1 { // NOP
             i += 1;
             continue;
         }
         if i + 1 >= options.len() { break; }
         let len = options[i+1] as usize;
-        if i + len > options.len() { break; }
+        // A length below 2 doesn't even cover kind and length: the rest can't be walked (and
+        // 0 would never advance)
+        if len < 2 || i + len > options.len() { break; }
         
         if kind == 2 { // MSS
             if len == 4 {
                 // Found MSS option!
                 let old_mss = ((options[i+2] as u16) << 8) | (options[i+3] as u16);
-                if old_mss > DEFAULT_MSS_CLAMP {
-                    options[i+2] = (DEFAULT_MSS_CLAMP >> 8) as u8;
-                    options[i+3] = (DEFAULT_MSS_CLAMP & 0xFF) as u8;
+                if old_mss > clamp {
+                    options[i+2] = (clamp >> 8) as u8;
+                    options[i+3] = (clamp & 0xFF) as u8;
+                    // A value at an odd offset sits byte-swapped in the 16-bit checksum words
+                    let (old, new) = if (20 + i) % 2 == 0 {
+                        (old_mss, clamp)
+                    } else {
+                        (old_mss.swap_bytes(), clamp.swap_bytes())
+                    };
+                    let checksum = u16::from_be_bytes([buffer[16], buffer[17]]);
+                    buffer[16..18].copy_from_slice(&update_checksum(checksum, old, new).to_be_bytes());
                 }
             }
             break; // MSS only appears once
@@ -261,6 +658,16 @@ fn clamp_mss_raw(buffer: &mut [u8]) {
     }
 }
 
+/// Incremental Internet checksum update for one 16-bit word changing from `old` to `new`
+/// (RFC 1624, eqn. 3).
+fn update_checksum(checksum: u16, old: u16, new: u16) -> u16 {
+    let mut sum = (!checksum) as u32 + (!old) as u32 + new as u32;
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -433,6 +840,143 @@ mod tests {
         assert!(matches!(get_packet_type(&[0xFF, 0x00]), PacketType::Unknown));
     }
 
+    #[test]
+    fn test_tcp_flow() {
+        let v4 = build_ipv4_tcp_syn(1460);
+        assert_eq!(
+            tcp_flow(&v4),
+            Some(("192.168.1.1:12345".parse().unwrap(), "10.0.0.1:80".parse().unwrap()))
+        );
+        let v6 = build_ipv6_tcp_syn(1460);
+        assert_eq!(
+            tcp_flow(&v6),
+            Some(("[fd00::2]:12345".parse().unwrap(), "[fd00::1]:443".parse().unwrap()))
+        );
+        assert_eq!(tcp_flow(&build_ipv4_udp()), None);
+    }
+
+    #[test]
+    fn test_flow_hash() {
+        let syn = build_ipv4_tcp_syn(1460);
+        // Flags and payload don't matter
+        let mut ack = syn.clone();
+        ack[33] = 0x10;
+        assert_eq!(flow_hash(&ack), flow_hash(&syn));
+
+        // The reply direction hashes the same, another port doesn't
+        let mut reply = syn.clone();
+        reply[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        reply[16..20].copy_from_slice(&[192, 168, 1, 1]);
+        reply[20..24].copy_from_slice(&[0, 80, (12345 >> 8) as u8, (12345 & 0xFF) as u8]);
+        assert_eq!(flow_hash(&reply), flow_hash(&syn));
+        let mut other = syn.clone();
+        other[21] ^= 1;
+        assert_ne!(flow_hash(&other), flow_hash(&syn));
+
+        assert_eq!(flow_hash(&build_ipv6_tcp_syn(1460)), flow_hash(&build_ipv6_tcp_syn(536)));
+        assert_eq!(flow_hash(&[0x00; 40]), 0);
+    }
+
+    #[test]
+    fn test_flow_hash_keeps_fragments_together() {
+        let syn = build_ipv4_tcp_syn(1460);
+        let fragment = |range: std::ops::Range<usize>, more: bool| {
+            let mut pkt = syn[..20].to_vec();
+            pkt.extend_from_slice(&syn[20 + range.start..20 + range.end]);
+            pkt[3] = pkt.len() as u8;
+            let flags_offset = (more as u16) << 13 | (range.start / 8) as u16;
+            pkt[6..8].copy_from_slice(&flags_offset.to_be_bytes());
+            compute_ipv4_checksum(&mut pkt);
+            pkt
+        };
+        // The first fragment carries the ports, the second doesn't: both hash alike
+        assert_eq!(flow_hash(&fragment(0..16, true)), flow_hash(&fragment(16..24, false)));
+
+        let first = build_ipv6_fragment(0, true, 24);
+        let tail = build_ipv6_fragment(3, false, 16);
+        assert_eq!(flow_hash(&first), flow_hash(&tail));
+        assert_eq!(ipv6_fragment_ident(&tail), Some(0x1234_5678));
+        assert_eq!(ipv6_fragment_ident(&build_ipv6_tcp_syn(1460)), None);
+    }
+
+    #[test]
+    fn test_invalid_tcp_flags() {
+        let with_flags = |flags: u8| {
+            let mut pkt = build_ipv4_tcp_syn(1460);
+            pkt[20 + 13] = flags;
+            pkt
+        };
+        assert!(has_invalid_tcp_flags(&with_flags(0x03))); // SYN+FIN
+        assert!(has_invalid_tcp_flags(&with_flags(0x06))); // SYN+RST
+        assert!(has_invalid_tcp_flags(&with_flags(0x00))); // NULL
+        assert!(has_invalid_tcp_flags(&with_flags(0x29))); // XMAS
+        assert!(!has_invalid_tcp_flags(&with_flags(0x02))); // SYN
+        assert!(!has_invalid_tcp_flags(&with_flags(0xC2))); // SYN+ECE+CWR (ECN setup)
+        assert!(!has_invalid_tcp_flags(&with_flags(0x11))); // FIN+ACK
+        assert!(!has_invalid_tcp_flags(&build_ipv4_udp()));
+    }
+
+    #[test]
+    fn test_is_trackable() {
+        for (flags, stray_ok) in [
+            (0x04, false), // RST
+            (0x14, false), // RST+ACK
+            (0x12, false), // SYN+ACK
+            (0x52, false), // SYN+ACK+ECE
+            (0x10, true),  // ACK
+            (0x18, true),  // PSH+ACK
+            (0x11, true),  // FIN+ACK
+            (0x02, true),  // SYN
+        ] {
+            assert_eq!(is_trackable(flags, false), stray_ok, "flags {flags:#04x}");
+            assert!(is_trackable(flags, true), "flags {flags:#04x}");
+        }
+    }
+
+    #[test]
+    fn test_build_rst_reply() {
+        let syn = build_ipv4_tcp_syn(1460);
+        let rst = build_rst_reply(&syn).unwrap();
+        let ip = Ipv4Packet::new_checked(&rst[..]).unwrap();
+        assert!(ip.verify_checksum());
+        let tcp = TcpPacket::new_checked(ip.payload()).unwrap();
+        let syn_ip = Ipv4Packet::new_checked(&syn[..]).unwrap();
+        let syn_tcp = TcpPacket::new_checked(syn_ip.payload()).unwrap();
+        assert_eq!((ip.src_addr(), ip.dst_addr()), (syn_ip.dst_addr(), syn_ip.src_addr()));
+        assert_eq!((tcp.src_port(), tcp.dst_port()), (syn_tcp.dst_port(), syn_tcp.src_port()));
+        assert!(tcp.rst() && tcp.ack() && !tcp.syn());
+        assert_eq!(tcp.ack_number(), syn_tcp.seq_number() + 1);
+        assert!(tcp.verify_checksum(&ip.src_addr().into(), &ip.dst_addr().into()));
+        assert!(build_rst_reply(&build_ipv4_udp()).is_none());
+    }
+
+    #[test]
+    fn test_syn_summary_rebuilds_the_syn() {
+        let syn = build_ipv4_tcp_syn(1460);
+        let summary = SynSummary::from_packet(&syn).unwrap();
+        assert_eq!(summary.max_seg_size, Some(1460));
+        let trap = inspect_packet(&syn).unwrap();
+        let rebuilt = summary.to_packet(trap.src, trap.dst).unwrap();
+        // Same TCP segment; only the IP header is smoltcp's own
+        assert_eq!(rebuilt[20..], syn[20..]);
+        assert_eq!(SynSummary::from_packet(&rebuilt), Some(summary));
+
+        // What smoltcp would drop, or a SYN carrying data, isn't summarized
+        let mut bad_checksum = syn.clone();
+        bad_checksum[20 + 16] ^= 0xff;
+        assert_eq!(SynSummary::from_packet(&bad_checksum), None);
+        let mut with_data = syn.clone();
+        with_data.extend_from_slice(b"data");
+        with_data[3] += 4;
+        with_data[20 + 16..20 + 18].fill(0);
+        compute_ipv4_checksum(&mut with_data);
+        compute_tcp_checksum_v4(&mut with_data, 20);
+        let ip = Ipv4Packet::new_checked(&with_data[..]).unwrap();
+        assert!(TcpPacket::new_checked(ip.payload()).unwrap().verify_checksum(&ip.src_addr().into(), &ip.dst_addr().into()));
+        assert_eq!(SynSummary::from_packet(&with_data), None);
+        assert_eq!(SynSummary::from_packet(&build_ipv4_udp()), None);
+    }
+
     #[test]
     fn test_inspect_ipv4_syn_detected() {
         let pkt = build_ipv4_tcp_syn(1460);
@@ -440,6 +984,7 @@ mod tests {
         assert!(trap.is_some());
         let trap = trap.unwrap();
         assert_eq!(trap.dst.port(), 80);
+        assert_eq!(trap.src, "192.168.1.1:12345".parse().unwrap());
     }
 
     #[test]
@@ -466,6 +1011,31 @@ mod tests {
         assert_eq!(clamped_mss, DEFAULT_MSS_CLAMP);
     }
 
+    #[test]
+    fn test_mss_clamping_ipv4_with_ip_options() {
+        // Same SYN with one IP option word (IHL = 6): Router Alert
+        let plain = build_ipv4_tcp_syn(1460);
+        let mut pkt = plain[..20].to_vec();
+        pkt.extend_from_slice(&[0x94, 0x04, 0x00, 0x00]);
+        pkt.extend_from_slice(&plain[20..]);
+        pkt[0] = 0x46;
+        pkt[3] = 48;
+        Ipv4Packet::new_unchecked(&mut pkt[..]).fill_checksum();
+        compute_tcp_checksum_v4(&mut pkt, 24);
+
+        let trap = inspect_packet(&pkt).expect("Should detect SYN");
+        assert_eq!(trap.dst.port(), 80);
+        let ip = Ipv4Packet::new_checked(&trap.packet[..]).unwrap();
+        assert_eq!(ip.header_len(), 24);
+        assert!(ip.verify_checksum());
+        let tcp = TcpPacket::new_checked(ip.payload()).unwrap();
+        assert!(tcp.verify_checksum(&ip.src_addr().into(), &ip.dst_addr().into()));
+        // The IP option is untouched, the MSS sits after IP(24) + TCP(20)
+        assert_eq!(&trap.packet[20..24], &[0x94, 0x04, 0x00, 0x00]);
+        let mss = u16::from_be_bytes([trap.packet[46], trap.packet[47]]);
+        assert_eq!(mss, DEFAULT_MSS_CLAMP);
+    }
+
     #[test]
     fn test_mss_not_clamped_if_small() {
         let pkt = build_ipv4_tcp_syn(536); // Already smaller than DEFAULT_MSS_CLAMP
@@ -483,6 +1053,7 @@ mod tests {
         assert!(trap.is_some());
         let trap = trap.unwrap();
         assert_eq!(trap.dst.port(), 443);
+        assert_eq!(trap.src, "[fd00::2]:12345".parse().unwrap());
     }
 
     #[test]
@@ -497,6 +1068,62 @@ mod tests {
         assert_eq!(clamped_mss, DEFAULT_MSS_CLAMP);
     }
 
+    #[test]
+    fn test_mss_clamp_per_family() {
+        let clamp = MssClamp::for_mtu(1400);
+        assert_eq!(clamp, MssClamp { v4: 1360, v6: 1340 });
+
+        let trap = inspect_packet_with_clamp(&build_ipv4_tcp_syn(1460), || clamp).unwrap();
+        let ip = Ipv4Packet::new_checked(&trap.packet[..]).unwrap();
+        let tcp = TcpPacket::new_checked(ip.payload()).unwrap();
+        assert!(tcp.verify_checksum(&ip.src_addr().into(), &ip.dst_addr().into()));
+        assert_eq!(u16::from_be_bytes([trap.packet[42], trap.packet[43]]), 1360);
+
+        let mut pkt = build_ipv6_tcp_syn(1460);
+        let (src, dst) = {
+            let ip = Ipv6Packet::new_checked(&pkt[..]).unwrap();
+            (ip.src_addr(), ip.dst_addr())
+        };
+        TcpPacket::new_unchecked(&mut pkt[40..]).fill_checksum(&src.into(), &dst.into());
+        let trap = inspect_packet_with_clamp(&pkt, || clamp).unwrap();
+        let ip = Ipv6Packet::new_checked(&trap.packet[..]).unwrap();
+        let tcp = TcpPacket::new_checked(ip.payload()).unwrap();
+        assert!(tcp.verify_checksum(&ip.src_addr().into(), &ip.dst_addr().into()));
+        assert_eq!(u16::from_be_bytes([trap.packet[62], trap.packet[63]]), 1340);
+    }
+
+    #[test]
+    fn test_mss_clamp_only_computed_for_syn() {
+        let mut pkt = build_ipv4_tcp_syn(1460);
+        pkt[33] = 0x10; // ACK
+        assert!(inspect_packet_with_clamp(&pkt, || panic!("clamp computed for an ACK")).is_none());
+    }
+
+    #[test]
+    fn test_mss_clamp_keeps_checksum_valid_at_odd_offset() {
+        // NOP, MSS, EOL padding: the MSS value starts at an odd offset of the TCP header
+        let plain = build_ipv4_tcp_syn(1460);
+        let mut pkt = plain[..40].to_vec();
+        pkt.extend_from_slice(&[1, 2, 4, 0x05, 0xB4, 0, 0, 0]);
+        pkt[3] = 48;
+        pkt[20 + 12] = 7 << 4;
+        Ipv4Packet::new_unchecked(&mut pkt[..]).fill_checksum();
+        compute_tcp_checksum_v4(&mut pkt, 20);
+
+        let trap = inspect_packet(&pkt).unwrap();
+        assert_eq!(u16::from_be_bytes([trap.packet[43], trap.packet[44]]), DEFAULT_MSS_CLAMP);
+        let ip = Ipv4Packet::new_checked(&trap.packet[..]).unwrap();
+        let tcp = TcpPacket::new_checked(ip.payload()).unwrap();
+        assert!(tcp.verify_checksum(&ip.src_addr().into(), &ip.dst_addr().into()));
+
+        // A bad checksum stays bad
+        pkt[20 + 16] ^= 0xFF;
+        let trap = inspect_packet(&pkt).unwrap();
+        let ip = Ipv4Packet::new_checked(&trap.packet[..]).unwrap();
+        let tcp = TcpPacket::new_checked(ip.payload()).unwrap();
+        assert!(!tcp.verify_checksum(&ip.src_addr().into(), &ip.dst_addr().into()));
+    }
+
     #[test]
     fn test_clamp_mss_raw_directly() {
         // Build a raw TCP header with MSS = 8960
@@ -508,12 +1135,195 @@ mod tests {
         tcp[22] = (8960 >> 8) as u8;
         tcp[23] = (8960 & 0xFF) as u8;
 
-        clamp_mss_raw(&mut tcp);
+        clamp_mss_raw(&mut tcp, DEFAULT_MSS_CLAMP);
 
         let new_mss = ((tcp[22] as u16) << 8) | (tcp[23] as u16);
         assert_eq!(new_mss, DEFAULT_MSS_CLAMP);
     }
 
+    /// A bare TCP header (checksum 0) carrying `options`, padded to a multiple of 4 bytes.
+    fn tcp_with_options(options: &[u8]) -> Vec<u8> {
+        let mut tcp = vec![0u8; 20];
+        tcp.extend_from_slice(options);
+        tcp.resize(20 + options.len().div_ceil(4) * 4, 0);
+        tcp[12] = ((tcp.len() / 4) as u8) << 4;
+        tcp[13] = 0x02; // SYN
+        tcp
+    }
+
+    #[test]
+    fn test_clamp_mss_raw_option_ordering() {
+        // MSS behind NOPs and a window scale option
+        let mut tcp = tcp_with_options(&[1, 1, 3, 3, 7, 2, 4, 0x23, 0x28]);
+        clamp_mss_raw(&mut tcp, 1400);
+        assert_eq!(&tcp[25..29], &[2, 4, 0x05, 0x78]);
+
+        // Behind an option claiming a length of 0 or 1, nothing can be trusted (or walked)
+        for len in [0, 1] {
+            let mut tcp = tcp_with_options(&[8, len, 2, 4, 0x23, 0x28]);
+            clamp_mss_raw(&mut tcp, 1400);
+            assert_eq!(&tcp[22..26], &[2, 4, 0x23, 0x28]);
+        }
+
+        // After EOL is only padding
+        let mut tcp = tcp_with_options(&[0, 2, 4, 0x23, 0x28]);
+        clamp_mss_raw(&mut tcp, 1400);
+        assert_eq!(&tcp[21..25], &[2, 4, 0x23, 0x28]);
+    }
+
+    #[test]
+    fn test_clamp_mss_raw_random_options() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        /// One's complement sum of the segment, checksum included: unchanged by a clamp that
+        /// patches the checksum correctly
+        fn folded_sum(tcp: &[u8]) -> u32 {
+            let mut sum: u32 = tcp.chunks(2).map(|w| u16::from_be_bytes([w[0], *w.get(1).unwrap_or(&0)]) as u32).sum();
+            while sum >> 16 != 0 {
+                sum = (sum & 0xFFFF) + (sum >> 16);
+            }
+            sum % 0xFFFF
+        }
+
+        let mut rng = StdRng::seed_from_u64(1084);
+        for _ in 0..20_000 {
+            // Mostly option kinds and lengths that matter here, so MSS options do show up
+            let len = rng.gen_range(0..=40);
+            let options: Vec<u8> = (0..len).map(|_| if rng.gen_bool(0.7) { rng.gen_range(0..5) } else { rng.gen() }).collect();
+            let mut tcp = tcp_with_options(&options);
+            tcp[16..18].copy_from_slice(&rng.gen::<u16>().to_be_bytes());
+            let before = tcp.clone();
+            clamp_mss_raw(&mut tcp, 536);
+
+            // At most the MSS value and the checksum changed, and the checksum still adds up
+            let changed: Vec<usize> = (0..tcp.len()).filter(|&i| tcp[i] != before[i]).collect();
+            assert!(changed.iter().filter(|&&i| !(16..18).contains(&i)).count() <= 2, "{:?}", options);
+            assert_eq!(folded_sum(&tcp), folded_sum(&before), "{:?}", options);
+        }
+    }
+
+    /// Wraps the TCP segment of `build_ipv6_tcp_syn` in a Fragment header.
+    /// `tcp_bytes` limits how much of the TCP header ends up in this fragment.
+    fn build_ipv6_fragment(frag_offset: u16, more: bool, tcp_bytes: usize) -> Vec<u8> {
+        let syn = build_ipv6_tcp_syn(1460);
+        let mut pkt = syn[..40].to_vec();
+        pkt[6] = 44; // Next Header = Fragment
+        let mut frag = [0u8; 8];
+        frag[0] = 6; // Next Header = TCP
+        let off_flags = (frag_offset << 3) | more as u16;
+        frag[2..4].copy_from_slice(&off_flags.to_be_bytes());
+        frag[4..8].copy_from_slice(&0x1234_5678u32.to_be_bytes()); // Identification
+        pkt.extend_from_slice(&frag);
+        pkt.extend_from_slice(&syn[40..40 + tcp_bytes]);
+        let payload_len = (pkt.len() - 40) as u16;
+        pkt[4..6].copy_from_slice(&payload_len.to_be_bytes());
+        pkt
+    }
+
+    #[test]
+    fn test_ipv6_first_fragment_is_no_udp_datagram() {
+        let mut pkt = build_ipv6_fragment(0, true, 20);
+        pkt[40] = 17; // Next Header = UDP
+        assert_eq!(skip_ipv6_headers(&pkt).unwrap().0, IpProtocol::Udp);
+        assert!(udp_datagram(&pkt).is_none());
+    }
+
+    #[test]
+    fn test_ipv6_non_first_fragment_is_other() {
+        // Offset 3 (24 bytes in): this fragment carries no TCP header at all
+        let pkt = build_ipv6_fragment(3, false, 16);
+        assert!(matches!(get_packet_type(&pkt), PacketType::Other));
+        assert!(inspect_packet(&pkt).is_none());
+        let (proto, offset) = skip_ipv6_headers(&pkt).unwrap();
+        assert_eq!(proto, IpProtocol::Ipv6Frag);
+        assert_eq!(offset, 40);
+    }
+
+    #[test]
+    fn test_ipv6_first_fragment_with_partial_tcp_header() {
+        // First fragment (offset 0, M=1) that only holds 8 bytes of the TCP header
+        let pkt = build_ipv6_fragment(0, true, 8);
+        assert!(matches!(get_packet_type(&pkt), PacketType::Other));
+        assert!(inspect_packet(&pkt).is_none());
+    }
+
+    #[test]
+    fn test_ipv6_first_fragment_with_full_tcp_header() {
+        // Even with the complete SYN header, a first fragment follows its tail to the blind relay
+        let pkt = build_ipv6_fragment(0, true, 24);
+        assert!(matches!(get_packet_type(&pkt), PacketType::Other));
+        assert!(inspect_packet(&pkt).is_none());
+        assert!(matches!(get_packet_type(&build_ipv6_fragment(3, false, 16)), PacketType::Other));
+    }
+
+    #[test]
+    fn test_ipv6_truncated_fragment_header() {
+        // Fragment header announced but cut short
+        let mut pkt = build_ipv6_tcp_syn(1460)[..44].to_vec();
+        pkt[5] = 4; // Payload length
+        pkt[6] = 44;
+        assert!(skip_ipv6_headers(&pkt).is_err());
+        assert!(matches!(get_packet_type(&pkt), PacketType::Other));
+        assert!(inspect_packet(&pkt).is_none());
+    }
+
+    #[test]
+    fn test_ipv4_fragments_of_a_syn() {
+        // Split after 16 bytes of the TCP header, then a second fragment at offset 16
+        let syn = build_ipv4_tcp_syn(1460);
+        let fragment = |range: std::ops::Range<usize>, more: bool| {
+            let mut pkt = syn[..20].to_vec();
+            pkt.extend_from_slice(&syn[20 + range.start..20 + range.end]);
+            pkt[3] = pkt.len() as u8;
+            let flags_offset = (more as u16) << 13 | (range.start / 8) as u16;
+            pkt[6..8].copy_from_slice(&flags_offset.to_be_bytes());
+            compute_ipv4_checksum(&mut pkt);
+            pkt
+        };
+        let first = fragment(0..16, true);
+        let second = fragment(16..24, false);
+        assert!(is_ipv4_tcp_fragment(&first) && is_ipv4_tcp_fragment(&second));
+        assert!(!is_ipv4_tcp_fragment(&syn) && !is_ipv4_tcp_fragment(&build_ipv4_udp()));
+
+        assert!(matches!(get_packet_type(&first), PacketType::Other));
+        assert!(inspect_packet(&first).is_none());
+        // The non-first fragment is never read as a TCP header of its own
+        assert!(matches!(get_packet_type(&second), PacketType::Other));
+        assert!(inspect_packet(&second).is_none());
+        assert!(tcp_flow(&second).is_none());
+    }
+
+    /// `build_ipv6_tcp_syn` with `n` Destination Options headers (8 bytes each) before TCP.
+    fn build_ipv6_ext_chain(n: usize) -> Vec<u8> {
+        let syn = build_ipv6_tcp_syn(1460);
+        let mut pkt = syn[..40].to_vec();
+        pkt[6] = if n == 0 { 6 } else { 60 };
+        for i in 0..n {
+            let next = if i + 1 == n { 6 } else { 60 };
+            // Next Header, Hdr Ext Len 0, then a PadN option filling the other 6 bytes
+            pkt.extend_from_slice(&[next, 0, 1, 4, 0, 0, 0, 0]);
+        }
+        pkt.extend_from_slice(&syn[40..]);
+        let payload_len = (pkt.len() - 40) as u16;
+        pkt[4..6].copy_from_slice(&payload_len.to_be_bytes());
+        pkt
+    }
+
+    #[test]
+    fn test_ipv6_ext_header_chain_limit() {
+        // Up to IPV6_MAX_EXT_HEADERS headers are skipped and the SYN is still trapped
+        let pkt = build_ipv6_ext_chain(IPV6_MAX_EXT_HEADERS);
+        assert_eq!(skip_ipv6_headers(&pkt), Ok((IpProtocol::Tcp, 40 + 8 * IPV6_MAX_EXT_HEADERS)));
+        assert!(matches!(get_packet_type(&pkt), PacketType::Tcp));
+        assert_eq!(inspect_packet(&pkt).unwrap().dst.port(), 443);
+
+        // One more (11 with the default) and the chain is given up on: the packet isn't
+        // trapped but still classified as IPv6 (Other, i.e. relayed), never as Unknown
+        let pkt = build_ipv6_ext_chain(IPV6_MAX_EXT_HEADERS + 1);
+        assert!(skip_ipv6_headers(&pkt).is_err());
+        assert!(matches!(get_packet_type(&pkt), PacketType::Other));
+        assert!(inspect_packet(&pkt).is_none());
+    }
+
     #[test]
     fn test_skip_ipv6_headers_simple() {
         let pkt = build_ipv6_tcp_syn(1460);
@@ -0,0 +1,267 @@
+//! Drives the stack end to end through a loopback device: no TUN, no run loop.
+
+use bytes::{Bytes, BytesMut};
+use prism::device::PrismDevice;
+use prism::stack::{PrismConfig, PrismStack};
+use smoltcp::phy::{ChecksumCapabilities, Medium};
+use smoltcp::time::Instant;
+use smoltcp::wire::{IpProtocol, Ipv4Address, Ipv4Packet, Ipv4Repr, TcpControl, TcpPacket, TcpRepr, TcpSeqNumber};
+use tokio::sync::mpsc;
+
+const CLIENT: Ipv4Address = Ipv4Address::new(10, 11, 12, 2);
+const TARGET: Ipv4Address = Ipv4Address::new(10, 11, 12, 9);
+
+/// A segment from CLIENT:40000 to TARGET:8080.
+fn segment(control: TcpControl, seq: u32, ack: Option<TcpSeqNumber>, payload: &[u8]) -> BytesMut {
+    scaled_segment(control, seq, ack, payload, None)
+}
+
+/// `segment`, with a window scale option (only sent on a SYN).
+fn scaled_segment(control: TcpControl, seq: u32, ack: Option<TcpSeqNumber>, payload: &[u8], window_scale: Option<u8>) -> BytesMut {
+    let caps = ChecksumCapabilities::default();
+    let tcp_repr = TcpRepr {
+        src_port: 40000,
+        dst_port: 8080,
+        control,
+        seq_number: TcpSeqNumber(seq as i32),
+        ack_number: ack,
+        window_len: 65535,
+        window_scale: window_scale.filter(|_| control == TcpControl::Syn),
+        max_seg_size: (control == TcpControl::Syn).then_some(1460),
+        sack_permitted: false,
+        sack_ranges: [None; 3],
+        payload,
+    };
+    let ip_repr = Ipv4Repr {
+        src_addr: CLIENT,
+        dst_addr: TARGET,
+        next_header: IpProtocol::Tcp,
+        payload_len: tcp_repr.buffer_len(),
+        hop_limit: 64,
+    };
+    let mut buf = vec![0u8; 20 + tcp_repr.buffer_len()];
+    let mut ip = Ipv4Packet::new_unchecked(&mut buf);
+    ip_repr.emit(&mut ip, &caps);
+    let mut tcp = TcpPacket::new_unchecked(ip.payload_mut());
+    tcp_repr.emit(&mut tcp, &CLIENT.into(), &TARGET.into(), &caps);
+    BytesMut::from(&buf[..])
+}
+
+/// Returns (seq, ack, syn, payload) of an emitted IPv4 TCP segment.
+fn parse(packet: &Bytes) -> (TcpSeqNumber, TcpSeqNumber, bool, Vec<u8>) {
+    let ip = Ipv4Packet::new_checked(&packet[..]).unwrap();
+    assert_eq!(ip.dst_addr(), CLIENT);
+    let tcp = TcpPacket::new_checked(ip.payload()).unwrap();
+    (tcp.seq_number(), tcp.ack_number(), tcp.syn(), tcp.payload().to_vec())
+}
+
+fn loopback_stack() -> PrismStack {
+    PrismStack::new(PrismDevice::loopback(1500, Medium::Ip), PrismConfig::default())
+}
+
+#[test]
+fn test_syn_is_trapped_and_answered() {
+    let mut stack = loopback_stack();
+    let (req_tx, mut req_rx) = mpsc::channel(4);
+    stack.set_tunnel_request_sender(req_tx);
+
+    stack.inject(segment(TcpControl::Syn, 1000, None, &[]));
+    stack.poll_once(Instant::from_millis(0));
+
+    let request = req_rx.try_recv().unwrap();
+    assert_eq!(request.target, "10.11.12.9:8080".parse().unwrap());
+    let emitted = stack.device.take_transmitted();
+    assert_eq!(emitted.len(), 1);
+    let (_, ack, syn, _) = parse(&emitted[0]);
+    assert!(syn);
+    assert_eq!(ack, TcpSeqNumber(1001));
+}
+
+#[tokio::test]
+async fn test_data_flows_through_tunnel_both_ways() {
+    let mut stack = loopback_stack();
+    let (req_tx, mut req_rx) = mpsc::channel(4);
+    stack.set_tunnel_request_sender(req_tx);
+    let now = Instant::from_millis(0);
+
+    stack.inject(segment(TcpControl::Syn, 1000, None, &[]));
+    stack.poll_once(now);
+    let mut relayer = req_rx.try_recv().unwrap();
+    let (server_seq, _, _, _) = parse(&stack.device.take_transmitted()[0]);
+    let ack = Some(server_seq + 1);
+
+    // Client -> remote
+    stack.inject(segment(TcpControl::None, 1001, ack, &[]));
+    stack.inject(segment(TcpControl::Psh, 1001, ack, b"request"));
+    stack.poll_once(now);
+    assert_eq!(relayer.rx.try_recv().unwrap(), Bytes::from_static(b"request"));
+
+    // Remote -> client
+    relayer.tx.send(Bytes::from_static(b"response")).await.unwrap();
+    stack.poll_once(now);
+    let payloads: Vec<u8> = stack.device.take_transmitted().iter().flat_map(|pkt| parse(pkt).3).collect();
+    assert_eq!(payloads, b"response");
+}
+
+#[tokio::test]
+async fn test_jumbo_tunnel_chunks_are_segmented_for_egress_mtu() {
+    // Jumbo TUN, narrow physical path: the trapped SYN's MSS is clamped to 1240, so smoltcp
+    // must cut 64KB remote chunks into segments that fit egress_mtu
+    let config = PrismConfig { egress_mtu: 1280, ..Default::default() };
+    let mut stack = PrismStack::new(PrismDevice::loopback(65535, Medium::Ip), config);
+    let (req_tx, mut req_rx) = mpsc::channel(4);
+    stack.set_tunnel_request_sender(req_tx);
+
+    stack.inject(segment(TcpControl::Syn, 1000, None, &[]));
+    stack.poll_once(Instant::from_millis(0));
+    let relayer = req_rx.try_recv().unwrap();
+    let (server_seq, _, _, _) = parse(&stack.device.take_transmitted()[0]);
+    let mut ack = server_seq + 1;
+    stack.inject(segment(TcpControl::None, 1001, Some(ack), &[]));
+
+    let data: Vec<u8> = (0..100 * 1024).map(|i| (i % 251) as u8).collect();
+    for chunk in data.chunks(64 * 1024) {
+        relayer.tx.send(Bytes::copy_from_slice(chunk)).await.unwrap();
+    }
+
+    let mut received = Vec::new();
+    let mut largest = 0;
+    for ms in 1..1000 {
+        stack.poll_once(Instant::from_millis(ms));
+        for packet in stack.device.take_transmitted() {
+            assert!(packet.len() <= 1280, "{} byte packet", packet.len());
+            largest = largest.max(packet.len());
+            let (seq, _, _, payload) = parse(&packet);
+            if seq == ack {
+                ack += payload.len();
+                received.extend_from_slice(&payload);
+            }
+        }
+        if received.len() == data.len() {
+            break;
+        }
+        // The client acknowledges everything it got, reopening the window
+        stack.inject(segment(TcpControl::None, 1001, Some(ack), &[]));
+    }
+    assert_eq!(received, data);
+    // Full-sized segments, not just small ones
+    assert_eq!(largest, 1280);
+}
+
+/// Client request at 0ms, relayer response at 5ms. Returns when (in ms) the request was first
+/// acknowledged and how many pure ACKs the stack sent for it.
+async fn request_response_acks(config: PrismConfig) -> (i64, usize) {
+    let mut stack = PrismStack::new(PrismDevice::loopback(1500, Medium::Ip), config);
+    let (req_tx, mut req_rx) = mpsc::channel(4);
+    stack.set_tunnel_request_sender(req_tx);
+
+    stack.inject(segment(TcpControl::Syn, 1000, None, &[]));
+    stack.poll_once(Instant::from_millis(0));
+    let mut relayer = req_rx.try_recv().unwrap();
+    let (server_seq, _, _, _) = parse(&stack.device.take_transmitted()[0]);
+    let ack = Some(server_seq + 1);
+    stack.inject(segment(TcpControl::None, 1001, ack, &[]));
+    stack.inject(segment(TcpControl::Psh, 1001, ack, b"request"));
+
+    let mut acked_at = None;
+    let mut pure_acks = 0;
+    for ms in 0..=50 {
+        if ms == 5 {
+            assert_eq!(relayer.rx.try_recv().unwrap(), Bytes::from_static(b"request"));
+            relayer.tx.send(Bytes::from_static(b"response")).await.unwrap();
+        }
+        stack.poll_once(Instant::from_millis(ms));
+        for packet in stack.device.take_transmitted() {
+            let (_, ack, _, payload) = parse(&packet);
+            if ack == TcpSeqNumber(1001 + 7) {
+                acked_at.get_or_insert(ms);
+                pure_acks += payload.is_empty() as usize;
+            }
+        }
+    }
+    (acked_at.unwrap(), pure_acks)
+}
+
+#[tokio::test]
+async fn test_ack_timing_follows_preset() {
+    // Acknowledged at once with its own segment, ahead of the response
+    assert_eq!(request_response_acks(PrismConfig::low_latency()).await, (0, 1));
+    // The ACK waits and rides on the response
+    assert_eq!(request_response_acks(PrismConfig::high_throughput()).await, (5, 0));
+}
+
+/// A client uploading `len` bytes that keeps as much in flight as the stack's window allows;
+/// each poll is one round trip. Returns the window scale of the SYN-ACK, the most bytes ever in
+/// flight and the round trips the upload took.
+fn bulk_upload(config: PrismConfig, window_scale: Option<u8>, len: usize) -> (Option<u8>, usize, i64) {
+    let mut stack = PrismStack::new(PrismDevice::loopback(1500, Medium::Ip), config);
+    let (req_tx, mut req_rx) = mpsc::channel(4);
+    stack.set_tunnel_request_sender(req_tx);
+    let caps = ChecksumCapabilities::default();
+    let reprs = |packets: Vec<Bytes>| {
+        packets
+            .iter()
+            .map(|packet| {
+                let ip = Ipv4Packet::new_checked(&packet[..]).unwrap();
+                let tcp = TcpPacket::new_checked(ip.payload()).unwrap();
+                let repr = TcpRepr::parse(&tcp, &TARGET.into(), &CLIENT.into(), &caps).unwrap();
+                (repr.ack_number.unwrap(), repr.window_len as usize, repr.window_scale)
+            })
+            .collect::<Vec<_>>()
+    };
+
+    stack.inject(scaled_segment(TcpControl::Syn, 1000, None, &[], window_scale));
+    stack.poll_once(Instant::from_millis(0));
+    let mut relayer = req_rx.try_recv().unwrap();
+    let syn_ack = stack.device.take_transmitted();
+    let (server_seq, _, _, _) = parse(&syn_ack[0]);
+    // The SYN-ACK's own window is never scaled
+    let (_, mut window, offered) = reprs(syn_ack)[0];
+    let shift = offered.filter(|_| window_scale.is_some()).unwrap_or(0);
+    let ack = Some(server_seq + 1);
+
+    let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+    let (mut acked, mut sent, mut received, mut in_flight) = (0, 0, 0, 0);
+    for ms in 1..1000 {
+        for chunk in data[sent..(acked + window).min(len)].chunks(1460) {
+            stack.inject(segment(TcpControl::Psh, 1001 + sent as u32, ack, chunk));
+            sent += chunk.len();
+        }
+        in_flight = in_flight.max(sent - acked);
+
+        stack.poll_once(Instant::from_millis(ms));
+        for (ack, window_len, _) in reprs(stack.device.take_transmitted()) {
+            acked = ack - TcpSeqNumber(1001);
+            window = window_len << shift;
+        }
+        while let Ok(message) = relayer.rx.try_recv() {
+            received += message.len();
+        }
+        if received == len {
+            return (offered, in_flight, ms);
+        }
+    }
+    panic!("{} of {} bytes uploaded", received, len);
+}
+
+#[test]
+fn test_large_window_needs_client_window_scaling() {
+    // The first flight is bounded by the unscaled SYN-ACK window, after that the whole 2MB
+    // buffer is advertised and 1MB goes out in one go
+    let (offered, in_flight, round_trips) = bulk_upload(PrismConfig::default(), Some(7), 1024 * 1024);
+    assert_eq!(offered, Some(6));
+    assert!(in_flight > 1024 * 1024 - 64 * 1024, "{} bytes in flight", in_flight);
+    assert_eq!(round_trips, 2);
+
+    // The window follows the configured buffer
+    let config = PrismConfig { tcp_rx_buffer: 256 * 1024, ..Default::default() };
+    let (offered, in_flight, _) = bulk_upload(config, Some(7), 1024 * 1024);
+    assert_eq!(offered, Some(3));
+    assert!((128 * 1024..=256 * 1024).contains(&in_flight), "{} bytes in flight", in_flight);
+
+    // A client that doesn't offer scaling is held to 64KB per round trip, whatever the buffer
+    let (offered, in_flight, round_trips) = bulk_upload(PrismConfig::default(), None, 1024 * 1024);
+    assert_eq!(offered, None);
+    assert!(in_flight < 64 * 1024, "{} bytes in flight", in_flight);
+    assert!(round_trips > 16, "{} round trips", round_trips);
+}
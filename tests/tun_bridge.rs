@@ -0,0 +1,240 @@
+//! The built-in TUN bridges (`PrismDevice::spawn_tun_bridge`, `spawn_multi_queue_bridge`)
+//! against mock TUN devices.
+
+use prism::constants::TUN_WRITE_ERROR_LIMIT;
+use prism::device::{PrismDevice, TunIo};
+use prism::stack::{PrismConfig, PrismStack};
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::wire::{
+    Icmpv4Message, Icmpv4Packet, Icmpv4Repr, IpProtocol, Ipv4Address, Ipv4Packet, Ipv4Repr, TcpControl, TcpPacket, TcpRepr,
+    TcpSeqNumber,
+};
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+
+/// Echo request from a client to the stack's gateway, which answers it itself.
+fn gateway_ping() -> Vec<u8> {
+    let icmp = Icmpv4Repr::EchoRequest { ident: 1, seq_no: 1, data: b"ping" };
+    let ip = Ipv4Repr {
+        src_addr: Ipv4Address::new(10, 11, 12, 2),
+        dst_addr: Ipv4Address::new(10, 11, 12, 1),
+        next_header: IpProtocol::Icmp,
+        payload_len: icmp.buffer_len(),
+        hop_limit: 64,
+    };
+    let caps = ChecksumCapabilities::default();
+    let mut pkt = vec![0u8; ip.buffer_len() + icmp.buffer_len()];
+    ip.emit(&mut Ipv4Packet::new_unchecked(&mut pkt[..]), &caps);
+    icmp.emit(&mut Icmpv4Packet::new_unchecked(&mut pkt[ip.buffer_len()..]), &caps);
+    pkt
+}
+
+/// A segment from 10.11.12.2:`src_port` to 1.2.3.4:443.
+fn tcp_segment(src_port: u16, control: TcpControl, payload: &[u8]) -> Vec<u8> {
+    let tcp = TcpRepr {
+        src_port,
+        dst_port: 443,
+        control,
+        seq_number: TcpSeqNumber(1000),
+        ack_number: (control == TcpControl::None).then_some(TcpSeqNumber(1)),
+        window_len: 65535,
+        window_scale: None,
+        max_seg_size: None,
+        sack_permitted: false,
+        sack_ranges: [None; 3],
+        payload,
+    };
+    let (src_addr, dst_addr) = (Ipv4Address::new(10, 11, 12, 2), Ipv4Address::new(1, 2, 3, 4));
+    let ip = Ipv4Repr { src_addr, dst_addr, next_header: IpProtocol::Tcp, payload_len: tcp.buffer_len(), hop_limit: 64 };
+    let caps = ChecksumCapabilities::default();
+    let mut pkt = vec![0u8; ip.buffer_len() + tcp.buffer_len()];
+    ip.emit(&mut Ipv4Packet::new_unchecked(&mut pkt[..]), &caps);
+    tcp.emit(&mut TcpPacket::new_unchecked(&mut pkt[ip.buffer_len()..]), &src_addr.into(), &dst_addr.into(), &caps);
+    pkt
+}
+
+/// A working TUN: reads come from `inbound`, writes go to `written`.
+struct ChannelTun {
+    inbound: Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+    written: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl TunIo for ChannelTun {
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inbound.lock().await.recv().await {
+            Some(pkt) => {
+                buf[..pkt.len()].copy_from_slice(&pkt);
+                Ok(pkt.len())
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    fn try_recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let pkt = self
+            .inbound
+            .try_lock()
+            .ok()
+            .and_then(|mut inbound| inbound.try_recv().ok())
+            .ok_or(io::ErrorKind::WouldBlock)?;
+        buf[..pkt.len()].copy_from_slice(&pkt);
+        Ok(pkt.len())
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let _ = self.written.send(buf.to_vec());
+        Ok(buf.len())
+    }
+}
+
+/// A TUN whose reads fail right away.
+struct UnreadableTun;
+
+impl TunIo for UnreadableTun {
+    async fn recv(&self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::other("device removed"))
+    }
+
+    fn try_recv(&self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+}
+
+/// A TUN that keeps delivering pings to the gateway but can't be written to.
+struct UnwritableTun {
+    writes: AtomicUsize,
+}
+
+impl TunIo for UnwritableTun {
+    fn recv(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<usize>> + Send {
+        let ping = gateway_ping();
+        buf[..ping.len()].copy_from_slice(&ping);
+        async move {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            Ok(ping.len())
+        }
+    }
+
+    fn try_recv(&self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+
+    fn send(&self, _buf: &[u8]) -> impl Future<Output = io::Result<usize>> + Send {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        async { Err(io::Error::other("device removed")) }
+    }
+}
+
+#[tokio::test]
+async fn test_bridge_carries_packets_both_ways() {
+    let (in_tx, in_rx) = mpsc::unbounded_channel();
+    let (out_tx, mut out_rx) = mpsc::unbounded_channel();
+    let tun = Arc::new(ChannelTun { inbound: Mutex::new(in_rx), written: out_tx });
+    let (device, handles) = PrismDevice::spawn_tun_bridge(tun, 1500);
+    let stack = tokio::spawn(PrismStack::new(device, PrismConfig::default()).run());
+
+    // Queued together, so the reader picks most of them up in one batch
+    let ping = gateway_ping();
+    for _ in 0..3 {
+        in_tx.send(ping.clone()).unwrap();
+    }
+    for _ in 0..3 {
+        let reply = tokio::time::timeout(Duration::from_secs(5), out_rx.recv()).await.unwrap().unwrap();
+        // Each packet reached the stack at its exact size: the reply mirrors it
+        assert_eq!(reply.len(), ping.len());
+        let ip = Ipv4Packet::new_checked(&reply[..]).unwrap();
+        assert_eq!(ip.dst_addr(), Ipv4Address::new(10, 11, 12, 2));
+        assert_eq!(Icmpv4Packet::new_checked(ip.payload()).unwrap().msg_type(), Icmpv4Message::EchoReply);
+    }
+
+    // Both tasks end once the stack is gone
+    stack.abort();
+    tokio::time::timeout(Duration::from_secs(5), handles.reader).await.unwrap().unwrap();
+    tokio::time::timeout(Duration::from_secs(5), handles.writer).await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_read_failure_ends_the_stack() {
+    let device = PrismDevice::run_tun_bridge(Arc::new(UnreadableTun), 1500);
+    let stack = PrismStack::new(device, PrismConfig::default());
+
+    let err = tokio::time::timeout(Duration::from_secs(5), stack.run()).await.unwrap().unwrap_err();
+    let io_err = err.downcast_ref::<io::Error>().unwrap();
+    assert_eq!(io_err.to_string(), "device removed");
+}
+
+#[tokio::test]
+async fn test_persistent_write_failure_ends_the_stack() {
+    let tun = Arc::new(UnwritableTun { writes: AtomicUsize::new(0) });
+    let device = PrismDevice::run_tun_bridge(tun.clone(), 1500);
+    let stack = PrismStack::new(device, PrismConfig::default());
+
+    let err = tokio::time::timeout(Duration::from_secs(5), stack.run()).await.unwrap().unwrap_err();
+    assert_eq!(err.downcast_ref::<io::Error>().unwrap().to_string(), "device removed");
+    // The bridge gave up after the limit instead of on the first failed write
+    assert_eq!(tun.writes.load(Ordering::Relaxed), TUN_WRITE_ERROR_LIMIT);
+}
+
+#[tokio::test]
+async fn test_custom_writer_failure_ends_the_stack() {
+    // The reading side stays open: only the writer reports the failure
+    let (_rx_tx, rx_queue) = mpsc::channel(16);
+    let (tx_queue, tx_rx) = mpsc::channel(16);
+    let device = PrismDevice::new(rx_queue, tx_queue, 1500, smoltcp::phy::Medium::Ip);
+    let link_error = device.link_error.clone();
+    let stack = PrismStack::new(device, PrismConfig::default());
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        link_error.lock().unwrap().get_or_insert(io::Error::other("device removed"));
+        drop(tx_rx);
+    });
+
+    let err = tokio::time::timeout(Duration::from_secs(5), stack.run()).await.unwrap().unwrap_err();
+    assert_eq!(err.downcast_ref::<io::Error>().unwrap().to_string(), "device removed");
+}
+
+#[tokio::test]
+async fn test_multi_queue_bridge_keeps_flows_together() {
+    const FLOWS: u16 = 32;
+    let (in_tx, in_rx) = mpsc::unbounded_channel();
+    let (out_tx, _out_rx) = mpsc::unbounded_channel();
+    let tun = Arc::new(ChannelTun { inbound: Mutex::new(in_rx), written: out_tx });
+    let (mut devices, handles) = PrismDevice::spawn_multi_queue_bridge(tun, 1500, 4);
+    assert_eq!(devices.len(), 4);
+
+    // Each flow's SYN, then its data
+    for port in 40000..40000 + FLOWS {
+        in_tx.send(tcp_segment(port, TcpControl::Syn, &[])).unwrap();
+    }
+    for port in 40000..40000 + FLOWS {
+        in_tx.send(tcp_segment(port, TcpControl::None, b"data")).unwrap();
+    }
+
+    let mut queue_of = std::collections::HashMap::new();
+    let mut received = 0;
+    while received < 2 * FLOWS {
+        for (queue, device) in devices.iter_mut().enumerate() {
+            while let Ok(pkt) = device.rx_queue.try_recv() {
+                let port = TcpPacket::new_checked(Ipv4Packet::new_checked(&pkt[..]).unwrap().payload()).unwrap().src_port();
+                assert_eq!(*queue_of.entry(port).or_insert(queue), queue, "flow {} split across queues", port);
+                received += 1;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    // Spread over more than one stack
+    assert!(queue_of.values().collect::<std::collections::HashSet<_>>().len() > 1);
+
+    // Dropping one stack stops the bridge, which closes the other queues
+    devices.pop();
+    tokio::time::timeout(Duration::from_secs(5), handles.reader).await.unwrap().unwrap();
+    assert!(devices[0].rx_queue.recv().await.is_none());
+}
@@ -0,0 +1,109 @@
+//! TX arena recycling, checked with a counting allocator.
+//!
+//! Lives in its own test binary because it installs a global allocator.
+
+use prism::constants::{TX_ARENA_SIZE, TX_POOL_MAX_SIZE};
+use prism::device::PrismDevice;
+use prism::stack::{PrismConfig, PrismStack};
+use smoltcp::phy::{Device, Medium, TxToken};
+use smoltcp::time::Instant;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use tokio::sync::mpsc;
+
+struct CountingAlloc;
+
+thread_local! {
+    // Per-thread so allocations made by the test harness on other threads don't count
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(|n| n.get())
+}
+
+#[test]
+fn test_small_packets_share_arenas() {
+    const PACKETS: usize = 10_000;
+    const LEN: usize = 100;
+
+    let (_os_tx, os_rx) = mpsc::channel(16);
+    let (tun_tx, mut tun_rx) = mpsc::channel(PACKETS);
+    let mut device = PrismDevice::new(os_rx, tun_tx, 1500, Medium::Ip);
+
+    // Warm the channel up so its block allocations don't count
+    let token = device.transmit(Instant::from_millis(0)).unwrap();
+    token.consume(LEN, |buf| buf.fill(0));
+    while tun_rx.try_recv().is_ok() {}
+
+    let before = allocations();
+    for i in 0..PACKETS {
+        let token = device.transmit(Instant::from_millis(0)).unwrap();
+        token.consume(LEN, |buf| buf.fill(i as u8));
+        let packet = tun_rx.try_recv().unwrap();
+        assert_eq!(packet.len(), LEN);
+        assert!(packet.iter().all(|b| *b == i as u8));
+    }
+    let allocated = allocations() - before;
+
+    // One arena (plus its shared header) per ~TX_ARENA_SIZE / LEN packets, nowhere near one per packet
+    let arenas = PACKETS * LEN / TX_ARENA_SIZE + 1;
+    assert!(allocated <= 4 * arenas, "{} allocations for {} packets", allocated, PACKETS);
+    assert!(device.tx_pool.len() <= TX_POOL_MAX_SIZE);
+}
+
+#[test]
+fn test_oversized_packet_gets_its_own_arena() {
+    let (_os_tx, os_rx) = mpsc::channel(16);
+    let (tun_tx, mut tun_rx) = mpsc::channel(16);
+    let mut device = PrismDevice::new(os_rx, tun_tx, TX_ARENA_SIZE * 2, Medium::Ip);
+
+    let token = device.transmit(Instant::from_millis(0)).unwrap();
+    token.consume(100, |buf| buf.fill(1));
+    let token = device.transmit(Instant::from_millis(0)).unwrap();
+    token.consume(TX_ARENA_SIZE + 1, |buf| buf.fill(2));
+
+    assert_eq!(tun_rx.try_recv().unwrap().len(), 100);
+    assert_eq!(tun_rx.try_recv().unwrap().len(), TX_ARENA_SIZE + 1);
+    // The remainder of the first arena is still pooled
+    assert!(!device.tx_pool.is_empty());
+}
+
+#[test]
+fn test_pool_counters() {
+    let (_os_tx, os_rx) = mpsc::channel(16);
+    let (tun_tx, _tun_rx) = mpsc::channel(64);
+    let device = PrismDevice::new(os_rx, tun_tx, TX_ARENA_SIZE * 2, Medium::Ip);
+    // The stack hands the device its counters
+    let mut stack = PrismStack::new(device, PrismConfig::default());
+    let stats = stack.stats();
+    let mut send = |len: usize| stack.device.transmit(Instant::from_millis(0)).unwrap().consume(len, |buf| buf.fill(0));
+
+    // One fresh arena, whose remainder serves the next packets and goes back each time
+    for _ in 0..10 {
+        send(1000);
+    }
+    let snapshot = stats.snapshot();
+    assert_eq!((snapshot.tx_pool_misses, snapshot.tx_pool_hits), (1, 9));
+    assert_eq!((snapshot.tx_pool_recycled, snapshot.tx_pool_discarded), (10, 0));
+
+    // Too big for the pooled remainder: a miss, and nothing left of its own arena to keep
+    send(TX_ARENA_SIZE + 1);
+    let snapshot = stats.snapshot();
+    assert_eq!((snapshot.tx_pool_misses, snapshot.tx_pool_hits), (2, 9));
+    assert_eq!((snapshot.tx_pool_recycled, snapshot.tx_pool_discarded), (10, 1));
+}